@@ -2,6 +2,7 @@
 
 extern crate test;
 
+use rand::rngs::OsRng;
 use tbs::{
     blind_message, combine_valid_shares, dealer_keygen, sign_blinded_msg, unblind_signature,
     verify, Message,
@@ -20,7 +21,7 @@ fn bench_blinding(bencher: &mut Bencher) {
 fn bench_signing(bencher: &mut Bencher) {
     let msg = Message::from_bytes(b"Hello World!");
     let (_bk, bmsg) = blind_message(msg);
-    let (_pk, _pks, sks) = dealer_keygen(4, 5);
+    let (_pk, _pks, sks) = dealer_keygen(4, 5, &mut OsRng);
 
     bencher.iter(|| sign_blinded_msg(bmsg, sks[0]));
 }
@@ -29,7 +30,7 @@ fn bench_signing(bencher: &mut Bencher) {
 fn bench_combine(bencher: &mut Bencher) {
     let msg = Message::from_bytes(b"Hello World!");
     let (_bk, bmsg) = blind_message(msg);
-    let (_pk, _pks, sks) = dealer_keygen(4, 5);
+    let (_pk, _pks, sks) = dealer_keygen(4, 5, &mut OsRng);
     let shares = sks
         .iter()
         .map(|sk| sign_blinded_msg(bmsg, *sk))
@@ -43,7 +44,7 @@ fn bench_combine(bencher: &mut Bencher) {
 fn bench_unblind(bencher: &mut Bencher) {
     let msg = Message::from_bytes(b"Hello World!");
     let (bk, bmsg) = blind_message(msg);
-    let (_pk, _pks, sks) = dealer_keygen(4, 5);
+    let (_pk, _pks, sks) = dealer_keygen(4, 5, &mut OsRng);
     let shares = sks
         .iter()
         .map(|sk| sign_blinded_msg(bmsg, *sk))
@@ -58,7 +59,7 @@ fn bench_unblind(bencher: &mut Bencher) {
 fn bench_verify(bencher: &mut Bencher) {
     let msg = Message::from_bytes(b"Hello World!");
     let (bk, bmsg) = blind_message(msg);
-    let (pk, _pks, sks) = dealer_keygen(4, 5);
+    let (pk, _pks, sks) = dealer_keygen(4, 5, &mut OsRng);
     let shares = sks
         .iter()
         .map(|sk| sign_blinded_msg(bmsg, *sk))
@@ -1,3 +1,4 @@
+use rand::rngs::OsRng;
 use serde::Serialize;
 use structopt::StructOpt;
 use tbs::dealer_keygen;
@@ -11,7 +12,7 @@ struct Args {
 fn main() {
     let args: Args = StructOpt::from_args();
 
-    let (pk, pks, sks) = dealer_keygen(args.threshold, args.number);
+    let (pk, pks, sks) = dealer_keygen(args.threshold, args.number, &mut OsRng);
 
     println!("apk={}", to_hex(&pk));
     for (idx, (pk, sk)) in pks.iter().zip(sks.iter()).enumerate() {
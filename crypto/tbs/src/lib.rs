@@ -9,7 +9,7 @@ use bls12_381::{pairing, G1Affine, G1Projective, G2Affine, G2Projective};
 use ff::Field;
 use group::Curve;
 use rand::rngs::OsRng;
-use rand::RngCore;
+use rand::{CryptoRng, RngCore};
 use serde::{Deserialize, Serialize};
 use sha3::digest::generic_array::typenum::U32;
 use sha3::Digest;
@@ -31,6 +31,16 @@ pub struct SecretKeyShare(#[serde(with = "serde_impl::scalar")] pub Scalar);
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct AggregatePublicKey(#[serde(with = "serde_impl::g2")] pub G2Affine);
 
+/// Secret polynomial a peer contributes to a distributed key generation. Every peer deals a share
+/// of its polynomial to every other peer, the resulting key is defined by the sum of all peers'
+/// polynomials, so no single peer knows the secret key.
+#[derive(Debug)]
+pub struct DkgPolynomial(Poly<Scalar, Scalar>);
+
+/// Public commitment to a [`DkgPolynomial`] that allows verifying the shares dealt from it
+#[derive(Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
+pub struct PolynomialCommitment(#[serde(with = "serde_impl::g2_vec")] pub Vec<G2Affine>);
+
 #[derive(Copy, Clone, Debug, Eq, PartialEq, Serialize, Deserialize)]
 pub struct BlindingKey(#[serde(with = "serde_impl::scalar")] pub Scalar);
 
@@ -119,9 +129,9 @@ impl BlindingKey {
 pub fn dealer_keygen(
     threshold: usize,
     keys: usize,
+    rng: &mut (impl RngCore + CryptoRng),
 ) -> (AggregatePublicKey, Vec<PublicKeyShare>, Vec<SecretKeyShare>) {
-    let mut rng = OsRng; // FIXME: pass rng
-    let poly = Poly::<Scalar, Scalar>::random(threshold - 1, &mut rng);
+    let poly = Poly::<Scalar, Scalar>::random(threshold - 1, rng);
     let (pub_shares, sec_shares) = (1..=keys)
         .map(|idx| {
             let sk = poly.evaluate(idx as u64);
@@ -139,6 +149,143 @@ pub fn dealer_keygen(
     )
 }
 
+impl DkgPolynomial {
+    /// * `threshold`: how many signature shares are needed to produce a signature
+    pub fn random(threshold: usize, rng: &mut (impl RngCore + CryptoRng)) -> DkgPolynomial {
+        DkgPolynomial(Poly::random(threshold - 1, rng))
+    }
+
+    /// Random polynomial that evaluates to the existing key share `share` at zero. Dealing it
+    /// reshares the key `share` belongs to among a new set of keys, the commitment's constant
+    /// term is the public key share of `share` so recipients can check the dealer's honesty.
+    pub fn reshare(
+        share: SecretKeyShare,
+        threshold: usize,
+        rng: &mut (impl RngCore + CryptoRng),
+    ) -> DkgPolynomial {
+        DkgPolynomial(Poly::random_with_constant(threshold - 1, share.0, rng))
+    }
+
+    pub fn commitment(&self) -> PolynomialCommitment {
+        PolynomialCommitment(
+            self.0
+                .coefficients()
+                .iter()
+                .map(|c| (G2Projective::generator() * c).to_affine())
+                .collect(),
+        )
+    }
+
+    /// Share of the polynomial dealt to the key with index `idx`
+    pub fn share(&self, idx: usize) -> SecretKeyShare {
+        SecretKeyShare(self.0.evaluate((idx + 1) as u64))
+    }
+}
+
+impl PolynomialCommitment {
+    /// Number of signature shares needed to produce a signature with the resulting key
+    pub fn threshold(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Public key share belonging to the secret key share with index `idx`
+    pub fn evaluate(&self, idx: usize) -> PublicKeyShare {
+        let x = Scalar::from((idx + 1) as u64);
+        let pk = self
+            .0
+            .iter()
+            .rev()
+            .fold(G2Projective::identity(), |acc, c| {
+                acc * x + G2Projective::from(c)
+            });
+        PublicKeyShare(pk.to_affine())
+    }
+
+    /// Returns `true` if `share` is the share with index `idx` of the committed polynomial
+    pub fn verify_share(&self, idx: usize, share: SecretKeyShare) -> bool {
+        share.to_pub_key_share() == self.evaluate(idx)
+    }
+
+    pub fn aggregate_public_key(&self) -> AggregatePublicKey {
+        AggregatePublicKey(self.0.first().copied().unwrap_or_else(G2Affine::identity))
+    }
+
+    /// Public key share of the key share the committed polynomial evaluates to at zero, which is
+    /// the dealer's old key share when resharing
+    pub fn constant_public_key_share(&self) -> PublicKeyShare {
+        PublicKeyShare(self.aggregate_public_key().0)
+    }
+}
+
+/// Combines the shares a new key received while resharing a key, see [`DkgPolynomial::reshare`].
+/// `shares` yields the index of each dealer's old key share and the share that dealer dealt. The
+/// result is a share of the original key if at least the original threshold of dealers took part.
+///
+/// # Panics
+/// If less than 2 shares are supplied.
+pub fn combine_reshared_shares<I>(shares: I) -> SecretKeyShare
+where
+    I: IntoIterator<Item = (usize, SecretKeyShare)>,
+    I::IntoIter: Clone,
+{
+    let points = shares
+        .into_iter()
+        .map(|(idx, share)| (Scalar::from((idx as u64) + 1), share.0));
+    SecretKeyShare(poly::interpolate_zero(points))
+}
+
+/// Commitment to the polynomial the shares combined by [`combine_reshared_shares`] lie on, given
+/// the index of each dealer's old key share and the commitment to the polynomial it dealt. All
+/// commitments have to have the same threshold.
+///
+/// # Panics
+/// If less than 2 commitments are supplied.
+pub fn combine_reshared_commitments(
+    commitments: &[(usize, PolynomialCommitment)],
+) -> PolynomialCommitment {
+    let threshold = commitments
+        .first()
+        .map(|(_, commitment)| commitment.threshold())
+        .unwrap_or(0);
+    assert!(commitments.iter().all(|(_, c)| c.threshold() == threshold));
+
+    PolynomialCommitment(
+        (0..threshold)
+            .map(|coefficient| {
+                let points = commitments.iter().map(|(idx, commitment)| {
+                    let x = Scalar::from((*idx as u64) + 1);
+                    (x, G2Projective::from(commitment.0[coefficient]))
+                });
+                let combined: G2Projective = poly::interpolate_zero(points);
+                combined.to_affine()
+            })
+            .collect(),
+    )
+}
+
+/// Commitment to the sum of the committed polynomials
+impl std::iter::Sum for PolynomialCommitment {
+    fn sum<I: Iterator<Item = PolynomialCommitment>>(iter: I) -> Self {
+        let mut sum: Vec<G2Projective> = Vec::new();
+        for commitment in iter {
+            if sum.len() < commitment.0.len() {
+                sum.resize(commitment.0.len(), G2Projective::identity());
+            }
+            for (acc, c) in sum.iter_mut().zip(commitment.0) {
+                *acc += G2Projective::from(c);
+            }
+        }
+        PolynomialCommitment(sum.into_iter().map(|c| c.to_affine()).collect())
+    }
+}
+
+/// Key share of the sum of the polynomials the shares were dealt from
+impl std::iter::Sum for SecretKeyShare {
+    fn sum<I: Iterator<Item = SecretKeyShare>>(iter: I) -> Self {
+        SecretKeyShare(iter.fold(Scalar::zero(), |acc, share| acc + share.0))
+    }
+}
+
 pub fn blind_message(msg: Message) -> (BlindingKey, BlindedMessage) {
     let mut rng = OsRng;
     let blinding_key = Scalar::random(&mut rng);
@@ -214,22 +361,142 @@ impl Aggregatable for Vec<PublicKeyShare> {
     }
 }
 
+/// Aggregates public key shares given together with the index of their key, so that the keys
+/// don't have to be consecutive
+impl Aggregatable for Vec<(usize, PublicKeyShare)> {
+    type Aggregate = AggregatePublicKey;
+
+    fn aggregate(&self, threshold: usize) -> Self::Aggregate {
+        let elements = self
+            .iter()
+            .map(|(idx, PublicKeyShare(pk))| (Scalar::from((*idx + 1) as u64), pk.into()))
+            .take(threshold);
+        let pk: G2Projective = poly::interpolate_zero(elements);
+        AggregatePublicKey(pk.to_affine())
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use crate::{
-        blind_message, combine_valid_shares, dealer_keygen, sign_blinded_msg, unblind_signature,
-        verify, Aggregatable, Message,
+        blind_message, combine_reshared_commitments, combine_reshared_shares, combine_valid_shares,
+        dealer_keygen, sign_blinded_msg, unblind_signature, verify, Aggregatable, DkgPolynomial,
+        Message, PolynomialCommitment, SecretKeyShare,
     };
+    use rand::rngs::OsRng;
 
     #[test]
     fn test_keygen() {
-        let (pk, pks, _sks) = dealer_keygen(5, 15);
+        let (pk, pks, _sks) = dealer_keygen(5, 15, &mut OsRng);
         assert_eq!(pks.len(), 15);
 
         let pka = pks.aggregate(5);
         assert_eq!(pka, pk);
     }
 
+    #[test]
+    fn test_dkg() {
+        let (threshold, keys) = (3, 4);
+        let polys = (0..keys)
+            .map(|_| DkgPolynomial::random(threshold, &mut OsRng))
+            .collect::<Vec<_>>();
+        let commitment: PolynomialCommitment = polys.iter().map(|p| p.commitment()).sum();
+        assert_eq!(commitment.threshold(), threshold);
+
+        let sks = (0..keys)
+            .map(|idx| {
+                for poly in &polys {
+                    assert!(poly.commitment().verify_share(idx, poly.share(idx)));
+                    assert!(!poly.commitment().verify_share(idx + 1, poly.share(idx)));
+                }
+                polys.iter().map(|p| p.share(idx)).sum::<SecretKeyShare>()
+            })
+            .collect::<Vec<_>>();
+        let pks = sks
+            .iter()
+            .map(|sk| sk.to_pub_key_share())
+            .collect::<Vec<_>>();
+        assert!(pks
+            .iter()
+            .enumerate()
+            .all(|(idx, pk)| commitment.evaluate(idx) == *pk));
+
+        let pk = commitment.aggregate_public_key();
+        assert_eq!(pks.aggregate(threshold), pk);
+
+        let msg = Message::from_bytes(b"Hello World!");
+        let (bkey, bmsg) = blind_message(msg);
+        let sigs = sks
+            .iter()
+            .enumerate()
+            .skip(1)
+            .map(|(idx, sk)| (idx, sign_blinded_msg(bmsg, *sk)))
+            .collect::<Vec<_>>();
+        let sig = unblind_signature(bkey, combine_valid_shares(sigs, threshold));
+        assert!(verify(msg, sig, pk));
+    }
+
+    #[test]
+    fn test_reshare() {
+        let (old_threshold, new_threshold, new_keys) = (3, 4, 5);
+        let (pk, old_pks, old_sks) = dealer_keygen(old_threshold, 4, &mut OsRng);
+
+        // Only the holders of the old keys 0, 2 and 3 take part in resharing
+        let dealers = [0, 2, 3]
+            .iter()
+            .map(|&idx| {
+                (
+                    idx,
+                    DkgPolynomial::reshare(old_sks[idx], new_threshold, &mut OsRng),
+                )
+            })
+            .collect::<Vec<_>>();
+        for (idx, poly) in &dealers {
+            assert_eq!(poly.commitment().constant_public_key_share(), old_pks[*idx]);
+        }
+
+        let commitment = combine_reshared_commitments(
+            &dealers
+                .iter()
+                .map(|(idx, poly)| (*idx, poly.commitment()))
+                .collect::<Vec<_>>(),
+        );
+        assert_eq!(commitment.threshold(), new_threshold);
+        assert_eq!(commitment.aggregate_public_key(), pk);
+
+        // The new keys don't have consecutive indices
+        let new_indices = [0, 2, 3, 5, 7];
+        assert_eq!(new_indices.len(), new_keys);
+        let sks = new_indices
+            .iter()
+            .map(|&new_idx| {
+                let sk = combine_reshared_shares(
+                    dealers
+                        .iter()
+                        .map(|(idx, poly)| (*idx, poly.share(new_idx)))
+                        .collect::<Vec<_>>(),
+                );
+                assert_eq!(commitment.evaluate(new_idx), sk.to_pub_key_share());
+                (new_idx, sk)
+            })
+            .collect::<Vec<_>>();
+        let pks = sks
+            .iter()
+            .map(|(idx, sk)| (*idx, sk.to_pub_key_share()))
+            .collect::<Vec<_>>();
+        assert_eq!(pks.aggregate(new_threshold), pk);
+
+        let msg = Message::from_bytes(b"Hello World!");
+        let (bkey, bmsg) = blind_message(msg);
+        let sigs = sks
+            .iter()
+            .skip(1)
+            .map(|(idx, sk)| (*idx, sign_blinded_msg(bmsg, *sk)))
+            .collect::<Vec<_>>();
+        let sig = unblind_signature(bkey, combine_valid_shares(sigs, new_threshold));
+        assert!(verify(msg, sig, pk));
+    }
+
     #[test]
     fn test_roundtrip() {
         let msg = Message::from_bytes(b"Hello World!");
@@ -237,7 +504,7 @@ mod tests {
 
         let (bkey, bmsg) = blind_message(msg);
 
-        let (pk, _pks, sks) = dealer_keygen(threshold, 15);
+        let (pk, _pks, sks) = dealer_keygen(threshold, 15, &mut OsRng);
 
         let mut sigs = sks
             .iter()
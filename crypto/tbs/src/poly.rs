@@ -28,6 +28,17 @@ where
         }
     }
 
+    /// Random polynomial of degree `degree` that evaluates to `constant` at zero
+    pub fn random_with_constant(degree: usize, constant: G, rng: &mut impl RngCore) -> Self {
+        let mut poly = Self::random(degree, rng);
+        poly.coefficients[0] = constant;
+        poly
+    }
+
+    pub fn coefficients(&self) -> &[G] {
+        &self.coefficients
+    }
+
     pub fn evaluate(&self, x: impl Into<S>) -> G {
         let mut result = *self
             .coefficients
@@ -47,3 +47,21 @@ pub mod g1 {
 pub mod g2 {
     impl_serde_g!(bls12_381::G2Affine, 96);
 }
+
+pub mod g2_vec {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    #[derive(Serialize, Deserialize)]
+    struct G2(#[serde(with = "super::g2")] bls12_381::G2Affine);
+
+    pub fn serialize<S: Serializer>(gs: &[bls12_381::G2Affine], s: S) -> Result<S::Ok, S::Error> {
+        gs.iter().map(|g| G2(*g)).collect::<Vec<_>>().serialize(s)
+    }
+
+    pub fn deserialize<'d, D: Deserializer<'d>>(
+        d: D,
+    ) -> Result<Vec<bls12_381::G2Affine>, D::Error> {
+        let gs: Vec<G2> = Deserialize::deserialize(d)?;
+        Ok(gs.into_iter().map(|G2(g)| g).collect())
+    }
+}
@@ -3,14 +3,13 @@ use clightningrpc::LightningRPC;
 use minimint::config::{load_from_file, ClientConfig};
 use minimint_api::transaction::OutPoint;
 use minimint_api::Coins;
-use mint_client::{ClientError, MintClient, SpendableCoin};
+use mint_client::{MintClient, SpendableCoin};
 use serde::{Deserialize, Serialize};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::Arc;
 use structopt::StructOpt;
 use tide::Response;
-use tokio::time::Duration;
 use tracing::debug;
 use tracing_subscriber::EnvFilter;
 
@@ -62,16 +61,10 @@ async fn pay_invoice(mut req: tide::Request<State>) -> tide::Result {
         .await
         .expect("error while starting reissuance");
     debug!("Fetching coins");
-    loop {
-        match mint_client.fetch_coins(OutPoint { txid, out_idx: 0 }).await {
-            Ok(()) => break,
-            // TODO: make mint error more expressive (currently any HTTP error) and maybe use custom return type instead of error for retrying
-            Err(ClientError::MintError | ClientError::OutputNotReadyYet(_)) => {
-                tokio::time::sleep(Duration::from_secs(1)).await
-            }
-            Err(_) => return Err(tide::Error::from_str(500, "fetching reissuance failed")),
-        }
-    }
+    mint_client
+        .await_coins(OutPoint { txid, out_idx: 0 })
+        .await
+        .map_err(|_| tide::Error::from_str(500, "fetching reissuance failed"))?;
 
     let invoice = pay_req.invoice;
     let ln_client = ln_client.clone();
@@ -1,8 +1,13 @@
 use crate::PeerId;
+use async_trait::async_trait;
 use rand::{CryptoRng, RngCore};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
 use std::collections::BTreeMap;
+use thiserror::Error;
 
 /// Part of a config that needs to be generated to bootstrap a new federation.
+#[async_trait(?Send)]
 pub trait GenerateConfig: Sized {
     type Params: ?Sized;
     type ClientConfig;
@@ -16,5 +21,92 @@ pub trait GenerateConfig: Sized {
         rng: impl RngCore + CryptoRng,
     ) -> (BTreeMap<PeerId, Self>, Self::ClientConfig);
 
-    // TODO: impl distributed config gen
+    /// Generates our own config together with all other peers, no peer learns the secrets of any
+    /// other peer in the process. All peers have to take part and call this function with the
+    /// same `max_evil` and `params`. If any peer misbehaves the generation fails.
+    async fn distributed_gen<R>(
+        connections: &mut dyn DkgConnections,
+        max_evil: usize,
+        params: &Self::Params,
+        rng: R,
+    ) -> Result<(Self, Self::ClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng;
+}
+
+/// Connections to the other peers used in [`GenerateConfig::distributed_gen`]. Messages may
+/// contain secret key shares, so implementations have to authenticate peers and encrypt messages.
+#[async_trait(?Send)]
+pub trait DkgConnections {
+    fn our_id(&self) -> PeerId;
+
+    /// All peers taking part in the key generation, including ourselves
+    fn peers(&self) -> &[PeerId];
+
+    /// Sends `msgs[peer]` to every peer and returns the message every peer sent us in the same
+    /// `step`. `msgs` has to contain a message for every peer, the one for ourselves is returned
+    /// as is.
+    async fn exchange_raw(
+        &mut self,
+        step: &str,
+        msgs: BTreeMap<PeerId, Vec<u8>>,
+    ) -> Result<BTreeMap<PeerId, Vec<u8>>, DkgError>;
+}
+
+#[derive(Debug, Error)]
+pub enum DkgError {
+    #[error("Peer {0} sent a malformed message")]
+    MalformedMessage(PeerId),
+    #[error("Peer {0} misbehaved: {1}")]
+    FaultyPeer(PeerId, String),
+    #[error("Peers did not agree on the generated config")]
+    ConfigMismatch,
+    #[error("Only {0} peers holding keys of the current federation took part, {1} are needed")]
+    NotEnoughDealers(usize, usize),
+}
+
+/// Typed version of [`DkgConnections::exchange_raw`]
+pub async fn exchange<T>(
+    connections: &mut dyn DkgConnections,
+    step: &str,
+    msgs: BTreeMap<PeerId, T>,
+) -> Result<BTreeMap<PeerId, T>, DkgError>
+where
+    T: Serialize + DeserializeOwned,
+{
+    let msgs = msgs
+        .into_iter()
+        .map(|(peer, msg)| {
+            let msg = bincode::serialize(&msg).expect("Serialization can't fail");
+            (peer, msg)
+        })
+        .collect();
+
+    connections
+        .exchange_raw(step, msgs)
+        .await?
+        .into_iter()
+        .map(|(peer, msg)| {
+            let msg = bincode::deserialize(&msg).map_err(|_| DkgError::MalformedMessage(peer))?;
+            Ok((peer, msg))
+        })
+        .collect()
+}
+
+/// Sends `msg` to every peer and returns the messages every peer broadcast in the same `step`,
+/// including our own
+pub async fn broadcast<T>(
+    connections: &mut dyn DkgConnections,
+    step: &str,
+    msg: T,
+) -> Result<BTreeMap<PeerId, T>, DkgError>
+where
+    T: Serialize + DeserializeOwned + Clone,
+{
+    let msgs = connections
+        .peers()
+        .iter()
+        .map(|peer| (*peer, msg.clone()))
+        .collect();
+    exchange(connections, step, msgs).await
 }
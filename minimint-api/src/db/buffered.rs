@@ -0,0 +1,259 @@
+use super::batch::{BatchItem, DbBatch, Element};
+use super::{DatabaseError, DatabaseKeyPrefix, PrefixIter, RawDatabase, SerializableDatabaseValue};
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use tracing::{error, trace};
+
+/// Database wrapper that holds back all batches applied to it until [`BufferedDatabase::commit`]
+/// is called, which writes them to the underlying database in one atomic batch. Buffered changes
+/// are visible to readers immediately, so code that reads state written by a previous batch keeps
+/// working.
+///
+/// This is used to commit all changes of a consensus epoch at once even though they are produced
+/// in several steps. Single entry writes are not buffered but applied to the underlying database
+/// directly.
+pub struct BufferedDatabase {
+    inner: Arc<dyn RawDatabase>,
+    /// Changes applied since the last commit, `None` marks a deleted key
+    pending: Mutex<BTreeMap<Vec<u8>, Option<Vec<u8>>>>,
+}
+
+/// Already serialized key, used to write buffered changes to the underlying database
+#[derive(Debug)]
+struct RawKey(Vec<u8>);
+
+/// Already serialized value, used to write buffered changes to the underlying database
+#[derive(Debug)]
+struct RawValue(Vec<u8>);
+
+impl BufferedDatabase {
+    pub fn new(inner: Arc<dyn RawDatabase>) -> BufferedDatabase {
+        BufferedDatabase {
+            inner,
+            pending: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Atomically writes all buffered changes to the underlying database
+    pub fn commit(&self) -> Result<(), DatabaseError> {
+        let mut pending = self.pending.lock().unwrap();
+
+        let mut batch = DbBatch::new();
+        batch.autocommit(|batch_tx| {
+            batch_tx.append_from_iter(pending.iter().map(|(key, value)| match value {
+                Some(value) => BatchItem::InsertElement(Element::new(
+                    RawKey(key.clone()),
+                    RawValue(value.clone()),
+                )),
+                None => BatchItem::MaybeDeleteElement(Box::new(RawKey(key.clone()))),
+            }))
+        });
+        self.inner.raw_apply_batch(batch)?;
+
+        // Only clear the buffer once the changes are persisted, otherwise they would vanish for
+        // readers in case of an error
+        pending.clear();
+        Ok(())
+    }
+
+    /// Returns `true` if there are changes that weren't committed yet
+    pub fn has_pending_changes(&self) -> bool {
+        !self.pending.lock().unwrap().is_empty()
+    }
+
+    /// Returns the buffered changes to keys starting with one of the given `prefixes`, ordered by
+    /// key. Deleted keys have no value.
+    pub fn pending_changes(&self, prefixes: &[u8]) -> Vec<(Vec<u8>, Option<Vec<u8>>)> {
+        self.pending
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(key, _)| matches!(key.first(), Some(prefix) if prefixes.contains(prefix)))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    fn get_value_buffered(
+        &self,
+        pending: &BTreeMap<Vec<u8>, Option<Vec<u8>>>,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        match pending.get(key) {
+            Some(value) => Ok(value.clone()),
+            None => self.inner.raw_get_value(key.to_vec()),
+        }
+    }
+}
+
+impl RawDatabase for BufferedDatabase {
+    fn raw_insert_entry(
+        &self,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let mut pending = self.pending.lock().unwrap();
+        let old_value = self.get_value_buffered(&pending, &key)?;
+        pending.remove(&key);
+        self.inner.raw_insert_entry(key, value)?;
+        Ok(old_value)
+    }
+
+    fn raw_get_value(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let pending = self.pending.lock().unwrap();
+        self.get_value_buffered(&pending, &key)
+    }
+
+    fn raw_remove_entry(&self, key: Vec<u8>) -> Result<Option<Vec<u8>>, DatabaseError> {
+        let mut pending = self.pending.lock().unwrap();
+        let old_value = self.get_value_buffered(&pending, &key)?;
+        pending.remove(&key);
+        self.inner.raw_remove_entry(key)?;
+        Ok(old_value)
+    }
+
+    fn raw_find_by_prefix(&self, key_prefix: Vec<u8>) -> PrefixIter {
+        let pending = self.pending.lock().unwrap();
+
+        let mut data = match self
+            .inner
+            .raw_find_by_prefix(key_prefix.clone())
+            .collect::<Result<BTreeMap<_, _>, _>>()
+        {
+            Ok(data) => data,
+            Err(e) => return Box::new(std::iter::once(Err(e))),
+        };
+
+        let buffered = pending
+            .range::<Vec<u8>, _>((&key_prefix)..)
+            .take_while(|(key, _)| key.starts_with(&key_prefix));
+        for (key, value) in buffered {
+            match value {
+                Some(value) => data.insert(key.clone(), value.clone()),
+                None => data.remove(key),
+            };
+        }
+
+        Box::new(data.into_iter().map(Ok))
+    }
+
+    fn raw_apply_batch(&self, batch: DbBatch) -> Result<(), DatabaseError> {
+        let batch: Vec<_> = batch.into();
+        let mut pending = self.pending.lock().unwrap();
+
+        for change in batch.iter() {
+            match change {
+                BatchItem::InsertNewElement(element) => {
+                    let key = element.key.to_bytes();
+                    if self.get_value_buffered(&pending, &key)?.is_some() {
+                        error!("Database replaced element! This should not happen!");
+                        trace!("Problematic key: {:?}", element.key);
+                    }
+                    pending.insert(key, Some(element.value.to_bytes()));
+                }
+                BatchItem::InsertElement(element) => {
+                    pending.insert(element.key.to_bytes(), Some(element.value.to_bytes()));
+                }
+                BatchItem::DeleteElement(key) => {
+                    let key_bytes = key.to_bytes();
+                    if self.get_value_buffered(&pending, &key_bytes)?.is_none() {
+                        error!("Database deleted absent element! This should not happen!");
+                        trace!("Problematic key: {:?}", key);
+                    }
+                    pending.insert(key_bytes, None);
+                }
+                BatchItem::MaybeDeleteElement(key) => {
+                    pending.insert(key.to_bytes(), None);
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl DatabaseKeyPrefix for RawKey {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+impl SerializableDatabaseValue for RawValue {
+    fn to_bytes(&self) -> Vec<u8> {
+        self.0.clone()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BufferedDatabase;
+    use crate::db::batch::DbBatch;
+    use crate::db::mem_impl::MemDatabase;
+    use crate::db::{Database, DatabaseKeyPrefix, DatabaseKeyPrefixConst, RawDatabase};
+    use crate::encoding::{Decodable, Encodable};
+    use std::sync::Arc;
+
+    #[derive(Debug, Encodable, Decodable)]
+    struct TestKey(u64);
+
+    impl DatabaseKeyPrefixConst for TestKey {
+        const DB_PREFIX: u8 = 0x42;
+    }
+
+    #[derive(Debug, Encodable, Decodable)]
+    struct TestKeyPrefix;
+
+    impl DatabaseKeyPrefixConst for TestKeyPrefix {
+        const DB_PREFIX: u8 = 0x42;
+    }
+
+    #[test]
+    fn test_basic_rw() {
+        let db = BufferedDatabase::new(Arc::new(MemDatabase::new()));
+        crate::db::tests::test_db_impl(Arc::new(db));
+    }
+
+    #[test]
+    fn test_batches_are_buffered_until_commit() {
+        let inner: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+        inner.insert_entry(&TestKey(1), &1u64).unwrap();
+        inner.insert_entry(&TestKey(2), &2u64).unwrap();
+
+        let buffered_db = BufferedDatabase::new(inner.clone());
+        let db: &dyn RawDatabase = &buffered_db;
+
+        let mut batch = DbBatch::new();
+        batch.autocommit(|batch_tx| {
+            batch_tx.append_insert(TestKey(1), 10u64);
+            batch_tx.append_delete(TestKey(2));
+            batch_tx.append_insert_new(TestKey(3), 30u64);
+        });
+        db.apply_batch(batch).unwrap();
+        assert!(buffered_db.has_pending_changes());
+        let changed_keys = buffered_db
+            .pending_changes(&[TestKey::DB_PREFIX])
+            .into_iter()
+            .map(|(key, value)| (key, value.is_some()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            changed_keys,
+            vec![
+                (DatabaseKeyPrefix::to_bytes(&TestKey(1)), true),
+                (DatabaseKeyPrefix::to_bytes(&TestKey(2)), false),
+                (DatabaseKeyPrefix::to_bytes(&TestKey(3)), true)
+            ]
+        );
+        assert!(buffered_db.pending_changes(&[0x43]).is_empty());
+
+        let load_state = |db: &dyn RawDatabase| {
+            db.find_by_prefix::<_, TestKey, u64>(&TestKeyPrefix)
+                .map(|res| res.map(|(key, value)| (key.0, value)).unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(load_state(db), vec![(1, 10), (3, 30)]);
+        assert_eq!(load_state(inner.as_ref()), vec![(1, 1), (2, 2)]);
+
+        buffered_db.commit().unwrap();
+        assert!(!buffered_db.has_pending_changes());
+        assert_eq!(load_state(inner.as_ref()), vec![(1, 10), (3, 30)]);
+    }
+}
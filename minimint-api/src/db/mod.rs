@@ -8,6 +8,7 @@ use thiserror::Error;
 use tracing::trace;
 
 pub mod batch;
+pub mod buffered;
 pub mod mem_impl;
 pub mod sled_impl;
 
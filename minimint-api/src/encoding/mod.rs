@@ -191,6 +191,18 @@ where
     }
 }
 
+impl Encodable for String {
+    fn consensus_encode<W: std::io::Write>(&self, writer: W) -> Result<usize, Error> {
+        self.as_bytes().consensus_encode(writer)
+    }
+}
+
+impl Decodable for String {
+    fn consensus_decode<D: std::io::Read>(d: D) -> Result<Self, DecodeError> {
+        String::from_utf8(Vec::<u8>::consensus_decode(d)?).map_err(DecodeError::from_err)
+    }
+}
+
 impl Encodable for () {
     fn consensus_encode<W: std::io::Write>(&self, _writer: W) -> Result<usize, std::io::Error> {
         Ok(0)
@@ -295,6 +307,14 @@ mod tests {
         test_roundtrip_expected(reference, &bytes);
     }
 
+    #[test]
+    fn test_string() {
+        test_roundtrip_expected(
+            "abc".to_string(),
+            &[3, 0, 0, 0, 0, 0, 0, 0, 0x61, 0x62, 0x63],
+        );
+    }
+
     #[test]
     fn test_derive_enum() {
         #[derive(Debug, Encodable, Decodable, Eq, PartialEq)]
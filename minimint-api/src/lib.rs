@@ -14,7 +14,8 @@ pub mod config;
 pub mod db;
 pub mod encoding;
 mod keys;
-mod module;
+pub mod metrics;
+pub mod module;
 pub mod outcome;
 pub mod transaction;
 mod tweakable;
@@ -108,21 +109,72 @@ pub struct SigResponse(pub Coins<tbs::BlindedSignature>);
 
 /// A cryptographic coin consisting of a token and a threshold signature by the federated mint. In
 /// this form it can oly be validated, not spent since for that the corresponding [`musig::SecKey`]
-/// is required.
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
-pub struct Coin(pub CoinNonce, pub tbs::Signature);
+/// is required. The coin also names the mint key set it was signed with.
+#[derive(Debug, Clone, Eq, PartialEq, Hash)]
+pub struct Coin(pub CoinNonce, pub tbs::Signature, pub KeySetId);
+
+/// Identifies a set of mint keys. The mint's keys are rotated from time to time, new coins are
+/// always issued under the newest key set while coins of older ones are accepted until the key
+/// set is deprecated. Key sets are numbered in the order they were introduced, starting at `0`.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    Eq,
+    PartialEq,
+    Ord,
+    PartialOrd,
+    Hash,
+    Deserialize,
+    Serialize,
+    Encodable,
+    Decodable,
+)]
+pub struct KeySetId(pub u16);
 
 /// A unique coin nonce which is also a MuSig pub key so that transactions can be signed by the
 /// spent coin's spending keys to avoid mint frontrunning.
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub struct CoinNonce(pub secp256k1_zkp::schnorrsig::PublicKey);
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Fees charged by the federation. Absolute fees are charged per coin for coin spends and
+/// issuances and per transaction for peg-ins and peg-outs, which additionally pay a fee
+/// proportional to their amount in parts per million.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
 pub struct FeeConsensus {
     pub fee_coin_spend_abs: Amount,
     pub fee_peg_in_abs: Amount,
     pub fee_coin_issuance_abs: Amount,
     pub fee_peg_out_abs: Amount,
+    #[serde(default)]
+    pub fee_peg_in_ppm: u64,
+    #[serde(default)]
+    pub fee_peg_out_ppm: u64,
+}
+
+impl FeeConsensus {
+    /// Highest proportional fee that can be configured, it takes the whole amount
+    pub const MAX_PPM: u64 = 1_000_000;
+
+    pub fn peg_in_fee(&self, amount: Amount) -> Amount {
+        self.fee_peg_in_abs + proportional_fee(amount, self.fee_peg_in_ppm)
+    }
+
+    pub fn peg_out_fee(&self, amount: Amount) -> Amount {
+        self.fee_peg_out_abs + proportional_fee(amount, self.fee_peg_out_ppm)
+    }
+
+    /// Checks that no proportional fee exceeds [`FeeConsensus::MAX_PPM`]
+    pub fn is_valid(&self) -> bool {
+        self.fee_peg_in_ppm <= Self::MAX_PPM && self.fee_peg_out_ppm <= Self::MAX_PPM
+    }
+}
+
+/// `ppm` parts per million of `amount`, rounded down
+fn proportional_fee(amount: Amount, ppm: u64) -> Amount {
+    let fee = amount.milli_sat as u128 * ppm as u128 / 1_000_000;
+    Amount::from_msat(fee.min(u64::MAX as u128) as u64)
 }
 
 impl PeerId {
@@ -155,12 +207,34 @@ impl Coin {
         tbs::verify(self.0.to_message(), self.1, pk)
     }
 
+    /// The key set the coin was signed with
+    pub fn key_set(&self) -> KeySetId {
+        self.2
+    }
+
     /// Access the nonce as the public key to the spend key
     pub fn spend_key(&self) -> &secp256k1_zkp::schnorrsig::PublicKey {
         &self.0 .0
     }
 }
 
+impl KeySetId {
+    /// Key set of federations created before key sets were introduced. Its coins and spent
+    /// nonces keep the encoding they had back then.
+    pub const LEGACY: KeySetId = KeySetId(0);
+
+    /// The key set following this one
+    pub fn next(self) -> KeySetId {
+        KeySetId(self.0 + 1)
+    }
+}
+
+impl std::fmt::Display for KeySetId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl CoinNonce {
     pub fn to_bytes(&self) -> Vec<u8> {
         let mut bytes = vec![];
@@ -516,6 +590,120 @@ where
     }
 }
 
+/// Flag that is set in the first byte of every compressed BLS12-381 point
+const COMPRESSION_FLAG: u8 = 0x80;
+
+/// Coins of [`KeySetId::LEGACY`] are encoded like before key sets existed, so coins held by
+/// clients and transactions in the epoch history remain valid. Coins of later key sets clear the
+/// compression flag of their signature, which is always set otherwise, and are followed by their
+/// key set.
+impl Encodable for Coin {
+    fn consensus_encode<W: std::io::Write>(&self, mut writer: W) -> Result<usize, Error> {
+        let mut len = self.0.consensus_encode(&mut writer)?;
+        let mut signature = self.1 .0.to_compressed();
+        if self.2 == KeySetId::LEGACY {
+            writer.write_all(&signature)?;
+            len += signature.len();
+        } else {
+            signature[0] &= !COMPRESSION_FLAG;
+            writer.write_all(&signature)?;
+            len += signature.len();
+            len += self.2.consensus_encode(&mut writer)?;
+        }
+        Ok(len)
+    }
+}
+
+impl Decodable for Coin {
+    fn consensus_decode<D: std::io::Read>(mut d: D) -> Result<Self, DecodeError> {
+        let nonce = CoinNonce::consensus_decode(&mut d)?;
+        let mut signature = [0u8; 48];
+        d.read_exact(&mut signature)
+            .map_err(DecodeError::from_err)?;
+        if signature[0] & COMPRESSION_FLAG != 0 {
+            let signature = tbs::Signature::consensus_decode(&signature[..])?;
+            return Ok(Coin(nonce, signature, KeySetId::LEGACY));
+        }
+
+        signature[0] |= COMPRESSION_FLAG;
+        let signature = tbs::Signature::consensus_decode(&signature[..])?;
+        let key_set = KeySetId::consensus_decode(&mut d)?;
+        if key_set == KeySetId::LEGACY {
+            return Err(DecodeError::from_str(
+                "Coins of the legacy key set have to use the legacy encoding",
+            ));
+        }
+        Ok(Coin(nonce, signature, key_set))
+    }
+}
+
+/// Serde layout of [`Coin`]: the nonce and the consensus encoding of the signature and key set.
+/// For the legacy key set the latter is just the compressed signature, so coins serialized before
+/// key sets were introduced can still be read.
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "Coin")]
+struct SerdeCoin(CoinNonce, SerdeCoinSignature);
+
+/// Serialized like a [`tbs::Signature`], as hex string in human readable formats and as bytes
+/// otherwise
+struct SerdeCoinSignature(Vec<u8>);
+
+impl Serialize for Coin {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut bytes = Vec::new();
+        let nonce_len = self
+            .0
+            .consensus_encode(&mut std::io::sink())
+            .expect("Writing to a sink can't fail");
+        self.consensus_encode(&mut bytes)
+            .expect("Writing to a vec can't fail");
+        SerdeCoin(
+            self.0.clone(),
+            SerdeCoinSignature(bytes.split_off(nonce_len)),
+        )
+        .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Coin {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let SerdeCoin(nonce, SerdeCoinSignature(signature)) = SerdeCoin::deserialize(deserializer)?;
+        let mut bytes = Vec::new();
+        nonce
+            .consensus_encode(&mut bytes)
+            .expect("Writing to a vec can't fail");
+        bytes.extend(signature);
+
+        let mut cursor = std::io::Cursor::new(&bytes);
+        let coin = Coin::consensus_decode(&mut cursor).map_err(serde::de::Error::custom)?;
+        if cursor.position() != bytes.len() as u64 {
+            return Err(serde::de::Error::custom("Trailing bytes after coin"));
+        }
+        Ok(coin)
+    }
+}
+
+impl Serialize for SerdeCoinSignature {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&hex::encode(&self.0))
+        } else {
+            serializer.serialize_bytes(&self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for SerdeCoinSignature {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let bytes = if deserializer.is_human_readable() {
+            hex::decode(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)?
+        } else {
+            Vec::<u8>::deserialize(deserializer)?
+        };
+        Ok(SerdeCoinSignature(bytes))
+    }
+}
+
 impl Encodable for TransactionId {
     fn consensus_encode<W: std::io::Write>(&self, mut writer: W) -> Result<usize, Error> {
         let bytes = &self[..];
@@ -541,3 +729,109 @@ impl From<SignRequest> for Coins<BlindToken> {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::encoding::{Decodable, Encodable};
+    use crate::{Amount, Coin, CoinNonce, FeeConsensus, KeySetId};
+    use serde::{Deserialize, Serialize};
+
+    fn coin(key_set: KeySetId) -> Coin {
+        let secp = secp256k1_zkp::Secp256k1::new();
+        let key_pair = secp256k1_zkp::schnorrsig::KeyPair::from_seckey_slice(&secp, &[1; 32])
+            .expect("Valid key");
+        let nonce = secp256k1_zkp::schnorrsig::PublicKey::from_keypair(&secp, &key_pair);
+        let signature = tbs::Signature(tbs::Message::from_bytes(b"coin").0);
+        Coin(CoinNonce(nonce), signature, key_set)
+    }
+
+    fn assert_encoding(coin: &Coin, expected: &[u8]) {
+        let mut bytes = Vec::new();
+        assert_eq!(coin.consensus_encode(&mut bytes).unwrap(), expected.len());
+        assert_eq!(bytes, expected);
+        assert_eq!(&Coin::consensus_decode(expected).unwrap(), coin);
+    }
+
+    #[test]
+    fn test_coin_encoding() {
+        // Coins of the legacy key set are encoded like before key sets were introduced
+        let legacy = coin(KeySetId::LEGACY);
+        let mut legacy_bytes = legacy.0 .0.serialize().to_vec();
+        legacy_bytes.extend_from_slice(&legacy.1 .0.to_compressed());
+        assert_encoding(&legacy, &legacy_bytes);
+
+        let rotated = coin(KeySetId(1));
+        let mut rotated_bytes = legacy_bytes.clone();
+        rotated_bytes[32] &= 0x7f;
+        rotated_bytes.extend_from_slice(&[1, 0]);
+        assert_encoding(&rotated, &rotated_bytes);
+
+        // Legacy coins can only be encoded one way, otherwise transactions would be malleable
+        let mut non_canonical = legacy_bytes;
+        non_canonical[32] &= 0x7f;
+        non_canonical.extend_from_slice(&[0, 0]);
+        assert!(Coin::consensus_decode(&non_canonical[..]).is_err());
+
+        // Coins following each other are decoded correctly
+        let coins = vec![coin(KeySetId(2)), legacy, coin(KeySetId(1))];
+        let mut bytes = Vec::new();
+        coins.consensus_encode(&mut bytes).unwrap();
+        assert_eq!(Vec::<Coin>::consensus_decode(&bytes[..]).unwrap(), coins);
+    }
+
+    #[test]
+    fn test_coin_serde() {
+        // Layout of coins before key sets were introduced, e.g. in exported coin strings
+        #[derive(Debug, Serialize, Deserialize)]
+        struct PreKeySetCoin(CoinNonce, tbs::Signature);
+
+        let legacy = coin(KeySetId::LEGACY);
+        let old_coin = PreKeySetCoin(legacy.0.clone(), legacy.1);
+        let old_bytes = bincode::serialize(&vec![old_coin]).unwrap();
+        assert_eq!(
+            bincode::deserialize::<Vec<Coin>>(&old_bytes).unwrap(),
+            vec![legacy.clone()]
+        );
+        assert_eq!(bincode::serialize(&vec![legacy]).unwrap(), old_bytes);
+
+        let rotated = coin(KeySetId(1));
+        let bytes = bincode::serialize(&rotated).unwrap();
+        assert_eq!(bincode::deserialize::<Coin>(&bytes).unwrap(), rotated);
+        // Old clients can't mistake coins of later key sets for legacy ones
+        assert!(bincode::deserialize::<PreKeySetCoin>(&bytes).is_err());
+    }
+
+    #[test]
+    fn test_proportional_fees() {
+        let fees = FeeConsensus {
+            fee_coin_spend_abs: Amount::ZERO,
+            fee_peg_in_abs: Amount::from_sat(500),
+            fee_coin_issuance_abs: Amount::ZERO,
+            fee_peg_out_abs: Amount::ZERO,
+            fee_peg_in_ppm: 1_000,
+            fee_peg_out_ppm: FeeConsensus::MAX_PPM,
+        };
+        assert!(fees.is_valid());
+
+        assert_eq!(fees.peg_in_fee(Amount::ZERO), Amount::from_sat(500));
+        assert_eq!(
+            fees.peg_in_fee(Amount::from_sat(100_000)),
+            Amount::from_sat(600)
+        );
+        // Rounded down to the next msat
+        assert_eq!(
+            fees.peg_in_fee(Amount::from_msat(1_999)),
+            Amount::from_msat(500_001)
+        );
+        assert_eq!(
+            fees.peg_out_fee(Amount::from_msat(u64::MAX)),
+            Amount::from_msat(u64::MAX)
+        );
+
+        let invalid = FeeConsensus {
+            fee_peg_out_ppm: FeeConsensus::MAX_PPM + 1,
+            ..fees
+        };
+        assert!(!invalid.is_valid());
+    }
+}
@@ -0,0 +1,259 @@
+//! Simple metrics that are exported in the [Prometheus text format]. Every component owns its
+//! metrics and writes them to a [`MetricsEncoder`] when they are scraped, see
+//! [`crate::FederationModule::encode_metrics`].
+//!
+//! [Prometheus text format]: https://prometheus.io/docs/instrumenting/exposition_formats/
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// Bucket boundaries in seconds suitable for timing network requests and consensus epochs
+pub const DURATION_BUCKETS: &[f64] = &[
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0,
+];
+
+/// Bucket boundaries suitable for counting items
+pub const COUNT_BUCKETS: &[f64] = &[0.0, 1.0, 2.0, 5.0, 10.0, 20.0, 50.0, 100.0, 200.0, 500.0];
+
+/// Value that only ever increases
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+/// Counters partitioned by the value of a single label, e.g. the error that occurred
+#[derive(Debug)]
+pub struct CounterVec {
+    label: &'static str,
+    counters: Mutex<BTreeMap<String, u64>>,
+}
+
+/// Samples counted in buckets by their value, e.g. request durations
+#[derive(Debug)]
+pub struct Histogram {
+    /// Upper bounds of the buckets in ascending order, the implicit `+Inf` bucket is not included
+    buckets: &'static [f64],
+    state: Mutex<HistogramState>,
+}
+
+#[derive(Debug)]
+struct HistogramState {
+    /// Number of samples per bucket, samples are only counted in the smallest matching bucket
+    counts: Vec<u64>,
+    sum: f64,
+}
+
+/// Collects metrics and renders them in the Prometheus text format
+#[derive(Debug, Default)]
+pub struct MetricsEncoder {
+    output: String,
+}
+
+impl Counter {
+    pub fn inc(&self) {
+        self.inc_by(1);
+    }
+
+    pub fn inc_by(&self, value: u64) {
+        self.0.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
+impl CounterVec {
+    pub fn new(label: &'static str) -> CounterVec {
+        CounterVec {
+            label,
+            counters: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub fn inc(&self, label_value: &str) {
+        *self
+            .counters
+            .lock()
+            .expect("Lock poisoned")
+            .entry(label_value.to_string())
+            .or_default() += 1;
+    }
+
+    pub fn get(&self, label_value: &str) -> u64 {
+        self.counters
+            .lock()
+            .expect("Lock poisoned")
+            .get(label_value)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl Histogram {
+    /// Creates a histogram with the given bucket boundaries, they have to be sorted
+    pub fn new(buckets: &'static [f64]) -> Histogram {
+        debug_assert!(buckets.windows(2).all(|pair| pair[0] < pair[1]));
+        Histogram {
+            buckets,
+            state: Mutex::new(HistogramState {
+                counts: vec![0; buckets.len() + 1],
+                sum: 0.0,
+            }),
+        }
+    }
+
+    pub fn observe(&self, value: f64) {
+        let bucket = self
+            .buckets
+            .iter()
+            .position(|bound| value <= *bound)
+            .unwrap_or(self.buckets.len());
+
+        let mut state = self.state.lock().expect("Lock poisoned");
+        state.counts[bucket] += 1;
+        state.sum += value;
+    }
+
+    pub fn observe_duration(&self, duration: Duration) {
+        self.observe(duration.as_secs_f64());
+    }
+
+    /// Returns the number of samples observed so far
+    pub fn count(&self) -> u64 {
+        self.state
+            .lock()
+            .expect("Lock poisoned")
+            .counts
+            .iter()
+            .sum()
+    }
+}
+
+impl MetricsEncoder {
+    pub fn new() -> MetricsEncoder {
+        MetricsEncoder::default()
+    }
+
+    pub fn counter(&mut self, name: &str, help: &str, counter: &Counter) {
+        self.header(name, help, "counter");
+        self.sample(name, &[], counter.get() as f64);
+    }
+
+    pub fn counter_vec(&mut self, name: &str, help: &str, counters: &CounterVec) {
+        self.header(name, help, "counter");
+        let values = counters.counters.lock().expect("Lock poisoned").clone();
+        for (label_value, value) in values {
+            self.sample(name, &[(counters.label, &label_value)], value as f64);
+        }
+    }
+
+    /// Writes a value that can go up and down, such values are usually read when the metrics are
+    /// scraped instead of being tracked continuously
+    pub fn gauge(&mut self, name: &str, help: &str, value: f64) {
+        self.header(name, help, "gauge");
+        self.sample(name, &[], value);
+    }
+
+    pub fn histogram(&mut self, name: &str, help: &str, histogram: &Histogram) {
+        let (counts, sum) = {
+            let state = histogram.state.lock().expect("Lock poisoned");
+            (state.counts.clone(), state.sum)
+        };
+
+        self.header(name, help, "histogram");
+        let bucket_name = format!("{}_bucket", name);
+        let mut cumulative = 0;
+        for (bound, count) in histogram.buckets.iter().zip(&counts) {
+            cumulative += count;
+            self.sample(
+                &bucket_name,
+                &[("le", &bound.to_string())],
+                cumulative as f64,
+            );
+        }
+        let total = counts.iter().sum::<u64>();
+        self.sample(&bucket_name, &[("le", "+Inf")], total as f64);
+        self.sample(&format!("{}_sum", name), &[], sum);
+        self.sample(&format!("{}_count", name), &[], total as f64);
+    }
+
+    /// Returns the metrics in the Prometheus text format
+    pub fn finish(self) -> String {
+        self.output
+    }
+
+    fn header(&mut self, name: &str, help: &str, metric_type: &str) {
+        writeln!(self.output, "# HELP {} {}", name, help.replace('\n', " ")).expect("Can't fail");
+        writeln!(self.output, "# TYPE {} {}", name, metric_type).expect("Can't fail");
+    }
+
+    fn sample(&mut self, name: &str, labels: &[(&str, &str)], value: f64) {
+        self.output.push_str(name);
+        if !labels.is_empty() {
+            let labels = labels
+                .iter()
+                .map(|(label, value)| format!("{}=\"{}\"", label, escape_label_value(value)))
+                .collect::<Vec<_>>();
+            write!(self.output, "{{{}}}", labels.join(",")).expect("Can't fail");
+        }
+        writeln!(self.output, " {}", value).expect("Can't fail");
+    }
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Counter, CounterVec, Histogram, MetricsEncoder};
+
+    #[test]
+    fn test_encode_metrics() {
+        let counter = Counter::default();
+        counter.inc_by(3);
+
+        let errors = CounterVec::new("error");
+        errors.inc("double_spend");
+        errors.inc("double_spend");
+        errors.inc("invalid \"sig\"");
+
+        let histogram = Histogram::new(&[1.0, 5.0]);
+        histogram.observe(0.5);
+        histogram.observe(2.0);
+        histogram.observe(10.0);
+
+        let mut encoder = MetricsEncoder::new();
+        encoder.counter("txs_total", "Transactions", &counter);
+        encoder.counter_vec("errors_total", "Errors", &errors);
+        encoder.gauge("utxos", "UTXOs", 7.0);
+        encoder.histogram("duration_seconds", "Duration", &histogram);
+
+        let expected = "\
+# HELP txs_total Transactions
+# TYPE txs_total counter
+txs_total 3
+# HELP errors_total Errors
+# TYPE errors_total counter
+errors_total{error=\"double_spend\"} 2
+errors_total{error=\"invalid \\\"sig\\\"\"} 1
+# HELP utxos UTXOs
+# TYPE utxos gauge
+utxos 7
+# HELP duration_seconds Duration
+# TYPE duration_seconds histogram
+duration_seconds_bucket{le=\"1\"} 1
+duration_seconds_bucket{le=\"5\"} 2
+duration_seconds_bucket{le=\"+Inf\"} 3
+duration_seconds_sum 12.5
+duration_seconds_count 3
+";
+        assert_eq!(encoder.finish(), expected);
+        assert_eq!(histogram.count(), 3);
+    }
+}
@@ -0,0 +1,258 @@
+mod registry;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+
+use crate::db::batch::BatchTx;
+use crate::encoding::{Decodable, DecodeError, Encodable};
+use crate::metrics::MetricsEncoder;
+use crate::outcome::Final;
+use crate::transaction::{TransactionInput, TransactionItem};
+use crate::{Amount, PeerId};
+use async_trait::async_trait;
+use rand::CryptoRng;
+use secp256k1_zkp::rand::RngCore;
+use serde::{Deserialize, Serialize};
+
+pub use registry::{
+    ErasedFederationModule, InputMeta, ModuleError, ModuleRegistry, ModuleRng, OutputMeta,
+};
+
+/// Module id of the built-in mint module
+pub const MINT_MODULE_ID: ModuleId = ModuleId(0);
+/// Module id of the built-in wallet module
+pub const WALLET_MODULE_ID: ModuleId = ModuleId(1);
+
+/// Identifies a module registered with the federation. All transaction in- and outputs, output
+/// outcomes and module consensus items are tagged with the id of the module responsible for them.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    Serialize,
+    Deserialize,
+    Encodable,
+    Decodable,
+)]
+pub struct ModuleId(pub u16);
+
+/// Type-erased item (input, output, output outcome or consensus item) belonging to a module. The
+/// `data` field holds the consensus encoding of the module's concrete type which only the module
+/// identified by `module` knows how to interpret.
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct ModuleItem {
+    pub module: ModuleId,
+    #[serde(with = "serde_hex")]
+    pub data: Vec<u8>,
+}
+
+/// Randomness the federation agreed on, which no peer could predict or bias. It is derived from
+/// the federation's threshold signature over an epoch number, modules receive each one once in
+/// [`FederationModule::begin_consensus_epoch`].
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct RandomnessBeacon {
+    /// Epoch whose number was signed, beacons of later epochs replace this one
+    pub epoch: u64,
+    pub value: [u8; 32],
+}
+
+/// Error that is reported to API clients, which can tell the different kinds apart by a stable,
+/// machine-readable code
+pub trait ErrorCode {
+    /// Code identifying the error variant, it must never change once released
+    fn error_code(&self) -> &'static str;
+}
+
+#[async_trait(?Send)]
+pub trait FederationModule {
+    type Error: std::error::Error + ErrorCode + Send + 'static;
+    type TxInput: TransactionInput + Encodable + Decodable;
+    type TxOutput: TransactionItem + Encodable + Decodable;
+    type TxOutputOutcome: Final + Encodable + Decodable;
+    type ConsensusItem: Encodable + Decodable;
+
+    /// This module's contribution to the next consensus proposal
+    async fn consensus_proposal<'a>(
+        &'a self,
+        rng: impl RngCore + CryptoRng + 'a,
+    ) -> Vec<Self::ConsensusItem>;
+
+    /// This function is called once before transaction processing starts. All module consensus
+    /// items of this round are supplied as `consensus_items`, `epoch` is the number of the
+    /// consensus epoch being processed. The batch will be committed to the database after all
+    /// other modules ran `begin_consensus_epoch`, so the results are available when processing
+    /// transactions.
+    ///
+    /// `beacon` is the [`RandomnessBeacon`] produced in this epoch, `None` if the peers didn't
+    /// reveal enough shares for a new one. Modules must use it instead of randomness contributed
+    /// by the peers, which the last peer to contribute could bias, and must not keep using it in
+    /// later epochs.
+    async fn begin_consensus_epoch<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        epoch: u64,
+        beacon: Option<RandomnessBeacon>,
+        consensus_items: Vec<(PeerId, Self::ConsensusItem)>,
+        rng: impl RngCore + CryptoRng + 'a,
+    );
+
+    /// Validate a transaction input before submitting it to the unconfirmed transaction pool. This
+    /// function has no side effects and may be called at any time. False positives due to outdated
+    /// database state are ok since they get filtered out after consensus has been reached on them
+    /// and merely generate a warning.
+    fn validate_input(&self, input: &Self::TxInput) -> Result<Amount, Self::Error>;
+
+    /// Try to spend a transaction input. On success all necessary updates will be part of the
+    /// database `batch`. On failure (e.g. double spend) the batch is reset and the operation will
+    /// take no effect.
+    ///
+    /// This function may only be called after `begin_consensus_epoch` and before
+    /// `end_consensus_epoch`. Data is only written to the database once all transaction have been
+    /// processed.
+    fn apply_input<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        input: &'a Self::TxInput,
+    ) -> Result<Amount, Self::Error>;
+
+    /// Validate a transaction output before submitting it to the unconfirmed transaction pool. This
+    /// function has no side effects and may be called at any time. False positives due to outdated
+    /// database state are ok since they get filtered out after consensus has been reached on them
+    /// and merely generate a warning.
+    fn validate_output(&self, output: &Self::TxOutput) -> Result<Amount, Self::Error>;
+
+    /// Try to create an output (e.g. issue coins, peg-out BTC, …). On success all necessary updates
+    /// to the database will be part of the `batch`. On failure (e.g. double spend) the batch is
+    /// reset and the operation will take no effect.
+    ///
+    /// The supplied `out_point` identifies the operation (e.g. a peg-out or coin issuance) and can
+    /// be used to retrieve its outcome later using `output_status`.
+    ///
+    /// This function may only be called after `begin_consensus_epoch` and before
+    /// `end_consensus_epoch`. Data is only written to the database once all transactions have been
+    /// processed.
+    fn apply_output<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        output: &'a Self::TxOutput,
+        out_point: crate::transaction::OutPoint,
+    ) -> Result<Amount, Self::Error>;
+
+    /// This function is called once all transactions have been processed and changes were written
+    /// to the database. This allows running finalization code before the next epoch.
+    async fn end_consensus_epoch<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        rng: impl RngCore + CryptoRng + 'a,
+    );
+
+    /// Retrieve the current status of the output. Depending on the module this might contain data
+    /// needed by the client to access funds or give an estimate of when funds will be available.
+    /// Returns `None` if the output is unknown, **NOT** if it is just not ready yet.
+    fn output_status(
+        &self,
+        out_point: crate::transaction::OutPoint,
+    ) -> Option<Self::TxOutputOutcome>;
+
+    /// Writes the module's metrics when they are scraped. Metric names should be prefixed with the
+    /// module's name to not collide with other modules' metrics.
+    fn encode_metrics(&self, _encoder: &mut MetricsEncoder) {}
+
+    /// DB prefixes of the module's state that follows deterministically from the consensus
+    /// outcomes and thus has to be the same on all peers. Changes to them are included in the
+    /// state digests the peers compare to detect diverging state. Prefixes of local data, e.g.
+    /// our own signature shares, must not be listed.
+    fn consensus_db_prefixes(&self) -> &'static [u8] {
+        &[]
+    }
+}
+
+impl ModuleItem {
+    /// Tags the consensus encoding of `item` with the id of the `module` it belongs to
+    pub fn new<T: Encodable>(module: ModuleId, item: &T) -> ModuleItem {
+        ModuleItem {
+            module,
+            data: encode_module_data(item),
+        }
+    }
+
+    /// Decodes the item as `T`. This does not check the module id, that is up to the caller.
+    pub fn decode<T: Decodable>(&self) -> Result<T, DecodeError> {
+        decode_module_data(&self.data)
+    }
+}
+
+impl std::fmt::Display for ModuleId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl From<u16> for ModuleId {
+    fn from(id: u16) -> Self {
+        Self(id)
+    }
+}
+
+fn encode_module_data<T: Encodable>(item: &T) -> Vec<u8> {
+    let mut data = Vec::new();
+    item.consensus_encode(&mut data)
+        .expect("writing to vec can't fail");
+    data
+}
+
+/// Decodes module item data, rejecting trailing bytes so that there is exactly one encoding per
+/// item. Otherwise the same transaction could be submitted under different ids.
+fn decode_module_data<T: Decodable>(data: &[u8]) -> Result<T, DecodeError> {
+    let mut cursor = std::io::Cursor::new(data);
+    let item = T::consensus_decode(&mut cursor)?;
+    if cursor.position() != data.len() as u64 {
+        return Err(DecodeError::from_str("Trailing bytes after module item"));
+    }
+    Ok(item)
+}
+
+mod serde_hex {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+    pub fn serialize<S: Serializer>(bytes: &[u8], s: S) -> Result<S::Ok, S::Error> {
+        if s.is_human_readable() {
+            s.serialize_str(&hex::encode(bytes))
+        } else {
+            Serialize::serialize(bytes, s)
+        }
+    }
+
+    pub fn deserialize<'d, D: Deserializer<'d>>(d: D) -> Result<Vec<u8>, D::Error> {
+        if d.is_human_readable() {
+            hex::decode::<String>(Deserialize::deserialize(d)?).map_err(serde::de::Error::custom)
+        } else {
+            Deserialize::deserialize(d)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::module::{ModuleItem, MINT_MODULE_ID};
+
+    #[test]
+    fn test_module_item_roundtrip() {
+        let item = ModuleItem::new(MINT_MODULE_ID, &(42u64, vec![1u8, 2, 3]));
+        assert_eq!(
+            item.decode::<(u64, Vec<u8>)>().unwrap(),
+            (42, vec![1, 2, 3])
+        );
+    }
+
+    #[test]
+    fn test_module_item_trailing_bytes() {
+        let mut item = ModuleItem::new(MINT_MODULE_ID, &42u64);
+        item.data.push(0);
+        assert!(item.decode::<u64>().is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use super::{
+    decode_module_data, encode_module_data, ErrorCode, FederationModule, ModuleId, RandomnessBeacon,
+};
+use crate::db::batch::BatchTx;
+use crate::encoding::DecodeError;
+use crate::metrics::MetricsEncoder;
+use crate::outcome::Final;
+use crate::transaction::{OutPoint, TransactionInput, TransactionItem};
+use crate::{Amount, FeeConsensus, PeerId};
+use async_trait::async_trait;
+use rand::{CryptoRng, RngCore};
+use secp256k1_zkp::schnorrsig;
+use std::any::Any;
+use std::collections::BTreeMap;
+use thiserror::Error;
+use tracing::warn;
+
+/// Object-safe random number generator handed to type-erased modules
+pub trait ModuleRng: RngCore + CryptoRng {}
+
+impl<R: RngCore + CryptoRng> ModuleRng for R {}
+
+/// Properties of a transaction input relevant to the transaction as a whole
+#[derive(Debug, Clone)]
+pub struct InputMeta {
+    pub amount: Amount,
+    pub fee: Amount,
+    /// Keys that need to sign the transaction for the input to be valid
+    pub keys: Vec<schnorrsig::PublicKey>,
+}
+
+/// Properties of a transaction output relevant to the transaction as a whole
+#[derive(Debug, Clone)]
+pub struct OutputMeta {
+    pub amount: Amount,
+    pub fee: Amount,
+}
+
+/// Error returned by a type-erased module, either because an item could not be decoded or because
+/// the module itself rejected it.
+#[derive(Debug, Error)]
+#[error("{error}")]
+pub struct ModuleError {
+    code: &'static str,
+    error: Box<dyn std::error::Error + Send>,
+}
+
+/// Object-safe version of [`FederationModule`] that works on consensus encoded items. It is
+/// implemented for every `FederationModule` and allows the consensus to treat all modules
+/// uniformly. See [`FederationModule`] for documentation of the individual functions.
+#[async_trait(?Send)]
+pub trait ErasedFederationModule {
+    /// Allows accessing the concrete module type, see [`ModuleRegistry::get_typed`]
+    fn as_any(&self) -> &dyn Any;
+
+    async fn consensus_proposal<'a>(&'a self, rng: &'a mut dyn ModuleRng) -> Vec<Vec<u8>>;
+
+    /// Consensus items that can't be decoded are dropped with a warning.
+    async fn begin_consensus_epoch<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        epoch: u64,
+        beacon: Option<RandomnessBeacon>,
+        consensus_items: Vec<(PeerId, Vec<u8>)>,
+        rng: &'a mut dyn ModuleRng,
+    );
+
+    fn validate_input(
+        &self,
+        input: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<InputMeta, ModuleError>;
+
+    /// Returns the [`TransactionInput::conflict_keys`] of an encoded input
+    fn input_conflict_keys(&self, input: &[u8]) -> Result<Vec<Vec<u8>>, ModuleError>;
+
+    fn apply_input<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        input: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<InputMeta, ModuleError>;
+
+    fn validate_output(
+        &self,
+        output: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<OutputMeta, ModuleError>;
+
+    fn apply_output<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        output: &[u8],
+        out_point: OutPoint,
+        fee_consensus: &FeeConsensus,
+    ) -> Result<OutputMeta, ModuleError>;
+
+    async fn end_consensus_epoch<'a>(&'a self, batch: BatchTx<'a>, rng: &'a mut dyn ModuleRng);
+
+    /// Returns the encoded output outcome and whether it is final
+    fn output_status(&self, out_point: OutPoint) -> Option<(Vec<u8>, bool)>;
+
+    fn encode_metrics(&self, encoder: &mut MetricsEncoder);
+
+    fn consensus_db_prefixes(&self) -> &'static [u8];
+}
+
+/// All modules run by the federation, indexed by their module id
+#[derive(Default)]
+pub struct ModuleRegistry {
+    modules: BTreeMap<ModuleId, Box<dyn ErasedFederationModule + Send + Sync>>,
+}
+
+#[async_trait(?Send)]
+impl<M> ErasedFederationModule for M
+where
+    M: FederationModule + 'static,
+{
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn consensus_proposal<'a>(&'a self, rng: &'a mut dyn ModuleRng) -> Vec<Vec<u8>> {
+        <M as FederationModule>::consensus_proposal(self, rng)
+            .await
+            .iter()
+            .map(encode_module_data)
+            .collect()
+    }
+
+    async fn begin_consensus_epoch<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        epoch: u64,
+        beacon: Option<RandomnessBeacon>,
+        consensus_items: Vec<(PeerId, Vec<u8>)>,
+        rng: &'a mut dyn ModuleRng,
+    ) {
+        let consensus_items = consensus_items
+            .into_iter()
+            .filter_map(|(peer, data)| match decode_module_data(&data) {
+                Ok(item) => Some((peer, item)),
+                Err(e) => {
+                    warn!("Peer {} sent undecodable consensus item: {}", peer, e);
+                    None
+                }
+            })
+            .collect();
+
+        <M as FederationModule>::begin_consensus_epoch(
+            self,
+            batch,
+            epoch,
+            beacon,
+            consensus_items,
+            rng,
+        )
+        .await
+    }
+
+    fn validate_input(
+        &self,
+        input: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<InputMeta, ModuleError> {
+        let input = decode_module_data::<M::TxInput>(input)?;
+        <M as FederationModule>::validate_input(self, &input).map_err(ModuleError::from_err)?;
+        Ok(input_meta(&input, fee_consensus))
+    }
+
+    fn input_conflict_keys(&self, input: &[u8]) -> Result<Vec<Vec<u8>>, ModuleError> {
+        Ok(decode_module_data::<M::TxInput>(input)?.conflict_keys())
+    }
+
+    fn apply_input<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        input: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<InputMeta, ModuleError> {
+        let input = decode_module_data::<M::TxInput>(input)?;
+        <M as FederationModule>::apply_input(self, batch, &input).map_err(ModuleError::from_err)?;
+        Ok(input_meta(&input, fee_consensus))
+    }
+
+    fn validate_output(
+        &self,
+        output: &[u8],
+        fee_consensus: &FeeConsensus,
+    ) -> Result<OutputMeta, ModuleError> {
+        let output = decode_module_data::<M::TxOutput>(output)?;
+        <M as FederationModule>::validate_output(self, &output).map_err(ModuleError::from_err)?;
+        Ok(output_meta(&output, fee_consensus))
+    }
+
+    fn apply_output<'a>(
+        &'a self,
+        batch: BatchTx<'a>,
+        output: &[u8],
+        out_point: OutPoint,
+        fee_consensus: &FeeConsensus,
+    ) -> Result<OutputMeta, ModuleError> {
+        let output = decode_module_data::<M::TxOutput>(output)?;
+        <M as FederationModule>::apply_output(self, batch, &output, out_point)
+            .map_err(ModuleError::from_err)?;
+        Ok(output_meta(&output, fee_consensus))
+    }
+
+    async fn end_consensus_epoch<'a>(&'a self, batch: BatchTx<'a>, rng: &'a mut dyn ModuleRng) {
+        <M as FederationModule>::end_consensus_epoch(self, batch, rng).await
+    }
+
+    fn output_status(&self, out_point: OutPoint) -> Option<(Vec<u8>, bool)> {
+        <M as FederationModule>::output_status(self, out_point)
+            .map(|outcome| (encode_module_data(&outcome), outcome.is_final()))
+    }
+
+    fn encode_metrics(&self, encoder: &mut MetricsEncoder) {
+        <M as FederationModule>::encode_metrics(self, encoder)
+    }
+
+    fn consensus_db_prefixes(&self) -> &'static [u8] {
+        <M as FederationModule>::consensus_db_prefixes(self)
+    }
+}
+
+impl ModuleRegistry {
+    pub fn new() -> ModuleRegistry {
+        ModuleRegistry::default()
+    }
+
+    /// Registers `module` under `id`
+    ///
+    /// # Panics
+    /// * If another module was already registered under `id`
+    pub fn register<M>(&mut self, id: ModuleId, module: M)
+    where
+        M: FederationModule + Send + Sync + 'static,
+    {
+        let previous = self.modules.insert(id, Box::new(module));
+        assert!(previous.is_none(), "Module id {} registered twice", id);
+    }
+
+    pub fn get(&self, id: ModuleId) -> Option<&(dyn ErasedFederationModule + Send + Sync)> {
+        self.modules.get(&id).map(|module| module.as_ref())
+    }
+
+    /// Returns the module registered under `id` if it is of type `M`
+    pub fn get_typed<M: 'static>(&self, id: ModuleId) -> Option<&M> {
+        self.get(id)?.as_any().downcast_ref()
+    }
+
+    pub fn iter(
+        &self,
+    ) -> impl Iterator<Item = (ModuleId, &(dyn ErasedFederationModule + Send + Sync))> {
+        self.modules.iter().map(|(id, module)| {
+            (
+                *id,
+                module.as_ref() as &(dyn ErasedFederationModule + Send + Sync),
+            )
+        })
+    }
+}
+
+impl ModuleError {
+    pub fn from_err<E: std::error::Error + ErrorCode + Send + 'static>(e: E) -> Self {
+        ModuleError {
+            code: e.error_code(),
+            error: Box::new(e),
+        }
+    }
+}
+
+impl ErrorCode for ModuleError {
+    fn error_code(&self) -> &'static str {
+        self.code
+    }
+}
+
+impl From<DecodeError> for ModuleError {
+    fn from(e: DecodeError) -> Self {
+        ModuleError {
+            code: "invalid_encoding",
+            error: Box::new(e),
+        }
+    }
+}
+
+fn input_meta<I: TransactionInput>(input: &I, fee_consensus: &FeeConsensus) -> InputMeta {
+    InputMeta {
+        amount: input.amount(),
+        fee: input.fee(fee_consensus),
+        keys: input.authorization_keys(),
+    }
+}
+
+fn output_meta<O: TransactionItem>(output: &O, fee_consensus: &FeeConsensus) -> OutputMeta {
+    OutputMeta {
+        amount: output.amount(),
+        fee: output.fee(fee_consensus),
+    }
+}
@@ -0,0 +1,202 @@
+use crate::db::batch::DbBatch;
+use crate::db::mem_impl::MemDatabase;
+use crate::db::{Database, RawDatabase};
+use crate::module::{FederationModule, RandomnessBeacon};
+use crate::transaction::OutPoint;
+use crate::PeerId;
+use bitcoin_hashes::{sha256, Hash as BitcoinHash};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+/// Transforms the consensus items an honest peer would have proposed into the ones a malicious
+/// peer sends instead
+pub type ByzantineBehaviour<CI> = Box<dyn FnMut(Vec<CI>) -> Vec<CI>>;
+
+/// Test harness running one instance of a module per federation member, each on its own
+/// in-memory database. Consensus is simulated by handing every member the same consensus items.
+///
+/// Up to `max_faulty()` members can be made byzantine: their modules still run like the honest
+/// ones, but the consensus items they contribute are replaced by the output of a
+/// [`ByzantineBehaviour`].
+pub struct FakeFed<M: FederationModule> {
+    members: Vec<(PeerId, M, Arc<dyn RawDatabase>)>,
+    byzantine: BTreeMap<PeerId, ByzantineBehaviour<M::ConsensusItem>>,
+    /// Number of the next consensus epoch
+    epoch: Cell<u64>,
+}
+
+impl<M> FakeFed<M>
+where
+    M: FederationModule,
+    M::ConsensusItem: Clone,
+{
+    /// Creates a federation of `members` peers using `constructor` to build each peer's module
+    pub fn new(
+        members: u16,
+        mut constructor: impl FnMut(PeerId, Arc<dyn RawDatabase>) -> M,
+    ) -> FakeFed<M> {
+        let members = (0..members)
+            .map(|peer| {
+                let peer = PeerId::from(peer);
+                let db: Arc<dyn RawDatabase> = Arc::new(MemDatabase::new());
+                (peer, constructor(peer, db.clone()), db)
+            })
+            .collect();
+
+        FakeFed {
+            members,
+            byzantine: BTreeMap::new(),
+            epoch: Cell::new(0),
+        }
+    }
+
+    /// Maximum number of byzantine members the federation can tolerate
+    pub fn max_faulty(&self) -> usize {
+        (self.members.len() - 1) / 3
+    }
+
+    /// Replaces the consensus items contributed by `peer` using `behaviour`
+    ///
+    /// # Panics
+    /// * If this would make more than `max_faulty()` members byzantine
+    pub fn make_byzantine(
+        &mut self,
+        peer: PeerId,
+        behaviour: impl FnMut(Vec<M::ConsensusItem>) -> Vec<M::ConsensusItem> + 'static,
+    ) {
+        self.byzantine.insert(peer, Box::new(behaviour));
+        assert!(
+            self.byzantine.len() <= self.max_faulty(),
+            "The federation can only tolerate {} byzantine members",
+            self.max_faulty()
+        );
+    }
+
+    /// Returns `true` if `peer` was made byzantine
+    pub fn is_byzantine(&self, peer: PeerId) -> bool {
+        self.byzantine.contains_key(&peer)
+    }
+
+    /// All members that were not made byzantine
+    pub fn honest_members(&self) -> impl Iterator<Item = (PeerId, &M)> {
+        self.members
+            .iter()
+            .filter(move |(peer, _, _)| !self.byzantine.contains_key(peer))
+            .map(|(peer, module, _)| (*peer, module))
+    }
+
+    /// The module of `peer` together with its database
+    pub fn member(&self, peer: PeerId) -> (&M, &Arc<dyn RawDatabase>) {
+        self.members
+            .iter()
+            .find(|(member, _, _)| *member == peer)
+            .map(|(_, module, db)| (module, db))
+            .expect("Unknown peer")
+    }
+
+    /// Runs `f` on the database of every member, e.g. to set up the initial state
+    pub fn patch_dbs(&self, mut f: impl FnMut(PeerId, &dyn RawDatabase)) {
+        for (peer, _, db) in &self.members {
+            f(*peer, db.as_ref());
+        }
+    }
+
+    /// Creates `output` identified by `out_point` on every member as if a transaction containing
+    /// it was accepted by consensus
+    ///
+    /// # Panics
+    /// * If any member fails to apply the output
+    pub fn apply_output(&self, output: &M::TxOutput, out_point: OutPoint) {
+        for (peer, module, db) in &self.members {
+            let mut batch = DbBatch::new();
+            if let Err(e) = module.apply_output(batch.transaction(), output, out_point) {
+                panic!("Peer {} failed to apply output: {}", peer, e);
+            }
+            db.apply_batch(batch).expect("DB error");
+        }
+    }
+
+    /// Runs a consensus epoch in which every member contributes its module's consensus proposal,
+    /// altered by its byzantine behaviour if it has one
+    pub async fn round(&mut self) {
+        let mut proposals = Vec::new();
+        for (peer, module, _) in &self.members {
+            proposals.push((*peer, module.consensus_proposal(rng()).await));
+        }
+
+        let consensus_items = self.contribute(proposals);
+        self.process_consensus_items(consensus_items).await;
+    }
+
+    /// Like [`FakeFed::round`], but every member proposes the items returned by `proposal` instead
+    /// of its module's consensus proposal. This is useful if the latter depends on external
+    /// services.
+    pub async fn round_with(
+        &mut self,
+        mut proposal: impl FnMut(PeerId, &M) -> Vec<M::ConsensusItem>,
+    ) {
+        let proposals = self
+            .members
+            .iter()
+            .map(|(peer, module, _)| (*peer, proposal(*peer, module)))
+            .collect();
+
+        let consensus_items = self.contribute(proposals);
+        self.process_consensus_items(consensus_items).await;
+    }
+
+    /// Runs a consensus epoch on every member in which consensus was reached on
+    /// `consensus_items`. These are not altered by byzantine behaviours. Unlike the federation's
+    /// randomness beacon the one handed to the members is derived from the epoch number alone, so
+    /// tests are deterministic.
+    pub async fn process_consensus_items(&self, consensus_items: Vec<(PeerId, M::ConsensusItem)>) {
+        let epoch = self.epoch.get();
+        self.epoch.set(epoch + 1);
+        let beacon = RandomnessBeacon {
+            epoch,
+            value: sha256::Hash::hash(&epoch.to_be_bytes()).into_inner(),
+        };
+
+        for (_, module, db) in &self.members {
+            let mut batch = DbBatch::new();
+            module
+                .begin_consensus_epoch(
+                    batch.transaction(),
+                    epoch,
+                    Some(beacon),
+                    consensus_items.clone(),
+                    rng(),
+                )
+                .await;
+            db.apply_batch(batch).expect("DB error");
+
+            let mut batch = DbBatch::new();
+            module.end_consensus_epoch(batch.transaction(), rng()).await;
+            db.apply_batch(batch).expect("DB error");
+        }
+    }
+
+    /// Applies the byzantine behaviours to the members' proposals and returns the resulting
+    /// consensus items
+    fn contribute(
+        &mut self,
+        proposals: Vec<(PeerId, Vec<M::ConsensusItem>)>,
+    ) -> Vec<(PeerId, M::ConsensusItem)> {
+        let byzantine = &mut self.byzantine;
+        proposals
+            .into_iter()
+            .flat_map(|(peer, proposal)| {
+                let proposal = match byzantine.get_mut(&peer) {
+                    Some(behaviour) => behaviour(proposal),
+                    None => proposal,
+                };
+                proposal.into_iter().map(move |item| (peer, item))
+            })
+            .collect()
+    }
+}
+
+fn rng() -> rand::rngs::OsRng {
+    rand::rngs::OsRng::new().expect("Could not initialize RNG")
+}
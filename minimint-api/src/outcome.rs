@@ -1,5 +1,8 @@
-use crate::SigResponse;
+use crate::encoding::{Decodable, Encodable};
+use crate::module::{ModuleId, ModuleItem};
+use crate::{Amount, SigResponse};
 use serde::{Deserialize, Serialize};
+use thiserror::Error;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
 pub enum TransactionStatus {
@@ -8,19 +11,72 @@ pub enum TransactionStatus {
     /// The error state is only recorded if the error happens after consensus is achieved on the
     /// transaction. This should happen only rarely, e.g. on double spends since a basic validity
     /// check is performed on transaction submission.
-    Error(String),
+    Error {
+        epoch: u64,
+        error: TransactionRejection,
+    },
     /// The transaction was accepted and is now being processed
     Accepted {
         epoch: u64,
         outputs: Vec<OutputOutcome>,
     },
+    /// The guardian dropped the transaction from its mempool after `epoch` without consensus
+    /// being reached on it. Other guardians may still propose it, so this is only the guardian's
+    /// local view and the transaction can be resubmitted.
+    Evicted { epoch: u64, reason: EvictionReason },
+}
+
+/// Reason why a guardian dropped a transaction from its mempool
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub enum EvictionReason {
+    /// It wasn't decided within the configured number of epochs
+    Expired,
+    /// Another accepted transaction spent one of its inputs
+    Conflict,
+}
+
+/// Reason why a transaction was rejected after consensus was reached on it
+#[derive(
+    Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable, Error,
+)]
+pub enum TransactionRejection {
+    #[error("Unknown module {0}")]
+    UnknownModule(ModuleId),
+    #[error("An input was rejected by module {module}: {reason}")]
+    InvalidInput { module: ModuleId, reason: String },
+    #[error("An output was rejected by module {module}: {reason}")]
+    InvalidOutput { module: ModuleId, reason: String },
+    #[error("The transaction is insufficiently funded (in={inputs}, out={outputs}, fee={fee})")]
+    InsufficientlyFunded {
+        inputs: Amount,
+        outputs: Amount,
+        fee: Amount,
+    },
+    #[error("The transaction's signature is invalid")]
+    InvalidSignature,
+    #[error("The mempool is full")]
+    MempoolFull,
+    #[error("The transaction spends an input that an earlier transaction of the epoch spent")]
+    Conflict,
 }
 
+/// Response body of a rejected transaction submission
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Error)]
+#[error("{message} (code: {code})")]
+pub struct SubmissionError {
+    /// Stable, machine-readable identifier of the error, see [`crate::module::ErrorCode`]
+    pub code: String,
+    /// Human-readable description of the error
+    pub message: String,
+}
+
+/// Outcome of a transaction output as reported by the module it belongs to
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize)]
-pub enum OutputOutcome {
-    Mint(Option<SigResponse>),
-    // TODO: maybe include the transaction id eventually. But unclear how to propagate it cleanly right now.
-    Wallet(()),
+pub struct OutputOutcome {
+    /// Module specific outcome, e.g. the blind signature of a coin issuance
+    pub outcome: ModuleItem,
+    /// Set by the module if the outcome won't change anymore
+    pub is_final: bool,
 }
 
 pub trait Final {
@@ -29,11 +85,20 @@ pub trait Final {
 
 impl Final for OutputOutcome {
     fn is_final(&self) -> bool {
-        match self {
-            OutputOutcome::Mint(Some(_)) => true,
-            OutputOutcome::Mint(None) => false,
-            OutputOutcome::Wallet(()) => true,
-        }
+        self.is_final
+    }
+}
+
+impl Final for Option<SigResponse> {
+    fn is_final(&self) -> bool {
+        self.is_some()
+    }
+}
+
+// TODO: maybe include the transaction id eventually. But unclear how to propagate it cleanly right now.
+impl Final for () {
+    fn is_final(&self) -> bool {
+        true
     }
 }
 
@@ -41,8 +106,9 @@ impl Final for TransactionStatus {
     fn is_final(&self) -> bool {
         match self {
             TransactionStatus::AwaitingConsensus => false,
-            TransactionStatus::Error(_) => true,
+            TransactionStatus::Error { .. } => true,
             TransactionStatus::Accepted { outputs, .. } => outputs.iter().all(|out| out.is_final()),
+            TransactionStatus::Evicted { .. } => false,
         }
     }
 }
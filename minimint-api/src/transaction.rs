@@ -1,4 +1,5 @@
 use crate::encoding::{Decodable, Encodable};
+use crate::module::{ErrorCode, ModuleItem};
 use crate::{Amount, Coin, Coins, FeeConsensus, PegInProof, TransactionId};
 use bitcoin_hashes::Hash as BitcoinHash;
 use rand::Rng;
@@ -13,19 +14,11 @@ pub struct Transaction {
     pub signature: schnorrsig::Signature,
 }
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
-pub enum Input {
-    // TODO: maybe treat every coin as a seperate input?
-    Coins(Coins<Coin>),
-    PegIn(Box<PegInProof>),
-}
+/// Transaction input, the module it belongs to decides how it is interpreted
+pub type Input = ModuleItem;
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
-pub enum Output {
-    Coins(Coins<BlindToken>),
-    PegOut(PegOut),
-    // TODO: lightning integration goes here
-}
+/// Transaction output, the module it belongs to decides how it is interpreted
+pub type Output = ModuleItem;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
 pub struct PegOut {
@@ -52,84 +45,123 @@ pub trait TransactionItem {
     fn fee(&self, fee_consensus: &FeeConsensus) -> crate::Amount;
 }
 
-impl Input {
+/// Properties of transaction inputs
+pub trait TransactionInput: TransactionItem {
     // TODO: probably make this a single returned key once coins are separate inputs
-    /// Returns an iterator over all the keys that need to sign the transaction for the input to
-    /// be valid.
-    fn authorization_keys<'a>(&'a self) -> Box<dyn Iterator<Item = schnorrsig::PublicKey> + 'a> {
-        match self {
-            Input::Coins(coins) => Box::new(coins.iter().map(|(_, coin)| *coin.spend_key())),
-            Input::PegIn(proof) => Box::new(std::iter::once(*proof.tweak_contract_key())),
-        }
+    /// Returns all the keys that need to sign the transaction for the input to be valid.
+    fn authorization_keys(&self) -> Vec<schnorrsig::PublicKey>;
+
+    /// Returns the consensus encoded identifiers of everything the input spends (e.g. coin
+    /// nonces). Inputs of the same module that share any of them must not both be accepted.
+    fn conflict_keys(&self) -> Vec<Vec<u8>>;
+}
+
+/// Sums up the amounts and fees of all in- and outputs of a transaction to check that it is
+/// sufficiently funded.
+#[derive(Debug)]
+pub struct FundingVerifier {
+    input_amount: Amount,
+    output_amount: Amount,
+    fee_amount: Amount,
+}
+
+impl TransactionItem for Coins<Coin> {
+    fn amount(&self) -> Amount {
+        Coins::amount(self)
+    }
+
+    fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
+        fee_consensus.fee_coin_spend_abs * (self.coin_count() as u64)
     }
 }
 
-impl TransactionItem for Input {
+impl TransactionInput for Coins<Coin> {
+    fn authorization_keys(&self) -> Vec<schnorrsig::PublicKey> {
+        self.iter().map(|(_, coin)| *coin.spend_key()).collect()
+    }
+
+    fn conflict_keys(&self) -> Vec<Vec<u8>> {
+        self.iter()
+            .map(|(_, coin)| encode_to_vec(&coin.0))
+            .collect()
+    }
+}
+
+impl TransactionItem for PegInProof {
     fn amount(&self) -> Amount {
-        match self {
-            Input::Coins(coins) => coins.amount(),
-            Input::PegIn(peg_in) => Amount::from_sat(peg_in.tx_output().value),
-        }
+        Amount::from_sat(self.tx_output().value)
     }
 
     fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
-        match self {
-            Input::Coins(coins) => fee_consensus.fee_coin_spend_abs * (coins.coins.len() as u64),
-            Input::PegIn(_) => fee_consensus.fee_peg_in_abs,
-        }
+        fee_consensus.peg_in_fee(self.amount())
+    }
+}
+
+impl TransactionInput for PegInProof {
+    fn authorization_keys(&self) -> Vec<schnorrsig::PublicKey> {
+        vec![*self.tweak_contract_key()]
+    }
+
+    fn conflict_keys(&self) -> Vec<Vec<u8>> {
+        vec![encode_to_vec(&self.outpoint())]
     }
 }
 
-impl TransactionItem for Output {
+impl TransactionItem for Coins<BlindToken> {
     fn amount(&self) -> Amount {
-        match self {
-            Output::Coins(coins) => coins.amount(),
-            Output::PegOut(peg_out) => peg_out.amount.into(),
-        }
+        Coins::amount(self)
     }
 
     fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
-        match self {
-            Output::Coins(coins) => fee_consensus.fee_coin_spend_abs * (coins.coins.len() as u64),
-            Output::PegOut(_) => fee_consensus.fee_peg_out_abs,
+        fee_consensus.fee_coin_issuance_abs * (self.coin_count() as u64)
+    }
+}
+
+impl TransactionItem for PegOut {
+    fn amount(&self) -> Amount {
+        self.amount.into()
+    }
+
+    fn fee(&self, fee_consensus: &FeeConsensus) -> Amount {
+        fee_consensus.peg_out_fee(self.amount())
+    }
+}
+
+impl Default for FundingVerifier {
+    fn default() -> Self {
+        FundingVerifier {
+            input_amount: Amount::ZERO,
+            output_amount: Amount::ZERO,
+            fee_amount: Amount::ZERO,
         }
     }
 }
 
-impl Transaction {
-    pub fn validate_funding(&self, fee_consensus: &FeeConsensus) -> Result<(), TransactionError> {
-        let in_amount = self
-            .inputs
-            .iter()
-            .map(TransactionItem::amount)
-            .sum::<Amount>();
-        let out_amount = self
-            .outputs
-            .iter()
-            .map(TransactionItem::amount)
-            .sum::<Amount>();
-        let fee_amount = self
-            .inputs
-            .iter()
-            .map(|input| input.fee(fee_consensus))
-            .sum::<Amount>()
-            + self
-                .outputs
-                .iter()
-                .map(|output| output.fee(fee_consensus))
-                .sum::<Amount>();
-
-        if in_amount >= (out_amount + fee_amount) {
+impl FundingVerifier {
+    pub fn add_input(&mut self, amount: Amount, fee: Amount) {
+        self.input_amount = self.input_amount + amount;
+        self.fee_amount = self.fee_amount + fee;
+    }
+
+    pub fn add_output(&mut self, amount: Amount, fee: Amount) {
+        self.output_amount = self.output_amount + amount;
+        self.fee_amount = self.fee_amount + fee;
+    }
+
+    pub fn verify_funding(self) -> Result<(), TransactionError> {
+        if self.input_amount >= (self.output_amount + self.fee_amount) {
             Ok(())
         } else {
             Err(TransactionError::InsufficientlyFunded {
-                inputs: in_amount,
-                outputs: out_amount,
-                fee: fee_amount,
+                inputs: self.input_amount,
+                outputs: self.output_amount,
+                fee: self.fee_amount,
             })
         }
     }
+}
 
+impl Transaction {
     /// Hash the transaction excluding the signature. This hash is what the signature inside the
     /// transaction commits to. To generate it without already having a signature use [tx_hash_from_parts].
     pub fn tx_hash(&self) -> TransactionId {
@@ -149,13 +181,18 @@ impl Transaction {
         TransactionId::from_engine(engine)
     }
 
-    pub fn validate_signature(&self) -> Result<(), TransactionError> {
+    /// Verifies the transaction's signature against the `keys` of all inputs, which have to be
+    /// supplied in the order of the inputs.
+    pub fn validate_signature(
+        &self,
+        keys: impl Iterator<Item = schnorrsig::PublicKey>,
+    ) -> Result<(), TransactionError> {
         let ctx = secp256k1_zkp::global::SECP256K1;
-        let agg_pub_key = agg_keys(
-            self.inputs
-                .iter()
-                .flat_map(|input| input.authorization_keys()),
-        );
+        let keys = keys.collect::<Vec<_>>();
+        if keys.is_empty() {
+            return Err(TransactionError::InvalidSignature);
+        }
+        let agg_pub_key = agg_keys(keys.into_iter());
         let msg =
             secp256k1_zkp::Message::from_slice(&self.tx_hash()[..]).expect("hash has right length");
 
@@ -202,6 +239,13 @@ where
     secp256k1_zkp::MusigPreSession::new(ctx, &keys).expect("more than zero were supplied")
 }
 
+fn encode_to_vec<T: Encodable>(item: &T) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    item.consensus_encode(&mut bytes)
+        .expect("writing to vec can't fail");
+    bytes
+}
+
 pub fn agg_sign<I, R, C, M>(
     keys: I,
     msg: M,
@@ -266,3 +310,12 @@ pub enum TransactionError {
     #[error("The transaction's signature is invalid")]
     InvalidSignature,
 }
+
+impl ErrorCode for TransactionError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            TransactionError::InsufficientlyFunded { .. } => "insufficiently_funded",
+            TransactionError::InvalidSignature => "invalid_signature",
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use minimint_api::module::{ModuleRegistry, MINT_MODULE_ID, WALLET_MODULE_ID};
+use minimint_api::Amount;
+use minimint_mint::{Mint, MintAudit};
+use minimint_wallet::{Wallet, WalletAudit};
+use serde::{Deserialize, Serialize};
+
+/// The federation's liabilities and the reserves backing them, returned by `GET /audit`. The
+/// wallet's part lists all UTXOs and descriptors so anyone can check the reserves against their
+/// own view of the blockchain.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditReport {
+    /// Last processed epoch, the report reflects the state after it
+    pub epoch: Option<u64>,
+    /// Reports of the built-in modules, `None` if the module isn't run by this guardian
+    pub mint: Option<MintAudit>,
+    pub wallet: Option<WalletAudit>,
+    /// Value of the outstanding e-cash and the pending peg-outs
+    pub liabilities: Amount,
+    /// Value of the wallet's UTXOs
+    pub reserves: Amount,
+    /// `false` as long as the mint's totals don't include the coins issued and redeemed before it
+    /// tracked them, the liabilities may be understated until then
+    pub complete: bool,
+}
+
+impl AuditReport {
+    pub fn new(epoch: Option<u64>, modules: &ModuleRegistry) -> AuditReport {
+        let mint = modules.get_typed::<Mint>(MINT_MODULE_ID).map(Mint::audit);
+        let wallet = modules
+            .get_typed::<Wallet>(WALLET_MODULE_ID)
+            .map(Wallet::audit);
+
+        let ecash = mint.as_ref().map_or(Amount::ZERO, |mint| mint.liabilities);
+        let (pending_peg_outs, reserves) = wallet
+            .as_ref()
+            .map_or((Amount::ZERO, Amount::ZERO), |wallet| {
+                (wallet.pending_peg_outs.into(), wallet.reserves.into())
+            });
+
+        let complete = mint.as_ref().map_or(true, |mint| mint.complete);
+
+        AuditReport {
+            epoch,
+            mint,
+            wallet,
+            liabilities: ecash + pending_peg_outs,
+            reserves,
+            complete,
+        }
+    }
+
+    /// Whether the reserves cover all liabilities. Usually they exceed them by the fees the
+    /// federation collected minus the on-chain fees it paid. Without a wallet there are no
+    /// reserves to check. An incomplete report can't show that the federation is backed.
+    pub fn is_backed(&self) -> bool {
+        self.complete && (self.wallet.is_none() || self.reserves >= self.liabilities)
+    }
+}
@@ -1,56 +1,351 @@
-use minimint::config::{ServerConfig, ServerConfigParams};
+use minimint::config::{ClientConfig, InviteCode, ServerConfig, ServerConfigParams, SetupKey};
+use minimint::net::connect::Connections;
+use minimint::net::dkg::{DkgMessage, DkgNetwork};
+use minimint::net::encryption::{InvalidNetworkKeyError, NetworkPublicKey, NetworkSecretKey};
 use minimint_api::config::GenerateConfig;
 use minimint_api::{Amount, PeerId};
 use rand::rngs::OsRng;
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
-struct Options {
-    cfg_path: PathBuf,
-    nodes: u16,
-    hbbft_base_port: u16,
-    api_base_port: u16,
-    amount_tiers: Vec<Amount>,
+enum Options {
+    #[structopt(
+        about = "Generate the config of all peers on this machine, only meant for testing since the machine learns all secrets"
+    )]
+    TrustedDealer {
+        cfg_path: PathBuf,
+        nodes: u16,
+        hbbft_base_port: u16,
+        api_base_port: u16,
+        amount_tiers: Vec<Amount>,
+        /// Host under which a peer is reachable by the others and by clients, given once per peer
+        /// in the order of their ids. Defaults to `127.0.0.1` for all peers.
+        #[structopt(long = "host", number_of_values = 1)]
+        hosts: Vec<String>,
+        /// Host the peers' sockets bind to, use `0.0.0.0` to accept connections from other machines
+        #[structopt(long, default_value = "127.0.0.1")]
+        bind_host: String,
+    },
+    #[structopt(
+        about = "Generate the key identifying this guardian during the distributed key generation, prints the public key to share with the other guardians"
+    )]
+    SetupKey { path: PathBuf },
+    #[structopt(
+        about = "Generate the config of this guardian together with the other guardians, all of them have to run this command with the same parameters except for their id and setup key"
+    )]
+    Dkg {
+        cfg_path: PathBuf,
+        /// Our own peer id
+        id: u16,
+        /// File containing our setup key
+        setup_key: PathBuf,
+        hbbft_base_port: u16,
+        api_base_port: u16,
+        amount_tiers: Vec<Amount>,
+        /// Public setup key of a peer, given once per peer including ourselves in the order of
+        /// their ids
+        #[structopt(long = "peer-key", number_of_values = 1)]
+        peer_keys: Vec<NetworkPublicKey>,
+        /// Host under which a peer is reachable by the others and by clients, given once per peer
+        /// in the order of their ids. Defaults to `127.0.0.1` for all peers.
+        #[structopt(long = "host", number_of_values = 1)]
+        hosts: Vec<String>,
+        /// Host our sockets bind to, use `0.0.0.0` to accept connections from other machines
+        #[structopt(long, default_value = "127.0.0.1")]
+        bind_host: String,
+    },
+    #[structopt(
+        about = "Generate the config of a federation with changed guardians together with the other guardians of the new federation, the current federation switches to it once its guardians run with --next-cfg-path"
+    )]
+    Reshare {
+        cfg_path: PathBuf,
+        /// Our own peer id, guardians of the current federation keep theirs, new guardians need an
+        /// id that isn't used by the current federation
+        id: u16,
+        /// File containing our setup key
+        setup_key: PathBuf,
+        /// Client config of the current federation
+        old_client_cfg: PathBuf,
+        /// Have to differ from the ports the current federation is running on
+        hbbft_base_port: u16,
+        api_base_port: u16,
+        /// Our config in the current federation, if we are part of it
+        #[structopt(long)]
+        old_cfg: Option<PathBuf>,
+        /// Public setup key of a guardian of the new federation as `id:key`, given once per
+        /// guardian including ourselves
+        #[structopt(long = "peer-key", number_of_values = 1, parse(try_from_str = parse_peer_key))]
+        peer_keys: Vec<(PeerId, NetworkPublicKey)>,
+        /// Host under which a guardian of the new federation is reachable by the others and by
+        /// clients, given once per guardian in the order of their ids. Defaults to `127.0.0.1`.
+        #[structopt(long = "host", number_of_values = 1)]
+        hosts: Vec<String>,
+        /// Host our sockets bind to, use `0.0.0.0` to accept connections from other machines
+        #[structopt(long, default_value = "127.0.0.1")]
+        bind_host: String,
+    },
+    #[structopt(
+        about = "Generate a new mint key set together with all other guardians of the federation, the federation switches to it once its guardians run with --next-cfg-path"
+    )]
+    RotateMintKeys {
+        cfg_path: PathBuf,
+        /// Our current config
+        cfg: PathBuf,
+        /// Current client config
+        client_cfg: PathBuf,
+        /// First consensus epoch in which coins of the current key set are rejected
+        deprecation_epoch: u64,
+        /// Offset added to the ports of the running federation for the key generation
+        #[structopt(long, default_value = "1000")]
+        port_offset: u16,
+    },
 }
 
-fn main() {
-    let Options {
-        cfg_path,
-        nodes,
-        hbbft_base_port,
-        api_base_port,
-        amount_tiers,
-    } = StructOpt::from_args();
+#[tokio::main]
+async fn main() {
     let mut rng = OsRng::new().unwrap();
 
-    let peers = (0..nodes).map(PeerId::from).collect::<Vec<_>>();
-    let max_evil = hbbft::util::max_faulty(peers.len());
-    println!(
-        "Generating keys such that up to {} peers may fail/be evil",
-        max_evil
-    );
-    let params = ServerConfigParams {
-        hbbft_base_port,
-        api_base_port,
-        amount_tiers,
-    };
+    match StructOpt::from_args() {
+        Options::TrustedDealer {
+            cfg_path,
+            nodes,
+            hbbft_base_port,
+            api_base_port,
+            amount_tiers,
+            hosts,
+            bind_host,
+        } => {
+            let peers = (0..nodes).map(PeerId::from).collect::<Vec<_>>();
+            let max_evil = hbbft::util::max_faulty(peers.len());
+            println!(
+                "Generating keys such that up to {} peers may fail/be evil",
+                max_evil
+            );
+            let params = ServerConfigParams {
+                hbbft_base_port,
+                api_base_port,
+                peer_hosts: peer_hosts(&peers, hosts),
+                bind_host,
+                amount_tiers,
+            };
+
+            let (server_cfg, client_cfg) =
+                ServerConfig::trusted_dealer_gen(&peers, max_evil, &params, &mut rng);
+
+            for (id, cfg) in server_cfg {
+                write_server_config(&cfg_path, id, &cfg);
+            }
+            write_client_config(&cfg_path, &client_cfg);
+        }
+        Options::SetupKey { path } => {
+            let secret_key = NetworkSecretKey::generate(&mut rng);
+            let public_key = secret_key.public_key();
+
+            let file = std::fs::File::create(path).expect("Could not create setup key file");
+            serde_json::to_writer_pretty(file, &SetupKey { secret_key }).unwrap();
+
+            println!("{}", public_key);
+        }
+        Options::Dkg {
+            cfg_path,
+            id,
+            setup_key,
+            hbbft_base_port,
+            api_base_port,
+            amount_tiers,
+            peer_keys,
+            hosts,
+            bind_host,
+        } => {
+            let our_id = PeerId::from(id);
+            let peers = (0..peer_keys.len() as u16)
+                .map(PeerId::from)
+                .collect::<Vec<_>>();
+            assert!(peers.contains(&our_id), "Our id has no peer key");
+            let max_evil = hbbft::util::max_faulty(peers.len());
+            let params = ServerConfigParams {
+                hbbft_base_port,
+                api_base_port,
+                peer_hosts: peer_hosts(&peers, hosts),
+                bind_host,
+                amount_tiers,
+            };
 
-    let (server_cfg, client_cfg) =
-        ServerConfig::trusted_dealer_gen(&peers, max_evil, &params, &mut rng);
+            let setup_key: SetupKey = minimint::config::load_from_file(&setup_key);
+            assert_eq!(
+                setup_key.secret_key.public_key(),
+                peer_keys[our_id.to_usize()],
+                "Our setup key does not match our peer key"
+            );
+            let network_cfg = params.network_config(
+                our_id,
+                setup_key.secret_key,
+                peers.iter().copied().zip(peer_keys).collect(),
+            );
 
-    for (id, cfg) in server_cfg {
-        let mut path: PathBuf = cfg_path.clone();
-        path.push(format!("server-{}.json", id));
+            println!(
+                "Generating keys with {} peers such that up to {} peers may fail/be evil",
+                peers.len(),
+                max_evil
+            );
+            let connections = Connections::<DkgMessage>::connect_to_all(&network_cfg).await;
+            let mut dkg = DkgNetwork::new(our_id, peers, connections);
+            let (server_cfg, client_cfg) =
+                ServerConfig::distributed_gen(&mut dkg, max_evil, &params, &mut rng)
+                    .await
+                    .expect("Distributed key generation failed");
+            dkg.into_inner().shutdown().await;
 
-        let file = std::fs::File::create(path).expect("Could not create cfg file");
-        serde_json::to_writer_pretty(file, &cfg).unwrap();
+            write_server_config(&cfg_path, our_id, &server_cfg);
+            write_client_config(&cfg_path, &client_cfg);
+        }
+        Options::Reshare {
+            cfg_path,
+            id,
+            setup_key,
+            old_client_cfg,
+            hbbft_base_port,
+            api_base_port,
+            old_cfg,
+            peer_keys,
+            hosts,
+            bind_host,
+        } => {
+            let our_id = PeerId::from(id);
+            let peer_keys = peer_keys.into_iter().collect::<BTreeMap<_, _>>();
+            let peers = peer_keys.keys().copied().collect::<Vec<_>>();
+            let old_client_cfg: ClientConfig = minimint::config::load_from_file(&old_client_cfg);
+            let old_cfg: Option<ServerConfig> =
+                old_cfg.map(|old_cfg| minimint::config::load_from_file(&old_cfg));
+            let params = ServerConfigParams {
+                hbbft_base_port,
+                api_base_port,
+                peer_hosts: peer_hosts(&peers, hosts),
+                bind_host,
+                // The mint keeps its amount tiers
+                amount_tiers: old_client_cfg.mint.tbs_pks.tiers().copied().collect(),
+            };
+
+            let setup_key: SetupKey = minimint::config::load_from_file(&setup_key);
+            assert_eq!(
+                Some(&setup_key.secret_key.public_key()),
+                peer_keys.get(&our_id),
+                "Our setup key does not match our peer key"
+            );
+            let network_cfg = params.network_config(our_id, setup_key.secret_key, peer_keys);
+
+            println!(
+                "Generating keys for the new federation with {} guardians such that up to {} may fail/be evil",
+                peers.len(),
+                hbbft::util::max_faulty(peers.len())
+            );
+            let connections = Connections::<DkgMessage>::connect_to_all(&network_cfg).await;
+            let mut dkg = DkgNetwork::new(our_id, peers, connections);
+            let (server_cfg, client_cfg) = ServerConfig::reshare(
+                &mut dkg,
+                old_cfg.as_ref(),
+                &old_client_cfg,
+                &params,
+                &mut rng,
+            )
+            .await
+            .expect("Distributed key generation failed");
+            dkg.into_inner().shutdown().await;
+
+            write_server_config(&cfg_path, our_id, &server_cfg);
+            write_client_config(&cfg_path, &client_cfg);
+            println!(
+                "Config digest to check with the other guardians: {}",
+                hex::encode(server_cfg.public_digest())
+            );
+        }
+        Options::RotateMintKeys {
+            cfg_path,
+            cfg,
+            client_cfg,
+            deprecation_epoch,
+            port_offset,
+        } => {
+            let cfg: ServerConfig = minimint::config::load_from_file(&cfg);
+            let client_cfg: ClientConfig = minimint::config::load_from_file(&client_cfg);
+            let network_cfg = cfg.dkg_network_config(port_offset);
+
+            println!(
+                "Generating mint key set {}, coins of key set {} are accepted until epoch {}",
+                client_cfg.mint.key_set.next(),
+                client_cfg.mint.key_set,
+                deprecation_epoch
+            );
+            let connections = Connections::<DkgMessage>::connect_to_all(&network_cfg).await;
+            let mut dkg = DkgNetwork::new(
+                cfg.identity,
+                cfg.peers.keys().copied().collect(),
+                connections,
+            );
+            let (server_cfg, client_cfg) = ServerConfig::rotate_mint_keys(
+                &mut dkg,
+                &cfg,
+                &client_cfg,
+                deprecation_epoch,
+                &mut rng,
+            )
+            .await
+            .expect("Distributed key generation failed");
+            dkg.into_inner().shutdown().await;
+
+            write_server_config(&cfg_path, cfg.identity, &server_cfg);
+            write_client_config(&cfg_path, &client_cfg);
+            println!(
+                "Config digest to check with the other guardians: {}",
+                hex::encode(server_cfg.public_digest())
+            );
+        }
     }
+}
 
-    let mut client_cfg_file_path: PathBuf = cfg_path;
+fn peer_hosts(peers: &[PeerId], hosts: Vec<String>) -> BTreeMap<PeerId, String> {
+    assert!(
+        hosts.is_empty() || hosts.len() == peers.len(),
+        "Either no or exactly one host per peer has to be given"
+    );
+    peers.iter().copied().zip(hosts).collect()
+}
+
+fn parse_peer_key(s: &str) -> Result<(PeerId, NetworkPublicKey), String> {
+    let (id, key) = s
+        .split_once(':')
+        .ok_or_else(|| "Expected peer key as id:key".to_string())?;
+    let id = id.parse::<u16>().map_err(|e| e.to_string())?;
+    let key = key
+        .parse()
+        .map_err(|e: InvalidNetworkKeyError| e.to_string())?;
+    Ok((PeerId::from(id), key))
+}
+
+fn write_server_config(cfg_path: &Path, id: PeerId, cfg: &ServerConfig) {
+    let mut path: PathBuf = cfg_path.to_owned();
+    path.push(format!("server-{}.json", id));
+
+    let file = std::fs::File::create(path).expect("Could not create cfg file");
+    serde_json::to_writer_pretty(file, cfg).unwrap();
+}
+
+fn write_client_config(cfg_path: &Path, cfg: &ClientConfig) {
+    let mut client_cfg_file_path: PathBuf = cfg_path.to_owned();
     client_cfg_file_path.push("client.json");
     let client_cfg_file =
         std::fs::File::create(client_cfg_file_path).expect("Could not create cfg file");
 
-    serde_json::to_writer_pretty(client_cfg_file, &client_cfg).unwrap();
+    serde_json::to_writer_pretty(client_cfg_file, cfg).unwrap();
+
+    let invite_code = InviteCode {
+        endpoint: cfg.api_endpoints[0].clone(),
+        config_digest: cfg.digest(),
+    };
+    println!(
+        "Clients can join the federation with the invite code {}",
+        invite_code
+    );
 }
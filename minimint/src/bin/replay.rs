@@ -0,0 +1,72 @@
+use minimint::config::{load_from_file, ServerConfig};
+use minimint::replay::{replay_and_verify, StateMismatch};
+use minimint_api::db::RawDatabase;
+use std::path::PathBuf;
+use std::sync::Arc;
+use structopt::StructOpt;
+use tracing_subscriber::EnvFilter;
+
+#[derive(StructOpt)]
+struct ReplayOpts {
+    /// Current config of the guardian
+    cfg_path: PathBuf,
+    /// Configs the guardian ran with before its current one, starting with the one it was
+    /// created with. The server keeps them next to its config when switching to a new one.
+    previous_cfg_paths: Vec<PathBuf>,
+}
+
+/// Rebuilds the state of a stopped guardian from its consensus history and verifies that it
+/// matches its database.
+#[tokio::main]
+async fn main() {
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info,tide=error")),
+        )
+        .init();
+
+    let opts: ReplayOpts = StructOpt::from_args();
+    let cfg: ServerConfig = load_from_file(&opts.cfg_path);
+    let configs = opts
+        .previous_cfg_paths
+        .iter()
+        .map(|path| load_from_file(path))
+        .chain(std::iter::once(cfg.clone()))
+        .collect::<Vec<ServerConfig>>();
+
+    let database: Arc<dyn RawDatabase> =
+        Arc::new(sled::open(&cfg.db_path).unwrap().open_tree("mint").unwrap());
+
+    let (epochs, mismatches) = match replay_and_verify(&configs, database).await {
+        Ok(result) => result,
+        Err(e) => {
+            println!("Replay failed: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    for mismatch in &mismatches {
+        match mismatch {
+            StateMismatch::MissingInReplay(key) => {
+                println!("Key {} missing in replayed state", hex::encode(key))
+            }
+            StateMismatch::MissingInLive(key) => {
+                println!("Key {} missing in live state", hex::encode(key))
+            }
+            StateMismatch::ValueMismatch(key) => {
+                println!("Value of key {} differs", hex::encode(key))
+            }
+        }
+    }
+
+    if mismatches.is_empty() {
+        println!("Replayed {} epochs, state matches", epochs);
+    } else {
+        println!(
+            "Replayed {} epochs, found {} mismatches",
+            epochs,
+            mismatches.len()
+        );
+        std::process::exit(1);
+    }
+}
@@ -1,6 +1,7 @@
-use minimint::config::{load_from_file, ServerConfig, ServerOpts};
+use minimint::config::{archived_cfg_path, load_from_file, ServerConfig, ServerOpts};
 use minimint::run_minimint;
 use structopt::StructOpt;
+use tracing::{info, warn};
 use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
@@ -13,6 +14,28 @@ async fn main() {
 
     let opts: ServerOpts = StructOpt::from_args();
     let cfg: ServerConfig = load_from_file(&opts.cfg_path);
+    let next_cfg: Option<ServerConfig> = opts.next_cfg_path.as_deref().map(load_from_file);
 
-    run_minimint(cfg).await;
+    let change = run_minimint(cfg, next_cfg.as_ref().map(ServerConfig::public_digest)).await;
+
+    // The server has to be restarted to switch to the new config, e.g. by its process manager
+    match (next_cfg, opts.next_cfg_path) {
+        (Some(next_cfg), Some(next_cfg_path)) if next_cfg.public_digest() == change.digest => {
+            // Replaying the epochs before the change needs the old config
+            std::fs::copy(
+                &opts.cfg_path,
+                archived_cfg_path(&opts.cfg_path, change.activation_epoch),
+            )
+            .expect("Could not keep the old config");
+            std::fs::rename(&next_cfg_path, &opts.cfg_path)
+                .expect("Could not replace config with the next config");
+            info!(
+                "Switched to the new config, it takes effect in epoch {} once restarted",
+                change.activation_epoch
+            );
+        }
+        _ => warn!(
+            "The federation switched to a config we don't have, we are not part of it anymore"
+        ),
+    }
 }
@@ -1,26 +1,41 @@
-use bitcoin::secp256k1::rand::{CryptoRng, RngCore};
+use crate::net::encryption::{NetworkPublicKey, NetworkSecretKey};
+use async_trait::async_trait;
+use bitcoin::hashes::{sha256, Hash as BitcoinHash};
+use bitcoin::secp256k1::rand::{CryptoRng, Rng, RngCore};
 use hbbft::crypto::serde_impl::SerdeSecret;
-use minimint_api::config::GenerateConfig;
-use minimint_api::{FeeConsensus, PeerId};
-use minimint_mint::config::{MintClientConfig, MintConfig};
+use hbbft::sync_key_gen::{AckOutcome, PartOutcome, SyncKeyGen};
+use minimint_api::config::{broadcast, DkgConnections, DkgError, GenerateConfig};
+use minimint_api::CompressedPublicKey;
+use minimint_api::{FeeConsensus, Keys, PeerId};
+use minimint_mint::config::{MintClientConfig, MintConfig, MintResharingKeys};
 use minimint_wallet::config::{WalletClientConfig, WalletConfig};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
 use std::path::Path;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
 use structopt::StructOpt;
+use thiserror::Error;
 
 #[derive(StructOpt)]
 pub struct ServerOpts {
     pub cfg_path: PathBuf,
+    /// Config of the federation after a membership change, we vote to switch to it and replace
+    /// the config at `cfg_path` with it once the switch takes effect
+    #[structopt(long)]
+    pub next_cfg_path: Option<PathBuf>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ServerConfig {
     pub identity: PeerId,
-    pub hbbft_port: u16,
-    pub api_port: u16,
+    /// Address the socket for connections from other peers binds to, e.g. `0.0.0.0:4000`
+    pub hbbft_bind_addr: String,
+    /// Address the API server binds to, e.g. `0.0.0.0:5000`
+    pub api_bind_addr: String,
 
     pub peers: BTreeMap<PeerId, Peer>,
     #[serde(with = "serde_binary_human_readable")]
@@ -29,39 +44,126 @@ pub struct ServerConfig {
     pub hbbft_sks: hbbft::crypto::serde_impl::SerdeSecret<hbbft::crypto::SecretKeyShare>,
     #[serde(with = "serde_binary_human_readable")]
     pub hbbft_pk_set: hbbft::crypto::PublicKeySet,
+    /// Key identifying us to the other peers when connecting to them
+    #[serde(with = "serde_binary_human_readable")]
+    pub network_sk: NetworkSecretKey,
 
     pub db_path: PathBuf,
 
     pub wallet: WalletConfig,
     pub mint: MintConfig,
 
-    // TODO: make consensus defined
+    /// Fees the federation started with, the guardians can change them by vote, see
+    /// [`crate::consensus::FeeSchedule`]
     pub fee_consensus: FeeConsensus,
+
+    #[serde(default)]
+    pub mempool: MempoolConfig,
+
+    /// Bearer token authorizing requests to the admin API, see [`crate::net::api`]. It is local
+    /// to each guardian, if it is unset the admin API is disabled.
+    #[serde(default)]
+    pub admin_token: Option<String>,
+}
+
+/// Limits of the pool of submitted transactions that are proposed until consensus is reached on
+/// them. They are local to each guardian and don't need to be the same across the federation.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MempoolConfig {
+    /// Maximum number of transactions in the mempool
+    pub max_transactions: usize,
+    /// Maximum total size of all transactions in the mempool in bytes
+    pub max_bytes: usize,
+    /// Number of epochs after which a transaction that wasn't agreed upon is dropped
+    pub expiry_epochs: u64,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Peer {
-    pub hbbft_port: u16,
-    pub api_port: u16,
+    /// Address other peers connect to, as `host:port`
+    pub hbbft_addr: String,
+    /// Address clients send API requests to, as `host:port`
+    pub api_addr: String,
     #[serde(with = "serde_binary_human_readable")]
     pub hbbft_pk: hbbft::crypto::PublicKey,
+    /// Key the peer authenticates connections from other peers with, it is independent of its
+    /// consensus keys
+    #[serde(with = "serde_binary_human_readable")]
+    pub network_pk: NetworkPublicKey,
+}
+
+/// Everything needed to connect to the other peers of the federation
+#[derive(Debug, Clone)]
+pub struct NetworkConfig {
+    pub identity: PeerId,
+    pub bind_addr: String,
+    pub peers: BTreeMap<PeerId, NetworkPeer>,
+    /// Key used to prove our identity to the peers, the peers' keys are part of `peers`
+    pub secret_key: NetworkSecretKey,
+}
+
+/// Where to reach a peer and the key it has to prove its identity with
+#[derive(Debug, Clone)]
+pub struct NetworkPeer {
+    /// Address to connect to, as `host:port`
+    pub addr: String,
+    pub public_key: NetworkPublicKey,
+}
+
+/// Key identifying a guardian to the other guardians while running the distributed key
+/// generation, see [`GenerateConfig::distributed_gen`]. Its public key has to be shared with the
+/// other guardians beforehand.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SetupKey {
+    #[serde(with = "serde_binary_human_readable")]
+    pub secret_key: NetworkSecretKey,
 }
 
 #[derive(Debug)]
 pub struct ServerConfigParams {
     pub hbbft_base_port: u16,
     pub api_base_port: u16,
+    /// Host under which each peer is reachable, peers not contained in the map are assumed to run
+    /// on `127.0.0.1`
+    pub peer_hosts: BTreeMap<PeerId, String>,
+    /// Host all sockets bind to, e.g. `0.0.0.0` to listen on all interfaces
+    pub bind_host: String,
     pub amount_tiers: Vec<minimint_api::Amount>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ClientConfig {
     pub api_endpoints: Vec<String>,
+    /// Threshold public key of the guardians' HBBFT keys, it signs the headers of all epochs, see
+    /// [`crate::consensus::header::EpochHeader`]
+    #[serde(with = "serde_binary_human_readable")]
+    pub federation_pk: hbbft::crypto::PublicKey,
     pub mint: MintClientConfig,
     pub wallet: WalletClientConfig,
+    /// Fees the federation started with, the active ones are served under `GET /fees`
     pub fee_consensus: FeeConsensus,
 }
 
+/// Invitation to join a federation as a client. It names the API endpoint of one guardian to
+/// fetch the [`ClientConfig`] from and the config's [`ClientConfig::digest`] to check it against.
+/// Encoded as `minimint:<hex digest>@<endpoint>`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InviteCode {
+    pub endpoint: String,
+    pub config_digest: [u8; 32],
+}
+
+const INVITE_CODE_PREFIX: &str = "minimint:";
+
+#[derive(Debug, Error)]
+pub enum InviteCodeError {
+    #[error("Invite codes have the format minimint:<config digest>@<endpoint>")]
+    InvalidFormat,
+    #[error("The config digest has to be 32 bytes encoded as hex")]
+    InvalidDigest,
+}
+
+#[async_trait(?Send)]
 impl GenerateConfig for ServerConfig {
     type Params = ServerConfigParams;
     type ClientConfig = ClientConfig;
@@ -74,64 +176,60 @@ impl GenerateConfig for ServerConfig {
     ) -> (BTreeMap<PeerId, Self>, Self::ClientConfig) {
         let netinfo = hbbft::NetworkInfo::generate_map(peers.to_vec(), &mut rng)
             .expect("Could not generate HBBFT netinfo");
-
-        let cfg_peers = netinfo
+        let network_sks = peers
             .iter()
-            .map(|(&id, netinf)| {
-                let id_u16: u16 = id.into();
-                let peer = Peer {
-                    hbbft_port: params.hbbft_base_port + id_u16,
-                    api_port: params.api_base_port + id_u16,
-                    hbbft_pk: *netinf.public_key(&id).unwrap(),
-                };
-
-                (id, peer)
-            })
+            .map(|&id| (id, NetworkSecretKey::generate(&mut rng)))
             .collect::<BTreeMap<_, _>>();
 
+        let cfg_peers = params.peers(
+            &netinfo
+                .iter()
+                .map(|(&id, netinf)| (id, *netinf.public_key(&id).unwrap()))
+                .collect(),
+            &network_sks
+                .iter()
+                .map(|(&id, sk)| (id, sk.public_key()))
+                .collect(),
+        );
+
         let (wallet_server_cfg, wallet_client_cfg) =
             WalletConfig::trusted_dealer_gen(peers, max_evil, &(), &mut rng);
         let (mint_server_cfg, mint_client_cfg) =
             MintConfig::trusted_dealer_gen(peers, max_evil, params.amount_tiers.as_ref(), &mut rng);
 
-        let fee_consensus = FeeConsensus {
-            fee_coin_spend_abs: minimint_api::Amount::ZERO,
-            fee_peg_in_abs: minimint_api::Amount::from_sat(500),
-            fee_coin_issuance_abs: minimint_api::Amount::ZERO,
-            fee_peg_out_abs: minimint_api::Amount::from_sat(500),
-        };
+        let fee_consensus = default_fee_consensus();
 
         let server_config = netinfo
             .iter()
             .map(|(&id, netinf)| {
-                let id_u16: u16 = id.into();
                 let config = ServerConfig {
                     identity: id,
-                    hbbft_port: params.hbbft_base_port + id_u16,
-                    api_port: params.api_base_port + id_u16,
+                    hbbft_bind_addr: params.hbbft_bind_addr(id),
+                    api_bind_addr: params.api_bind_addr(id),
                     peers: cfg_peers.clone(),
                     hbbft_sk: SerdeSecret(netinf.secret_key().clone()),
                     hbbft_sks: SerdeSecret(netinf.secret_key_share().unwrap().clone()),
                     hbbft_pk_set: netinf.public_key_set().clone(),
+                    network_sk: network_sks[&id].clone(),
                     db_path: format!("cfg/mint-{}.db", id).into(),
                     wallet: wallet_server_cfg[&id].clone(),
                     mint: mint_server_cfg[&id].clone(),
                     fee_consensus: fee_consensus.clone(),
+                    mempool: MempoolConfig::default(),
+                    admin_token: Some(generate_admin_token(&mut rng)),
                 };
                 (id, config)
             })
             .collect();
 
         let client_config = ClientConfig {
-            api_endpoints: peers
-                .iter()
-                .map(|&peer| {
-                    format!(
-                        "http://127.0.0.1:{}",
-                        params.api_base_port + u16::from(peer)
-                    )
-                })
-                .collect(),
+            api_endpoints: api_endpoints(&cfg_peers),
+            federation_pk: netinfo
+                .values()
+                .next()
+                .expect("At least one peer")
+                .public_key_set()
+                .public_key(),
             mint: mint_client_cfg,
             wallet: wallet_client_cfg,
             fee_consensus,
@@ -139,23 +237,601 @@ impl GenerateConfig for ServerConfig {
 
         (server_config, client_config)
     }
+
+    /// Generates fresh HBBFT keys using hbbft's [`SyncKeyGen`] and runs the key generation of all
+    /// modules. Since a malicious peer could send different messages to different peers, all
+    /// peers compare the resulting public config in the end.
+    async fn distributed_gen<R>(
+        connections: &mut dyn DkgConnections,
+        max_evil: usize,
+        params: &Self::Params,
+        mut rng: R,
+    ) -> Result<(Self, Self::ClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let our_id = connections.our_id();
+
+        let (hbbft_sk, hbbft_pks, hbbft_pk_set, hbbft_sks) =
+            generate_hbbft_keys(connections, max_evil, &mut rng).await?;
+        let (network_sk, network_pks) = generate_network_key(connections, &mut rng).await?;
+
+        let (wallet_cfg, wallet_client_cfg) =
+            WalletConfig::distributed_gen(connections, max_evil, &(), &mut rng).await?;
+        let (mint_cfg, mint_client_cfg) = MintConfig::distributed_gen(
+            connections,
+            max_evil,
+            params.amount_tiers.as_ref(),
+            &mut rng,
+        )
+        .await?;
+
+        let cfg_peers = params.peers(&hbbft_pks, &network_pks);
+        let fee_consensus = default_fee_consensus();
+        let server_config = ServerConfig {
+            identity: our_id,
+            hbbft_bind_addr: params.hbbft_bind_addr(our_id),
+            api_bind_addr: params.api_bind_addr(our_id),
+            peers: cfg_peers.clone(),
+            hbbft_sk: SerdeSecret(hbbft_sk),
+            hbbft_sks: SerdeSecret(hbbft_sks),
+            hbbft_pk_set,
+            network_sk,
+            db_path: format!("cfg/mint-{}.db", our_id).into(),
+            wallet: wallet_cfg,
+            mint: mint_cfg,
+            fee_consensus: fee_consensus.clone(),
+            mempool: MempoolConfig::default(),
+            admin_token: Some(generate_admin_token(&mut rng)),
+        };
+        let client_config = ClientConfig {
+            api_endpoints: api_endpoints(&cfg_peers),
+            federation_pk: server_config.hbbft_pk_set.public_key(),
+            mint: mint_client_cfg,
+            wallet: wallet_client_cfg,
+            fee_consensus,
+        };
+
+        verify_public_config(connections, &server_config, &client_config).await?;
+
+        Ok((server_config, client_config))
+    }
 }
 
-impl ServerConfig {
-    pub fn get_hbbft_port(&self) -> u16 {
-        self.hbbft_port
+impl ServerConfigParams {
+    /// Public information about all peers given their HBBFT and network public keys
+    pub fn peers(
+        &self,
+        hbbft_pks: &BTreeMap<PeerId, hbbft::crypto::PublicKey>,
+        network_pks: &BTreeMap<PeerId, NetworkPublicKey>,
+    ) -> BTreeMap<PeerId, Peer> {
+        hbbft_pks
+            .iter()
+            .map(|(&id, &hbbft_pk)| {
+                let id_u16: u16 = id.into();
+                let peer = Peer {
+                    hbbft_addr: self.peer_hbbft_addr(id),
+                    api_addr: host_port(self.peer_host(id), self.api_base_port + id_u16),
+                    hbbft_pk,
+                    network_pk: network_pks[&id],
+                };
+
+                (id, peer)
+            })
+            .collect()
+    }
+
+    /// Config for connecting to the other peers during the distributed key generation, using
+    /// the peers' setup keys to authenticate them
+    pub fn network_config(
+        &self,
+        identity: PeerId,
+        setup_key: NetworkSecretKey,
+        peer_setup_keys: BTreeMap<PeerId, NetworkPublicKey>,
+    ) -> NetworkConfig {
+        NetworkConfig {
+            identity,
+            bind_addr: self.hbbft_bind_addr(identity),
+            peers: peer_setup_keys
+                .into_iter()
+                .map(|(id, public_key)| {
+                    let peer = NetworkPeer {
+                        addr: self.peer_hbbft_addr(id),
+                        public_key,
+                    };
+                    (id, peer)
+                })
+                .collect(),
+            secret_key: setup_key,
+        }
+    }
+
+    /// Host under which `peer` is reachable
+    fn peer_host(&self, peer: PeerId) -> &str {
+        self.peer_hosts
+            .get(&peer)
+            .map(String::as_str)
+            .unwrap_or("127.0.0.1")
+    }
+
+    fn peer_hbbft_addr(&self, peer: PeerId) -> String {
+        host_port(self.peer_host(peer), self.hbbft_base_port + u16::from(peer))
     }
-    pub fn get_api_port(&self) -> u16 {
-        self.api_port
+
+    pub fn hbbft_bind_addr(&self, peer: PeerId) -> String {
+        host_port(&self.bind_host, self.hbbft_base_port + u16::from(peer))
+    }
+
+    pub fn api_bind_addr(&self, peer: PeerId) -> String {
+        host_port(&self.bind_host, self.api_base_port + u16::from(peer))
     }
+}
 
-    pub fn get_incoming_count(&self) -> u16 {
-        self.identity.into()
+impl Default for MempoolConfig {
+    fn default() -> Self {
+        MempoolConfig {
+            max_transactions: 1000,
+            max_bytes: 1_000_000,
+            expiry_epochs: 10,
+        }
     }
+}
 
+impl ServerConfig {
     pub fn max_faulty(&self) -> usize {
         hbbft::util::max_faulty(self.peers.len())
     }
+
+    pub fn network_config(&self) -> NetworkConfig {
+        NetworkConfig {
+            identity: self.identity,
+            bind_addr: self.hbbft_bind_addr.clone(),
+            peers: self
+                .peers
+                .iter()
+                .map(|(&id, peer)| {
+                    let peer = NetworkPeer {
+                        addr: peer.hbbft_addr.clone(),
+                        public_key: peer.network_pk,
+                    };
+                    (id, peer)
+                })
+                .collect(),
+            secret_key: self.network_sk.clone(),
+        }
+    }
+
+    /// Config for connecting to the other guardians to run a key generation while the federation
+    /// is running. The guardians' keys and hosts are used, but all ports are offset by
+    /// `port_offset` to not collide with the running federation.
+    pub fn dkg_network_config(&self, port_offset: u16) -> NetworkConfig {
+        let offset_port = |addr: &str| {
+            let (host, port) = addr.rsplit_once(':').expect("Address contains a port");
+            let port = port.parse::<u16>().expect("Invalid port") + port_offset;
+            format!("{}:{}", host, port)
+        };
+
+        let mut network_cfg = self.network_config();
+        network_cfg.bind_addr = offset_port(&network_cfg.bind_addr);
+        for peer in network_cfg.peers.values_mut() {
+            peer.addr = offset_port(&peer.addr);
+        }
+        network_cfg
+    }
+
+    /// Config for clients of the federation, it is the same for all guardians
+    pub fn client_config(&self) -> ClientConfig {
+        ClientConfig {
+            api_endpoints: api_endpoints(&self.peers),
+            federation_pk: self.hbbft_pk_set.public_key(),
+            mint: self
+                .mint
+                .client_config(self.peers.len() - self.max_faulty()),
+            wallet: self.wallet.client_config(),
+            fee_consensus: self.fee_consensus.clone(),
+        }
+    }
+
+    /// Digest of the parts of the config that are the same for all guardians. Guardians vote for
+    /// switching to a new config by its digest.
+    pub fn public_digest(&self) -> [u8; 32] {
+        let legacy_peg_in = self
+            .wallet
+            .legacy_peg_in
+            .as_ref()
+            .map(|legacy| (&legacy.peg_in_descriptor, &legacy.peer_peg_in_keys));
+        let public_config = bincode::serialize(&(
+            &self.peers,
+            &self.hbbft_pk_set,
+            &self.mint.peer_tbs_pks,
+            self.mint.key_set,
+            &self.mint.retired_key_sets,
+            &self.wallet.peg_in_descriptor,
+            &self.wallet.peer_peg_in_keys,
+            legacy_peg_in,
+            &self.fee_consensus,
+        ))
+        .expect("Serialization can't fail");
+        sha256::Hash::hash(&public_config).into_inner()
+    }
+
+    /// Generates the config of a federation whose set of guardians changes, e.g. to replace a
+    /// compromised guardian. All guardians of the new federation take part, the ones that are
+    /// part of the current federation pass their `old_cfg`. Guardians keep their peer ids, new
+    /// guardians have to use ids that weren't used by the current federation.
+    ///
+    /// The new federation gets fresh HBBFT keys and a new peg-in wallet that the current wallet's
+    /// funds are swept to. The mint's keys are reshared, so coins issued by the current federation
+    /// stay valid. Both resharing the mint keys and sweeping the wallet require that at least the
+    /// current threshold of guardians continues.
+    ///
+    /// The current federation switches to the new config once enough of its guardians voted for
+    /// it, see [`crate::consensus::ConsensusItem::ConfigChange`].
+    pub async fn reshare<R>(
+        connections: &mut dyn DkgConnections,
+        old_cfg: Option<&ServerConfig>,
+        old_client_cfg: &ClientConfig,
+        params: &ServerConfigParams,
+        mut rng: R,
+    ) -> Result<(ServerConfig, ClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let our_id = connections.our_id();
+        let max_evil = hbbft::util::max_faulty(connections.peers().len());
+        if let Some(old_cfg) = old_cfg {
+            assert_eq!(old_cfg.identity, our_id, "Guardians have to keep their id");
+        }
+
+        // New guardians learn the current federation's public key shares from the continuing
+        // ones, the keys are checked against the aggregate keys in `old_client_cfg` later on
+        let old_keys: Option<OldFederationKeys> = old_cfg.map(|old_cfg| {
+            (
+                old_cfg.mint.peer_tbs_pks.clone(),
+                old_cfg.wallet.peer_peg_in_keys.clone(),
+            )
+        });
+        let received = broadcast(connections, "old-keys", old_keys).await?;
+        let (old_peer_tbs_pks, old_peer_peg_in_keys) = received
+            .values()
+            .flatten()
+            .next()
+            .cloned()
+            .ok_or(DkgError::NotEnoughDealers(0, 1))?;
+        for (peer, keys) in &received {
+            match keys {
+                Some(keys) if *keys != (old_peer_tbs_pks.clone(), old_peer_peg_in_keys.clone()) => {
+                    return Err(DkgError::ConfigMismatch)
+                }
+                Some(_) if !old_peer_tbs_pks.contains_key(peer) => {
+                    return Err(DkgError::FaultyPeer(
+                        *peer,
+                        "claims to be part of the current federation".to_string(),
+                    ))
+                }
+                None if old_peer_tbs_pks.contains_key(peer) => {
+                    return Err(DkgError::FaultyPeer(
+                        *peer,
+                        "uses the id of a current guardian".to_string(),
+                    ))
+                }
+                _ => {}
+            }
+        }
+
+        let old_peers = old_peer_tbs_pks.len();
+        let old_threshold = old_peers - hbbft::util::max_faulty(old_peers);
+        let continuing = received.values().filter(|keys| keys.is_some()).count();
+        if continuing < old_threshold {
+            return Err(DkgError::NotEnoughDealers(continuing, old_threshold));
+        }
+
+        let (hbbft_sk, hbbft_pks, hbbft_pk_set, hbbft_sks) =
+            generate_hbbft_keys(connections, max_evil, &mut rng).await?;
+        let (network_sk, network_pks) = generate_network_key(connections, &mut rng).await?;
+
+        let (wallet_cfg, wallet_client_cfg) = WalletConfig::reshare(
+            connections,
+            max_evil,
+            old_cfg.map(|old_cfg| &old_cfg.wallet),
+            &old_client_cfg.wallet,
+            &old_peer_peg_in_keys,
+            old_threshold,
+            &mut rng,
+        )
+        .await?;
+        let (mint_cfg, mint_client_cfg) = MintConfig::reshare(
+            connections,
+            max_evil,
+            old_cfg.map(|old_cfg| &old_cfg.mint),
+            &MintResharingKeys {
+                peer_tbs_pks: old_peer_tbs_pks,
+                client_cfg: old_client_cfg.mint.clone(),
+            },
+            old_threshold,
+            &mut rng,
+        )
+        .await?;
+
+        let cfg_peers = params.peers(&hbbft_pks, &network_pks);
+        let fee_consensus = old_client_cfg.fee_consensus.clone();
+        let server_config = ServerConfig {
+            identity: our_id,
+            hbbft_bind_addr: params.hbbft_bind_addr(our_id),
+            api_bind_addr: params.api_bind_addr(our_id),
+            peers: cfg_peers.clone(),
+            hbbft_sk: SerdeSecret(hbbft_sk),
+            hbbft_sks: SerdeSecret(hbbft_sks),
+            hbbft_pk_set,
+            network_sk,
+            // Continuing guardians keep their database, new guardians start from a copy of a
+            // continuing guardian's database
+            db_path: old_cfg.map_or_else(
+                || format!("cfg/mint-{}.db", our_id).into(),
+                |old_cfg| old_cfg.db_path.clone(),
+            ),
+            wallet: wallet_cfg,
+            mint: mint_cfg,
+            fee_consensus: fee_consensus.clone(),
+            mempool: old_cfg.map_or_else(MempoolConfig::default, |old_cfg| old_cfg.mempool.clone()),
+            admin_token: match old_cfg {
+                Some(old_cfg) => old_cfg.admin_token.clone(),
+                None => Some(generate_admin_token(&mut rng)),
+            },
+        };
+        let client_config = ClientConfig {
+            api_endpoints: api_endpoints(&cfg_peers),
+            federation_pk: server_config.hbbft_pk_set.public_key(),
+            mint: mint_client_cfg,
+            wallet: wallet_client_cfg,
+            fee_consensus,
+        };
+
+        verify_public_config(connections, &server_config, &client_config).await?;
+
+        Ok((server_config, client_config))
+    }
+
+    /// Generates a config in which the mint uses a fresh key set, everything else stays the same.
+    /// All guardians of the federation have to take part. The federation switches to the new
+    /// config once enough guardians voted for it, see
+    /// [`crate::consensus::ConsensusItem::ConfigChange`].
+    ///
+    /// Coins of the current key set are accepted until `deprecation_epoch`, clients have to
+    /// reissue them before.
+    pub async fn rotate_mint_keys<R>(
+        connections: &mut dyn DkgConnections,
+        cfg: &ServerConfig,
+        client_cfg: &ClientConfig,
+        deprecation_epoch: u64,
+        rng: R,
+    ) -> Result<(ServerConfig, ClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        assert!(
+            connections.peers().iter().eq(cfg.peers.keys()),
+            "All guardians have to take part"
+        );
+
+        let (mint_cfg, mint_client_cfg) = MintConfig::rotate(
+            connections,
+            cfg.max_faulty(),
+            &client_cfg.mint,
+            deprecation_epoch,
+            rng,
+        )
+        .await?;
+
+        let server_config = ServerConfig {
+            mint: mint_cfg,
+            ..cfg.clone()
+        };
+        let client_config = ClientConfig {
+            mint: mint_client_cfg,
+            ..client_cfg.clone()
+        };
+
+        verify_public_config(connections, &server_config, &client_config).await?;
+
+        Ok((server_config, client_config))
+    }
+}
+
+impl ClientConfig {
+    pub fn max_faulty(&self) -> usize {
+        hbbft::util::max_faulty(self.api_endpoints.len())
+    }
+
+    /// Digest all guardians agree on, clients use it to authenticate the config they received
+    /// from a single guardian, see [`InviteCode`]
+    pub fn digest(&self) -> [u8; 32] {
+        let encoded = bincode::serialize(self).expect("Serialization can't fail");
+        sha256::Hash::hash(&encoded).into_inner()
+    }
+}
+
+impl Display for InviteCode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}{}@{}",
+            INVITE_CODE_PREFIX,
+            hex::encode(self.config_digest),
+            self.endpoint
+        )
+    }
+}
+
+impl FromStr for InviteCode {
+    type Err = InviteCodeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (digest, endpoint) = s
+            .strip_prefix(INVITE_CODE_PREFIX)
+            .and_then(|code| code.split_once('@'))
+            .ok_or(InviteCodeError::InvalidFormat)?;
+
+        let mut config_digest = [0u8; 32];
+        hex::decode_to_slice(digest, &mut config_digest)
+            .map_err(|_| InviteCodeError::InvalidDigest)?;
+        if endpoint.is_empty() {
+            return Err(InviteCodeError::InvalidFormat);
+        }
+
+        Ok(InviteCode {
+            endpoint: endpoint.to_string(),
+            config_digest,
+        })
+    }
+}
+
+/// Public key shares of the mint and peg-in keys of a federation's guardians
+type OldFederationKeys = (
+    BTreeMap<PeerId, Keys<tbs::PublicKeyShare>>,
+    BTreeMap<PeerId, CompressedPublicKey>,
+);
+
+/// Generates fresh HBBFT keys using hbbft's [`SyncKeyGen`]. Returns our secret key, all peers'
+/// public keys, the public key set and our secret key share.
+async fn generate_hbbft_keys<R>(
+    connections: &mut dyn DkgConnections,
+    max_evil: usize,
+    rng: &mut R,
+) -> Result<
+    (
+        hbbft::crypto::SecretKey,
+        BTreeMap<PeerId, hbbft::crypto::PublicKey>,
+        hbbft::crypto::PublicKeySet,
+        hbbft::crypto::SecretKeyShare,
+    ),
+    DkgError,
+>
+where
+    R: RngCore + CryptoRng,
+{
+    let our_id = connections.our_id();
+    let peers = connections.peers().to_vec();
+
+    let hbbft_sk: hbbft::crypto::SecretKey = rng.gen();
+    let hbbft_pks = broadcast(connections, "hbbft-key", hbbft_sk.public_key()).await?;
+
+    let (mut key_gen, part) = SyncKeyGen::new(
+        our_id,
+        hbbft_sk.clone(),
+        Arc::new(hbbft_pks.clone()),
+        max_evil,
+        rng,
+    )
+    .expect("Our own keys are valid");
+    let part = part.expect("We are part of the key generation");
+
+    let mut acks = Vec::new();
+    for (peer, part) in broadcast(connections, "hbbft-part", part).await? {
+        match key_gen.handle_part(&peer, part, rng) {
+            Ok(PartOutcome::Valid(Some(ack))) => acks.push(ack),
+            Ok(PartOutcome::Valid(None)) => unreachable!("We are part of the key generation"),
+            Ok(PartOutcome::Invalid(fault)) => {
+                return Err(DkgError::FaultyPeer(peer, format!("{:?}", fault)))
+            }
+            Err(e) => return Err(DkgError::FaultyPeer(peer, e.to_string())),
+        }
+    }
+
+    for (peer, acks) in broadcast(connections, "hbbft-acks", acks).await? {
+        if acks.len() != peers.len() {
+            return Err(DkgError::FaultyPeer(
+                peer,
+                "missing HBBFT key generation acks".to_string(),
+            ));
+        }
+        for ack in acks {
+            match key_gen.handle_ack(&peer, ack) {
+                Ok(AckOutcome::Valid) => {}
+                Ok(AckOutcome::Invalid(fault)) => {
+                    return Err(DkgError::FaultyPeer(peer, format!("{:?}", fault)))
+                }
+                Err(e) => return Err(DkgError::FaultyPeer(peer, e.to_string())),
+            }
+        }
+    }
+
+    assert!(key_gen.is_ready(), "All parts and acks were handled");
+    let (hbbft_pk_set, hbbft_sks) = key_gen.generate().expect("All parts and acks were valid");
+
+    Ok((
+        hbbft_sk,
+        hbbft_pks,
+        hbbft_pk_set,
+        hbbft_sks.expect("We are part of the key generation"),
+    ))
+}
+
+/// Since a malicious peer could send different messages to different peers, all peers compare
+/// the resulting public config in the end
+async fn verify_public_config(
+    connections: &mut dyn DkgConnections,
+    server_config: &ServerConfig,
+    client_config: &ClientConfig,
+) -> Result<(), DkgError> {
+    let public_config = bincode::serialize(&(client_config, server_config.public_digest()))
+        .expect("Serialization can't fail");
+    let digest = sha256::Hash::hash(&public_config).into_inner();
+    let digests = broadcast(connections, "verify", digest).await?;
+    if digests.values().any(|peer_digest| *peer_digest != digest) {
+        return Err(DkgError::ConfigMismatch);
+    }
+
+    Ok(())
+}
+
+/// Generates our key for connecting to the other peers and exchanges the public keys with them.
+/// The exchange is authenticated by the setup keys the key generation runs with. Returns our
+/// secret key and all peers' public keys.
+async fn generate_network_key<R>(
+    connections: &mut dyn DkgConnections,
+    rng: &mut R,
+) -> Result<(NetworkSecretKey, BTreeMap<PeerId, NetworkPublicKey>), DkgError>
+where
+    R: RngCore + CryptoRng,
+{
+    let network_sk = NetworkSecretKey::generate(rng);
+    let network_pks = broadcast(connections, "network-key", network_sk.public_key()).await?;
+    Ok((network_sk, network_pks))
+}
+
+/// Generates a random token for authenticating to the admin API
+fn generate_admin_token(rng: &mut impl RngCore) -> String {
+    let mut token = [0u8; 32];
+    rng.fill_bytes(&mut token);
+    hex::encode(token)
+}
+
+fn default_fee_consensus() -> FeeConsensus {
+    FeeConsensus {
+        fee_coin_spend_abs: minimint_api::Amount::ZERO,
+        fee_peg_in_abs: minimint_api::Amount::from_sat(500),
+        fee_coin_issuance_abs: minimint_api::Amount::ZERO,
+        fee_peg_out_abs: minimint_api::Amount::from_sat(500),
+        fee_peg_in_ppm: 0,
+        fee_peg_out_ppm: 0,
+    }
+}
+
+fn api_endpoints(peers: &BTreeMap<PeerId, Peer>) -> Vec<String> {
+    peers
+        .values()
+        .map(|peer| format!("http://{}", peer.api_addr))
+        .collect()
+}
+
+/// Formats `host` and `port` as a socket address, IPv6 addresses are put in brackets
+fn host_port(host: &str, port: u16) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
 }
 
 pub fn load_from_file<T: DeserializeOwned>(path: &Path) -> T {
@@ -163,7 +839,15 @@ pub fn load_from_file<T: DeserializeOwned>(path: &Path) -> T {
     serde_json::from_reader(file).expect("Could not parse cfg file.")
 }
 
-mod serde_binary_human_readable {
+/// Path the config at `cfg_path` is kept under once it is replaced by a config taking effect in
+/// `activation_epoch`, e.g. `server-0.json.until-1234`
+pub fn archived_cfg_path(cfg_path: &Path, activation_epoch: u64) -> PathBuf {
+    let mut path = cfg_path.as_os_str().to_owned();
+    path.push(format!(".until-{}", activation_epoch));
+    path.into()
+}
+
+pub(crate) mod serde_binary_human_readable {
     use serde::de::DeserializeOwned;
     use serde::{Deserialize, Deserializer, Serialize, Serializer};
 
@@ -187,3 +871,130 @@ mod serde_binary_human_readable {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{host_port, InviteCode, InviteCodeError, ServerConfig, ServerConfigParams};
+    use crate::net::encryption::NetworkSecretKey;
+    use minimint_api::config::GenerateConfig;
+    use minimint_api::{Amount, PeerId};
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_host_port() {
+        assert_eq!(host_port("127.0.0.1", 4000), "127.0.0.1:4000");
+        assert_eq!(
+            host_port("guardian.example.com", 4000),
+            "guardian.example.com:4000"
+        );
+        assert_eq!(host_port("::1", 4000), "[::1]:4000");
+        assert_eq!(host_port("[fe80::1]", 4000), "[fe80::1]:4000");
+    }
+
+    #[test]
+    fn test_peer_addresses() {
+        let peers = (0..3).map(PeerId::from).collect::<Vec<_>>();
+        let params = ServerConfigParams {
+            hbbft_base_port: 4000,
+            api_base_port: 5000,
+            peer_hosts: vec![
+                (PeerId::from(1), "10.0.0.2".to_string()),
+                (PeerId::from(2), "fd00::3".to_string()),
+            ]
+            .into_iter()
+            .collect(),
+            bind_host: "::".to_string(),
+            amount_tiers: vec![Amount::from_sat(1)],
+        };
+        let (cfgs, client_cfg) =
+            ServerConfig::trusted_dealer_gen(&peers, 0, &params, rand::rngs::OsRng::new().unwrap());
+
+        // Peers without a host run on localhost
+        let addrs = cfgs[&PeerId::from(0)]
+            .peers
+            .values()
+            .map(|peer| (peer.hbbft_addr.as_str(), peer.api_addr.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            addrs,
+            vec![
+                ("127.0.0.1:4000", "127.0.0.1:5000"),
+                ("10.0.0.2:4001", "10.0.0.2:5001"),
+                ("[fd00::3]:4002", "[fd00::3]:5002"),
+            ]
+        );
+        assert_eq!(
+            client_cfg.api_endpoints,
+            vec![
+                "http://127.0.0.1:5000",
+                "http://10.0.0.2:5001",
+                "http://[fd00::3]:5002"
+            ]
+        );
+
+        // Sockets bind to the bind host instead of the host the peer is reachable under
+        let cfg = &cfgs[&PeerId::from(1)];
+        assert_eq!(cfg.hbbft_bind_addr, "[::]:4001");
+        assert_eq!(cfg.api_bind_addr, "[::]:5001");
+        assert_eq!(params.hbbft_bind_addr(PeerId::from(2)), "[::]:4002");
+        assert_eq!(params.api_bind_addr(PeerId::from(2)), "[::]:5002");
+
+        let network_cfg = cfg.network_config();
+        assert_eq!(network_cfg.bind_addr, "[::]:4001");
+        assert_eq!(network_cfg.peers[&PeerId::from(2)].addr, "[fd00::3]:4002");
+        assert_eq!(
+            network_cfg.peers[&PeerId::from(2)].public_key,
+            cfg.peers[&PeerId::from(2)].network_pk
+        );
+
+        // The key generation of a running federation uses offset ports
+        let dkg_cfg = cfg.dkg_network_config(1000);
+        assert_eq!(dkg_cfg.bind_addr, "[::]:5001");
+        assert_eq!(dkg_cfg.peers[&PeerId::from(0)].addr, "127.0.0.1:5000");
+        assert_eq!(dkg_cfg.peers[&PeerId::from(2)].addr, "[fd00::3]:5002");
+
+        // Before the key generation peers are authenticated by their setup keys
+        let mut rng = rand::rngs::OsRng::new().unwrap();
+        let setup_keys = peers
+            .iter()
+            .map(|&peer| (peer, NetworkSecretKey::generate(&mut rng)))
+            .collect::<BTreeMap<_, _>>();
+        let setup_cfg = params.network_config(
+            PeerId::from(0),
+            setup_keys[&PeerId::from(0)].clone(),
+            setup_keys
+                .iter()
+                .map(|(&peer, key)| (peer, key.public_key()))
+                .collect(),
+        );
+        assert_eq!(setup_cfg.bind_addr, "[::]:4000");
+        assert_eq!(setup_cfg.peers[&PeerId::from(1)].addr, "10.0.0.2:4001");
+        assert_eq!(
+            setup_cfg.peers[&PeerId::from(1)].public_key,
+            setup_keys[&PeerId::from(1)].public_key()
+        );
+    }
+
+    #[test]
+    fn test_invite_code() {
+        let invite_code = InviteCode {
+            endpoint: "http://127.0.0.1:5000".to_string(),
+            config_digest: [0x42; 32],
+        };
+        let encoded = invite_code.to_string();
+        assert_eq!(
+            encoded,
+            format!("minimint:{}@http://127.0.0.1:5000", "42".repeat(32))
+        );
+        assert_eq!(encoded.parse::<InviteCode>().unwrap(), invite_code);
+
+        assert!(matches!(
+            "minimint:42@http://127.0.0.1:5000".parse::<InviteCode>(),
+            Err(InviteCodeError::InvalidDigest)
+        ));
+        assert!(matches!(
+            format!("{}@http://127.0.0.1:5000", "42".repeat(32)).parse::<InviteCode>(),
+            Err(InviteCodeError::InvalidFormat)
+        ));
+    }
+}
@@ -0,0 +1,71 @@
+//! Randomness beacon of the federation. For every epoch the peers contribute shares of the
+//! federation's threshold signature over the epoch number, which are combined into the signature
+//! once more than `threshold` peers contributed one. Threshold signatures are unique, so no peer
+//! can bias the beacon by choosing or withholding its share, and nobody can predict it before
+//! enough honest peers revealed their shares.
+
+use bitcoin::hashes::{sha256, Hash as BitcoinHash};
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::module::RandomnessBeacon;
+use serde::{Deserialize, Serialize};
+
+/// Separates the signed beacon messages from the epoch headers signed with the same key
+const BEACON_TAG: &[u8] = b"minimint-randomness-beacon";
+
+/// A peer's share of the signature the beacon of `epoch` is derived from, exchanged as
+/// [`crate::consensus::ConsensusItem::BeaconShare`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct BeaconShare {
+    pub epoch: u64,
+    /// bincode encoded [`hbbft::crypto::SignatureShare`] over [`beacon_message`]
+    pub share: Vec<u8>,
+}
+
+/// The message the federation signs to produce the beacon of `epoch`
+pub fn beacon_message(epoch: u64) -> Vec<u8> {
+    let mut message = BEACON_TAG.to_vec();
+    message.extend_from_slice(&epoch.to_be_bytes());
+    message
+}
+
+/// Derives the beacon of `epoch` from the federation's signature over [`beacon_message`]
+pub fn beacon_from_signature(epoch: u64, signature: &hbbft::crypto::Signature) -> RandomnessBeacon {
+    RandomnessBeacon {
+        epoch,
+        value: sha256::Hash::hash(&signature.to_bytes()).into_inner(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consensus::beacon::{beacon_from_signature, beacon_message};
+    use hbbft::crypto::SecretKeySet;
+
+    #[test]
+    fn test_beacon() {
+        let mut rng = rand::rngs::OsRng::new().unwrap();
+        let sk_set = SecretKeySet::random(1, &mut rng);
+        let pk_set = sk_set.public_keys();
+
+        let beacon = |epoch: u64, peers: &[usize]| {
+            let shares = peers
+                .iter()
+                .map(|&peer| {
+                    (
+                        peer,
+                        sk_set.secret_key_share(peer).sign(beacon_message(epoch)),
+                    )
+                })
+                .collect::<Vec<_>>();
+            let signature = pk_set
+                .combine_signatures(shares.iter().map(|(peer, share)| (*peer, share)))
+                .unwrap();
+            beacon_from_signature(epoch, &signature)
+        };
+
+        // Every set of more than `threshold` shares results in the same beacon
+        assert_eq!(beacon(0, &[0, 1]), beacon(0, &[2, 3]));
+        assert_eq!(beacon(0, &[0, 1]), beacon(0, &[1, 2, 3]));
+        assert_ne!(beacon(0, &[0, 1]).value, beacon(1, &[0, 1]).value);
+    }
+}
@@ -1,69 +1,182 @@
-use minimint_api::transaction::{Input, Transaction};
-use minimint_api::{Coin, Coins, PegInProof};
+use minimint_api::module::{ModuleId, ModuleRegistry};
+use minimint_api::transaction::Transaction;
 use std::collections::HashSet;
+use tracing::debug;
 
 pub trait ConflictFilterable<T>
 where
     Self: Iterator<Item = T> + Sized,
 {
-    fn filter_conflicts<F>(self, map: F) -> ConflictFilter<Self, T, F>
+    fn filter_conflicts<F>(
+        self,
+        modules: &ModuleRegistry,
+        map: F,
+    ) -> ConflictFilter<'_, Self, T, F>
     where
         F: Fn(&T) -> &Transaction;
 }
 
-pub struct ConflictFilter<I, T, F>
+/// Iterator adapter that yields every transaction spending something (e.g. a coin or a peg-in
+/// output) that was already spent by a previous transaction or by another one of its own inputs as
+/// `Err`, all others as `Ok`. Conflicting transactions don't mark their inputs as spent.
+pub struct ConflictFilter<'a, I, T, F>
 where
     I: Iterator<Item = T>,
     F: Fn(&T) -> &Transaction,
 {
     inner_iter: I,
     tx_accessor: F,
-    coin_set: HashSet<Coins<Coin>>,
-    peg_in_set: HashSet<PegInProof>,
+    modules: &'a ModuleRegistry,
+    spent_set: HashSet<(ModuleId, Vec<u8>)>,
 }
 
 impl<I, T> ConflictFilterable<T> for I
 where
     I: Iterator<Item = T>,
 {
-    fn filter_conflicts<F>(self, tx_accessor: F) -> ConflictFilter<Self, T, F>
+    fn filter_conflicts<F>(
+        self,
+        modules: &ModuleRegistry,
+        tx_accessor: F,
+    ) -> ConflictFilter<'_, Self, T, F>
     where
         F: Fn(&T) -> &Transaction,
     {
         ConflictFilter {
             inner_iter: self,
             tx_accessor,
-            coin_set: Default::default(),
-            peg_in_set: Default::default(),
+            modules,
+            spent_set: Default::default(),
         }
     }
 }
 
-impl<I, T, F> Iterator for ConflictFilter<I, T, F>
+impl<'a, I, T, F> Iterator for ConflictFilter<'a, I, T, F>
 where
     I: Iterator<Item = T>,
     F: Fn(&T) -> &Transaction,
 {
-    type Item = T;
+    type Item = Result<T, T>;
 
     fn next(&mut self) -> Option<Self::Item> {
         let next = self.inner_iter.next()?;
         let tx = (self.tx_accessor)(&next);
-        for input in &tx.inputs {
-            match input {
-                Input::Coins(ref coins) => {
-                    // TODO: can this be done without cloning? E.g. hashing?
-                    if !self.coin_set.insert(coins.clone()) {
-                        return None;
-                    }
-                }
-                Input::PegIn(ref peg_in) => {
-                    if !self.peg_in_set.insert(peg_in.as_ref().clone()) {
-                        return None;
-                    }
-                }
+
+        let mut tx_spent_set = HashSet::new();
+        let mut is_conflicting = false;
+        for key in conflict_keys(self.modules, tx) {
+            if self.spent_set.contains(&key) || !tx_spent_set.insert(key) {
+                is_conflicting = true;
             }
         }
-        Some(next)
+
+        if is_conflicting {
+            debug!("Transaction {} conflicts with a previous one", tx.tx_hash());
+            return Some(Err(next));
+        }
+
+        self.spent_set.extend(tx_spent_set);
+        Some(Ok(next))
+    }
+}
+
+/// Returns the conflict keys of all inputs of `tx`, qualified by the module they belong to.
+/// Inputs that can't be decoded are skipped since they make the transaction fail when it is
+/// processed anyway.
+pub fn conflict_keys(modules: &ModuleRegistry, tx: &Transaction) -> Vec<(ModuleId, Vec<u8>)> {
+    tx.inputs
+        .iter()
+        .filter_map(|input| {
+            let keys = modules
+                .get(input.module)?
+                .input_conflict_keys(&input.data)
+                .ok()?;
+            Some(keys.into_iter().map(move |key| (input.module, key)))
+        })
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consensus::conflictfilter::ConflictFilterable;
+    use crate::testing::{
+        coin_input, federation_configs, mem_db, mint_modules, peg_in_input, transaction,
+        wallet_module,
+    };
+    use minimint_api::module::{ModuleRegistry, WALLET_MODULE_ID};
+    use minimint_api::transaction::Transaction;
+    use minimint_api::{PeerId, TransactionId};
+
+    async fn modules() -> ModuleRegistry {
+        let cfg = federation_configs(4).remove(&PeerId::from(0)).unwrap();
+        let db = mem_db();
+        let mut modules = mint_modules(&cfg, db.clone());
+        modules.register(WALLET_MODULE_ID, wallet_module(&cfg, db).await);
+        modules
+    }
+
+    /// Ids of the transactions that pass the filter
+    fn filter(modules: &ModuleRegistry, transactions: &[Transaction]) -> Vec<TransactionId> {
+        transactions
+            .iter()
+            .filter_conflicts(modules, |tx| *tx)
+            .filter_map(Result::ok)
+            .map(Transaction::tx_hash)
+            .collect()
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_double_spent_coin() {
+        let modules = modules().await;
+        let first = transaction(vec![coin_input(1), coin_input(2)]);
+        let second = transaction(vec![coin_input(2)]);
+        let self_conflicting = transaction(vec![coin_input(3), coin_input(3)]);
+
+        assert_eq!(
+            filter(&modules, &[first.clone(), second, self_conflicting]),
+            vec![first.tx_hash()]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_duplicate_peg_in() {
+        let modules = modules().await;
+        let first = transaction(vec![peg_in_input(1, 1)]);
+        // Same outpoint claimed for a different contract key
+        let second = transaction(vec![peg_in_input(1, 2)]);
+        let other_output = transaction(vec![peg_in_input(2, 1)]);
+
+        assert_eq!(
+            filter(&modules, &[first.clone(), second, other_output.clone()]),
+            vec![first.tx_hash(), other_output.tx_hash()]
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_continues_after_conflict() {
+        let modules = modules().await;
+        let first = transaction(vec![coin_input(1), peg_in_input(1, 1)]);
+        let conflicting = transaction(vec![coin_input(2), peg_in_input(1, 1)]);
+        let independent = transaction(vec![coin_input(3), peg_in_input(2, 1)]);
+        // The coin of the dropped transaction isn't marked as spent
+        let reusing_dropped_coin = transaction(vec![coin_input(2)]);
+
+        assert_eq!(
+            filter(
+                &modules,
+                &[
+                    first.clone(),
+                    conflicting,
+                    independent.clone(),
+                    reusing_dropped_coin.clone()
+                ]
+            ),
+            vec![
+                first.tx_hash(),
+                independent.tx_hash(),
+                reusing_dropped_coin.tx_hash()
+            ]
+        );
     }
 }
@@ -0,0 +1,113 @@
+//! Detection of peers whose consensus state diverged from the rest of the federation, e.g. due to
+//! a bug, DB corruption or a manual intervention. After every epoch each peer digests the changes
+//! to its consensus state and reports the digest to the other peers, which compare it to their own.
+
+use bitcoin::hashes::{sha256, Hash as BitcoinHash, HashEngine};
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+
+/// A peer's digest of its consensus state after `epoch`, exchanged as
+/// [`crate::consensus::ConsensusItem::StateDigest`]
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct StateDigest {
+    pub epoch: u64,
+    pub digest: [u8; 32],
+}
+
+/// Chains the changes to the consensus state made in an epoch to the digest of the previous
+/// epoch. Hashing the whole state every epoch would be too expensive, but since every epoch's
+/// changes are included the digest still commits to the entire state built since the first epoch.
+///
+/// The `changes` have to be ordered by key, deleted keys have no value.
+pub fn chain_state_digest(prev: [u8; 32], changes: &[(Vec<u8>, Option<Vec<u8>>)]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&prev);
+    for change in changes {
+        change
+            .consensus_encode(&mut engine)
+            .expect("Writing to a hash engine can't fail");
+    }
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+/// Returns the digest reported by at least `threshold` peers together with the peers that
+/// reported a different one. Returns `None` as long as no digest has enough support.
+///
+/// Since `threshold` is more than half of all peers, at most one digest can reach it.
+pub fn deviating_peers(
+    digests: &BTreeMap<PeerId, [u8; 32]>,
+    threshold: usize,
+) -> Option<([u8; 32], BTreeSet<PeerId>)> {
+    let agreed = *digests.values().find(|digest| {
+        digests
+            .values()
+            .filter(|other_digest| other_digest == digest)
+            .count()
+            >= threshold
+    })?;
+
+    let deviating = digests
+        .iter()
+        .filter(|(_, digest)| **digest != agreed)
+        .map(|(peer, _)| *peer)
+        .collect();
+    Some((agreed, deviating))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consensus::divergence::{chain_state_digest, deviating_peers};
+    use minimint_api::PeerId;
+    use std::collections::{BTreeMap, BTreeSet};
+
+    #[test]
+    fn test_state_digest() {
+        let changes = vec![(vec![0x02, 1], Some(vec![1])), (vec![0x02, 2], None)];
+        let digest = chain_state_digest([0; 32], &changes);
+        assert_eq!(digest, chain_state_digest([0; 32], &changes));
+        assert_ne!(digest, chain_state_digest([1; 32], &changes));
+        assert_ne!(digest, chain_state_digest([0; 32], &changes[..1]));
+
+        // A deleted key differs from one set to an empty value
+        let emptied = vec![
+            (vec![0x02, 1], Some(vec![1])),
+            (vec![0x02, 2], Some(vec![])),
+        ];
+        assert_ne!(digest, chain_state_digest([0; 32], &emptied));
+    }
+
+    #[test]
+    fn test_deviating_peers() {
+        let digests = |digests: &[(u16, u8)]| {
+            digests
+                .iter()
+                .map(|(peer, digest)| (PeerId::from(*peer), [*digest; 32]))
+                .collect::<BTreeMap<_, _>>()
+        };
+        let peers = |peers: &[u16]| {
+            peers
+                .iter()
+                .map(|peer| PeerId::from(*peer))
+                .collect::<BTreeSet<_>>()
+        };
+
+        assert_eq!(
+            deviating_peers(&digests(&[(0, 1), (1, 1), (2, 2)]), 3),
+            None
+        );
+        assert_eq!(
+            deviating_peers(&digests(&[(0, 1), (1, 1), (2, 1)]), 3),
+            Some(([1; 32], peers(&[])))
+        );
+        assert_eq!(
+            deviating_peers(&digests(&[(0, 1), (1, 2), (2, 1), (3, 1)]), 3),
+            Some(([1; 32], peers(&[1])))
+        );
+        assert_eq!(
+            deviating_peers(&digests(&[(0, 1), (1, 2), (2, 3), (3, 1)]), 3),
+            None
+        );
+    }
+}
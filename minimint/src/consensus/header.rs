@@ -0,0 +1,258 @@
+//! Headers committing to the outcome of every epoch. The guardians threshold-sign them with their
+//! HBBFT keys, so a client can check the answer of a single guardian against the federation's
+//! public key instead of having to trust it.
+
+use bitcoin::hashes::{sha256, Hash as BitcoinHash, HashEngine};
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::outcome::{TransactionRejection, TransactionStatus};
+use minimint_api::TransactionId;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Domain separation of leaves and inner nodes of the merkle tree over an epoch's transactions
+const MERKLE_LEAF_TAG: u8 = 0x00;
+const MERKLE_NODE_TAG: u8 = 0x01;
+
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Encodable, Decodable)]
+pub struct EpochHeader {
+    pub epoch: u64,
+    /// [`EpochHeader::hash`] of the previous epoch's header, all zeros for the first epoch
+    pub prev_header: [u8; 32],
+    /// Merkle root over the [`TransactionLeaf`]s of all transactions decided in the epoch, ordered
+    /// by their id
+    pub transactions_root: [u8; 32],
+    /// Digest of the consensus state after the epoch, see
+    /// [`crate::consensus::divergence::chain_state_digest`]. It is chained to the digest of the
+    /// previous epoch, so it commits to the entire state and not just to the epoch's changes.
+    pub state_commitment: [u8; 32],
+}
+
+/// Outcome of a transaction that was decided in an epoch
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Encodable, Decodable)]
+pub struct TransactionLeaf {
+    pub txid: TransactionId,
+    /// `None` if the transaction was accepted
+    pub rejection: Option<TransactionRejection>,
+}
+
+/// A guardian's share of the federation's signature over an epoch header, the shares are
+/// exchanged as [`crate::consensus::ConsensusItem::EpochSignatureShare`]
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable)]
+pub struct EpochSignatureShare {
+    pub epoch: u64,
+    /// bincode encoded [`hbbft::crypto::SignatureShare`] over [`EpochHeader::hash`]
+    pub share: Vec<u8>,
+}
+
+/// Path from a leaf of a merkle tree to its root. Nodes without a sibling are moved up a level
+/// unchanged, so they have no entry in `siblings`.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub index: u64,
+    pub leaf_count: u64,
+    /// Hashes of the siblings on the path, starting at the leaf
+    pub siblings: Vec<[u8; 32]>,
+}
+
+/// Proof that the federation decided the outcome of a transaction, returned by
+/// `GET /transaction/:txid/proof`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TransactionProof {
+    pub header: EpochHeader,
+    /// Threshold signature of the federation over [`EpochHeader::hash`]
+    #[serde(with = "crate::config::serde_binary_human_readable")]
+    pub signature: hbbft::crypto::Signature,
+    pub leaf: TransactionLeaf,
+    /// Proof of `leaf` being part of `header.transactions_root`
+    pub merkle_proof: MerkleProof,
+}
+
+#[derive(Debug, Error)]
+pub enum ProofError {
+    #[error("The proof is about a different transaction")]
+    WrongTransaction,
+    #[error("The proven outcome differs from the transaction's status")]
+    StatusMismatch,
+    #[error("The transaction is not part of the epoch header")]
+    InvalidMerkleProof,
+    #[error("The federation's signature over the epoch header is invalid")]
+    InvalidSignature,
+}
+
+impl EpochHeader {
+    pub fn hash(&self) -> [u8; 32] {
+        let mut engine = sha256::Hash::engine();
+        self.consensus_encode(&mut engine)
+            .expect("Writing to a hash engine can't fail");
+        sha256::Hash::from_engine(engine).into_inner()
+    }
+}
+
+impl TransactionLeaf {
+    pub fn hash(&self) -> [u8; 32] {
+        let mut engine = sha256::Hash::engine();
+        engine.input(&[MERKLE_LEAF_TAG]);
+        self.consensus_encode(&mut engine)
+            .expect("Writing to a hash engine can't fail");
+        sha256::Hash::from_engine(engine).into_inner()
+    }
+}
+
+impl MerkleProof {
+    /// Checks that the leaf with hash `leaf` is part of the tree with the given `root`
+    pub fn verify(&self, leaf: [u8; 32], root: [u8; 32]) -> bool {
+        if self.index >= self.leaf_count {
+            return false;
+        }
+
+        let mut siblings = self.siblings.iter();
+        let mut hash = leaf;
+        let mut index = self.index;
+        let mut level_len = self.leaf_count;
+        while level_len > 1 {
+            if index % 2 == 1 {
+                match siblings.next() {
+                    Some(sibling) => hash = node_hash(sibling, &hash),
+                    None => return false,
+                }
+            } else if index + 1 < level_len {
+                match siblings.next() {
+                    Some(sibling) => hash = node_hash(&hash, sibling),
+                    None => return false,
+                }
+            }
+            index /= 2;
+            level_len = (level_len + 1) / 2;
+        }
+
+        siblings.next().is_none() && hash == root
+    }
+}
+
+impl TransactionProof {
+    /// Checks that the federation signed that transaction `txid` has the final `status`
+    pub fn verify(
+        &self,
+        txid: TransactionId,
+        status: &TransactionStatus,
+        federation_pk: &hbbft::crypto::PublicKey,
+    ) -> Result<(), ProofError> {
+        if self.leaf.txid != txid {
+            return Err(ProofError::WrongTransaction);
+        }
+
+        let status_matches = match status {
+            TransactionStatus::Accepted { epoch, .. } => {
+                *epoch == self.header.epoch && self.leaf.rejection.is_none()
+            }
+            TransactionStatus::Error { epoch, error } => {
+                *epoch == self.header.epoch && self.leaf.rejection.as_ref() == Some(error)
+            }
+            TransactionStatus::AwaitingConsensus | TransactionStatus::Evicted { .. } => false,
+        };
+        if !status_matches {
+            return Err(ProofError::StatusMismatch);
+        }
+
+        if !self
+            .merkle_proof
+            .verify(self.leaf.hash(), self.header.transactions_root)
+        {
+            return Err(ProofError::InvalidMerkleProof);
+        }
+
+        if !federation_pk.verify(&self.signature, self.header.hash()) {
+            return Err(ProofError::InvalidSignature);
+        }
+
+        Ok(())
+    }
+}
+
+/// Root of the merkle tree over the given leaf hashes, all zeros if there are none
+pub fn merkle_root(leaves: &[[u8; 32]]) -> [u8; 32] {
+    merkle_levels(leaves)
+        .last()
+        .and_then(|root_level| root_level.first())
+        .copied()
+        .unwrap_or([0; 32])
+}
+
+/// Proof of the leaf at `index` being part of the merkle tree over `leaves`
+pub fn merkle_proof(leaves: &[[u8; 32]], index: usize) -> MerkleProof {
+    assert!(index < leaves.len(), "Leaf index out of bounds");
+
+    let levels = merkle_levels(leaves);
+    let mut siblings = Vec::new();
+    let mut level_index = index;
+    for level in &levels[..levels.len() - 1] {
+        if let Some(sibling) = level.get(level_index ^ 1) {
+            siblings.push(*sibling);
+        }
+        level_index /= 2;
+    }
+
+    MerkleProof {
+        index: index as u64,
+        leaf_count: leaves.len() as u64,
+        siblings,
+    }
+}
+
+/// All levels of the merkle tree over `leaves`, starting with the leaves and ending with the root
+fn merkle_levels(leaves: &[[u8; 32]]) -> Vec<Vec<[u8; 32]>> {
+    let mut levels = vec![leaves.to_vec()];
+    while levels.last().expect("There is at least one level").len() > 1 {
+        let next_level = levels
+            .last()
+            .expect("There is at least one level")
+            .chunks(2)
+            .map(|nodes| match nodes {
+                [left, right] => node_hash(left, right),
+                [single] => *single,
+                _ => unreachable!("Chunks have one or two nodes"),
+            })
+            .collect();
+        levels.push(next_level);
+    }
+    levels
+}
+
+fn node_hash(left: &[u8; 32], right: &[u8; 32]) -> [u8; 32] {
+    let mut engine = sha256::Hash::engine();
+    engine.input(&[MERKLE_NODE_TAG]);
+    engine.input(left);
+    engine.input(right);
+    sha256::Hash::from_engine(engine).into_inner()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::consensus::header::{merkle_proof, merkle_root};
+    use bitcoin::hashes::{sha256, Hash as BitcoinHash};
+
+    #[test]
+    fn test_merkle_proofs() {
+        assert_eq!(merkle_root(&[]), [0; 32]);
+
+        for leaf_count in 1..=9u8 {
+            let leaves = (0..leaf_count)
+                .map(|leaf| sha256::Hash::hash(&[leaf]).into_inner())
+                .collect::<Vec<_>>();
+            let root = merkle_root(&leaves);
+
+            for (index, leaf) in leaves.iter().enumerate() {
+                let proof = merkle_proof(&leaves, index);
+                assert!(proof.verify(*leaf, root));
+
+                let other_leaf = leaves[(index + 1) % leaves.len()];
+                assert_eq!(proof.verify(other_leaf, root), leaf_count == 1);
+                assert!(!proof.verify(*leaf, [0; 32]));
+
+                let mut moved_proof = proof.clone();
+                moved_proof.index = (moved_proof.index + 1) % moved_proof.leaf_count;
+                assert_eq!(moved_proof.verify(*leaf, root), leaf_count == 1);
+            }
+        }
+    }
+}
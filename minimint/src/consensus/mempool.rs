@@ -0,0 +1,329 @@
+use super::conflictfilter::conflict_keys;
+use super::{FediMintConsensus, TransactionSubmissionError};
+use crate::db::{EvictedTransactionKey, ProposedTransactionKey, ProposedTransactionKeyPrefix};
+use minimint_api::db::batch::BatchTx;
+use minimint_api::db::{Database, DatabaseKeyPrefix};
+use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::module::ModuleId;
+use minimint_api::outcome::EvictionReason;
+use minimint_api::transaction::Transaction;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use tracing::{debug, warn};
+
+/// Submitted transaction that is proposed every epoch until consensus is reached on it
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct ProposedTransaction {
+    /// First epoch the transaction was proposed in, used to expire it
+    pub epoch: u64,
+    pub transaction: Transaction,
+}
+
+/// Record of a transaction dropped from the mempool, reported as
+/// [`minimint_api::outcome::TransactionStatus::Evicted`] until the transaction is resubmitted or
+/// decided
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+pub struct EvictedTransaction {
+    /// Epoch in which the transaction was evicted
+    pub epoch: u64,
+    pub reason: EvictionReason,
+}
+
+impl<R> FediMintConsensus<R>
+where
+    R: RngCore + CryptoRng,
+{
+    /// Returns the number of transactions in the mempool and their total size in bytes
+    pub fn mempool_size(&self) -> (usize, usize) {
+        self.db()
+            .raw_find_by_prefix(ProposedTransactionKeyPrefix.to_bytes())
+            .fold((0usize, 0usize), |(count, bytes), res| {
+                let (_key, value) = res.expect("DB error");
+                (count + 1, bytes + value.len())
+            })
+    }
+
+    /// Adds `transaction` to the mempool if it still fits. Returns `false` if it already was in
+    /// the mempool. The limits are checked and the transaction is inserted while holding
+    /// [`FediMintConsensus::mempool_lock`], so concurrent submissions can't exceed them.
+    pub(super) fn insert_into_mempool(
+        &self,
+        transaction: Transaction,
+    ) -> Result<bool, TransactionSubmissionError> {
+        let _lock = self.mempool_lock.lock().expect("Lock poisoned");
+
+        let key = ProposedTransactionKey(transaction.tx_hash());
+        if self
+            .db()
+            .get_value::<_, ProposedTransaction>(&key)
+            .expect("DB error")
+            .is_some()
+        {
+            warn!("Added consensus item was already in consensus queue");
+            return Ok(false);
+        }
+
+        let proposed = ProposedTransaction {
+            epoch: self.last_processed_epoch().map_or(0, |epoch| epoch + 1),
+            transaction,
+        };
+        let tx_size = proposed
+            .consensus_encode(std::io::sink())
+            .expect("Writing to sink can't fail");
+
+        let (count, bytes) = self.mempool_size();
+        let cfg = &self.cfg.mempool;
+        if count >= cfg.max_transactions || bytes + tx_size > cfg.max_bytes {
+            return Err(TransactionSubmissionError::MempoolFull);
+        }
+
+        self.db().insert_entry(&key, &proposed).expect("DB error");
+        Ok(true)
+    }
+
+    /// Removes transactions from the mempool that expired in `epoch` or that spend something
+    /// (see [`conflict_keys`]) already spent by an accepted transaction. Each of them is recorded
+    /// as [`EvictedTransaction`] so submitters can learn why it won't be decided.
+    pub(super) fn evict_mempool_transactions(
+        &self,
+        batch: &mut BatchTx,
+        epoch: u64,
+        spent_keys: &HashSet<(ModuleId, Vec<u8>)>,
+    ) {
+        let evicted = self
+            .db()
+            .find_by_prefix::<_, ProposedTransactionKey, ProposedTransaction>(
+                &ProposedTransactionKeyPrefix,
+            )
+            .map(|res| res.expect("DB error"))
+            .filter_map(|(key, proposed)| {
+                let is_expired =
+                    (epoch + 1).saturating_sub(proposed.epoch) >= self.cfg.mempool.expiry_epochs;
+                let is_conflicting = conflict_keys(&self.modules, &proposed.transaction)
+                    .iter()
+                    .any(|key| spent_keys.contains(key));
+
+                if is_conflicting {
+                    Some((key, EvictionReason::Conflict))
+                } else if is_expired {
+                    Some((key, EvictionReason::Expired))
+                } else {
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+
+        for (key, reason) in evicted {
+            debug!("Evicting transaction {} from mempool: {:?}", key.0, reason);
+            batch.append_insert(
+                EvictedTransactionKey(key.0),
+                EvictedTransaction { epoch, reason },
+            );
+            batch.append_maybe_delete(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::MempoolConfig;
+    use crate::consensus::conflictfilter::conflict_keys;
+    use crate::consensus::mempool::ProposedTransaction;
+    use crate::consensus::{FediMintConsensus, TransactionSubmissionError};
+    use crate::db::ProposedTransactionKey;
+    use crate::testing::{coin_input, federation_configs, guardian, mem_db, transaction};
+    use hbbft::honey_badger::Batch;
+    use minimint_api::db::batch::DbBatch;
+    use minimint_api::db::Database;
+    use minimint_api::encoding::Encodable;
+    use minimint_api::outcome::{EvictionReason, TransactionStatus};
+    use minimint_api::transaction::Transaction;
+    use minimint_api::PeerId;
+    use rand::rngs::OsRng;
+    use std::collections::{BTreeMap, HashSet};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    fn guardian_with(mempool: MempoolConfig) -> FediMintConsensus<OsRng> {
+        let mut cfg = federation_configs(4).remove(&PeerId::from(0)).unwrap();
+        cfg.mempool = mempool;
+        guardian(cfg, mem_db())
+    }
+
+    /// Size of `transaction` in the mempool
+    fn encoded_size(transaction: &Transaction) -> usize {
+        ProposedTransaction {
+            epoch: 0,
+            transaction: transaction.clone(),
+        }
+        .consensus_encode(std::io::sink())
+        .unwrap()
+    }
+
+    /// Processes an epoch in which nothing was decided
+    async fn empty_epoch(consensus: &FediMintConsensus<OsRng>) {
+        let epoch = consensus
+            .last_processed_epoch()
+            .map_or(0, |epoch| epoch + 1);
+        consensus
+            .process_consensus_outcome(Batch {
+                epoch,
+                contributions: BTreeMap::new(),
+            })
+            .await;
+    }
+
+    fn is_in_mempool(consensus: &FediMintConsensus<OsRng>, transaction: &Transaction) -> bool {
+        consensus
+            .db()
+            .get_value::<_, ProposedTransaction>(&ProposedTransactionKey(transaction.tx_hash()))
+            .unwrap()
+            .is_some()
+    }
+
+    #[test]
+    fn test_transaction_limit() {
+        let consensus = guardian_with(MempoolConfig {
+            max_transactions: 2,
+            ..Default::default()
+        });
+
+        let first = transaction(vec![coin_input(1)]);
+        assert!(consensus.insert_into_mempool(first.clone()).unwrap());
+        assert!(consensus
+            .insert_into_mempool(transaction(vec![coin_input(2)]))
+            .unwrap());
+        // Resubmissions don't take up space
+        assert!(!consensus.insert_into_mempool(first).unwrap());
+        assert!(matches!(
+            consensus.insert_into_mempool(transaction(vec![coin_input(3)])),
+            Err(TransactionSubmissionError::MempoolFull)
+        ));
+        assert_eq!(consensus.mempool_size().0, 2);
+    }
+
+    #[test]
+    fn test_byte_limit() {
+        let tx_size = encoded_size(&transaction(vec![coin_input(1)]));
+        let consensus = guardian_with(MempoolConfig {
+            max_bytes: 2 * tx_size + tx_size / 2,
+            ..Default::default()
+        });
+
+        assert!(consensus
+            .insert_into_mempool(transaction(vec![coin_input(1)]))
+            .unwrap());
+        assert!(consensus
+            .insert_into_mempool(transaction(vec![coin_input(2)]))
+            .unwrap());
+        assert!(matches!(
+            consensus.insert_into_mempool(transaction(vec![coin_input(3)])),
+            Err(TransactionSubmissionError::MempoolFull)
+        ));
+        assert_eq!(consensus.mempool_size(), (2, 2 * tx_size));
+    }
+
+    #[test]
+    fn test_concurrent_submissions() {
+        let consensus = Arc::new(guardian_with(MempoolConfig {
+            max_transactions: 5,
+            ..Default::default()
+        }));
+
+        let submissions = (1..=20u8)
+            .map(|seed| {
+                let consensus = consensus.clone();
+                std::thread::spawn(move || {
+                    consensus
+                        .insert_into_mempool(transaction(vec![coin_input(seed)]))
+                        .is_ok()
+                })
+            })
+            .collect::<Vec<_>>();
+        let accepted = submissions
+            .into_iter()
+            .filter(|submission| submission.join().unwrap())
+            .count();
+
+        assert_eq!(accepted, 5);
+        assert_eq!(consensus.mempool_size().0, 5);
+    }
+
+    #[tokio::test]
+    async fn test_expiry() {
+        let consensus = guardian_with(MempoolConfig {
+            expiry_epochs: 2,
+            ..Default::default()
+        });
+        let expiring = transaction(vec![coin_input(1)]);
+        consensus.insert_into_mempool(expiring.clone()).unwrap();
+
+        empty_epoch(&consensus).await;
+        let later = transaction(vec![coin_input(2)]);
+        consensus.insert_into_mempool(later.clone()).unwrap();
+        assert!(is_in_mempool(&consensus, &expiring));
+
+        // Waiters learn about the eviction as soon as the epoch was processed
+        let known = vec![(
+            expiring.tx_hash(),
+            Some(TransactionStatus::AwaitingConsensus),
+        )]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+        let (changed, ()) = tokio::join!(
+            consensus.await_status_changes(&known, Duration::from_secs(30)),
+            empty_epoch(&consensus)
+        );
+        let evicted = Some(TransactionStatus::Evicted {
+            epoch: 1,
+            reason: EvictionReason::Expired,
+        });
+        assert_eq!(
+            changed,
+            vec![(expiring.tx_hash(), evicted.clone())]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        );
+        assert!(!is_in_mempool(&consensus, &expiring));
+        assert!(is_in_mempool(&consensus, &later));
+        assert_eq!(consensus.transaction_status(expiring.tx_hash()), evicted);
+
+        empty_epoch(&consensus).await;
+        assert_eq!(consensus.mempool_size().0, 0);
+
+        // A resubmission is reported as pending again
+        consensus.insert_into_mempool(expiring.clone()).unwrap();
+        assert_eq!(
+            consensus.transaction_status(expiring.tx_hash()),
+            Some(TransactionStatus::AwaitingConsensus)
+        );
+    }
+
+    #[test]
+    fn test_eviction_of_conflicting_transactions() {
+        let consensus = guardian_with(MempoolConfig::default());
+        let conflicting = transaction(vec![coin_input(1), coin_input(2)]);
+        let independent = transaction(vec![coin_input(3)]);
+        consensus.insert_into_mempool(conflicting.clone()).unwrap();
+        consensus.insert_into_mempool(independent.clone()).unwrap();
+
+        // Another transaction spending coin 2 was accepted
+        let spent_keys = conflict_keys(&consensus.modules, &transaction(vec![coin_input(2)]))
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let mut batch = DbBatch::new();
+        batch.autocommit(|batch_tx| consensus.evict_mempool_transactions(batch_tx, 0, &spent_keys));
+        consensus.db().apply_batch(batch).unwrap();
+
+        assert!(!is_in_mempool(&consensus, &conflicting));
+        assert!(is_in_mempool(&consensus, &independent));
+        assert_eq!(
+            consensus.transaction_status(conflicting.tx_hash()),
+            Some(TransactionStatus::Evicted {
+                epoch: 0,
+                reason: EvictionReason::Conflict
+            })
+        );
+    }
+}
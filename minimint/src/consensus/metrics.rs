@@ -0,0 +1,59 @@
+use minimint_api::metrics::{
+    Counter, CounterVec, Histogram, MetricsEncoder, COUNT_BUCKETS, DURATION_BUCKETS,
+};
+
+/// Metrics of the consensus process, the modules' metrics are tracked by the modules themselves
+pub struct ConsensusMetrics {
+    /// Time between receiving the outcomes of consecutive epochs
+    pub epoch_duration: Histogram,
+    /// Number of consensus items per epoch
+    pub epoch_items: Histogram,
+    pub accepted_transactions: Counter,
+    /// Rejected transactions by error code
+    pub rejected_transactions: CounterVec,
+    /// Epochs in which a peer's consensus state diverged from the federation's, by peer
+    pub state_divergences: CounterVec,
+}
+
+impl Default for ConsensusMetrics {
+    fn default() -> Self {
+        ConsensusMetrics {
+            epoch_duration: Histogram::new(DURATION_BUCKETS),
+            epoch_items: Histogram::new(COUNT_BUCKETS),
+            accepted_transactions: Counter::default(),
+            rejected_transactions: CounterVec::new("error"),
+            state_divergences: CounterVec::new("peer"),
+        }
+    }
+}
+
+impl ConsensusMetrics {
+    pub fn encode(&self, encoder: &mut MetricsEncoder) {
+        encoder.histogram(
+            "consensus_epoch_duration_seconds",
+            "Time between receiving the outcomes of consecutive epochs",
+            &self.epoch_duration,
+        );
+        encoder.histogram(
+            "consensus_epoch_items",
+            "Number of consensus items per epoch",
+            &self.epoch_items,
+        );
+        encoder.counter(
+            "consensus_transactions_accepted_total",
+            "Transactions accepted by the federation",
+            &self.accepted_transactions,
+        );
+        encoder.counter_vec(
+            "consensus_transactions_rejected_total",
+            "Transactions rejected by the federation by error code",
+            &self.rejected_transactions,
+        );
+        encoder.counter_vec(
+            "consensus_state_divergences_total",
+            "Epochs in which a peer's consensus state diverged from the federation's, any \
+             increase requires immediate attention",
+            &self.state_divergences,
+        );
+    }
+}
@@ -1,36 +1,171 @@
+pub mod beacon;
 mod conflictfilter;
+pub mod divergence;
+pub mod header;
+mod mempool;
+mod metrics;
 
+use crate::audit::AuditReport;
 use crate::config::ServerConfig;
-use crate::consensus::conflictfilter::ConflictFilterable;
-use crate::db::{AcceptedTransactionKey, ProposedTransactionKey, ProposedTransactionKeyPrefix};
+use crate::consensus::beacon::{beacon_from_signature, beacon_message, BeaconShare};
+use crate::consensus::conflictfilter::{conflict_keys, ConflictFilterable};
+use crate::consensus::divergence::{chain_state_digest, deviating_peers, StateDigest};
+use crate::consensus::header::{
+    merkle_proof, merkle_root, EpochHeader, EpochSignatureShare, TransactionLeaf, TransactionProof,
+};
+use crate::consensus::mempool::{EvictedTransaction, ProposedTransaction};
+pub use crate::consensus::metrics::ConsensusMetrics;
+use crate::db::{
+    AcceptedTransactionKey, AcceptedTransactionKeyPrefix, BeaconShareEpochPrefix, BeaconShareKey,
+    BeaconShareKeyPrefix, ConfigChangeVoteKey, ConfigChangeVoteKeyPrefix, EpochHeaderKey,
+    EpochHistoryKey, EpochSignatureKey, EpochSignatureShareEpochPrefix, EpochSignatureShareKey,
+    EvictedTransactionKey, FeeScheduleKey, FeeVoteKey, FeeVoteKeyPrefix, LastEpochKey,
+    PeerStateDigestEpochPrefix, PeerStateDigestKey, ProposedFeesKey, ProposedTransactionKey,
+    ProposedTransactionKeyPrefix, RandomnessBeaconKey, RejectedTransactionKey,
+    ScheduledConfigChangeKey, StateDigestKey, CONSENSUS_DB_PREFIXES,
+};
 use crate::rng::RngGenerator;
+use hbbft::crypto::{Signature, SignatureShare};
 use hbbft::honey_badger::Batch;
 use minimint_api::db::batch::{BatchTx, DbBatch};
+use minimint_api::db::buffered::BufferedDatabase;
 use minimint_api::db::{Database, RawDatabase};
 use minimint_api::encoding::{Decodable, Encodable};
-use minimint_api::outcome::OutputOutcome;
-use minimint_api::transaction::{Input, OutPoint, Output, Transaction, TransactionError};
-use minimint_api::{FederationModule, PeerId, TransactionId};
+use minimint_api::metrics::MetricsEncoder;
+use minimint_api::module::{
+    ErasedFederationModule, ErrorCode, ModuleError, ModuleId, ModuleItem, ModuleRegistry,
+    RandomnessBeacon, MINT_MODULE_ID,
+};
+use minimint_api::outcome::{OutputOutcome, TransactionRejection, TransactionStatus};
+use minimint_api::transaction::{FundingVerifier, OutPoint, Transaction, TransactionError};
+use minimint_api::{Amount, Coin, Coins, FeeConsensus, PeerId, TransactionId};
 use minimint_derive::UnzipConsensus;
-use minimint_mint::{Mint, MintError};
-use minimint_wallet::{Wallet, WalletError};
+use minimint_mint::Mint;
 use rand::{CryptoRng, RngCore};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::Notify;
+use tokio::time::{timeout_at, Instant};
 use tracing::{debug, error, info, trace, warn};
 
-#[derive(Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, UnzipConsensus)]
+#[derive(
+    Debug, Clone, Eq, PartialEq, Hash, Serialize, Deserialize, Encodable, Decodable, UnzipConsensus,
+)]
 pub enum ConsensusItem {
     Transaction(Transaction),
-    Mint(<Mint as FederationModule>::ConsensusItem),
-    Wallet(<Wallet as FederationModule>::ConsensusItem),
+    Module(ModuleItem),
+    /// Vote to switch to the config with the given [`ServerConfig::public_digest`], e.g. after
+    /// the guardians of the federation changed. Once enough peers voted for the same config the
+    /// switch is scheduled [`CONFIG_CHANGE_DELAY`] epochs later.
+    ConfigChange([u8; 32]),
+    /// Our share of the federation's signature over the header of an already processed epoch
+    EpochSignatureShare(EpochSignatureShare),
+    /// Our digest of the consensus state after the last processed epoch, peers reporting a
+    /// different digest than the federation's majority have diverging state
+    StateDigest(StateDigest),
+    /// Vote to switch to the given fees. Once enough peers voted for the same fees the switch is
+    /// scheduled [`FEE_CHANGE_DELAY`] epochs later.
+    FeeVote(FeeConsensus),
+    /// Our share of the federation's signature over the number of the next epoch we expect to
+    /// process, the signature is the source of the [`RandomnessBeacon`]
+    BeaconShare(BeaconShare),
 }
 
+/// Number of epochs between agreeing on a config change and the change taking effect. This gives
+/// all peers time to process the agreement before the federation stops.
+pub const CONFIG_CHANGE_DELAY: u64 = 10;
+
+/// Number of epochs between agreeing on new fees and the fees taking effect. This gives clients
+/// time to learn about the new fees before transactions paying the old ones are rejected.
+pub const FEE_CHANGE_DELAY: u64 = 100;
+
+/// Number of most recent epochs whose headers we keep proposing our signature shares for until
+/// the federation signed them
+const EPOCH_SIGNATURE_WINDOW: u64 = 10;
+
+/// Number of most recent epochs whose state digests we keep, reports about older epochs are
+/// ignored
+const STATE_DIGEST_RETENTION: u64 = 100;
+
 pub type HoneyBadgerMessage = hbbft::honey_badger::Message<PeerId>;
 pub type ConsensusOutcome = Batch<Vec<ConsensusItem>, PeerId>;
 
+/// A [`ConsensusOutcome`] as it is persisted in the database so that the state can be rebuilt by
+/// replaying all epochs. It is also sent to peers that need to catch up on missed epochs.
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Encodable, Decodable)]
+pub struct EpochHistory {
+    pub epoch: u64,
+    pub contributions: Vec<(PeerId, Vec<ConsensusItem>)>,
+}
+
+/// A config change the federation agreed on, all peers stop before processing
+/// `activation_epoch` and restart with the new config
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Serialize, Deserialize, Encodable, Decodable)]
+pub struct ScheduledConfigChange {
+    /// [`ServerConfig::public_digest`] of the new config
+    pub digest: [u8; 32],
+    pub activation_epoch: u64,
+}
+
+/// The fees transactions have to pay, returned by `GET /fees`
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Encodable, Decodable)]
+pub struct FeeSchedule {
+    /// Fees charged since the last change took effect
+    pub active: FeeConsensus,
+    /// Fees the federation agreed to switch to, if any
+    pub scheduled: Option<ScheduledFeeChange>,
+}
+
+/// A fee change the federation agreed on, it applies to all transactions processed in
+/// `activation_epoch` or later
+#[derive(Debug, Clone, Eq, PartialEq, Serialize, Deserialize, Encodable, Decodable)]
+pub struct ScheduledFeeChange {
+    pub fees: FeeConsensus,
+    pub activation_epoch: u64,
+}
+
+impl FeeSchedule {
+    /// The fees charged in `epoch`
+    pub fn fees_at(&self, epoch: u64) -> &FeeConsensus {
+        match &self.scheduled {
+            Some(change) if change.activation_epoch <= epoch => &change.fees,
+            _ => &self.active,
+        }
+    }
+
+    /// Makes the scheduled change active if it takes effect in `epoch` or earlier, returns
+    /// whether it did
+    pub fn advance(&mut self, epoch: u64) -> bool {
+        match self.scheduled.take() {
+            Some(change) if change.activation_epoch <= epoch => {
+                self.active = change.fees;
+                true
+            }
+            scheduled => {
+                self.scheduled = scheduled;
+                false
+            }
+        }
+    }
+
+    /// The highest of the active and the scheduled fee returned by `fee`. Paying it ensures a
+    /// transaction is funded even if it is only processed after the scheduled change took effect,
+    /// paying more than the fee is allowed.
+    pub fn max_fee(&self, fee: impl Fn(&FeeConsensus) -> Amount) -> Amount {
+        let active_fee = fee(&self.active);
+        match &self.scheduled {
+            Some(change) => std::cmp::max(active_fee, fee(&change.fees)),
+            None => active_fee,
+        }
+    }
+}
+
 pub struct FediMintConsensus<R>
 where
     R: RngCore + CryptoRng,
@@ -40,12 +175,29 @@ where
     /// Configuration describing the federation and containing our secrets
     pub cfg: ServerConfig, // TODO: make custom config
 
-    /// Our local mint
-    pub mint: Mint, // TODO: generate consensus code using Macro, making modules replaceable for testing and easy adaptability
-    pub wallet: Wallet,
+    /// All modules (mint, wallet, …) run by the federation
+    pub modules: ModuleRegistry,
+
+    /// KV Database into which all state is persisted to recover from in case of a crash. All
+    /// changes of an epoch are buffered and committed at once when it has been processed, so the
+    /// modules have to operate on the same database.
+    pub db: Arc<BufferedDatabase>,
 
-    /// KV Database into which all state is persisted to recover from in case of a crash
-    pub db: Arc<dyn RawDatabase>,
+    /// [`ServerConfig::public_digest`] of the config we vote to switch to, if any
+    pub next_cfg_digest: Option<[u8; 32]>,
+
+    /// Epoch HoneyBadger is currently working on, it runs ahead of the last processed epoch
+    pub hbbft_epoch: Arc<AtomicU64>,
+
+    pub metrics: ConsensusMetrics,
+
+    /// Notified whenever transaction statuses may have changed, i.e. after a transaction was
+    /// submitted or an epoch was processed
+    pub transaction_updates: Notify,
+
+    /// Held while adding a transaction to the mempool, so concurrent submissions can't exceed
+    /// its limits
+    pub mempool_lock: std::sync::Mutex<()>,
 }
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
@@ -54,6 +206,12 @@ struct AcceptedTransaction {
     transaction: Transaction,
 }
 
+#[derive(Debug, Clone, Eq, PartialEq, Hash, Deserialize, Serialize, Encodable, Decodable)]
+struct RejectedTransaction {
+    epoch: u64,
+    error: TransactionRejection,
+}
+
 impl<R> FediMintConsensus<R>
 where
     R: RngCore + CryptoRng,
@@ -61,77 +219,117 @@ where
     pub fn submit_transaction(
         &self,
         transaction: Transaction,
-    ) -> Result<(), TransactionSubmissionError> {
+    ) -> Result<TransactionId, TransactionSubmissionError> {
         let tx_hash = transaction.tx_hash();
         debug!("Received mint transaction {}", tx_hash);
 
-        transaction.validate_funding(&self.cfg.fee_consensus)?;
-        transaction.validate_signature()?;
-
+        let fees = self.fee_schedule().active;
+        let mut funding_verifier = FundingVerifier::default();
+        let mut keys = Vec::new();
         for input in &transaction.inputs {
-            match input {
-                Input::Coins(coins) => {
-                    self.mint
-                        .validate_input(coins)
-                        .map_err(TransactionSubmissionError::InputCoinError)?;
-                }
-                Input::PegIn(peg_in) => {
-                    self.wallet
-                        .validate_input(peg_in)
-                        .map_err(TransactionSubmissionError::InputPegIn)?;
-                }
-            }
+            let meta = self
+                .module(input.module)?
+                .validate_input(&input.data, &fees)
+                .map_err(|e| TransactionSubmissionError::InputError(input.module, e))?;
+            funding_verifier.add_input(meta.amount, meta.fee);
+            keys.extend(meta.keys);
         }
 
         for output in &transaction.outputs {
-            match output {
-                Output::Coins(coins) => {
-                    self.mint
-                        .validate_output(coins)
-                        .map_err(TransactionSubmissionError::OutputCoinError)?;
-                }
-                Output::PegOut(peg_out) => {
-                    self.wallet
-                        .validate_output(peg_out)
-                        .map_err(TransactionSubmissionError::OutputPegOut)?;
-                }
-            }
+            let meta = self
+                .module(output.module)?
+                .validate_output(&output.data, &fees)
+                .map_err(|e| TransactionSubmissionError::OutputError(output.module, e))?;
+            funding_verifier.add_output(meta.amount, meta.fee);
         }
 
-        let new = self
-            .db
-            .insert_entry(&ProposedTransactionKey(tx_hash), &transaction)
-            .expect("DB error");
+        funding_verifier.verify_funding()?;
+        transaction.validate_signature(keys.into_iter())?;
 
-        if new.is_some() {
-            warn!("Added consensus item was already in consensus queue");
+        if self.insert_into_mempool(transaction)? {
+            self.transaction_updates.notify_waiters();
         }
 
-        Ok(())
+        Ok(tx_hash)
     }
 
+    /// Processes the outcome of one epoch. All resulting changes are committed to the database
+    /// atomically together with the epoch number, so after a crash the epoch is either fully
+    /// processed or not at all. Epochs that were already processed are ignored.
     pub async fn process_consensus_outcome(&self, consensus_outcome: ConsensusOutcome) {
         let epoch = consensus_outcome.epoch;
+        if matches!(self.last_processed_epoch(), Some(last_epoch) if epoch <= last_epoch) {
+            warn!("Ignoring output of already processed epoch {}", epoch);
+            return;
+        }
         info!("Processing output of epoch {}", epoch);
+        self.metrics
+            .epoch_items
+            .observe(consensus_outcome.contributions.values().flatten().count() as f64);
+
+        // The following batches are only buffered so that later steps can read the changes of
+        // earlier ones. Nothing is written to disk before the final commit.
+        debug_assert!(!self.db.has_pending_changes());
+
+        let epoch_history = EpochHistory::from(consensus_outcome.clone());
+        let mut db_batch = DbBatch::new();
+        db_batch
+            .autocommit(|batch_tx| batch_tx.append_insert(EpochHistoryKey(epoch), epoch_history));
+        self.db().apply_batch(db_batch).expect("DB error");
 
         let UnzipConsensusItem {
             transaction: transaction_cis,
-            wallet: wallet_cis,
-            mint: mint_cis,
+            module: module_cis,
+            config_change: config_change_cis,
+            epoch_signature_share: epoch_signature_share_cis,
+            state_digest: state_digest_cis,
+            fee_vote: fee_vote_cis,
+            beacon_share: beacon_share_cis,
         } = consensus_outcome
             .contributions
             .into_iter()
             .flat_map(|(peer, cis)| cis.into_iter().map(move |ci| (peer, ci)))
             .unzip_consensus_item();
 
+        let mut module_cis = module_cis.into_iter().fold(
+            BTreeMap::<ModuleId, Vec<(PeerId, Vec<u8>)>>::new(),
+            |mut module_cis, (peer, ci)| {
+                module_cis
+                    .entry(ci.module)
+                    .or_default()
+                    .push((peer, ci.data));
+                module_cis
+            },
+        );
+
         let mut db_batch = DbBatch::new();
-        self.wallet
-            .begin_consensus_epoch(db_batch.transaction(), wallet_cis, self.rng_gen.get_rng())
-            .await;
-        self.mint
-            .begin_consensus_epoch(db_batch.transaction(), mint_cis, self.rng_gen.get_rng())
-            .await;
-        self.db.apply_batch(db_batch).expect("DB error");
+        let mut new_beacon = None;
+        db_batch.autocommit(|batch_tx| {
+            new_beacon = self.process_beacon_shares(batch_tx, epoch, beacon_share_cis);
+        });
+        // Modules only receive a beacon in the epoch it was produced in, so they never derive the
+        // same values from it twice
+        let beacon = new_beacon;
+        for (module_id, module) in self.modules.iter() {
+            let cis = module_cis.remove(&module_id).unwrap_or_default();
+            module
+                .begin_consensus_epoch(
+                    db_batch.transaction(),
+                    epoch,
+                    beacon,
+                    cis,
+                    &mut self.rng_gen.get_rng(),
+                )
+                .await;
+        }
+        for (module_id, cis) in module_cis {
+            warn!(
+                "Received {} consensus items for unknown module {}",
+                cis.len(),
+                module_id
+            );
+        }
+        self.db().apply_batch(db_batch).expect("DB error");
 
         // Since the changes to the database will happen all at once we won't be able to handle
         // conflicts between consensus items in one batch there. Thus we need to make sure that
@@ -139,13 +337,26 @@ where
         // There are two item types that need checking:
         //  * peg-ins that each peg-in tx is only used to issue coins once
         //  * coin spends to avoid double spends in one batch
-        let filtered_transactions = transaction_cis
+        let mut filtered_transactions = Vec::new();
+        let mut conflicting_transactions = Vec::new();
+        for item in transaction_cis
             .into_iter()
-            .filter_conflicts(|(_, tx)| tx)
-            .collect::<Vec<_>>();
+            .filter_conflicts(&self.modules, |(_, tx)| tx)
+        {
+            match item {
+                Ok(transaction) => filtered_transactions.push(transaction),
+                Err(transaction) => conflicting_transactions.push(transaction),
+            }
+        }
+        let processed_txids = filtered_transactions
+            .iter()
+            .map(|(_, tx)| tx.tx_hash())
+            .collect::<HashSet<_>>();
+
+        let fees = self.stored_fee_schedule().fees_at(epoch).clone();
 
         // TODO: implement own parallel execution to avoid allocations and get rid of rayon
-        let par_db_batches = filtered_transactions
+        let (par_db_batches, results): (Vec<_>, Vec<_>) = filtered_transactions
             .into_par_iter()
             .map(|(peer, transaction)| {
                 trace!(
@@ -153,137 +364,992 @@ where
                     transaction,
                     peer
                 );
+                let txid = transaction.tx_hash();
                 let mut db_batch = DbBatch::new();
                 db_batch.autocommit(|batch_tx| {
-                    batch_tx.append_maybe_delete(ProposedTransactionKey(transaction.tx_hash()))
+                    batch_tx.append_maybe_delete(ProposedTransactionKey(txid))
                 });
                 // TODO: use borrowed transaction
-                match self.process_transaction(db_batch.transaction(), transaction.clone()) {
+                let (spent_keys, rejection) = match self.process_transaction(
+                    db_batch.transaction(),
+                    transaction.clone(),
+                    &fees,
+                ) {
                     Ok(()) => {
+                        self.metrics.accepted_transactions.inc();
+                        let spent_keys = conflict_keys(&self.modules, &transaction);
                         db_batch.autocommit(|batch_tx| {
                             batch_tx.append_insert(
-                                AcceptedTransactionKey(transaction.tx_hash()),
+                                AcceptedTransactionKey(txid),
                                 AcceptedTransaction { epoch, transaction },
                             );
                         });
+                        (spent_keys, None)
                     }
                     Err(e) => {
-                        // TODO: log error for user
                         warn!("Transaction proposed by peer {} failed: {}", peer, e);
+                        self.metrics.rejected_transactions.inc(e.error_code());
+                        let error = TransactionRejection::from(e);
+                        db_batch.autocommit(|batch_tx| {
+                            batch_tx.append_insert(
+                                RejectedTransactionKey(txid),
+                                RejectedTransaction {
+                                    epoch,
+                                    error: error.clone(),
+                                },
+                            );
+                        });
+                        (Vec::new(), Some(error))
                     }
-                }
+                };
 
-                db_batch
+                (db_batch, (spent_keys, TransactionLeaf { txid, rejection }))
             })
-            .collect::<Vec<_>>();
+            .unzip();
+        let (spent_keys, mut decided_transactions): (Vec<_>, Vec<_>) = results.into_iter().unzip();
+        let spent_keys = spent_keys.into_iter().flatten().collect::<HashSet<_>>();
         let mut db_batch = DbBatch::new();
         db_batch.autocommit(|tx| tx.append_from_accumulators(par_db_batches.into_iter()));
-        self.db.apply_batch(db_batch).expect("DB error");
+        db_batch.autocommit(|batch_tx| {
+            decided_transactions.extend(self.reject_conflicting_transactions(
+                batch_tx,
+                epoch,
+                conflicting_transactions,
+                &processed_txids,
+            ));
+        });
+        self.db().apply_batch(db_batch).expect("DB error");
 
         let mut db_batch = DbBatch::new();
-        self.wallet
-            .end_consensus_epoch(db_batch.transaction(), self.rng_gen.get_rng())
-            .await;
-        self.mint
-            .end_consensus_epoch(db_batch.transaction(), self.rng_gen.get_rng())
-            .await;
-        self.db.apply_batch(db_batch).expect("DB error");
+        for (_, module) in self.modules.iter() {
+            module
+                .end_consensus_epoch(db_batch.transaction(), &mut self.rng_gen.get_rng())
+                .await;
+        }
+        db_batch.autocommit(|batch_tx| {
+            self.evict_mempool_transactions(batch_tx, epoch, &spent_keys);
+            self.process_config_change_votes(batch_tx, epoch, config_change_cis);
+            self.process_fee_votes(batch_tx, epoch, fee_vote_cis);
+            self.process_epoch_signature_shares(batch_tx, epoch_signature_share_cis);
+            self.process_state_digests(batch_tx, epoch, state_digest_cis);
+            batch_tx.append_insert(LastEpochKey, epoch);
+        });
+        self.db().apply_batch(db_batch).expect("DB error");
+
+        // Has to run last so the digest covers all changes of the epoch. The header commits to the
+        // digest, so it isn't covered by it itself.
+        let mut db_batch = DbBatch::new();
+        db_batch.autocommit(|batch_tx| {
+            let state_digest = self.append_state_digest(batch_tx, epoch);
+            self.append_epoch_header(batch_tx, epoch, state_digest, decided_transactions);
+        });
+        self.db().apply_batch(db_batch).expect("DB error");
+
+        self.db.commit().expect("DB error");
+        // Wakes up everyone waiting for the transactions decided or evicted from the mempool in
+        // this epoch
+        self.transaction_updates.notify_waiters();
+
+        let audit = self.audit();
+        if audit.complete && !audit.is_backed() {
+            error!(
+                "Liabilities of {} exceed the reserves of {} after epoch {}",
+                audit.liabilities, audit.reserves, epoch
+            );
+        }
     }
 
-    pub async fn get_consensus_proposal(&self) -> Vec<ConsensusItem> {
-        self.db
-            .find_by_prefix::<_, ProposedTransactionKey, _>(&ProposedTransactionKeyPrefix)
+    /// Rejects the transactions that were filtered out for conflicting with an earlier transaction
+    /// of the epoch and returns their leaves. Every peer sees the same conflicts, so the rejection
+    /// is as deterministic as the ones of processed transactions. Copies of a transaction that was
+    /// processed in this epoch, e.g. because several peers contributed it, aren't rejected.
+    fn reject_conflicting_transactions(
+        &self,
+        batch: &mut BatchTx,
+        epoch: u64,
+        conflicting_transactions: Vec<(PeerId, Transaction)>,
+        processed_txids: &HashSet<TransactionId>,
+    ) -> Vec<TransactionLeaf> {
+        let mut rejected_txids = HashSet::new();
+        conflicting_transactions
+            .into_iter()
+            .filter_map(|(peer, transaction)| {
+                let txid = transaction.tx_hash();
+                if processed_txids.contains(&txid) || !rejected_txids.insert(txid) {
+                    return None;
+                }
+
+                let e = TransactionSubmissionError::Conflict;
+                warn!("Transaction proposed by peer {} failed: {}", peer, e);
+                self.metrics.rejected_transactions.inc(e.error_code());
+                let error = TransactionRejection::from(e);
+                batch.append_maybe_delete(ProposedTransactionKey(txid));
+                batch.append_insert(
+                    RejectedTransactionKey(txid),
+                    RejectedTransaction {
+                        epoch,
+                        error: error.clone(),
+                    },
+                );
+                Some(TransactionLeaf {
+                    txid,
+                    rejection: Some(error),
+                })
+            })
+            .collect()
+    }
+
+    /// Returns the last epoch whose outcome was fully processed, `None` if none was processed yet
+    pub fn last_processed_epoch(&self) -> Option<u64> {
+        self.db()
+            .get_value::<_, u64>(&LastEpochKey)
+            .expect("DB error")
+    }
+
+    /// Returns the metrics of the consensus and all modules in the Prometheus text format
+    pub fn encode_metrics(&self) -> String {
+        let mut encoder = MetricsEncoder::new();
+        self.metrics.encode(&mut encoder);
+
+        let audit = self.audit();
+        encoder.gauge(
+            "audit_liabilities_msat",
+            "Value of the outstanding e-cash and pending peg-outs",
+            audit.liabilities.milli_sat as f64,
+        );
+        encoder.gauge(
+            "audit_reserves_msat",
+            "Value of the UTXOs backing the liabilities",
+            audit.reserves.milli_sat as f64,
+        );
+        encoder.gauge(
+            "audit_complete",
+            "1 once the liabilities include the e-cash issued before it was tracked",
+            if audit.complete { 1.0 } else { 0.0 },
+        );
+
+        for (_, module) in self.modules.iter() {
+            module.encode_metrics(&mut encoder);
+        }
+        encoder.finish()
+    }
+
+    /// Returns the federation's liabilities and the reserves backing them
+    pub fn audit(&self) -> AuditReport {
+        AuditReport::new(self.last_processed_epoch(), &self.modules)
+    }
+
+    /// Runs [`Mint::backfill_audit`] unless it already ran, so the audit includes the coins issued
+    /// and redeemed before the mint tracked them. Has to be called between epochs.
+    pub fn backfill_mint_audit(&self) {
+        let mint = match self.modules.get_typed::<Mint>(MINT_MODULE_ID) {
+            Some(mint) if !mint.is_audit_complete() => mint,
+            _ => return,
+        };
+        info!("Backfilling the audit of the mint from the accepted transactions");
+
+        let redeemed = self
+            .db()
+            .find_by_prefix::<_, AcceptedTransactionKey, AcceptedTransaction>(
+                &AcceptedTransactionKeyPrefix,
+            )
+            .flat_map(|res| res.expect("DB error").1.transaction.inputs)
+            .filter(|input| input.module == MINT_MODULE_ID)
+            .map(|input| {
+                input
+                    .decode::<Coins<Coin>>()
+                    .expect("Accepted mint inputs are valid")
+            });
+        let mut db_batch = DbBatch::new();
+        mint.backfill_audit(db_batch.transaction(), redeemed);
+        self.db().apply_batch(db_batch).expect("DB error");
+        self.db.commit().expect("DB error");
+    }
+
+    /// Returns the config change the federation agreed on, if any. Once its activation epoch is
+    /// reached it is kept until the next change is scheduled.
+    pub fn scheduled_config_change(&self) -> Option<ScheduledConfigChange> {
+        self.db()
+            .get_value::<_, ScheduledConfigChange>(&ScheduledConfigChangeKey)
+            .expect("DB error")
+    }
+
+    /// Returns the scheduled config change if it takes effect in `epoch` or earlier and we are not
+    /// running with the new config yet. In that case we must not take part in `epoch`.
+    pub fn due_config_change(&self, epoch: u64) -> Option<ScheduledConfigChange> {
+        self.scheduled_config_change().filter(|change| {
+            change.activation_epoch <= epoch && change.digest != self.cfg.public_digest()
+        })
+    }
+
+    /// Records the peers' votes for config changes and schedules a change once `n - f` peers
+    /// voted for the same config. Votes are ignored while another change is pending.
+    fn process_config_change_votes(
+        &self,
+        batch: &mut BatchTx,
+        epoch: u64,
+        votes: Vec<(PeerId, [u8; 32])>,
+    ) {
+        let change_pending = matches!(
+            self.scheduled_config_change(),
+            Some(change) if change.activation_epoch > epoch
+        );
+        if change_pending {
+            if !votes.is_empty() {
+                warn!("Ignoring config change votes while another change is pending");
+            }
+            return;
+        }
+
+        let stored_votes = self
+            .db()
+            .find_by_prefix::<_, ConfigChangeVoteKey, [u8; 32]>(&ConfigChangeVoteKeyPrefix)
             .map(|res| {
-                let (_key, value) = res.expect("DB error");
-                ConsensusItem::Transaction(value)
+                let (ConfigChangeVoteKey(peer), digest) = res.expect("DB error");
+                (peer, digest)
             })
-            .chain(
-                self.wallet
-                    .consensus_proposal(self.rng_gen.get_rng())
-                    .await
-                    .into_iter()
-                    .map(ConsensusItem::Wallet),
+            .collect::<BTreeMap<_, _>>();
+
+        let mut all_votes = stored_votes.clone();
+        let mut new_votes = BTreeMap::new();
+        for (peer, digest) in votes {
+            all_votes.insert(peer, digest);
+            new_votes.insert(peer, digest);
+        }
+
+        let threshold = self.cfg.peers.len() - hbbft::util::max_faulty(self.cfg.peers.len());
+        let agreed_digest = all_votes.values().find(|digest| {
+            all_votes
+                .values()
+                .filter(|other_digest| other_digest == digest)
+                .count()
+                >= threshold
+        });
+
+        if let Some(&digest) = agreed_digest {
+            let activation_epoch = epoch + CONFIG_CHANGE_DELAY;
+            info!(
+                "Peers agreed on config change to {}, it takes effect in epoch {}",
+                hex::encode(digest),
+                activation_epoch
+            );
+            batch.append_insert(
+                ScheduledConfigChangeKey,
+                ScheduledConfigChange {
+                    digest,
+                    activation_epoch,
+                },
+            );
+            for peer in stored_votes.keys() {
+                batch.append_delete(ConfigChangeVoteKey(*peer));
+            }
+        } else {
+            for (peer, digest) in new_votes {
+                batch.append_insert(ConfigChangeVoteKey(peer), digest);
+            }
+        }
+    }
+
+    /// Returns the fees charged for transactions submitted now, i.e. that are processed in the
+    /// next epoch at the earliest, and the fee change the federation agreed on, if any
+    pub fn fee_schedule(&self) -> FeeSchedule {
+        let mut schedule = self.stored_fee_schedule();
+        schedule.advance(self.last_processed_epoch().map_or(0, |epoch| epoch + 1));
+        schedule
+    }
+
+    /// The fee schedule as of the last processed epoch, the config's fees until the guardians
+    /// changed them for the first time
+    fn stored_fee_schedule(&self) -> FeeSchedule {
+        self.db()
+            .get_value::<_, FeeSchedule>(&FeeScheduleKey)
+            .expect("DB error")
+            .unwrap_or_else(|| FeeSchedule {
+                active: self.cfg.fee_consensus.clone(),
+                scheduled: None,
+            })
+    }
+
+    /// Stores the fees we vote to switch to until the federation agreed on them. Only fees that
+    /// are [`FeeConsensus::is_valid`] are accepted.
+    pub fn propose_fees(&self, fees: FeeConsensus) -> Result<(), InvalidFeesError> {
+        if !fees.is_valid() {
+            return Err(InvalidFeesError);
+        }
+
+        self.db()
+            .insert_entry(&ProposedFeesKey, &fees)
+            .expect("DB error");
+        Ok(())
+    }
+
+    /// Activates the fee change that takes effect after `epoch`, records the peers' votes for
+    /// new fees and schedules a change once `n - f` peers voted for the same fees. Votes are
+    /// ignored while another change is pending.
+    fn process_fee_votes(
+        &self,
+        batch: &mut BatchTx,
+        epoch: u64,
+        votes: Vec<(PeerId, FeeConsensus)>,
+    ) {
+        let mut schedule = self.stored_fee_schedule();
+        if schedule.advance(epoch + 1) {
+            info!("New fees take effect in epoch {}", epoch + 1);
+            batch.append_insert(FeeScheduleKey, schedule.clone());
+        }
+
+        if schedule.scheduled.is_some() {
+            if !votes.is_empty() {
+                warn!("Ignoring fee votes while another fee change is pending");
+            }
+            return;
+        }
+
+        let stored_votes = self
+            .db()
+            .find_by_prefix::<_, FeeVoteKey, FeeConsensus>(&FeeVoteKeyPrefix)
+            .map(|res| {
+                let (FeeVoteKey(peer), fees) = res.expect("DB error");
+                (peer, fees)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let mut all_votes = stored_votes.clone();
+        let mut new_votes = BTreeMap::new();
+        for (peer, fees) in votes {
+            if !fees.is_valid() {
+                warn!("Ignoring invalid fee vote of peer {}", peer);
+                continue;
+            }
+            all_votes.insert(peer, fees.clone());
+            new_votes.insert(peer, fees);
+        }
+
+        let threshold = self.cfg.peers.len() - hbbft::util::max_faulty(self.cfg.peers.len());
+        let agreed_fees = all_votes.values().find(|fees| {
+            all_votes
+                .values()
+                .filter(|other_fees| other_fees == fees)
+                .count()
+                >= threshold
+        });
+
+        if let Some(fees) = agreed_fees {
+            let activation_epoch = epoch + FEE_CHANGE_DELAY;
+            info!(
+                "Peers agreed on new fees {:?}, they take effect in epoch {}",
+                fees, activation_epoch
+            );
+            schedule.scheduled = Some(ScheduledFeeChange {
+                fees: fees.clone(),
+                activation_epoch,
+            });
+            batch.append_insert(FeeScheduleKey, schedule);
+            for peer in stored_votes.keys() {
+                batch.append_delete(FeeVoteKey(*peer));
+            }
+        } else {
+            for (peer, fees) in new_votes {
+                batch.append_insert(FeeVoteKey(peer), fees);
+            }
+        }
+    }
+
+    /// Our vote for switching to the fees proposed by our admin, unless they are already active,
+    /// a fee change is pending or our vote was already recorded
+    fn fee_vote(&self) -> Option<FeeConsensus> {
+        let fees = self
+            .db()
+            .get_value::<_, FeeConsensus>(&ProposedFeesKey)
+            .expect("DB error")?;
+
+        // Votes are ignored while a change is pending, we vote once it took effect
+        let schedule = self.stored_fee_schedule();
+        if schedule.scheduled.is_some() || schedule.active == fees {
+            return None;
+        }
+
+        let our_vote = self
+            .db()
+            .get_value::<_, FeeConsensus>(&FeeVoteKey(self.cfg.identity))
+            .expect("DB error");
+        if our_vote.as_ref() == Some(&fees) {
+            return None;
+        }
+
+        Some(fees)
+    }
+
+    pub async fn get_consensus_proposal(&self) -> Vec<ConsensusItem> {
+        let mut proposal = self
+            .db()
+            .find_by_prefix::<_, ProposedTransactionKey, ProposedTransaction>(
+                &ProposedTransactionKeyPrefix,
             )
-            .chain(
-                self.mint
-                    .consensus_proposal(self.rng_gen.get_rng())
+            .map(|res| {
+                let (_key, proposed) = res.expect("DB error");
+                ConsensusItem::Transaction(proposed.transaction)
+            })
+            .collect::<Vec<_>>();
+
+        for (module_id, module) in self.modules.iter() {
+            proposal.extend(
+                module
+                    .consensus_proposal(&mut self.rng_gen.get_rng())
                     .await
                     .into_iter()
-                    .map(ConsensusItem::Mint),
-            )
+                    .map(|data| {
+                        ConsensusItem::Module(ModuleItem {
+                            module: module_id,
+                            data,
+                        })
+                    }),
+            );
+        }
+
+        if let Some(digest) = self.config_change_vote() {
+            proposal.push(ConsensusItem::ConfigChange(digest));
+        }
+
+        if let Some(fees) = self.fee_vote() {
+            proposal.push(ConsensusItem::FeeVote(fees));
+        }
+
+        if let Some(share) = self.beacon_share() {
+            proposal.push(ConsensusItem::BeaconShare(share));
+        }
+
+        proposal.extend(
+            self.epoch_signature_shares()
+                .into_iter()
+                .map(ConsensusItem::EpochSignatureShare),
+        );
+
+        if let Some(state_digest) = self.state_digest_report() {
+            proposal.push(ConsensusItem::StateDigest(state_digest));
+        }
+
+        proposal
+    }
+
+    /// Stores the header of `epoch`, which commits to the transactions decided in it and to our
+    /// digest of the state after it and is chained to the header of the previous epoch
+    fn append_epoch_header(
+        &self,
+        batch: &mut BatchTx,
+        epoch: u64,
+        state_digest: [u8; 32],
+        mut transactions: Vec<TransactionLeaf>,
+    ) {
+        transactions.sort_by_key(|leaf| leaf.txid);
+        transactions.dedup_by_key(|leaf| leaf.txid);
+
+        let prev_header = epoch
+            .checked_sub(1)
+            .and_then(|prev_epoch| self.epoch_header(prev_epoch))
+            .map_or([0; 32], |(header, _)| header.hash());
+        let leaf_hashes = transactions
+            .iter()
+            .map(TransactionLeaf::hash)
+            .collect::<Vec<_>>();
+        let header = EpochHeader {
+            epoch,
+            prev_header,
+            transactions_root: merkle_root(&leaf_hashes),
+            state_commitment: state_digest,
+        };
+
+        batch.append_insert(EpochHeaderKey(epoch), (header, transactions));
+    }
+
+    /// Our signature shares over the headers of recent epochs the federation didn't sign yet and
+    /// that we didn't contribute a share to yet
+    fn epoch_signature_shares(&self) -> Vec<EpochSignatureShare> {
+        let last_epoch = match self.last_processed_epoch() {
+            Some(last_epoch) => last_epoch,
+            None => return vec![],
+        };
+
+        (last_epoch.saturating_sub(EPOCH_SIGNATURE_WINDOW - 1)..=last_epoch)
+            .filter(|&epoch| {
+                self.epoch_signature(epoch).is_none()
+                    && self
+                        .db()
+                        .get_value::<_, Vec<u8>>(&EpochSignatureShareKey(epoch, self.cfg.identity))
+                        .expect("DB error")
+                        .is_none()
+            })
+            .filter_map(|epoch| {
+                // Epochs processed before headers were introduced have none
+                let (header, _) = self.epoch_header(epoch)?;
+                let share = self.cfg.hbbft_sks.inner().sign(header.hash());
+                Some(EpochSignatureShare {
+                    epoch,
+                    share: bincode::serialize(&share).expect("Serialization can't fail"),
+                })
+            })
             .collect()
     }
 
-    fn process_transaction(
+    /// Records the valid signature shares over epoch headers and combines them into the
+    /// federation's signature once more than `threshold` peers contributed one. Any such set of
+    /// shares results in the same signature, so all peers store the same one.
+    fn process_epoch_signature_shares(
         &self,
-        mut batch: BatchTx,
-        transaction: Transaction,
-    ) -> Result<(), TransactionSubmissionError> {
-        transaction.validate_funding(&self.cfg.fee_consensus)?;
-        transaction.validate_signature()?;
+        batch: &mut BatchTx,
+        shares: Vec<(PeerId, EpochSignatureShare)>,
+    ) {
+        let mut new_shares = BTreeMap::<u64, BTreeMap<PeerId, SignatureShare>>::new();
+        for (peer, EpochSignatureShare { epoch, share }) in shares {
+            if self.epoch_signature(epoch).is_some() {
+                continue;
+            }
 
-        let tx_hash = transaction.tx_hash();
+            let header = match self.epoch_header(epoch) {
+                Some((header, _)) => header,
+                None => {
+                    warn!("Peer {} signed the header of unknown epoch {}", peer, epoch);
+                    continue;
+                }
+            };
+
+            let share_valid = bincode::deserialize::<SignatureShare>(&share)
+                .ok()
+                .zip(self.peer_index(peer))
+                .filter(|(share, peer_idx)| {
+                    self.cfg
+                        .hbbft_pk_set
+                        .public_key_share(*peer_idx)
+                        .verify(share, header.hash())
+                });
+            match share_valid {
+                Some((share, _)) => {
+                    new_shares.entry(epoch).or_default().insert(peer, share);
+                }
+                None => warn!(
+                    "Peer {} sent an invalid signature share for epoch {}",
+                    peer, epoch
+                ),
+            }
+        }
+
+        for (epoch, new_shares) in new_shares {
+            let stored_shares = self
+                .db()
+                .find_by_prefix::<_, EpochSignatureShareKey, Vec<u8>>(
+                    &EpochSignatureShareEpochPrefix(epoch),
+                )
+                .map(|res| {
+                    let (EpochSignatureShareKey(_, peer), share) = res.expect("DB error");
+                    let share = bincode::deserialize::<SignatureShare>(&share)
+                        .expect("Only valid shares are stored");
+                    (peer, share)
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            let mut all_shares = stored_shares.clone();
+            all_shares.extend(new_shares.clone());
+            if all_shares.len() > self.cfg.hbbft_pk_set.threshold() {
+                let signature = self
+                    .cfg
+                    .hbbft_pk_set
+                    .combine_signatures(all_shares.iter().map(|(peer, share)| {
+                        let peer_idx = self.peer_index(*peer).expect("Shares were verified");
+                        (peer_idx, share)
+                    }))
+                    .expect("Shares were verified");
+                debug!("Federation signed the header of epoch {}", epoch);
+
+                batch.append_insert(
+                    EpochSignatureKey(epoch),
+                    bincode::serialize(&signature).expect("Serialization can't fail"),
+                );
+                for peer in stored_shares.keys() {
+                    batch.append_delete(EpochSignatureShareKey(epoch, *peer));
+                }
+            } else {
+                for (peer, share) in new_shares {
+                    batch.append_insert(
+                        EpochSignatureShareKey(epoch, peer),
+                        bincode::serialize(&share).expect("Serialization can't fail"),
+                    );
+                }
+            }
+        }
+    }
+
+    /// Returns the federation's most recent randomness beacon, `None` until the first one was
+    /// produced
+    pub fn randomness_beacon(&self) -> Option<RandomnessBeacon> {
+        self.db()
+            .get_value::<_, RandomnessBeacon>(&RandomnessBeaconKey)
+            .expect("DB error")
+    }
+
+    /// Our share of the signature over the number of the next epoch to process, unless it was
+    /// already recorded
+    fn beacon_share(&self) -> Option<BeaconShare> {
+        let epoch = self.last_processed_epoch().map_or(0, |epoch| epoch + 1);
+        let recorded = self
+            .db()
+            .get_value::<_, Vec<u8>>(&BeaconShareKey(epoch, self.cfg.identity))
+            .expect("DB error")
+            .is_some();
+        if recorded {
+            return None;
+        }
+
+        let share = self.cfg.hbbft_sks.inner().sign(beacon_message(epoch));
+        Some(BeaconShare {
+            epoch,
+            share: bincode::serialize(&share).expect("Serialization can't fail"),
+        })
+    }
+
+    /// Records the valid beacon shares contributed in `epoch` and returns the newest beacon that
+    /// could be produced from them, if any. Shares are only accepted for epochs up to `epoch`,
+    /// since honest peers only reveal them after processing the previous epoch, and for epochs
+    /// after the last beacon. Once a beacon was produced all shares for it and earlier epochs are
+    /// dropped.
+    fn process_beacon_shares(
+        &self,
+        batch: &mut BatchTx,
+        epoch: u64,
+        shares: Vec<(PeerId, BeaconShare)>,
+    ) -> Option<RandomnessBeacon> {
+        let last_beacon_epoch = self.randomness_beacon().map(|beacon| beacon.epoch);
+
+        let mut new_shares = BTreeMap::<u64, BTreeMap<PeerId, SignatureShare>>::new();
+        for (peer, share) in shares {
+            let outdated = matches!(last_beacon_epoch, Some(last) if share.epoch <= last);
+            if outdated || share.epoch > epoch {
+                continue;
+            }
+
+            let share_valid = bincode::deserialize::<SignatureShare>(&share.share)
+                .ok()
+                .zip(self.peer_index(peer))
+                .filter(|(signature_share, peer_idx)| {
+                    self.cfg
+                        .hbbft_pk_set
+                        .public_key_share(*peer_idx)
+                        .verify(signature_share, beacon_message(share.epoch))
+                });
+            match share_valid {
+                Some((signature_share, _)) => {
+                    new_shares
+                        .entry(share.epoch)
+                        .or_default()
+                        .insert(peer, signature_share);
+                }
+                None => warn!(
+                    "Peer {} sent an invalid beacon share for epoch {}",
+                    peer, share.epoch
+                ),
+            }
+        }
+
+        // Only the newest beacon is of interest, so we start with the latest epoch
+        let mut beacon = None;
+        for (share_epoch, new_shares) in new_shares.into_iter().rev() {
+            let mut all_shares = self
+                .db()
+                .find_by_prefix::<_, BeaconShareKey, Vec<u8>>(&BeaconShareEpochPrefix(share_epoch))
+                .map(|res| {
+                    let (BeaconShareKey(_, peer), share) = res.expect("DB error");
+                    let share = bincode::deserialize::<SignatureShare>(&share)
+                        .expect("Only valid shares are stored");
+                    (peer, share)
+                })
+                .collect::<BTreeMap<_, _>>();
+            all_shares.extend(new_shares.clone());
+
+            if all_shares.len() > self.cfg.hbbft_pk_set.threshold() {
+                let signature = self
+                    .cfg
+                    .hbbft_pk_set
+                    .combine_signatures(all_shares.iter().map(|(peer, share)| {
+                        let peer_idx = self.peer_index(*peer).expect("Shares were verified");
+                        (peer_idx, share)
+                    }))
+                    .expect("Shares were verified");
+                beacon = Some(beacon_from_signature(share_epoch, &signature));
+                break;
+            }
+
+            for (peer, share) in new_shares {
+                batch.append_insert(
+                    BeaconShareKey(share_epoch, peer),
+                    bincode::serialize(&share).expect("Serialization can't fail"),
+                );
+            }
+        }
+
+        let beacon = beacon?;
+        debug!("Produced the randomness beacon of epoch {}", beacon.epoch);
+        batch.append_insert(RandomnessBeaconKey, beacon);
+        for res in self
+            .db()
+            .find_by_prefix::<_, BeaconShareKey, Vec<u8>>(&BeaconShareKeyPrefix)
+        {
+            let (BeaconShareKey(share_epoch, peer), _) = res.expect("DB error");
+            if share_epoch <= beacon.epoch {
+                batch.append_delete(BeaconShareKey(share_epoch, peer));
+            }
+        }
+        Some(beacon)
+    }
+
+    /// Stores our digest of the consensus state after `epoch`, which is chained to the digest of
+    /// the previous epoch, and drops the digests that are no longer retained. Returns the digest.
+    fn append_state_digest(&self, batch: &mut BatchTx, epoch: u64) -> [u8; 32] {
+        let prefixes = CONSENSUS_DB_PREFIXES
+            .iter()
+            .chain(
+                self.modules
+                    .iter()
+                    .flat_map(|(_, module)| module.consensus_db_prefixes()),
+            )
+            .copied()
+            .collect::<Vec<_>>();
+        let prev_digest = epoch
+            .checked_sub(1)
+            .and_then(|prev_epoch| self.state_digest(prev_epoch))
+            .unwrap_or([0; 32]);
+        let digest = chain_state_digest(prev_digest, &self.db.pending_changes(&prefixes));
+        batch.append_insert(StateDigestKey(epoch), digest);
+
+        if let Some(expired_epoch) = epoch.checked_sub(STATE_DIGEST_RETENTION) {
+            batch.append_maybe_delete(StateDigestKey(expired_epoch));
+            let expired_reports = self
+                .db()
+                .find_by_prefix::<_, PeerStateDigestKey, [u8; 32]>(&PeerStateDigestEpochPrefix(
+                    expired_epoch,
+                ))
+                .map(|res| res.expect("DB error").0)
+                .collect::<Vec<_>>();
+            for key in expired_reports {
+                batch.append_delete(key);
+            }
+        }
+
+        digest
+    }
 
-        for input in transaction.inputs {
-            match input {
-                Input::Coins(coins) => {
-                    self.mint
-                        .apply_input(batch.subtransaction(), &coins)
-                        .map_err(TransactionSubmissionError::InputCoinError)?;
+    /// Returns our digest of the consensus state after `epoch` if it is still retained
+    pub fn state_digest(&self, epoch: u64) -> Option<[u8; 32]> {
+        self.db()
+            .get_value::<_, [u8; 32]>(&StateDigestKey(epoch))
+            .expect("DB error")
+    }
+
+    /// Our digest of the state after the last processed epoch, unless it was already reported
+    fn state_digest_report(&self) -> Option<StateDigest> {
+        let epoch = self.last_processed_epoch()?;
+        let digest = self.state_digest(epoch)?;
+        let reported = self
+            .db()
+            .get_value::<_, [u8; 32]>(&PeerStateDigestKey(epoch, self.cfg.identity))
+            .expect("DB error")
+            .is_some();
+        (!reported).then(|| StateDigest { epoch, digest })
+    }
+
+    /// Records the state digests reported by the peers and raises an alert for every peer whose
+    /// digest differs from the one at least `n - f` peers agree on, which may include ourselves.
+    /// Every deviating peer is reported once per epoch, as soon as agreement was reached or the
+    /// peer's digest arrived, whatever happens later.
+    fn process_state_digests(
+        &self,
+        batch: &mut BatchTx,
+        current_epoch: u64,
+        reports: Vec<(PeerId, StateDigest)>,
+    ) {
+        let mut new_reports = BTreeMap::<u64, BTreeMap<PeerId, [u8; 32]>>::new();
+        for (peer, StateDigest { epoch, digest }) in reports {
+            if epoch >= current_epoch || epoch + STATE_DIGEST_RETENTION <= current_epoch {
+                warn!(
+                    "Peer {} reported the state digest of epoch {} outside the retained epochs",
+                    peer, epoch
+                );
+                continue;
+            }
+            match new_reports.entry(epoch).or_default().entry(peer) {
+                Entry::Vacant(entry) => {
+                    entry.insert(digest);
                 }
-                Input::PegIn(peg_in) => {
-                    self.wallet
-                        .apply_input(batch.subtransaction(), &peg_in)
-                        .map_err(TransactionSubmissionError::InputPegIn)?;
+                Entry::Occupied(_) => {
+                    warn!(
+                        "Ignoring duplicate report of the state digest of epoch {} by peer {}",
+                        epoch, peer
+                    );
                 }
             }
         }
 
-        for (idx, output) in transaction.outputs.into_iter().enumerate() {
-            match output {
-                Output::Coins(new_tokens) => {
-                    self.mint
-                        .apply_output(
-                            batch.subtransaction(),
-                            &new_tokens,
-                            OutPoint {
-                                txid: tx_hash,
-                                out_idx: idx as u64,
-                            },
-                        )
-                        .map_err(TransactionSubmissionError::OutputCoinError)?;
+        let threshold = self.cfg.peers.len() - hbbft::util::max_faulty(self.cfg.peers.len());
+        for (epoch, mut new_reports) in new_reports {
+            let stored_reports = self
+                .db()
+                .find_by_prefix::<_, PeerStateDigestKey, [u8; 32]>(&PeerStateDigestEpochPrefix(
+                    epoch,
+                ))
+                .map(|res| {
+                    let (PeerStateDigestKey(_, peer), digest) = res.expect("DB error");
+                    (peer, digest)
+                })
+                .collect::<BTreeMap<_, _>>();
+
+            // A peer can't revise its report, otherwise it could hide a divergence it reported
+            new_reports.retain(|peer, _| {
+                let is_duplicate = stored_reports.contains_key(peer);
+                if is_duplicate {
+                    warn!(
+                        "Ignoring duplicate report of the state digest of epoch {} by peer {}",
+                        epoch, peer
+                    );
+                }
+                !is_duplicate
+            });
+
+            let mut all_reports = stored_reports.clone();
+            all_reports.extend(new_reports.clone());
+
+            let already_alerted = deviating_peers(&stored_reports, threshold)
+                .map(|(_, deviating)| deviating)
+                .unwrap_or_default();
+            match deviating_peers(&all_reports, threshold) {
+                Some((_, deviating)) => {
+                    for peer in deviating.difference(&already_alerted) {
+                        self.alert_state_divergence(epoch, *peer);
+                    }
                 }
-                Output::PegOut(peg_out) => {
-                    self.wallet
-                        .apply_output(
-                            batch.subtransaction(),
-                            &peg_out,
-                            OutPoint {
-                                txid: tx_hash,
-                                out_idx: idx as u64,
-                            },
-                        )
-                        .map_err(TransactionSubmissionError::OutputPegOut)?;
+                None if all_reports.len() == self.cfg.peers.len() => {
+                    error!(
+                        "No {} peers agree on the consensus state after epoch {}, the state of \
+                         the federation diverged",
+                        threshold, epoch
+                    );
                 }
+                None => {}
             }
+
+            for (peer, digest) in new_reports {
+                batch.append_insert(PeerStateDigestKey(epoch, peer), digest);
+            }
+        }
+    }
+
+    fn alert_state_divergence(&self, epoch: u64, peer: PeerId) {
+        if peer == self.cfg.identity {
+            error!(
+                "Our consensus state diverged from the federation's in epoch {}! Stop this \
+                 guardian and rebuild its database from the other guardians' epoch history",
+                epoch
+            );
+        } else {
+            error!(
+                "The consensus state of peer {} diverged from the federation's in epoch {}",
+                peer, epoch
+            );
+        }
+        self.metrics.state_divergences.inc(&peer.to_string());
+    }
+
+    /// Index of `peer`'s key share in the HBBFT public key set, peers are indexed in the order of
+    /// their ids
+    fn peer_index(&self, peer: PeerId) -> Option<usize> {
+        self.cfg.peers.keys().position(|&id| id == peer)
+    }
+
+    /// Returns the header of `epoch` together with the transactions decided in it
+    pub fn epoch_header(&self, epoch: u64) -> Option<(EpochHeader, Vec<TransactionLeaf>)> {
+        self.db()
+            .get_value::<_, (EpochHeader, Vec<TransactionLeaf>)>(&EpochHeaderKey(epoch))
+            .expect("DB error")
+    }
+
+    /// Returns the federation's signature over the header of `epoch` once enough peers signed it
+    pub fn epoch_signature(&self, epoch: u64) -> Option<Signature> {
+        self.db()
+            .get_value::<_, Vec<u8>>(&EpochSignatureKey(epoch))
+            .expect("DB error")
+            .map(|signature| {
+                bincode::deserialize(&signature).expect("Only valid signatures are stored")
+            })
+    }
+
+    /// Our vote for switching to the next config, unless it was already recorded or the switch is
+    /// already scheduled
+    fn config_change_vote(&self) -> Option<[u8; 32]> {
+        let digest = self.next_cfg_digest?;
+        if digest == self.cfg.public_digest() {
+            return None;
+        }
+
+        let scheduled = self.scheduled_config_change();
+        if matches!(scheduled, Some(change) if change.digest == digest) {
+            return None;
+        }
+
+        let our_vote = self
+            .db()
+            .get_value::<_, [u8; 32]>(&ConfigChangeVoteKey(self.cfg.identity))
+            .expect("DB error");
+        if our_vote == Some(digest) {
+            return None;
+        }
+
+        Some(digest)
+    }
+
+    fn process_transaction(
+        &self,
+        mut batch: BatchTx,
+        transaction: Transaction,
+        fees: &FeeConsensus,
+    ) -> Result<(), TransactionSubmissionError> {
+        let tx_hash = transaction.tx_hash();
+
+        let mut funding_verifier = FundingVerifier::default();
+        let mut keys = Vec::new();
+        for input in &transaction.inputs {
+            let meta = self
+                .module(input.module)?
+                .apply_input(batch.subtransaction(), &input.data, fees)
+                .map_err(|e| TransactionSubmissionError::InputError(input.module, e))?;
+            funding_verifier.add_input(meta.amount, meta.fee);
+            keys.extend(meta.keys);
+        }
+
+        for (idx, output) in transaction.outputs.iter().enumerate() {
+            let out_point = OutPoint {
+                txid: tx_hash,
+                out_idx: idx as u64,
+            };
+            let meta = self
+                .module(output.module)?
+                .apply_output(batch.subtransaction(), &output.data, out_point, fees)
+                .map_err(|e| TransactionSubmissionError::OutputError(output.module, e))?;
+            funding_verifier.add_output(meta.amount, meta.fee);
         }
 
+        funding_verifier.verify_funding()?;
+        transaction.validate_signature(keys.into_iter())?;
+
         batch.commit();
         Ok(())
     }
 
-    pub fn transaction_status(
-        &self,
-        txid: TransactionId,
-    ) -> Option<minimint_api::outcome::TransactionStatus> {
+    pub fn transaction_status(&self, txid: TransactionId) -> Option<TransactionStatus> {
         let is_proposal = self
-            .db
-            .get_value::<_, Transaction>(&ProposedTransactionKey(txid))
+            .db()
+            .get_value::<_, ProposedTransaction>(&ProposedTransactionKey(txid))
             .expect("DB error")
             .is_some();
 
         let accepted: Option<AcceptedTransaction> = self
-            .db
+            .db()
             .get_value::<_, AcceptedTransaction>(&AcceptedTransactionKey(txid))
             .expect("DB error");
 
@@ -298,33 +1364,140 @@ where
                         txid,
                         out_idx: out_idx as u64,
                     };
-                    match output {
-                        Output::Coins(_) => {
-                            let outcome = self
-                                .mint
-                                .output_status(outpoint)
-                                .expect("the transaction was processed, so should be known");
-                            OutputOutcome::Mint(outcome)
-                        }
-                        Output::PegOut(_) => {
-                            let outcome = self
-                                .wallet
-                                .output_status(outpoint)
-                                .expect("the transaction was processed, so should be known");
-                            OutputOutcome::Wallet(outcome)
-                        }
+                    let (data, is_final) = self
+                        .modules
+                        .get(output.module)
+                        .expect("the transaction was processed, so its modules are known")
+                        .output_status(outpoint)
+                        .expect("the transaction was processed, so should be known");
+                    OutputOutcome {
+                        outcome: ModuleItem {
+                            module: output.module,
+                            data,
+                        },
+                        is_final,
                     }
                 })
                 .collect();
 
-            Some(minimint_api::outcome::TransactionStatus::Accepted {
+            return Some(TransactionStatus::Accepted {
                 epoch: accepted_tx.epoch,
                 outputs,
+            });
+        }
+
+        let rejected: Option<RejectedTransaction> = self
+            .db()
+            .get_value::<_, RejectedTransaction>(&RejectedTransactionKey(txid))
+            .expect("DB error");
+
+        if let Some(rejected_tx) = rejected {
+            Some(TransactionStatus::Error {
+                epoch: rejected_tx.epoch,
+                error: rejected_tx.error,
             })
         } else if is_proposal {
-            Some(minimint_api::outcome::TransactionStatus::AwaitingConsensus)
+            Some(TransactionStatus::AwaitingConsensus)
         } else {
-            None
+            self.db()
+                .get_value::<_, EvictedTransaction>(&EvictedTransactionKey(txid))
+                .expect("DB error")
+                .map(|evicted| TransactionStatus::Evicted {
+                    epoch: evicted.epoch,
+                    reason: evicted.reason,
+                })
+        }
+    }
+
+    /// Returns a proof of the outcome of transaction `txid` that clients can verify against the
+    /// federation's public key. It is `None` until the transaction was decided and the federation
+    /// signed the header of that epoch, which usually happens one or two epochs later.
+    pub fn transaction_proof(&self, txid: TransactionId) -> Option<TransactionProof> {
+        let accepted_epoch = self
+            .db()
+            .get_value::<_, AcceptedTransaction>(&AcceptedTransactionKey(txid))
+            .expect("DB error")
+            .map(|accepted| accepted.epoch);
+        let epoch = match accepted_epoch {
+            Some(epoch) => epoch,
+            None => {
+                self.db()
+                    .get_value::<_, RejectedTransaction>(&RejectedTransactionKey(txid))
+                    .expect("DB error")?
+                    .epoch
+            }
+        };
+
+        let (header, transactions) = self.epoch_header(epoch)?;
+        let signature = self.epoch_signature(epoch)?;
+        let index = transactions.iter().position(|leaf| leaf.txid == txid)?;
+        let leaf_hashes = transactions
+            .iter()
+            .map(TransactionLeaf::hash)
+            .collect::<Vec<_>>();
+
+        Some(TransactionProof {
+            header,
+            signature,
+            leaf: transactions[index].clone(),
+            merkle_proof: merkle_proof(&leaf_hashes, index),
+        })
+    }
+
+    /// Waits until the status of any of the `known` transactions differs from the given one or
+    /// `timeout` passed. Returns the current status of all transactions whose status changed,
+    /// which is empty on timeout.
+    pub async fn await_status_changes(
+        &self,
+        known: &BTreeMap<TransactionId, Option<TransactionStatus>>,
+        timeout: Duration,
+    ) -> BTreeMap<TransactionId, Option<TransactionStatus>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            // We have to register for updates before checking the statuses to not miss updates
+            // happening in between
+            let updated = self.transaction_updates.notified();
+
+            let changed = known
+                .iter()
+                .map(|(txid, known_status)| (*txid, known_status, self.transaction_status(*txid)))
+                .filter(|(_, known_status, status)| *known_status != status)
+                .map(|(txid, _, status)| (txid, status))
+                .collect::<BTreeMap<_, _>>();
+            if !changed.is_empty() || timeout_at(deadline, updated).await.is_err() {
+                return changed;
+            }
+        }
+    }
+
+    fn db(&self) -> &dyn RawDatabase {
+        self.db.as_ref()
+    }
+
+    fn module(
+        &self,
+        module_id: ModuleId,
+    ) -> Result<&(dyn ErasedFederationModule + Send + Sync), TransactionSubmissionError> {
+        self.modules
+            .get(module_id)
+            .ok_or(TransactionSubmissionError::UnknownModule(module_id))
+    }
+}
+
+impl From<ConsensusOutcome> for EpochHistory {
+    fn from(outcome: ConsensusOutcome) -> Self {
+        EpochHistory {
+            epoch: outcome.epoch,
+            contributions: outcome.contributions.into_iter().collect(),
+        }
+    }
+}
+
+impl From<EpochHistory> for ConsensusOutcome {
+    fn from(history: EpochHistory) -> Self {
+        Batch {
+            epoch: history.epoch,
+            contributions: history.contributions.into_iter().collect(),
         }
     }
 }
@@ -333,14 +1506,71 @@ where
 pub enum TransactionSubmissionError {
     #[error("High level transaction error: {0}")]
     TransactionError(TransactionError),
-    #[error("Input coin error: {0}")]
-    InputCoinError(MintError),
-    #[error("Input peg-in error: {0}")]
-    InputPegIn(WalletError),
-    #[error("Output coin error: {0}")]
-    OutputCoinError(MintError),
-    #[error("Output coin error: {0}")]
-    OutputPegOut(WalletError),
+    #[error("Unknown module {0}")]
+    UnknownModule(ModuleId),
+    #[error("Input error in module {0}: {1}")]
+    InputError(ModuleId, ModuleError),
+    #[error("Output error in module {0}: {1}")]
+    OutputError(ModuleId, ModuleError),
+    #[error("The mempool is full")]
+    MempoolFull,
+    #[error("The transaction spends an input that an earlier transaction of the epoch spent")]
+    Conflict,
+}
+
+#[derive(Debug, Error)]
+#[error("Proportional fees must not exceed {} ppm", FeeConsensus::MAX_PPM)]
+pub struct InvalidFeesError;
+
+impl ErrorCode for TransactionSubmissionError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            TransactionSubmissionError::TransactionError(e) => e.error_code(),
+            TransactionSubmissionError::UnknownModule(_) => "unknown_module",
+            TransactionSubmissionError::InputError(_, e) => e.error_code(),
+            TransactionSubmissionError::OutputError(_, e) => e.error_code(),
+            TransactionSubmissionError::MempoolFull => "mempool_full",
+            TransactionSubmissionError::Conflict => "conflict",
+        }
+    }
+}
+
+impl From<TransactionSubmissionError> for TransactionRejection {
+    fn from(e: TransactionSubmissionError) -> Self {
+        match e {
+            TransactionSubmissionError::TransactionError(
+                TransactionError::InsufficientlyFunded {
+                    inputs,
+                    outputs,
+                    fee,
+                },
+            ) => TransactionRejection::InsufficientlyFunded {
+                inputs,
+                outputs,
+                fee,
+            },
+            TransactionSubmissionError::TransactionError(TransactionError::InvalidSignature) => {
+                TransactionRejection::InvalidSignature
+            }
+            TransactionSubmissionError::UnknownModule(module) => {
+                TransactionRejection::UnknownModule(module)
+            }
+            TransactionSubmissionError::InputError(module, e) => {
+                TransactionRejection::InvalidInput {
+                    module,
+                    reason: e.to_string(),
+                }
+            }
+            TransactionSubmissionError::OutputError(module, e) => {
+                TransactionRejection::InvalidOutput {
+                    module,
+                    reason: e.to_string(),
+                }
+            }
+            TransactionSubmissionError::MempoolFull => TransactionRejection::MempoolFull,
+            TransactionSubmissionError::Conflict => TransactionRejection::Conflict,
+        }
+    }
 }
 
 impl From<TransactionError> for TransactionSubmissionError {
@@ -348,3 +1578,320 @@ impl From<TransactionError> for TransactionSubmissionError {
         TransactionSubmissionError::TransactionError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::consensus::divergence::StateDigest;
+    use crate::consensus::{ConsensusItem, FediMintConsensus, TransactionSubmissionError};
+    use crate::db::PeerStateDigestKey;
+    use crate::testing::{
+        coin_input, federation_configs, guardian, guardian_with_modules, mem_db, mint_modules,
+        next_outcome, run_epoch, transaction, wallet_module,
+    };
+    use hbbft::honey_badger::Batch;
+    use minimint_api::db::batch::DbBatch;
+    use minimint_api::db::Database;
+    use minimint_api::encoding::DecodeError;
+    use minimint_api::module::{ErrorCode, ModuleError, MINT_MODULE_ID, WALLET_MODULE_ID};
+    use minimint_api::outcome::{TransactionRejection, TransactionStatus};
+    use minimint_api::transaction::TransactionError;
+    use minimint_api::{Amount, PeerId};
+    use minimint_mint::MintError;
+    use minimint_wallet::{Wallet, WalletError};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[tokio::test]
+    async fn test_conflicting_transaction_rejected() {
+        let consensus = guardian(
+            federation_configs(4).remove(&PeerId::from(0)).unwrap(),
+            mem_db(),
+        );
+        let first = transaction(vec![coin_input(1)]);
+        let conflicting = transaction(vec![coin_input(1), coin_input(2)]);
+
+        consensus
+            .process_consensus_outcome(Batch {
+                epoch: 0,
+                contributions: vec![
+                    (
+                        PeerId::from(0),
+                        vec![
+                            ConsensusItem::Transaction(first.clone()),
+                            ConsensusItem::Transaction(first.clone()),
+                        ],
+                    ),
+                    (
+                        PeerId::from(1),
+                        vec![ConsensusItem::Transaction(conflicting.clone())],
+                    ),
+                ]
+                .into_iter()
+                .collect(),
+            })
+            .await;
+
+        // The duplicate of the first transaction doesn't overwrite its outcome
+        let first_status = consensus.transaction_status(first.tx_hash());
+        assert!(matches!(
+            first_status,
+            Some(TransactionStatus::Error { epoch: 0, ref error }) if *error != TransactionRejection::Conflict
+        ));
+        assert_eq!(
+            consensus.transaction_status(conflicting.tx_hash()),
+            Some(TransactionStatus::Error {
+                epoch: 0,
+                error: TransactionRejection::Conflict
+            })
+        );
+
+        let (_, leaves) = consensus.epoch_header(0).unwrap();
+        assert_eq!(leaves.len(), 2);
+        assert!(leaves.iter().any(|leaf| leaf.txid == conflicting.tx_hash()
+            && leaf.rejection == Some(TransactionRejection::Conflict)));
+    }
+
+    #[test]
+    fn test_duplicate_state_digest_reports() {
+        let consensus = guardian(
+            federation_configs(4).remove(&PeerId::from(0)).unwrap(),
+            mem_db(),
+        );
+        let report = |peer: u16, digest: u8| {
+            (
+                PeerId::from(peer),
+                StateDigest {
+                    epoch: 3,
+                    digest: [digest; 32],
+                },
+            )
+        };
+        let process = |reports| {
+            let mut batch = DbBatch::new();
+            batch.autocommit(|batch_tx| consensus.process_state_digests(batch_tx, 5, reports));
+            consensus.db().apply_batch(batch).unwrap();
+        };
+        let stored = |peer: u16| {
+            consensus
+                .db()
+                .get_value::<_, [u8; 32]>(&PeerStateDigestKey(3, PeerId::from(peer)))
+                .unwrap()
+        };
+
+        // Peer 3 diverges and tries to revise its report in the same epoch
+        process(vec![
+            report(0, 1),
+            report(1, 1),
+            report(2, 1),
+            report(3, 2),
+            report(3, 1),
+        ]);
+        assert_eq!(stored(3), Some([2; 32]));
+        assert_eq!(consensus.metrics.state_divergences.get("3"), 1);
+
+        // ... and in a later one
+        process(vec![report(3, 1), report(0, 2)]);
+        assert_eq!(stored(3), Some([2; 32]));
+        assert_eq!(stored(0), Some([1; 32]));
+        assert_eq!(consensus.metrics.state_divergences.get("0"), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_await_status_changes() {
+        const TIMEOUT: Duration = Duration::from_secs(30);
+        let consensus = guardian(
+            federation_configs(4).remove(&PeerId::from(0)).unwrap(),
+            mem_db(),
+        );
+        let tx = transaction(vec![coin_input(1)]);
+        let known = vec![(tx.tx_hash(), None)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+
+        // Nothing changed until the timeout
+        let start = Instant::now();
+        assert!(consensus
+            .await_status_changes(&known, TIMEOUT)
+            .await
+            .is_empty());
+        assert!(start.elapsed() >= TIMEOUT);
+
+        // Returns as soon as the transaction is decided, it gets rejected since its coin isn't
+        // signed by the federation
+        let start = Instant::now();
+        let (changed, ()) = tokio::join!(consensus.await_status_changes(&known, TIMEOUT), async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            consensus
+                .process_consensus_outcome(Batch {
+                    epoch: 0,
+                    contributions: vec![(
+                        PeerId::from(0),
+                        vec![ConsensusItem::Transaction(tx.clone())],
+                    )]
+                    .into_iter()
+                    .collect(),
+                })
+                .await;
+        });
+        assert!(start.elapsed() < TIMEOUT);
+        let status = consensus.transaction_status(tx.tx_hash());
+        assert!(matches!(
+            status,
+            Some(TransactionStatus::Error { epoch: 0, .. })
+        ));
+        assert_eq!(
+            changed,
+            vec![(tx.tx_hash(), status.clone())]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        );
+
+        // Changes that happened before subscribing are returned right away, while unchanged
+        // transactions are left out
+        let unknown = transaction(vec![coin_input(2)]).tx_hash();
+        let known = vec![(tx.tx_hash(), None), (unknown, None)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        let start = Instant::now();
+        assert_eq!(
+            consensus.await_status_changes(&known, TIMEOUT).await,
+            vec![(tx.tx_hash(), status.clone())]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        );
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        // Clients that know the current status wait for further changes
+        let known = vec![(tx.tx_hash(), status)]
+            .into_iter()
+            .collect::<BTreeMap<_, _>>();
+        assert!(consensus
+            .await_status_changes(&known, TIMEOUT)
+            .await
+            .is_empty());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_missing_beacon_shares() {
+        let configs = federation_configs(4);
+        let mut guardians = Vec::new();
+        for cfg in configs.values() {
+            let db = mem_db();
+            let mut modules = mint_modules(cfg, db.clone());
+            modules.register(WALLET_MODULE_ID, wallet_module(cfg, db.clone()).await);
+            guardians.push(guardian_with_modules(cfg.clone(), db, modules));
+        }
+        let wallet_beacon = |guardian: &FediMintConsensus<_>| {
+            guardian
+                .modules
+                .get_typed::<Wallet>(WALLET_MODULE_ID)
+                .unwrap()
+                .randomness_beacon()
+        };
+
+        // The shares revealed in epoch 0 produce its beacon right away
+        run_epoch(&guardians).await;
+        let first_beacon = wallet_beacon(&guardians[0]);
+        assert!(first_beacon.is_some());
+
+        // Without shares no beacon is produced and the previous one isn't handed out again
+        let mut outcome = next_outcome(&guardians).await;
+        for items in outcome.contributions.values_mut() {
+            items.retain(|item| !matches!(item, ConsensusItem::BeaconShare(_)));
+        }
+        for guardian in guardians.iter() {
+            guardian.process_consensus_outcome(outcome.clone()).await;
+        }
+        for guardian in guardians.iter() {
+            assert_eq!(wallet_beacon(guardian), None);
+            assert_eq!(
+                guardian.randomness_beacon().map(|beacon| beacon.value),
+                first_beacon
+            );
+        }
+
+        run_epoch(&guardians).await;
+        let next_beacon = wallet_beacon(&guardians[0]);
+        assert!(next_beacon.is_some());
+        assert_ne!(next_beacon, first_beacon);
+    }
+
+    #[test]
+    fn test_rejection_error_codes() {
+        let insufficiently_funded = TransactionError::InsufficientlyFunded {
+            inputs: Amount::from_sat(1),
+            outputs: Amount::from_sat(2),
+            fee: Amount::ZERO,
+        };
+        let cases = vec![
+            (
+                TransactionSubmissionError::TransactionError(insufficiently_funded),
+                "insufficiently_funded",
+                TransactionRejection::InsufficientlyFunded {
+                    inputs: Amount::from_sat(1),
+                    outputs: Amount::from_sat(2),
+                    fee: Amount::ZERO,
+                },
+            ),
+            (
+                TransactionSubmissionError::TransactionError(TransactionError::InvalidSignature),
+                "invalid_signature",
+                TransactionRejection::InvalidSignature,
+            ),
+            (
+                TransactionSubmissionError::UnknownModule(42.into()),
+                "unknown_module",
+                TransactionRejection::UnknownModule(42.into()),
+            ),
+            (
+                TransactionSubmissionError::InputError(
+                    MINT_MODULE_ID,
+                    ModuleError::from_err(MintError::SpentCoin),
+                ),
+                "mint_spent_coin",
+                TransactionRejection::InvalidInput {
+                    module: MINT_MODULE_ID,
+                    reason: MintError::SpentCoin.to_string(),
+                },
+            ),
+            (
+                TransactionSubmissionError::InputError(
+                    WALLET_MODULE_ID,
+                    ModuleError::from_err(WalletError::PegInAlreadyClaimed),
+                ),
+                "wallet_peg_in_already_claimed",
+                TransactionRejection::InvalidInput {
+                    module: WALLET_MODULE_ID,
+                    reason: WalletError::PegInAlreadyClaimed.to_string(),
+                },
+            ),
+            (
+                TransactionSubmissionError::OutputError(
+                    MINT_MODULE_ID,
+                    DecodeError::from_str("Trailing bytes").into(),
+                ),
+                "invalid_encoding",
+                TransactionRejection::InvalidOutput {
+                    module: MINT_MODULE_ID,
+                    reason: "Trailing bytes".to_string(),
+                },
+            ),
+            (
+                TransactionSubmissionError::MempoolFull,
+                "mempool_full",
+                TransactionRejection::MempoolFull,
+            ),
+            (
+                TransactionSubmissionError::Conflict,
+                "conflict",
+                TransactionRejection::Conflict,
+            ),
+        ];
+
+        for (error, code, rejection) in cases {
+            assert_eq!(error.error_code(), code);
+            assert_eq!(TransactionRejection::from(error), rejection);
+        }
+    }
+}
@@ -1,10 +1,45 @@
 use minimint_api::db::DatabaseKeyPrefixConst;
 use minimint_api::encoding::{Decodable, Encodable};
-use minimint_api::TransactionId;
+use minimint_api::{PeerId, TransactionId};
 use std::fmt::Debug;
 
 pub const DB_PREFIX_PROPOSED_TRANSACTION: u8 = 0x01;
 pub const DB_PREFIX_ACCEPTED_TRANSACTION: u8 = 0x02;
+pub const DB_PREFIX_EPOCH_HISTORY: u8 = 0x03;
+pub const DB_PREFIX_LAST_EPOCH: u8 = 0x04;
+pub const DB_PREFIX_REJECTED_TRANSACTION: u8 = 0x05;
+pub const DB_PREFIX_CONFIG_CHANGE_VOTE: u8 = 0x06;
+pub const DB_PREFIX_SCHEDULED_CONFIG_CHANGE: u8 = 0x07;
+pub const DB_PREFIX_EPOCH_HEADER: u8 = 0x08;
+pub const DB_PREFIX_EPOCH_SIGNATURE_SHARE: u8 = 0x09;
+pub const DB_PREFIX_EPOCH_SIGNATURE: u8 = 0x0a;
+pub const DB_PREFIX_STATE_DIGEST: u8 = 0x0b;
+pub const DB_PREFIX_PEER_STATE_DIGEST: u8 = 0x0c;
+pub const DB_PREFIX_FEE_VOTE: u8 = 0x0d;
+pub const DB_PREFIX_FEE_SCHEDULE: u8 = 0x0e;
+pub const DB_PREFIX_PROPOSED_FEES: u8 = 0x0f;
+pub const DB_PREFIX_BEACON_SHARE: u8 = 0x40;
+pub const DB_PREFIX_RANDOMNESS_BEACON: u8 = 0x41;
+pub const DB_PREFIX_EVICTED_TRANSACTION: u8 = 0x42;
+
+/// Prefixes of the consensus state all peers agree on. Proposed and evicted transactions and fees
+/// are local and the state digests are excluded since a peer with diverging state has a different
+/// one.
+pub const CONSENSUS_DB_PREFIXES: &[u8] = &[
+    DB_PREFIX_ACCEPTED_TRANSACTION,
+    DB_PREFIX_EPOCH_HISTORY,
+    DB_PREFIX_LAST_EPOCH,
+    DB_PREFIX_REJECTED_TRANSACTION,
+    DB_PREFIX_CONFIG_CHANGE_VOTE,
+    DB_PREFIX_SCHEDULED_CONFIG_CHANGE,
+    DB_PREFIX_EPOCH_HEADER,
+    DB_PREFIX_EPOCH_SIGNATURE_SHARE,
+    DB_PREFIX_EPOCH_SIGNATURE,
+    DB_PREFIX_FEE_VOTE,
+    DB_PREFIX_FEE_SCHEDULE,
+    DB_PREFIX_BEACON_SHARE,
+    DB_PREFIX_RANDOMNESS_BEACON,
+];
 
 #[derive(Debug, Encodable, Decodable)]
 pub struct ProposedTransactionKey(pub TransactionId);
@@ -20,9 +55,187 @@ impl DatabaseKeyPrefixConst for ProposedTransactionKeyPrefix {
     const DB_PREFIX: u8 = DB_PREFIX_PROPOSED_TRANSACTION;
 }
 
+/// A transaction we dropped from our mempool before it was decided, together with the epoch and
+/// the reason
+#[derive(Debug, Encodable, Decodable)]
+pub struct EvictedTransactionKey(pub TransactionId);
+
+impl DatabaseKeyPrefixConst for EvictedTransactionKey {
+    const DB_PREFIX: u8 = DB_PREFIX_EVICTED_TRANSACTION;
+}
+
 #[derive(Debug, Encodable, Decodable)]
 pub struct AcceptedTransactionKey(pub TransactionId);
 
 impl DatabaseKeyPrefixConst for AcceptedTransactionKey {
     const DB_PREFIX: u8 = DB_PREFIX_ACCEPTED_TRANSACTION;
 }
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct AcceptedTransactionKeyPrefix;
+
+impl DatabaseKeyPrefixConst for AcceptedTransactionKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_ACCEPTED_TRANSACTION;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct RejectedTransactionKey(pub TransactionId);
+
+impl DatabaseKeyPrefixConst for RejectedTransactionKey {
+    const DB_PREFIX: u8 = DB_PREFIX_REJECTED_TRANSACTION;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct EpochHistoryKey(pub u64);
+
+impl DatabaseKeyPrefixConst for EpochHistoryKey {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH_HISTORY;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct LastEpochKey;
+
+impl DatabaseKeyPrefixConst for LastEpochKey {
+    const DB_PREFIX: u8 = DB_PREFIX_LAST_EPOCH;
+}
+
+/// The digest of the config a peer voted to switch to
+#[derive(Debug, Encodable, Decodable)]
+pub struct ConfigChangeVoteKey(pub PeerId);
+
+impl DatabaseKeyPrefixConst for ConfigChangeVoteKey {
+    const DB_PREFIX: u8 = DB_PREFIX_CONFIG_CHANGE_VOTE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct ConfigChangeVoteKeyPrefix;
+
+impl DatabaseKeyPrefixConst for ConfigChangeVoteKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_CONFIG_CHANGE_VOTE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct ScheduledConfigChangeKey;
+
+impl DatabaseKeyPrefixConst for ScheduledConfigChangeKey {
+    const DB_PREFIX: u8 = DB_PREFIX_SCHEDULED_CONFIG_CHANGE;
+}
+
+/// Header of an epoch together with the transactions decided in it, see
+/// [`crate::consensus::header::EpochHeader`]
+#[derive(Debug, Encodable, Decodable)]
+pub struct EpochHeaderKey(pub u64);
+
+impl DatabaseKeyPrefixConst for EpochHeaderKey {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH_HEADER;
+}
+
+/// A peer's signature share over an epoch's header, kept until enough shares were collected
+#[derive(Debug, Encodable, Decodable)]
+pub struct EpochSignatureShareKey(pub u64, pub PeerId);
+
+impl DatabaseKeyPrefixConst for EpochSignatureShareKey {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH_SIGNATURE_SHARE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct EpochSignatureShareEpochPrefix(pub u64);
+
+impl DatabaseKeyPrefixConst for EpochSignatureShareEpochPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH_SIGNATURE_SHARE;
+}
+
+/// The federation's threshold signature over an epoch's header
+#[derive(Debug, Encodable, Decodable)]
+pub struct EpochSignatureKey(pub u64);
+
+impl DatabaseKeyPrefixConst for EpochSignatureKey {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH_SIGNATURE;
+}
+
+/// Our digest of the consensus state after an epoch, see
+/// [`crate::consensus::divergence::StateDigest`]
+#[derive(Debug, Encodable, Decodable)]
+pub struct StateDigestKey(pub u64);
+
+impl DatabaseKeyPrefixConst for StateDigestKey {
+    const DB_PREFIX: u8 = DB_PREFIX_STATE_DIGEST;
+}
+
+/// The digest of the consensus state after an epoch a peer reported
+#[derive(Debug, Encodable, Decodable)]
+pub struct PeerStateDigestKey(pub u64, pub PeerId);
+
+impl DatabaseKeyPrefixConst for PeerStateDigestKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PEER_STATE_DIGEST;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct PeerStateDigestEpochPrefix(pub u64);
+
+impl DatabaseKeyPrefixConst for PeerStateDigestEpochPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_PEER_STATE_DIGEST;
+}
+
+/// The fees a peer voted to switch to
+#[derive(Debug, Encodable, Decodable)]
+pub struct FeeVoteKey(pub PeerId);
+
+impl DatabaseKeyPrefixConst for FeeVoteKey {
+    const DB_PREFIX: u8 = DB_PREFIX_FEE_VOTE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct FeeVoteKeyPrefix;
+
+impl DatabaseKeyPrefixConst for FeeVoteKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_FEE_VOTE;
+}
+
+/// The active fees and the change the federation agreed on, see
+/// [`crate::consensus::FeeSchedule`]. Unset until the fees change for the first time.
+#[derive(Debug, Encodable, Decodable)]
+pub struct FeeScheduleKey;
+
+impl DatabaseKeyPrefixConst for FeeScheduleKey {
+    const DB_PREFIX: u8 = DB_PREFIX_FEE_SCHEDULE;
+}
+
+/// The fees our admin wants the federation to switch to, we vote for them until they are active
+#[derive(Debug, Encodable, Decodable)]
+pub struct ProposedFeesKey;
+
+impl DatabaseKeyPrefixConst for ProposedFeesKey {
+    const DB_PREFIX: u8 = DB_PREFIX_PROPOSED_FEES;
+}
+
+/// A peer's share of the signature over an epoch number, kept until a beacon of the same or a
+/// later epoch was produced, see [`crate::consensus::beacon`]
+#[derive(Debug, Encodable, Decodable)]
+pub struct BeaconShareKey(pub u64, pub PeerId);
+
+impl DatabaseKeyPrefixConst for BeaconShareKey {
+    const DB_PREFIX: u8 = DB_PREFIX_BEACON_SHARE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct BeaconShareKeyPrefix;
+
+impl DatabaseKeyPrefixConst for BeaconShareKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_BEACON_SHARE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct BeaconShareEpochPrefix(pub u64);
+
+impl DatabaseKeyPrefixConst for BeaconShareEpochPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_BEACON_SHARE;
+}
+
+/// The most recent randomness beacon of the federation
+#[derive(Debug, Encodable, Decodable)]
+pub struct RandomnessBeaconKey;
+
+impl DatabaseKeyPrefixConst for RandomnessBeaconKey {
+    const DB_PREFIX: u8 = DB_PREFIX_RANDOMNESS_BEACON;
+}
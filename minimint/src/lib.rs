@@ -1,18 +1,23 @@
 extern crate minimint_api;
 
-use crate::consensus::{ConsensusItem, FediMintConsensus};
+use crate::consensus::{ConsensusItem, FediMintConsensus, ScheduledConfigChange};
+use crate::net::catchup::{load_epochs, CatchUp, MAX_EPOCH_LAG};
 use crate::net::connect::Connections;
-use crate::net::PeerConnections;
+use crate::net::{PeerConnections, PeerMessage};
 use crate::rng::RngGenerator;
 use config::ServerConfig;
 use consensus::ConsensusOutcome;
 use hbbft::honey_badger::{HoneyBadger, Step};
-use hbbft::{Epoched, NetworkInfo};
+use hbbft::{Epoched, NetworkInfo, Target};
+use minimint_api::db::buffered::BufferedDatabase;
 use minimint_api::db::RawDatabase;
+use minimint_api::module::{ModuleItem, ModuleRegistry, MINT_MODULE_ID, WALLET_MODULE_ID};
 use minimint_api::PeerId;
 use rand::{CryptoRng, RngCore};
 use std::collections::HashSet;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
 use tokio::task::{spawn, JoinHandle};
 use tracing::{debug, info, trace, warn};
@@ -32,51 +37,130 @@ pub mod config;
 /// Some abstractions to handle randomness
 mod rng;
 
-/// Start all the components of the mintan d plug them together
-pub async fn run_minimint(cfg: ServerConfig) {
-    assert_eq!(
-        cfg.peers.keys().max().copied().map(|id| id.to_usize()),
-        Some(cfg.peers.len() - 1)
-    );
-    assert_eq!(cfg.peers.keys().min().copied(), Some(PeerId::from(0)));
+/// Rebuilding the state from the persisted consensus history
+pub mod replay;
 
-    let threshold = cfg.peers.len() - cfg.max_faulty();
+/// Audit of the federation's liabilities against its on-chain reserves
+pub mod audit;
+
+#[cfg(test)]
+mod testing;
+
+/// Start all the components of the mintan d plug them together. If `next_cfg_digest` is given we
+/// vote to switch to that config. Returns once a config change takes effect.
+pub async fn run_minimint(
+    cfg: ServerConfig,
+    next_cfg_digest: Option<[u8; 32]>,
+) -> ScheduledConfigChange {
+    let database = Arc::new(BufferedDatabase::new(Arc::new(
+        sled::open(&cfg.db_path).unwrap().open_tree("mint").unwrap(),
+    )));
+
+    let modules = default_modules(&cfg, database.clone()).await;
 
-    let database: Arc<dyn RawDatabase> =
-        Arc::new(sled::open(&cfg.db_path).unwrap().open_tree("mint").unwrap());
+    run_minimint_with_modules(cfg, database, modules, next_cfg_digest).await
+}
+
+/// Constructs the built-in mint and wallet modules operating on `database`
+pub async fn default_modules(cfg: &ServerConfig, database: Arc<dyn RawDatabase>) -> ModuleRegistry {
+    let threshold = cfg.peers.len() - cfg.max_faulty();
 
     let mint = minimint_mint::Mint::new(cfg.mint.clone(), threshold, database.clone());
 
-    let wallet = minimint_wallet::Wallet::new(cfg.wallet.clone(), database.clone())
+    let wallet = minimint_wallet::Wallet::new(cfg.wallet.clone(), database)
         .await
         .expect("Couldn't create wallet");
 
+    let mut modules = ModuleRegistry::new();
+    modules.register(MINT_MODULE_ID, mint);
+    modules.register(WALLET_MODULE_ID, wallet);
+    modules
+}
+
+/// Start all the components of the mint with a custom set of modules and plug them together.
+/// The modules have to operate on `database` so their changes are committed with the epoch.
+pub async fn run_minimint_with_modules(
+    cfg: ServerConfig,
+    database: Arc<BufferedDatabase>,
+    modules: ModuleRegistry,
+    next_cfg_digest: Option<[u8; 32]>,
+) -> ScheduledConfigChange {
     let mint_consensus = Arc::new(FediMintConsensus {
         rng_gen: Box::new(CloneRngGen(Mutex::new(rand::rngs::OsRng::new().unwrap()))), //FIXME
         cfg: cfg.clone(),
-        mint,
-        wallet,
+        modules,
         db: database,
+        next_cfg_digest,
+        hbbft_epoch: Default::default(),
+        metrics: Default::default(),
+        transaction_updates: Default::default(),
+        mempool_lock: Default::default(),
     });
 
-    spawn(net::api::run_server(cfg.clone(), mint_consensus.clone()));
+    let connections = Connections::<PeerMessage>::connect_to_all(&cfg.network_config()).await;
+
+    spawn(net::api::run_server(
+        cfg.clone(),
+        mint_consensus.clone(),
+        connections.status(),
+    ));
+
+    run_consensus(mint_consensus, connections).await
+}
+
+/// Runs the consensus process of `mint_consensus` exchanging messages with the other guardians
+/// over `connections`. This allows running several guardians in one process, e.g. on a
+/// [`net::sim::SimNetwork`] for tests.
+///
+/// Returns the scheduled config change once it takes effect, the caller has to restart with the
+/// new config then.
+pub async fn run_consensus<R, C>(
+    mint_consensus: Arc<FediMintConsensus<R>>,
+    connections: C,
+) -> ScheduledConfigChange
+where
+    R: RngCore + CryptoRng,
+    C: PeerConnections<PeerMessage, Id = PeerId> + Send + 'static,
+{
+    let cfg = mint_consensus.cfg.clone();
+
+    let first_epoch = mint_consensus
+        .last_processed_epoch()
+        .map_or(0, |last_epoch| last_epoch + 1);
+
+    if let Some(change) = mint_consensus.due_config_change(first_epoch) {
+        info!(
+            "Config change took effect in epoch {}",
+            change.activation_epoch
+        );
+        return change;
+    }
+
+    mint_consensus.backfill_mint_audit();
 
     let (output_sender, mut output_receiver) = channel::<ConsensusOutcome>(1);
     let (proposal_sender, proposal_receiver) = channel::<Vec<ConsensusItem>>(1);
 
-    info!("Spawning consensus with first proposal");
-    spawn_hbbft(
+    info!(
+        "Spawning consensus with first proposal, starting at epoch {}",
+        first_epoch
+    );
+    let hbbft_handle = spawn_hbbft(
         output_sender,
         proposal_receiver,
         cfg.clone(),
+        connections,
+        mint_consensus.db.clone(),
+        mint_consensus.hbbft_epoch.clone(),
+        first_epoch,
         mint_consensus.get_consensus_proposal().await,
         rand::rngs::OsRng::new().unwrap(),
     )
     .await;
 
-    // FIXME: reusing the wallet CI leads to duplicate randomness beacons, not a problem for change, but maybe later for other use cases
     debug!("Generating second proposal");
     let mut proposal = Some(mint_consensus.get_consensus_proposal().await);
+    let mut last_outcome_time = None;
     loop {
         debug!("Ready to exchange proposal for consensus outcome");
 
@@ -85,11 +169,26 @@ pub async fn run_minimint(cfg: ServerConfig) {
         // while processing the outcome.
         let outcome = {
             let outcome = output_receiver.recv().await.expect("other thread died");
+            let now = Instant::now();
+            if let Some(last_outcome_time) = last_outcome_time.replace(now) {
+                mint_consensus
+                    .metrics
+                    .epoch_duration
+                    .observe_duration(now - last_outcome_time);
+            }
             let outcome_filter_set = outcome
                 .contributions
                 .values()
                 .flatten()
-                .filter(|ci| !matches!(ci, ConsensusItem::Wallet(_)))
+                .filter(|ci| {
+                    !matches!(
+                        ci,
+                        ConsensusItem::Module(ModuleItem {
+                            module: WALLET_MODULE_ID,
+                            ..
+                        })
+                    )
+                })
                 .collect::<HashSet<_>>();
 
             let full_proposal = proposal.take().expect("Is always refilled");
@@ -106,6 +205,7 @@ pub async fn run_minimint(cfg: ServerConfig) {
         };
 
         let we_contributed = outcome.contributions.contains_key(&cfg.identity);
+        let next_epoch = outcome.epoch + 1;
 
         debug!(
             "Processing consensus outcome from epoch {} with {} items",
@@ -114,8 +214,17 @@ pub async fn run_minimint(cfg: ServerConfig) {
         );
         mint_consensus.process_consensus_outcome(outcome).await;
 
+        if let Some(change) = mint_consensus.due_config_change(next_epoch) {
+            info!(
+                "Config change takes effect in epoch {}, stopping",
+                change.activation_epoch
+            );
+            hbbft_handle.abort();
+            return change;
+        }
+
         if we_contributed {
-            // TODO: define latency target for consensus rounds and monitor it
+            // TODO: define latency target for consensus rounds, see the epoch duration metric
             // give others a chance to catch up
             tokio::time::sleep(tokio::time::Duration::from_millis(2000)).await;
         }
@@ -128,13 +237,15 @@ async fn spawn_hbbft(
     outcome_sender: Sender<ConsensusOutcome>,
     mut proposal_receiver: Receiver<Vec<ConsensusItem>>,
     cfg: ServerConfig,
+    mut connections: impl PeerConnections<PeerMessage, Id = PeerId> + Send + 'static,
+    db: Arc<dyn RawDatabase>,
+    hbbft_epoch: Arc<AtomicU64>,
+    first_epoch: u64,
     initial_cis: Vec<ConsensusItem>,
     mut rng: impl RngCore + CryptoRng + Clone + Send + 'static,
 ) -> JoinHandle<()> {
     spawn(async move {
-        let mut connections = Connections::connect_to_all(&cfg).await;
-
-        let net_info = NetworkInfo::new(
+        let net_info = Arc::new(NetworkInfo::new(
             cfg.identity,
             cfg.hbbft_sks.inner().clone(),
             cfg.hbbft_pk_set.clone(),
@@ -143,17 +254,20 @@ async fn spawn_hbbft(
                 .iter()
                 .map(|(id, peer)| (*id, peer.hbbft_pk))
                 .collect(),
-        );
+        ));
 
-        let mut hb: HoneyBadger<Vec<ConsensusItem>, _> =
-            HoneyBadger::builder(Arc::new(net_info)).build();
+        let mut hb: HoneyBadger<Vec<ConsensusItem>, _> = HoneyBadger::builder(net_info.clone())
+            .epoch(first_epoch)
+            .build();
         info!("Created Honey Badger instance");
 
+        let mut catch_up: Option<CatchUp> = None;
         let mut next_consensus_items = Some(initial_cis);
         loop {
             let contribution = next_consensus_items
                 .take()
                 .expect("This is always refilled");
+            hbbft_epoch.store(hb.epoch(), Ordering::Relaxed);
 
             debug!(
                 "Proposing a contribution with {} consensus items for epoch {}",
@@ -178,14 +292,66 @@ async fn spawn_hbbft(
                     None => {
                         let (peer, peer_msg) = connections.receive().await;
                         trace!("Received message from {}", peer);
-                        hb.handle_message(&peer, peer_msg)
-                            .expect("Failed to process HBBFT input")
+                        match peer_msg {
+                            PeerMessage::Consensus(msg) => {
+                                let is_lagging = msg.epoch() > hb.epoch() + MAX_EPOCH_LAG;
+                                if is_lagging && catch_up.as_ref().map_or(true, CatchUp::is_expired)
+                                {
+                                    info!(
+                                        "Peer {} is in epoch {} while we are in epoch {}, catching up",
+                                        peer,
+                                        msg.epoch(),
+                                        hb.epoch()
+                                    );
+                                    let new_catch_up =
+                                        CatchUp::new(hb.epoch(), cfg.max_faulty() + 1);
+                                    connections.send(Target::All, new_catch_up.request()).await;
+                                    catch_up = Some(new_catch_up);
+                                }
+
+                                hb.handle_message(&peer, msg)
+                                    .expect("Failed to process HBBFT input")
+                            }
+                            PeerMessage::EpochRequest(from) => {
+                                debug!("Peer {} requested epochs starting at {}", peer, from);
+                                let epochs = load_epochs(db.as_ref(), from);
+                                connections
+                                    .send(Target::Node(peer), PeerMessage::EpochResponse(epochs))
+                                    .await;
+                                continue;
+                            }
+                            PeerMessage::EpochResponse(epochs) => {
+                                let agreed_epochs = match catch_up.as_mut() {
+                                    Some(catch_up) => catch_up.handle_response(peer, epochs),
+                                    None => None,
+                                };
+                                let missed_outcomes = agreed_epochs
+                                    .unwrap_or_default()
+                                    .into_iter()
+                                    .filter(|history| history.epoch >= hb.epoch())
+                                    .map(ConsensusOutcome::from)
+                                    .collect::<Vec<_>>();
+
+                                if missed_outcomes.is_empty() {
+                                    continue;
+                                }
+
+                                info!(
+                                    "Caught up on {} missed epochs from peers",
+                                    missed_outcomes.len()
+                                );
+                                catch_up = None;
+                                break 'inner missed_outcomes;
+                            }
+                        }
                     }
                 };
 
                 for msg in messages {
                     trace!("sending message to {:?}", msg.target);
-                    connections.send(msg.target, msg.message).await;
+                    connections
+                        .send(msg.target, PeerMessage::Consensus(msg.message))
+                        .await;
                 }
 
                 if !fault_log.is_empty() {
@@ -198,6 +364,10 @@ async fn spawn_hbbft(
                 }
             };
 
+            let next_epoch = outcome
+                .last()
+                .map(|batch| batch.epoch + 1)
+                .expect("Outcome is never empty");
             for batch in outcome {
                 debug!("Exchanging consensus outcome of epoch {}", batch.epoch);
                 // Old consensus contributions are overwritten on case of multiple batches arriving
@@ -206,6 +376,15 @@ async fn spawn_hbbft(
                 next_consensus_items =
                     Some(proposal_receiver.recv().await.expect("other thread died"));
             }
+
+            // If we skipped epochs by catching up we have to restart HoneyBadger at the epoch
+            // following the last one we received from our peers
+            if next_epoch > hb.epoch() {
+                info!("Restarting Honey Badger at epoch {}", next_epoch);
+                hb = HoneyBadger::builder(net_info.clone())
+                    .epoch(next_epoch)
+                    .build();
+            }
         }
     })
 }
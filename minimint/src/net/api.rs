@@ -1,15 +1,75 @@
-use crate::config::ServerConfig;
-use crate::consensus::FediMintConsensus;
+use crate::config::{ClientConfig, ServerConfig};
+use crate::consensus::{FediMintConsensus, ScheduledConfigChange};
+use crate::net::connect::ConnectionStatus;
+use minimint_api::module::{ErrorCode, MINT_MODULE_ID, WALLET_MODULE_ID};
+use minimint_api::outcome::{SubmissionError, TransactionStatus};
 use minimint_api::transaction::Transaction;
-use minimint_api::TransactionId;
+use minimint_api::{FeeConsensus, PeerId, TransactionId};
+use minimint_mint::{Mint, MintStatus};
+use minimint_wallet::{Wallet, WalletStatus};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fmt::Formatter;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
-use tide::{Body, Request, Response};
-use tracing::{debug, trace};
+use std::time::Duration;
+use tide::{Body, Request, Response, StatusCode};
+use tracing::{debug, trace, warn};
+
+/// Time after which a status subscription is answered even if no status changed, clients are
+/// expected to subscribe again then
+const SUBSCRIPTION_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Maximum number of transactions a client can subscribe to with one request
+const MAX_SUBSCRIBED_TRANSACTIONS: usize = 1000;
 
 #[derive(Clone)]
 struct State {
     fedimint: Arc<FediMintConsensus<rand::rngs::OsRng>>,
+    connections: ConnectionStatus,
+    /// The API server doesn't run on the tokio runtime, waiting for status changes has to
+    /// happen on it though
+    runtime: tokio::runtime::Handle,
+    client_config: Arc<ClientConfigResponse>,
+}
+
+/// Response of `GET /config`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ClientConfigResponse {
+    pub config: ClientConfig,
+    /// [`ClientConfig::digest`] of `config`, all guardians serve the same one. Clients must not
+    /// trust it but compute the digest of `config` themselves.
+    #[serde(with = "crate::config::serde_binary_human_readable")]
+    pub digest: [u8; 32],
+}
+
+/// Request body of `POST /transaction/subscribe`. The guardian answers as soon as the status of
+/// any of the transactions differs from the one the client already knows, with the current
+/// status of all changed transactions. If nothing changed within [`SUBSCRIPTION_TIMEOUT`] it
+/// answers with no changes.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusSubscription {
+    /// The transactions' last status known to the client, `None` if unknown
+    pub known: BTreeMap<TransactionId, Option<TransactionStatus>>,
+}
+
+/// Status of a guardian returned by `GET /admin/status`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GuardianStatus {
+    /// Epoch HoneyBadger is currently working on
+    pub hbbft_epoch: u64,
+    /// Last epoch whose outcome was fully processed, `None` if none was processed yet
+    pub last_processed_epoch: Option<u64>,
+    /// Whether we are currently connected to each of our peers
+    pub peers: BTreeMap<PeerId, bool>,
+    /// Number of submitted transactions that are proposed until consensus is reached on them
+    pub mempool_transactions: usize,
+    /// Total size of the transactions in the mempool in bytes
+    pub mempool_bytes: usize,
+    pub scheduled_config_change: Option<ScheduledConfigChange>,
+    /// Status of the built-in modules, `None` if the module isn't run by this guardian
+    pub mint: Option<MintStatus>,
+    pub wallet: Option<WalletStatus>,
 }
 
 impl std::fmt::Debug for State {
@@ -18,28 +78,75 @@ impl std::fmt::Debug for State {
     }
 }
 
-pub async fn run_server(cfg: ServerConfig, fedimint: Arc<FediMintConsensus<rand::rngs::OsRng>>) {
-    let state = State { fedimint };
+/// Runs the API server for clients. If an admin token is configured, the admin endpoints under
+/// `/admin` and the Prometheus metrics under `/metrics` are enabled too. They require the token to
+/// be sent as `Authorization: Bearer <token>`.
+pub async fn run_server(
+    cfg: ServerConfig,
+    fedimint: Arc<FediMintConsensus<rand::rngs::OsRng>>,
+    connections: ConnectionStatus,
+) {
+    let client_config = cfg.client_config();
+    let state = State {
+        fedimint,
+        connections,
+        runtime: tokio::runtime::Handle::current(),
+        client_config: Arc::new(ClientConfigResponse {
+            digest: client_config.digest(),
+            config: client_config,
+        }),
+    };
     let mut server = tide::with_state(state);
     server.at("/transaction").put(submit_transaction);
+    server.at("/transaction/subscribe").post(subscribe_status);
     server.at("/transaction/:txid").get(fetch_outcome);
+    server.at("/transaction/:txid/proof").get(fetch_proof);
+    server.at("/config").get(client_config);
+    server.at("/audit").get(audit);
+    server.at("/fees").get(fees);
+    if cfg.admin_token.is_some() {
+        server.at("/admin/status").get(admin_status);
+        server.at("/admin/fees").post(propose_fees);
+        server.at("/metrics").get(metrics);
+    } else {
+        warn!("No admin token configured, admin API is disabled");
+    }
     server
-        .listen(format!("127.0.0.1:{}", cfg.get_api_port()))
+        .listen(cfg.api_bind_addr)
         .await
         .expect("Could not start API server");
 }
 
+/// Returns the transaction id on success, otherwise a [`SubmissionError`] with status 400
 async fn submit_transaction(mut req: Request<State>) -> tide::Result {
     trace!("Received API request {:?}", req);
-    let transaction: Transaction = req.body_json().await?;
-    debug!("Sending peg-in request to consensus");
-    req.state()
-        .fedimint
-        .submit_transaction(transaction)
-        .expect("Could not submit sign request to consensus");
+    let transaction: Transaction = match req.body_json().await {
+        Ok(transaction) => transaction,
+        Err(e) => {
+            return submission_error_response(SubmissionError {
+                code: "malformed_transaction".to_string(),
+                message: e.to_string(),
+            })
+        }
+    };
 
-    // TODO: give feedback
-    Ok(Response::new(200))
+    debug!("Sending transaction to consensus");
+    match req.state().fedimint.submit_transaction(transaction) {
+        Ok(txid) => Ok(Body::from_json(&txid)?.into()),
+        Err(e) => {
+            debug!("Rejected submitted transaction: {}", e);
+            submission_error_response(SubmissionError {
+                code: e.error_code().to_string(),
+                message: e.to_string(),
+            })
+        }
+    }
+}
+
+fn submission_error_response(error: SubmissionError) -> tide::Result {
+    let mut response = Response::new(StatusCode::BadRequest);
+    response.set_body(Body::from_json(&error)?);
+    Ok(response)
 }
 
 async fn fetch_outcome(req: Request<State>) -> tide::Result {
@@ -60,3 +167,211 @@ async fn fetch_outcome(req: Request<State>) -> tide::Result {
     let body = Body::from_json(&tx_status).expect("encoding error");
     Ok(body.into())
 }
+
+/// Returns a [`crate::consensus::header::TransactionProof`] of the transaction's outcome, 404
+/// until the transaction was decided and the federation signed the header of its epoch
+async fn fetch_proof(req: Request<State>) -> tide::Result {
+    let txid: TransactionId = match req.param("txid").expect("Request id not supplied").parse() {
+        Ok(id) => id,
+        Err(_) => return Ok(Response::new(400)),
+    };
+
+    let proof = req
+        .state()
+        .fedimint
+        .transaction_proof(txid)
+        .ok_or_else(|| tide::Error::from_str(404, "Not found"))?;
+
+    Ok(Body::from_json(&proof)?.into())
+}
+
+async fn client_config(req: Request<State>) -> tide::Result {
+    Ok(Body::from_json(req.state().client_config.as_ref())?.into())
+}
+
+async fn subscribe_status(mut req: Request<State>) -> tide::Result {
+    let subscription: StatusSubscription = req.body_json().await?;
+    if subscription.known.len() > MAX_SUBSCRIBED_TRANSACTIONS {
+        return Err(tide::Error::from_str(
+            StatusCode::BadRequest,
+            format!(
+                "Can't subscribe to more than {} transactions at once",
+                MAX_SUBSCRIBED_TRANSACTIONS
+            ),
+        ));
+    }
+
+    debug!(
+        "Client subscribed to status of {} transactions",
+        subscription.known.len()
+    );
+    let fedimint = req.state().fedimint.clone();
+    let changed = req
+        .state()
+        .runtime
+        .spawn(async move {
+            fedimint
+                .await_status_changes(&subscription.known, SUBSCRIPTION_TIMEOUT)
+                .await
+        })
+        .await?;
+
+    Ok(Body::from_json(&changed)?.into())
+}
+
+/// Returns the [`crate::audit::AuditReport`], it's public so anyone can check the reserves
+async fn audit(req: Request<State>) -> tide::Result {
+    Ok(Body::from_json(&req.state().fedimint.audit())?.into())
+}
+
+/// Returns the [`crate::consensus::FeeSchedule`] of transactions submitted now
+async fn fees(req: Request<State>) -> tide::Result {
+    Ok(Body::from_json(&req.state().fedimint.fee_schedule())?.into())
+}
+
+async fn admin_status(req: Request<State>) -> tide::Result {
+    if let Err(response) = authorize_admin(&req) {
+        return Ok(response);
+    }
+
+    let fedimint = &req.state().fedimint;
+    let (mempool_transactions, mempool_bytes) = fedimint.mempool_size();
+    let status = GuardianStatus {
+        hbbft_epoch: fedimint.hbbft_epoch.load(Ordering::Relaxed),
+        last_processed_epoch: fedimint.last_processed_epoch(),
+        peers: req.state().connections.peers(),
+        mempool_transactions,
+        mempool_bytes,
+        scheduled_config_change: fedimint.scheduled_config_change(),
+        mint: fedimint
+            .modules
+            .get_typed::<Mint>(MINT_MODULE_ID)
+            .map(Mint::status),
+        wallet: fedimint
+            .modules
+            .get_typed::<Wallet>(WALLET_MODULE_ID)
+            .map(Wallet::status),
+    };
+
+    Ok(Body::from_json(&status)?.into())
+}
+
+/// Makes us vote for switching to the [`FeeConsensus`] in the request body, the federation
+/// switches once enough guardians voted for the same fees
+async fn propose_fees(mut req: Request<State>) -> tide::Result {
+    if let Err(response) = authorize_admin(&req) {
+        return Ok(response);
+    }
+
+    let fees: FeeConsensus = req.body_json().await?;
+    req.state()
+        .fedimint
+        .propose_fees(fees)
+        .map_err(|e| tide::Error::from_str(StatusCode::BadRequest, e.to_string()))?;
+    debug!("Admin proposed new fees");
+
+    Ok(Response::new(StatusCode::Ok))
+}
+
+async fn metrics(req: Request<State>) -> tide::Result {
+    if let Err(response) = authorize_admin(&req) {
+        return Ok(response);
+    }
+
+    let mut response = Response::new(StatusCode::Ok);
+    response.set_content_type("text/plain; version=0.0.4");
+    response.set_body(req.state().fedimint.encode_metrics());
+    Ok(response)
+}
+
+/// Checks that the request carries the configured admin token, otherwise returns the response to
+/// reject it with
+fn authorize_admin(req: &Request<State>) -> Result<(), Response> {
+    let expected = match req.state().fedimint.cfg.admin_token.as_deref() {
+        Some(token) => token,
+        None => return Err(Response::new(StatusCode::NotFound)),
+    };
+    let provided = req
+        .header("Authorization")
+        .and_then(|values| values.last().as_str().strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    if tokens_match(provided.as_bytes(), expected.as_bytes()) {
+        Ok(())
+    } else {
+        debug!("Rejected unauthorized admin API request");
+        Err(Response::new(StatusCode::Unauthorized))
+    }
+}
+
+/// Compares tokens in constant time to not leak how much of a guessed token was correct
+fn tokens_match(provided: &[u8], expected: &[u8]) -> bool {
+    provided.len() == expected.len()
+        && provided
+            .iter()
+            .zip(expected)
+            .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        subscribe_status, tokens_match, ClientConfigResponse, State, StatusSubscription,
+        MAX_SUBSCRIBED_TRANSACTIONS,
+    };
+    use crate::net::connect::ConnectionStatus;
+    use crate::testing::{federation_configs, guardian, mem_db};
+    use minimint_api::outcome::TransactionStatus;
+    use minimint_api::{BitcoinHash, PeerId, TransactionId};
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+    use tide::http::{Method, Request, Response};
+    use tide::{Body, StatusCode};
+
+    fn state() -> State {
+        let cfg = federation_configs(4).remove(&PeerId::from(0)).unwrap();
+        let client_config = cfg.client_config();
+        State {
+            fedimint: Arc::new(guardian(cfg, mem_db())),
+            connections: ConnectionStatus::default(),
+            runtime: tokio::runtime::Handle::current(),
+            client_config: Arc::new(ClientConfigResponse {
+                digest: client_config.digest(),
+                config: client_config,
+            }),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_subscription_limit() {
+        let mut server = tide::with_state(state());
+        server.at("/transaction/subscribe").post(subscribe_status);
+        let subscribe = |transactions: usize| {
+            let known = (0..transactions)
+                .map(|idx| (TransactionId::hash(&idx.to_le_bytes()), None))
+                .collect();
+            let mut request = Request::new(Method::Post, "http://localhost/transaction/subscribe");
+            request.set_body(Body::from_json(&StatusSubscription { known }).unwrap());
+            server.respond::<_, Response>(request)
+        };
+
+        let response = subscribe(MAX_SUBSCRIBED_TRANSACTIONS + 1).await.unwrap();
+        assert_eq!(response.status(), StatusCode::BadRequest);
+
+        // None of the transactions is known, so the subscription times out without changes
+        let mut response = subscribe(MAX_SUBSCRIBED_TRANSACTIONS).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        let changed: BTreeMap<TransactionId, Option<TransactionStatus>> =
+            response.body_json().await.unwrap();
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn test_tokens_match() {
+        assert!(tokens_match(b"secret", b"secret"));
+        assert!(!tokens_match(b"secreT", b"secret"));
+        assert!(!tokens_match(b"secret", b"secret2"));
+        assert!(!tokens_match(b"", b"secret"));
+    }
+}
@@ -0,0 +1,233 @@
+use crate::consensus::EpochHistory;
+use crate::db::EpochHistoryKey;
+use crate::net::PeerMessage;
+use minimint_api::db::{Database, RawDatabase};
+use minimint_api::PeerId;
+use std::collections::BTreeMap;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// Number of epochs a peer's consensus messages may be ahead of our current epoch before we
+/// assume that we fell behind and start catching up
+pub const MAX_EPOCH_LAG: u64 = 3;
+
+/// Maximum number of epochs sent in response to a single [`PeerMessage::EpochRequest`]
+pub const MAX_EPOCHS_PER_RESPONSE: u64 = 16;
+
+/// Time after which an unsuccessful catch-up may be restarted
+const CATCH_UP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Fetches the outcomes of epochs we missed from our peers. Since any single peer might lie about
+/// them an outcome is only accepted once `threshold` peers sent the exact same one.
+#[derive(Debug)]
+pub struct CatchUp {
+    /// First epoch we are missing
+    next_epoch: u64,
+    /// Number of peers that have to agree on an epoch outcome before it is accepted
+    threshold: usize,
+    started: Instant,
+    /// Latest response of every peer, the first element is the outcome of `next_epoch`
+    responses: BTreeMap<PeerId, Vec<EpochHistory>>,
+}
+
+impl CatchUp {
+    /// Starts catching up on all epochs starting at `next_epoch`
+    pub fn new(next_epoch: u64, threshold: usize) -> CatchUp {
+        debug!("Starting to catch up from epoch {}", next_epoch);
+        CatchUp {
+            next_epoch,
+            threshold,
+            started: Instant::now(),
+            responses: BTreeMap::new(),
+        }
+    }
+
+    /// The message to broadcast to all peers to request the missing epochs
+    pub fn request(&self) -> PeerMessage {
+        PeerMessage::EpochRequest(self.next_epoch)
+    }
+
+    /// Returns `true` if the catch-up didn't succeed in time and should be restarted
+    pub fn is_expired(&self) -> bool {
+        self.started.elapsed() > CATCH_UP_TIMEOUT
+    }
+
+    /// Records the epoch outcomes sent by `peer`. Returns the outcomes of all consecutive epochs
+    /// starting at the first missing one that `threshold` peers agree on, if there are any.
+    pub fn handle_response(
+        &mut self,
+        peer: PeerId,
+        epochs: Vec<EpochHistory>,
+    ) -> Option<Vec<EpochHistory>> {
+        let is_well_formed = epochs.len() as u64 <= MAX_EPOCHS_PER_RESPONSE
+            && epochs
+                .iter()
+                .zip(self.next_epoch..)
+                .all(|(history, epoch)| history.epoch == epoch);
+        if !is_well_formed {
+            warn!("Peer {} sent malformed epoch response", peer);
+            return None;
+        }
+
+        self.responses.insert(peer, epochs);
+
+        let mut agreed = Vec::new();
+        loop {
+            let idx = agreed.len();
+            let agreed_history = self
+                .responses
+                .values()
+                .filter_map(|epochs| epochs.get(idx))
+                .find(|history| {
+                    self.responses
+                        .values()
+                        .filter(|epochs| epochs.get(idx) == Some(*history))
+                        .count()
+                        >= self.threshold
+                });
+
+            match agreed_history {
+                Some(history) => agreed.push(history.clone()),
+                None => break,
+            }
+        }
+
+        if agreed.is_empty() {
+            None
+        } else {
+            Some(agreed)
+        }
+    }
+}
+
+/// Loads the persisted outcomes of consecutive epochs starting at `from` to answer a peer's
+/// [`PeerMessage::EpochRequest`]
+pub fn load_epochs(db: &dyn RawDatabase, from: u64) -> Vec<EpochHistory> {
+    (from..from.saturating_add(MAX_EPOCHS_PER_RESPONSE))
+        .map(|epoch| {
+            db.get_value::<_, EpochHistory>(&EpochHistoryKey(epoch))
+                .expect("DB error")
+        })
+        .take_while(Option::is_some)
+        .flatten()
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{CatchUp, CATCH_UP_TIMEOUT, MAX_EPOCHS_PER_RESPONSE};
+    use crate::consensus::{ConsensusItem, EpochHistory};
+    use crate::net::PeerMessage;
+    use minimint_api::PeerId;
+    use std::time::Duration;
+
+    fn history(epoch: u64) -> EpochHistory {
+        EpochHistory {
+            epoch,
+            contributions: vec![(PeerId::from(0), vec![])],
+        }
+    }
+
+    fn forged_history(epoch: u64) -> EpochHistory {
+        EpochHistory {
+            epoch,
+            contributions: vec![(
+                PeerId::from(0),
+                vec![ConsensusItem::ConfigChange([0x42; 32])],
+            )],
+        }
+    }
+
+    #[test]
+    fn test_accepts_matching_responses() {
+        let mut catch_up = CatchUp::new(5, 2);
+        assert!(matches!(catch_up.request(), PeerMessage::EpochRequest(5)));
+
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(0), vec![history(5), history(6)]),
+            None
+        );
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(1), vec![history(5)]),
+            Some(vec![history(5)])
+        );
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(1), vec![history(5), history(6)]),
+            Some(vec![history(5), history(6)])
+        );
+    }
+
+    #[test]
+    fn test_ignores_lying_minority() {
+        let mut catch_up = CatchUp::new(5, 2);
+
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(0), vec![forged_history(5), history(6)]),
+            None
+        );
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(1), vec![history(5), history(6)]),
+            None
+        );
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(2), vec![history(5), forged_history(6)]),
+            Some(vec![history(5), history(6)])
+        );
+    }
+
+    #[test]
+    fn test_rejects_malformed_responses() {
+        let mut catch_up = CatchUp::new(5, 2);
+
+        let oversized = (5..5 + MAX_EPOCHS_PER_RESPONSE + 1)
+            .map(history)
+            .collect::<Vec<_>>();
+        assert_eq!(catch_up.handle_response(PeerId::from(0), oversized), None);
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(1), vec![history(4), history(5)]),
+            None
+        );
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(2), vec![history(5), history(7)]),
+            None
+        );
+
+        // None of the malformed responses was recorded
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(3), vec![history(5)]),
+            None
+        );
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(0), vec![history(5)]),
+            Some(vec![history(5)])
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_expiry_and_retry() {
+        let mut catch_up = CatchUp::new(5, 2);
+        assert_eq!(
+            catch_up.handle_response(PeerId::from(0), vec![history(5)]),
+            None
+        );
+
+        tokio::time::advance(CATCH_UP_TIMEOUT).await;
+        assert!(!catch_up.is_expired());
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(catch_up.is_expired());
+
+        // The retry starts from scratch and times out again only after another full timeout
+        let mut retry = CatchUp::new(5, 2);
+        assert!(matches!(retry.request(), PeerMessage::EpochRequest(5)));
+        assert!(!retry.is_expired());
+        assert_eq!(
+            retry.handle_response(PeerId::from(1), vec![history(5)]),
+            None
+        );
+        assert_eq!(
+            retry.handle_response(PeerId::from(2), vec![history(5)]),
+            Some(vec![history(5)])
+        );
+    }
+}
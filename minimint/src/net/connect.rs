@@ -1,122 +1,385 @@
-use crate::config::ServerConfig;
-use crate::net::framed::Framed;
+use crate::config::NetworkConfig;
+use crate::net::encryption::{HandshakeError, IdentityKeys, SessionKeys};
+use crate::net::framed::{FrameError, Framed};
 use crate::net::PeerConnections;
 use async_trait::async_trait;
-use futures::future::select_all;
-use futures::future::try_join_all;
-use futures::StreamExt;
-use futures::{FutureExt, SinkExt};
+use futures::{SinkExt, StreamExt};
 use hbbft::Target;
 use minimint_api::PeerId;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
 use std::time::Duration;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::tcp::OwnedReadHalf;
 use tokio::net::{TcpListener, TcpStream};
-use tokio::spawn;
-use tokio::time::sleep;
-use tokio_util::compat::{Compat, TokioAsyncReadCompatExt};
-use tracing::{debug, error, info, trace};
+use tokio::sync::mpsc::{
+    channel, unbounded_channel, Receiver, Sender, UnboundedReceiver, UnboundedSender,
+};
+use tokio::task::JoinHandle;
+use tokio::time::{sleep_until, timeout, Instant};
+use tokio_util::compat::{Compat, TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+use tracing::{debug, info, trace, warn};
 
-// FIXME: make connections dynamically managed
+/// Time to wait before the first reconnection attempt, it doubles with every failed attempt
+const MIN_BACKOFF: Duration = Duration::from_millis(500);
+
+/// Maximum time to wait between two reconnection attempts
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Time after which connecting to a peer or sending it a message is considered failed
+const IO_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Maximum number of messages buffered per peer while the connection is down. If more messages
+/// are sent the oldest ones are dropped, the peer will have to catch up on the missed epochs.
+const MAX_BUFFERED_MESSAGES: usize = 10_000;
+
+/// Maximum number of received messages that weren't consumed yet
+const INCOMING_QUEUE_SIZE: usize = 1024;
+
+/// Connections to all peers of the federation. Every connection is managed by its own task that
+/// reconnects with exponential backoff when the link breaks and buffers outgoing messages in the
+/// meantime, so a restarting peer doesn't affect the connections to the remaining ones.
+///
+/// The peer with the lower id dials, the other one accepts the connection. All connections are
+/// authenticated using the peers' keys from the config and encrypted, see
+/// [`IdentityKeys::handshake`].
 pub struct Connections<T> {
-    connections: HashMap<PeerId, Framed<Compat<TcpStream>, T>>,
+    outgoing: HashMap<PeerId, UnboundedSender<Arc<T>>>,
+    incoming: Receiver<(PeerId, T)>,
+    tasks: Vec<JoinHandle<()>>,
+    /// Task accepting connections from our peers
+    listener: JoinHandle<()>,
+    status: ConnectionStatus,
+}
+
+/// Whether we are currently connected to each of our peers. It is updated by the tasks managing
+/// the connections and can be cloned to observe it from elsewhere, e.g. the admin API.
+#[derive(Debug, Clone, Default)]
+pub struct ConnectionStatus(Arc<Mutex<BTreeMap<PeerId, bool>>>);
+
+/// State of the task managing the connection to a single peer
+struct PeerConnection<T> {
+    keys: Arc<IdentityKeys>,
+    peer: PeerId,
+    /// Address to dial if we are responsible for establishing the connection, otherwise we wait
+    /// for the peer to connect to us
+    dial_addr: Option<String>,
+    backoff: Duration,
+    /// Messages that were not yet sent to the peer
+    buffer: VecDeque<Arc<T>>,
+    outgoing: UnboundedReceiver<Arc<T>>,
+    /// Connections initiated by the peer, already past the handshake
+    accepted: Receiver<(TcpStream, SessionKeys)>,
+    incoming: Sender<(PeerId, T)>,
+    status: ConnectionStatus,
 }
 
-impl<T: 'static> Connections<T>
+impl<T> Connections<T>
 where
-    T: Serialize + DeserializeOwned + Unpin + Send,
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
 {
-    pub async fn connect_to_all(cfg: &ServerConfig) -> Self {
+    /// Starts listening for connections from our peers and connecting to them. This returns
+    /// immediately, messages sent before a connection is established are buffered.
+    pub async fn connect_to_all(cfg: &NetworkConfig) -> Self {
         info!("Starting mint {}", cfg.identity);
-        let listener = spawn(Self::await_peers(
-            cfg.get_hbbft_port(),
-            cfg.get_incoming_count(),
-        ));
+        let (incoming_sender, incoming) = channel(INCOMING_QUEUE_SIZE);
+        let keys = Arc::new(IdentityKeys::from_config(cfg));
 
-        sleep(Duration::from_millis(5000)).await;
+        let mut outgoing = HashMap::new();
+        let mut accepted_senders = HashMap::new();
+        let mut tasks = Vec::new();
+        let status = ConnectionStatus::default();
+        for (&peer, peer_cfg) in cfg.peers.iter().filter(|(id, _)| **id != cfg.identity) {
+            let (outgoing_sender, outgoing_receiver) = unbounded_channel();
+            let (accepted_sender, accepted_receiver) = channel(1);
+            status.set_connected(peer, false);
 
-        debug!("Beginning to connect to peers");
+            let connection = PeerConnection {
+                keys: keys.clone(),
+                peer,
+                dial_addr: if cfg.identity < peer {
+                    Some(peer_cfg.addr.clone())
+                } else {
+                    None
+                },
+                backoff: MIN_BACKOFF,
+                buffer: VecDeque::new(),
+                outgoing: outgoing_receiver,
+                accepted: accepted_receiver,
+                incoming: incoming_sender.clone(),
+                status: status.clone(),
+            };
+            tasks.push(tokio::spawn(connection.run()));
 
-        let out_conns = try_join_all(cfg.peers.iter().filter_map(|(id, peer)| {
-            if cfg.identity < *id {
-                info!("Connecting to mint {}", id);
-                Some(Self::connect_to_peer(peer.hbbft_port, *id))
-            } else {
-                None
-            }
-        }))
-        .await
-        .expect("Failed to connect to peer");
+            outgoing.insert(peer, outgoing_sender);
+            accepted_senders.insert(peer, accepted_sender);
+        }
 
-        let in_conns = listener
-            .await
-            .unwrap()
-            .expect("Failed to accept connection");
-
-        let identity = &cfg.identity;
-        let handshakes = out_conns
-            .into_iter()
-            .chain(in_conns)
-            .map(move |mut stream| async move {
-                stream.write_u16((*identity).into()).await?;
-                let peer = stream.read_u16().await?.into();
-                Result::<_, std::io::Error>::Ok((peer, stream))
-            });
-
-        let peers = try_join_all(handshakes)
+        let listener = TcpListener::bind(&cfg.bind_addr)
             .await
-            .expect("Error during peer handshakes")
-            .into_iter()
-            .map(|(id, stream)| (id, Framed::new(stream.compat())))
-            .collect::<HashMap<_, _>>();
+            .expect("Couldn't bind to port.");
+        debug!("Listening for incoming connections on {}", cfg.bind_addr);
+        let listener = tokio::spawn(accept_peers(listener, keys, Arc::new(accepted_senders)));
 
-        info!("Successfully connected to all peers");
+        Connections {
+            outgoing,
+            incoming,
+            tasks,
+            listener,
+            status,
+        }
+    }
 
-        Connections { connections: peers }
+    /// Returns a handle to observe which peers we are connected to
+    pub fn status(&self) -> ConnectionStatus {
+        self.status.clone()
     }
 
-    async fn await_peers(port: u16, num_awaited: u16) -> Result<Vec<TcpStream>, std::io::Error> {
-        let listener = TcpListener::bind(("127.0.0.1", port))
-            .await
-            .expect("Couldn't bind to port.");
+    /// Closes all connections once the messages sent to currently connected peers were delivered
+    /// and stops listening for new ones
+    pub async fn shutdown(self) {
+        self.listener.abort();
+        let _ = self.listener.await;
+
+        drop(self.outgoing);
+        for task in self.tasks {
+            let _ = task.await;
+        }
+    }
+}
+
+impl<T> PeerConnection<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    async fn run(mut self) {
+        let mut next_stream = None;
+        loop {
+            let (stream, session) = match next_stream.take() {
+                Some(connection) => connection,
+                None => match self.connect().await {
+                    Some(connection) => connection,
+                    None => break,
+                },
+            };
+
+            info!("Connected to peer {}", self.peer);
+            self.status.set_connected(self.peer, true);
+            self.backoff = MIN_BACKOFF;
+            next_stream = match self.run_connection(stream, session).await {
+                ConnectionEnd::Disconnected => {
+                    warn!("Lost connection to peer {}", self.peer);
+                    self.status.set_connected(self.peer, false);
+                    None
+                }
+                ConnectionEnd::Replaced(connection) => {
+                    info!("Peer {} reconnected", self.peer);
+                    Some(connection)
+                }
+                ConnectionEnd::Shutdown => break,
+            };
+        }
+
+        self.status.set_connected(self.peer, false);
+        debug!("Stopped managing connection to peer {}", self.peer);
+    }
+
+    /// Waits until a connection to the peer is established, either by dialing or by the peer
+    /// connecting to us. Returns `None` if we are shutting down.
+    async fn connect(&mut self) -> Option<(TcpStream, SessionKeys)> {
+        let mut next_dial = Instant::now();
+        loop {
+            tokio::select! {
+                msg = self.outgoing.recv() => self.buffer_message(msg?),
+                stream = self.accepted.recv() => return stream,
+                _ = sleep_until(next_dial), if self.dial_addr.is_some() => {
+                    let addr = self.dial_addr.as_deref().expect("checked by select guard");
+                    match timeout(IO_TIMEOUT, dial(addr, &self.keys, self.peer)).await {
+                        Ok(Ok(stream)) => return Some(stream),
+                        Ok(Err(e)) => debug!("Could not connect to peer {}: {}", self.peer, e),
+                        Err(_) => debug!("Could not connect to peer {}: timeout", self.peer),
+                    }
+
+                    debug!("Retrying to connect to peer {} in {:?}", self.peer, self.backoff);
+                    next_dial = Instant::now() + self.backoff;
+                    self.backoff = std::cmp::min(self.backoff * 2, MAX_BACKOFF);
+                }
+            }
+        }
+    }
 
-        debug!("Listening for incoming connections on port {}", port);
+    /// Exchanges messages with the peer until the connection breaks
+    async fn run_connection(&mut self, stream: TcpStream, session: SessionKeys) -> ConnectionEnd {
+        let (read_half, write_half) = stream.into_split();
+        let mut writer = Framed::<_, T>::new(write_half.compat_write(), session.send);
+        let mut reader_task = tokio::spawn(receive_messages(
+            self.peer,
+            Framed::new(read_half.compat(), session.receive),
+            self.incoming.clone(),
+        ));
+
+        let end = loop {
+            // A message is only removed from the buffer once it was sent successfully, otherwise
+            // it is sent again after reconnecting
+            if let Some(msg) = self.buffer.front().cloned() {
+                match timeout(IO_TIMEOUT, writer.send(msg.as_ref())).await {
+                    Ok(Ok(())) => {
+                        self.buffer.pop_front();
+                        continue;
+                    }
+                    // The peer would reject the message, retrying won't help
+                    Ok(Err(FrameError::TooLarge(len))) => {
+                        warn!(
+                            "Dropping message of {} bytes to peer {}, it is too large",
+                            len, self.peer
+                        );
+                        self.buffer.pop_front();
+                        continue;
+                    }
+                    Ok(Err(e)) => {
+                        warn!("Error sending message to peer {}: {:?}", self.peer, e);
+                        break ConnectionEnd::Disconnected;
+                    }
+                    Err(_) => {
+                        warn!("Sending message to peer {} timed out", self.peer);
+                        break ConnectionEnd::Disconnected;
+                    }
+                }
+            }
 
-        let peers = (0..num_awaited).map(|_| listener.accept());
-        let connections = try_join_all(peers)
-            .await?
-            .into_iter()
-            .map(|(socket, _)| socket)
-            .collect::<Vec<_>>();
+            tokio::select! {
+                msg = self.outgoing.recv() => match msg {
+                    Some(msg) => self.buffer_message(msg),
+                    None => break ConnectionEnd::Shutdown,
+                },
+                connection = self.accepted.recv() => match connection {
+                    Some(connection) => break ConnectionEnd::Replaced(connection),
+                    None => break ConnectionEnd::Shutdown,
+                },
+                _ = &mut reader_task => break ConnectionEnd::Disconnected,
+            }
+        };
 
-        debug!("Received all {} connections", connections.len());
-        Ok(connections)
+        reader_task.abort();
+        end
     }
 
-    async fn connect_to_peer(port: u16, peer: PeerId) -> Result<TcpStream, std::io::Error> {
-        debug!("Connecting to peer {}", peer);
-        let res = TcpStream::connect(("127.0.0.1", port)).await;
-        if res.is_err() {
-            error!("Could not connect to peer {}", peer);
+    fn buffer_message(&mut self, msg: Arc<T>) {
+        if self.buffer.len() >= MAX_BUFFERED_MESSAGES {
+            warn!(
+                "Too many messages buffered for peer {}, dropping the oldest one",
+                self.peer
+            );
+            self.buffer.pop_front();
         }
-        res
+        self.buffer.push_back(msg);
+    }
+}
+
+impl ConnectionStatus {
+    /// Returns for every peer whether we are currently connected to it
+    pub fn peers(&self) -> BTreeMap<PeerId, bool> {
+        self.0.lock().expect("Lock poisoned").clone()
     }
 
-    async fn receive_from_peer(id: PeerId, peer: &mut Framed<Compat<TcpStream>, T>) -> (PeerId, T) {
-        let msg = peer
-            .next()
-            .await
-            .expect("Stream closed unexpectedly")
-            .expect("Error receiving peer message");
+    fn set_connected(&self, peer: PeerId, connected: bool) {
+        self.0
+            .lock()
+            .expect("Lock poisoned")
+            .insert(peer, connected);
+    }
+}
+
+enum ConnectionEnd {
+    /// The connection broke and has to be re-established
+    Disconnected,
+    /// The peer established a new connection that replaces the current one
+    Replaced((TcpStream, SessionKeys)),
+    /// The [`Connections`] were dropped
+    Shutdown,
+}
+
+/// Forwards all messages received from `peer` until the connection breaks
+async fn receive_messages<T>(
+    peer: PeerId,
+    mut reader: Framed<Compat<OwnedReadHalf>, T>,
+    incoming: Sender<(PeerId, T)>,
+) where
+    T: DeserializeOwned + Unpin,
+{
+    while let Some(msg) = reader.next().await {
+        match msg {
+            Ok(msg) => {
+                trace!("Received msg from peer {}", peer);
+                if incoming.send((peer, msg)).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => {
+                warn!("Error receiving message from peer {}: {:?}", peer, e);
+                return;
+            }
+        }
+    }
+
+    debug!("Peer {} closed the connection", peer);
+}
+
+/// Accepts connections from peers and hands them to the task responsible for the peer once they
+/// proved their identity
+async fn accept_peers(
+    listener: TcpListener,
+    keys: Arc<IdentityKeys>,
+    accepted: Arc<HashMap<PeerId, Sender<(TcpStream, SessionKeys)>>>,
+) {
+    loop {
+        let (mut stream, address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                warn!("Error accepting connection: {}", e);
+                continue;
+            }
+        };
 
-        trace!("Received msg from peer {}", id);
+        let keys = keys.clone();
+        let accepted = accepted.clone();
+        tokio::spawn(async move {
+            let (peer, session) = match timeout(IO_TIMEOUT, keys.handshake(&mut stream, None)).await
+            {
+                Ok(Ok(handshake)) => handshake,
+                Ok(Err(e)) => {
+                    warn!("Handshake with {} failed: {}", address, e);
+                    return;
+                }
+                Err(_) => {
+                    debug!("Handshake with {} timed out", address);
+                    return;
+                }
+            };
 
-        (id, msg)
+            // Only peers with a lower id than ours are supposed to connect to us
+            match accepted.get(&peer) {
+                Some(sender) if peer < keys.identity() => {
+                    let _ = sender.send((stream, session)).await;
+                }
+                _ => warn!("Unexpected connection from peer {} ({})", peer, address),
+            }
+        });
     }
 }
 
+async fn dial(
+    addr: &str,
+    keys: &IdentityKeys,
+    peer: PeerId,
+) -> Result<(TcpStream, SessionKeys), HandshakeError> {
+    debug!("Connecting to peer {} at {}", peer, addr);
+    let mut stream = TcpStream::connect(addr).await?;
+    let (_, session) = keys.handshake(&mut stream, Some(peer)).await?;
+    Ok((stream, session))
+}
+
 #[async_trait]
 impl<T> PeerConnections<T> for Connections<T>
 where
@@ -126,31 +389,120 @@ where
 
     async fn send(&mut self, target: Target<Self::Id>, msg: T) {
         trace!("Sending message to {:?}", target);
+        let msg = Arc::new(msg);
         match target {
             Target::All => {
-                for peer in self.connections.values_mut() {
-                    peer.send(&msg)
-                        .await
-                        .expect("Failed to send message to peer");
+                for peer in self.outgoing.values() {
+                    peer.send(msg.clone()).expect("Connection task never stops");
                 }
             }
-            Target::Node(peer_id) => {
-                let peer = self.connections.get_mut(&peer_id).expect("Unknown peer");
-                peer.send(&msg)
-                    .await
-                    .expect("Failed to send message to peer");
-            }
+            Target::Node(peer_id) => match self.outgoing.get(&peer_id) {
+                Some(peer) => peer.send(msg).expect("Connection task never stops"),
+                None => warn!("Dropping message to unknown peer {}", peer_id),
+            },
         }
     }
 
     async fn receive(&mut self) -> (Self::Id, T) {
-        // TODO: optimize, don't throw away remaining futures
-        select_all(
-            self.connections
-                .iter_mut()
-                .map(|(id, peer)| Self::receive_from_peer(*id, peer).boxed()),
-        )
-        .map(|(msg, _, _)| msg)
+        self.incoming
+            .recv()
+            .await
+            .expect("Connection tasks never stop")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connections, MAX_BUFFERED_MESSAGES, MIN_BACKOFF};
+    use crate::config::NetworkConfig;
+    use crate::net::PeerConnections;
+    use crate::testing::federation_configs;
+    use hbbft::Target;
+    use minimint_api::PeerId;
+    use std::time::Duration;
+    use tokio::time::timeout;
+
+    /// Configs of two peers listening on `base_port` and the port after it
+    fn network_configs(base_port: u16) -> Vec<NetworkConfig> {
+        let addr = |peer: PeerId| format!("127.0.0.1:{}", base_port + u16::from(peer));
+        federation_configs(2)
+            .values()
+            .map(|cfg| {
+                let mut cfg = cfg.network_config();
+                cfg.bind_addr = addr(cfg.identity);
+                for (&id, peer) in cfg.peers.iter_mut() {
+                    peer.addr = addr(id);
+                }
+                cfg
+            })
+            .collect()
+    }
+
+    async fn receive(connections: &mut Connections<u64>) -> (PeerId, u64) {
+        timeout(Duration::from_secs(30), connections.receive())
+            .await
+            .expect("No message received")
+    }
+
+    async fn wait_until_connected(connections: &Connections<u64>, peer: PeerId, connected: bool) {
+        let status = connections.status();
+        timeout(Duration::from_secs(30), async {
+            while status.peers()[&peer] != connected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
         .await
+        .expect("Connection status didn't change");
+    }
+
+    #[tokio::test]
+    async fn test_reconnect_after_restart() {
+        let cfgs = network_configs(17440);
+        let mut first = Connections::<u64>::connect_to_all(&cfgs[0]).await;
+        let mut second = Connections::<u64>::connect_to_all(&cfgs[1]).await;
+
+        // Messages to unknown peers are dropped
+        first.send(Target::Node(PeerId::from(7)), 0).await;
+
+        first.send(Target::All, 1).await;
+        assert_eq!(receive(&mut second).await, (PeerId::from(0), 1));
+        second.send(Target::Node(PeerId::from(0)), 2).await;
+        assert_eq!(receive(&mut first).await, (PeerId::from(1), 2));
+
+        // While the second peer restarts messages to it are buffered
+        second.shutdown().await;
+        wait_until_connected(&first, PeerId::from(1), false).await;
+        first.send(Target::All, 3).await;
+
+        let mut second = Connections::<u64>::connect_to_all(&cfgs[1]).await;
+        assert_eq!(receive(&mut second).await, (PeerId::from(0), 3));
+        wait_until_connected(&first, PeerId::from(1), true).await;
+        second.send(Target::All, 4).await;
+        assert_eq!(receive(&mut first).await, (PeerId::from(1), 4));
+    }
+
+    #[tokio::test]
+    async fn test_buffer_limit() {
+        const DROPPED: u64 = 5;
+
+        let cfgs = network_configs(17450);
+        let peer = PeerId::from(1);
+
+        // The first peer dials the second one, which isn't running yet
+        let mut first = Connections::<u64>::connect_to_all(&cfgs[0]).await;
+        for msg in 0..MAX_BUFFERED_MESSAGES as u64 + DROPPED {
+            first.send(Target::Node(peer), msg).await;
+        }
+
+        // Let a few connection attempts fail, the backoff grows in the meantime
+        tokio::time::sleep(MIN_BACKOFF * 3).await;
+        assert!(!first.status().peers()[&peer]);
+
+        // The oldest messages were dropped
+        let mut second = Connections::<u64>::connect_to_all(&cfgs[1]).await;
+        for msg in DROPPED..MAX_BUFFERED_MESSAGES as u64 + DROPPED {
+            assert_eq!(receive(&mut second).await, (PeerId::from(0), msg));
+        }
+        assert!(first.status().peers()[&peer]);
     }
 }
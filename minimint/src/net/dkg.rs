@@ -0,0 +1,214 @@
+use crate::net::PeerConnections;
+use async_trait::async_trait;
+use hbbft::Target;
+use minimint_api::config::{DkgConnections, DkgError};
+use minimint_api::PeerId;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+/// Message exchanged during the distributed key generation
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DkgMessage {
+    /// Step of the key generation the message belongs to
+    step: String,
+    payload: Vec<u8>,
+}
+
+/// Runs the distributed key generation over [`PeerConnections`]. Peers may be ahead of us, so
+/// messages belonging to later steps are kept until we reach that step.
+pub struct DkgNetwork<C> {
+    our_id: PeerId,
+    peers: Vec<PeerId>,
+    connections: C,
+    pending: BTreeMap<String, BTreeMap<PeerId, Vec<u8>>>,
+}
+
+impl<C> DkgNetwork<C>
+where
+    C: PeerConnections<DkgMessage, Id = PeerId>,
+{
+    /// `peers` has to contain all peers taking part, including ourselves
+    pub fn new(our_id: PeerId, peers: Vec<PeerId>, connections: C) -> Self {
+        DkgNetwork {
+            our_id,
+            peers,
+            connections,
+            pending: BTreeMap::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> C {
+        self.connections
+    }
+}
+
+#[async_trait(?Send)]
+impl<C> DkgConnections for DkgNetwork<C>
+where
+    C: PeerConnections<DkgMessage, Id = PeerId>,
+{
+    fn our_id(&self) -> PeerId {
+        self.our_id
+    }
+
+    fn peers(&self) -> &[PeerId] {
+        &self.peers
+    }
+
+    async fn exchange_raw(
+        &mut self,
+        step: &str,
+        mut msgs: BTreeMap<PeerId, Vec<u8>>,
+    ) -> Result<BTreeMap<PeerId, Vec<u8>>, DkgError> {
+        let own_msg = msgs.remove(&self.our_id).expect("No message for ourselves");
+        for (peer, payload) in msgs {
+            let msg = DkgMessage {
+                step: step.to_string(),
+                payload,
+            };
+            self.connections.send(Target::Node(peer), msg).await;
+        }
+
+        let mut received = self.pending.remove(step).unwrap_or_default();
+        received.insert(self.our_id, own_msg);
+        while received.len() < self.peers.len() {
+            let (peer, msg) = self.connections.receive().await;
+            let step_msgs = if msg.step == step {
+                &mut received
+            } else {
+                self.pending.entry(msg.step).or_default()
+            };
+
+            if step_msgs.insert(peer, msg.payload).is_some() {
+                return Err(DkgError::FaultyPeer(
+                    peer,
+                    "sent more than one message per step".to_string(),
+                ));
+            }
+        }
+
+        Ok(received)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{ClientConfig, ServerConfig, ServerConfigParams};
+    use crate::net::dkg::DkgNetwork;
+    use crate::net::sim::{SimNetwork, SimNetworkConfig};
+    use minimint_api::config::GenerateConfig;
+    use minimint_api::{Amount, PeerId};
+    use std::collections::BTreeMap;
+    use std::time::Duration;
+
+    fn params() -> ServerConfigParams {
+        ServerConfigParams {
+            hbbft_base_port: 4000,
+            api_base_port: 5000,
+            peer_hosts: BTreeMap::new(),
+            bind_host: "127.0.0.1".to_string(),
+            amount_tiers: vec![Amount::from_sat(1), Amount::from_sat(10)],
+        }
+    }
+
+    async fn distributed_gen(peers: &[PeerId]) -> Vec<(ServerConfig, ClientConfig)> {
+        let net = SimNetwork::new(SimNetworkConfig {
+            max_latency: Duration::from_millis(5),
+            ..Default::default()
+        });
+        let params = params();
+
+        futures::future::join_all(peers.iter().map(|&peer| {
+            let mut connections = DkgNetwork::new(peer, peers.to_vec(), net.connect(peer));
+            let params = &params;
+            async move {
+                let rng = rand::rngs::OsRng::new().unwrap();
+                ServerConfig::distributed_gen(&mut connections, 1, params, rng)
+                    .await
+                    .expect("Key generation failed")
+            }
+        }))
+        .await
+    }
+
+    #[tokio::test]
+    async fn test_distributed_gen() {
+        let peers = (0..4u16).map(PeerId::from).collect::<Vec<_>>();
+        let configs = distributed_gen(&peers).await;
+
+        let client_cfg = serde_json::to_string(&configs[0].1).unwrap();
+        for (server_cfg, peer_client_cfg) in &configs {
+            assert_eq!(serde_json::to_string(peer_client_cfg).unwrap(), client_cfg);
+            assert_eq!(
+                serde_json::to_string(&server_cfg.client_config()).unwrap(),
+                client_cfg
+            );
+            assert_eq!(
+                server_cfg.mint.tbs_sks.to_public(),
+                configs[0].0.mint.peer_tbs_pks[&server_cfg.identity]
+            );
+            assert_eq!(
+                server_cfg.hbbft_sks.inner().public_key_share(),
+                configs[0]
+                    .0
+                    .hbbft_pk_set
+                    .public_key_share(server_cfg.identity.to_usize())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reshare() {
+        let old_peers = (0..4u16).map(PeerId::from).collect::<Vec<_>>();
+        let old_configs = distributed_gen(&old_peers).await;
+        let old_client_cfg = &old_configs[0].1;
+
+        // Peer 3 leaves the federation and peer 4 joins
+        let net = SimNetwork::new(SimNetworkConfig {
+            max_latency: Duration::from_millis(5),
+            ..Default::default()
+        });
+        let peers = [0u16, 1, 2, 4]
+            .iter()
+            .copied()
+            .map(PeerId::from)
+            .collect::<Vec<_>>();
+        let params = params();
+        let configs = futures::future::join_all(peers.iter().map(|&peer| {
+            let mut connections = DkgNetwork::new(peer, peers.clone(), net.connect(peer));
+            let old_cfg = old_configs
+                .iter()
+                .map(|(server_cfg, _)| server_cfg)
+                .find(|server_cfg| server_cfg.identity == peer);
+            let params = &params;
+            async move {
+                let rng = rand::rngs::OsRng::new().unwrap();
+                ServerConfig::reshare(&mut connections, old_cfg, old_client_cfg, params, rng)
+                    .await
+                    .expect("Resharing failed")
+            }
+        }))
+        .await;
+
+        let digest = configs[0].0.public_digest();
+        for (server_cfg, client_cfg) in &configs {
+            assert_eq!(server_cfg.public_digest(), digest);
+            assert_eq!(server_cfg.client_config().digest(), client_cfg.digest());
+            assert_eq!(client_cfg.mint.tbs_pks, old_client_cfg.mint.tbs_pks);
+            assert_eq!(
+                server_cfg.mint.tbs_sks.to_public(),
+                configs[0].0.mint.peer_tbs_pks[&server_cfg.identity]
+            );
+            assert_eq!(
+                server_cfg
+                    .wallet
+                    .legacy_peg_in
+                    .as_ref()
+                    .unwrap()
+                    .peg_in_descriptor,
+                old_configs[0].0.wallet.peg_in_descriptor
+            );
+        }
+        assert_ne!(digest, old_configs[0].0.public_digest());
+    }
+}
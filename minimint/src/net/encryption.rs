@@ -0,0 +1,552 @@
+use crate::config::NetworkConfig;
+use minimint_api::PeerId;
+use rand::{CryptoRng, RngCore};
+use serde::{Deserialize, Serialize};
+use snow::params::DHChoice;
+use snow::resolvers::{CryptoResolver, DefaultResolver};
+use snow::{HandshakeState, StatelessTransportState};
+use std::collections::BTreeMap;
+use std::fmt::{Debug, Display, Formatter};
+use std::str::FromStr;
+use std::sync::Arc;
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Noise protocol used for all connections between peers. Both sides transmit their static key
+/// during the handshake, so the accepting side learns which peer connected.
+const NOISE_PARAMS: &str = "Noise_XX_25519_ChaChaPoly_SHA256";
+
+/// Mixed into the handshake, so keys can't be used to authenticate to other protocols
+const PROLOGUE: &[u8] = b"minimint peer connection v1";
+
+/// Maximum length of a Noise message
+const MAX_MESSAGE_LEN: usize = 65535;
+
+/// Length of the authentication tag of every Noise message
+const TAG_LEN: usize = 16;
+
+/// Maximum number of plaintext bytes encrypted as a single Noise message
+const MAX_CHUNK_LEN: usize = MAX_MESSAGE_LEN - TAG_LEN;
+
+/// Length of the encrypted header preceding every frame, it contains the frame's length
+pub const FRAME_HEADER_LEN: usize = 8 + TAG_LEN;
+
+/// Static X25519 key identifying a guardian to its peers when connecting to them, see
+/// [`IdentityKeys::handshake`]. It is only used for the connections between peers.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct NetworkSecretKey([u8; 32]);
+
+/// Public key of a [`NetworkSecretKey`], encoded as hex when displayed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NetworkPublicKey(pub [u8; 32]);
+
+/// Long-term keys used to authenticate ourselves and our peers when establishing a connection.
+/// These are the keys listed in the [`NetworkConfig`].
+#[derive(Clone)]
+pub struct IdentityKeys {
+    identity: PeerId,
+    secret_key: NetworkSecretKey,
+    peer_keys: BTreeMap<PeerId, NetworkPublicKey>,
+}
+
+/// Keys of an authenticated session between two peers
+pub struct SessionKeys {
+    /// Encrypts the messages we send
+    pub send: FrameCipher,
+    /// Decrypts the messages we receive
+    pub receive: FrameCipher,
+}
+
+/// Encrypts and authenticates frames sent in one direction of a connection. Every Noise message
+/// uses the next nonce, so frames that are replayed, reordered or dropped by an attacker fail to
+/// decrypt.
+///
+/// A frame consists of a header containing the length of the plaintext, followed by the
+/// plaintext split into chunks that fit into a Noise message. Both are encrypted, so the length
+/// is authenticated before the receiver allocates memory for the frame.
+pub struct FrameCipher {
+    transport: Arc<StatelessTransportState>,
+    nonce: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum HandshakeError {
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Noise handshake failed: {0}")]
+    Noise(#[from] snow::Error),
+    #[error("Peer presented unknown key {0}")]
+    UnknownPeer(NetworkPublicKey),
+    #[error("Expected peer {expected}, but peer {actual} answered")]
+    UnexpectedPeer { expected: PeerId, actual: PeerId },
+}
+
+#[derive(Debug, Error)]
+#[error("Frame failed authentication")]
+pub struct FrameAuthenticationError;
+
+#[derive(Debug, Error)]
+#[error("Network public keys are 32 bytes encoded as hex")]
+pub struct InvalidNetworkKeyError;
+
+impl NetworkSecretKey {
+    pub fn generate(rng: &mut (impl RngCore + CryptoRng)) -> NetworkSecretKey {
+        // Every 32 byte string is a valid X25519 secret key
+        let mut key = [0u8; 32];
+        rng.fill_bytes(&mut key);
+        NetworkSecretKey(key)
+    }
+
+    pub fn public_key(&self) -> NetworkPublicKey {
+        let mut dh = DefaultResolver
+            .resolve_dh(&DHChoice::Curve25519)
+            .expect("X25519 is supported");
+        dh.set(&self.0);
+
+        let mut public_key = [0u8; 32];
+        public_key.copy_from_slice(dh.pubkey());
+        NetworkPublicKey(public_key)
+    }
+}
+
+impl Debug for NetworkSecretKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "NetworkSecretKey(<redacted>)")
+    }
+}
+
+impl Display for NetworkPublicKey {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", hex::encode(self.0))
+    }
+}
+
+impl FromStr for NetworkPublicKey {
+    type Err = InvalidNetworkKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut key = [0u8; 32];
+        hex::decode_to_slice(s, &mut key).map_err(|_| InvalidNetworkKeyError)?;
+        Ok(NetworkPublicKey(key))
+    }
+}
+
+impl IdentityKeys {
+    pub fn new(
+        identity: PeerId,
+        secret_key: NetworkSecretKey,
+        peer_keys: BTreeMap<PeerId, NetworkPublicKey>,
+    ) -> IdentityKeys {
+        IdentityKeys {
+            identity,
+            secret_key,
+            peer_keys,
+        }
+    }
+
+    pub fn from_config(cfg: &NetworkConfig) -> IdentityKeys {
+        IdentityKeys::new(
+            cfg.identity,
+            cfg.secret_key.clone(),
+            cfg.peers
+                .iter()
+                .map(|(id, peer)| (*id, peer.public_key))
+                .collect(),
+        )
+    }
+
+    pub fn identity(&self) -> PeerId {
+        self.identity
+    }
+
+    /// Runs a Noise XX handshake on a freshly established connection. Both sides prove that they
+    /// hold the static key of a peer listed in the config, the session keys are derived from
+    /// ephemeral keys, so past sessions stay confidential even if static keys are compromised
+    /// later.
+    ///
+    /// The side that dialed passes the peer it expected to reach as `expected_peer` and initiates
+    /// the handshake, connections to any other peer are rejected. Returns the authenticated id of
+    /// the remote peer and the session keys.
+    pub async fn handshake<S>(
+        &self,
+        stream: &mut S,
+        expected_peer: Option<PeerId>,
+    ) -> Result<(PeerId, SessionKeys), HandshakeError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let builder = snow::Builder::new(NOISE_PARAMS.parse().expect("Valid Noise parameters"))
+            .local_private_key(&self.secret_key.0)
+            .prologue(PROLOGUE);
+        let mut buffer = vec![0u8; MAX_MESSAGE_LEN];
+
+        let (peer, handshake) = match expected_peer {
+            Some(expected) => {
+                let mut handshake = builder.build_initiator()?;
+                // -> e
+                let len = handshake.write_message(&[], &mut buffer)?;
+                write_handshake_message(stream, &buffer[..len]).await?;
+                // <- e, ee, s, es
+                let msg = read_handshake_message(stream).await?;
+                handshake.read_message(&msg, &mut buffer)?;
+                let peer = self.remote_peer(&handshake)?;
+                if peer != expected {
+                    return Err(HandshakeError::UnexpectedPeer {
+                        expected,
+                        actual: peer,
+                    });
+                }
+                // -> s, se
+                let len = handshake.write_message(&[], &mut buffer)?;
+                write_handshake_message(stream, &buffer[..len]).await?;
+                (peer, handshake)
+            }
+            None => {
+                let mut handshake = builder.build_responder()?;
+                let msg = read_handshake_message(stream).await?;
+                handshake.read_message(&msg, &mut buffer)?;
+                let len = handshake.write_message(&[], &mut buffer)?;
+                write_handshake_message(stream, &buffer[..len]).await?;
+                let msg = read_handshake_message(stream).await?;
+                handshake.read_message(&msg, &mut buffer)?;
+                (self.remote_peer(&handshake)?, handshake)
+            }
+        };
+
+        let transport = Arc::new(handshake.into_stateless_transport_mode()?);
+        Ok((
+            peer,
+            SessionKeys {
+                send: FrameCipher::new(transport.clone()),
+                receive: FrameCipher::new(transport),
+            },
+        ))
+    }
+
+    /// Looks up the peer whose static key the remote side proved to hold
+    fn remote_peer(&self, handshake: &HandshakeState) -> Result<PeerId, HandshakeError> {
+        let mut remote_key = [0u8; 32];
+        remote_key.copy_from_slice(
+            handshake
+                .get_remote_static()
+                .expect("The XX pattern transmits the static key"),
+        );
+        let remote_key = NetworkPublicKey(remote_key);
+
+        self.peer_keys
+            .iter()
+            .find(|(id, key)| **key == remote_key && **id != self.identity)
+            .map(|(id, _)| *id)
+            .ok_or(HandshakeError::UnknownPeer(remote_key))
+    }
+}
+
+async fn write_handshake_message<S>(stream: &mut S, msg: &[u8]) -> Result<(), HandshakeError>
+where
+    S: AsyncWrite + Unpin,
+{
+    stream.write_u16(msg.len() as u16).await?;
+    stream.write_all(msg).await?;
+    Ok(())
+}
+
+async fn read_handshake_message<S>(stream: &mut S) -> Result<Vec<u8>, HandshakeError>
+where
+    S: AsyncRead + Unpin,
+{
+    let len = stream.read_u16().await?;
+    let mut msg = vec![0u8; len as usize];
+    stream.read_exact(&mut msg).await?;
+    Ok(msg)
+}
+
+impl FrameCipher {
+    fn new(transport: Arc<StatelessTransportState>) -> FrameCipher {
+        FrameCipher {
+            transport,
+            nonce: 0,
+        }
+    }
+
+    /// Length of the encrypted body of a frame with `plaintext_len` bytes of plaintext
+    pub fn ciphertext_len(plaintext_len: usize) -> usize {
+        let chunks = (plaintext_len + MAX_CHUNK_LEN - 1) / MAX_CHUNK_LEN;
+        plaintext_len + chunks * TAG_LEN
+    }
+
+    /// Encrypts `plaintext` as a frame: a header containing its length followed by the body
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Vec<u8> {
+        let mut frame = self.encrypt_header(plaintext.len() as u64).to_vec();
+        let mut body = vec![0u8; FrameCipher::ciphertext_len(plaintext.len())];
+        for (chunk, ciphertext) in plaintext
+            .chunks(MAX_CHUNK_LEN)
+            .zip(body.chunks_mut(MAX_CHUNK_LEN + TAG_LEN))
+        {
+            self.seal(chunk, ciphertext);
+        }
+        frame.append(&mut body);
+        frame
+    }
+
+    /// Encrypts the header announcing a frame with `plaintext_len` bytes of plaintext
+    pub fn encrypt_header(&mut self, plaintext_len: u64) -> [u8; FRAME_HEADER_LEN] {
+        let mut header = [0u8; FRAME_HEADER_LEN];
+        self.seal(&plaintext_len.to_be_bytes(), &mut header);
+        header
+    }
+
+    /// Checks the authentication tag of a frame's header and returns the length of the frame's
+    /// plaintext, see [`FrameCipher::ciphertext_len`] for the length of the body following it
+    pub fn decrypt_header(
+        &mut self,
+        header: &[u8; FRAME_HEADER_LEN],
+    ) -> Result<u64, FrameAuthenticationError> {
+        let mut plaintext_len = [0u8; 8];
+        self.open(header, &mut plaintext_len)?;
+        Ok(u64::from_be_bytes(plaintext_len))
+    }
+
+    /// Checks the authentication tags of a frame's body and decrypts it
+    pub fn decrypt_body(&mut self, body: &[u8]) -> Result<Vec<u8>, FrameAuthenticationError> {
+        let mut plaintext = Vec::with_capacity(body.len());
+        let mut chunk_plaintext = vec![0u8; MAX_CHUNK_LEN];
+        for chunk in body.chunks(MAX_CHUNK_LEN + TAG_LEN) {
+            let len = self.open(chunk, &mut chunk_plaintext)?;
+            plaintext.extend_from_slice(&chunk_plaintext[..len]);
+        }
+        Ok(plaintext)
+    }
+
+    fn seal(&mut self, plaintext: &[u8], ciphertext: &mut [u8]) {
+        self.transport
+            .write_message(self.nonce, plaintext, ciphertext)
+            .expect("Chunks fit into a Noise message");
+        self.nonce += 1;
+    }
+
+    fn open(
+        &mut self,
+        ciphertext: &[u8],
+        plaintext: &mut [u8],
+    ) -> Result<usize, FrameAuthenticationError> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(FrameAuthenticationError);
+        }
+        let len = self
+            .transport
+            .read_message(self.nonce, ciphertext, plaintext)
+            .map_err(|_| FrameAuthenticationError)?;
+        self.nonce += 1;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        FrameAuthenticationError, FrameCipher, HandshakeError, IdentityKeys, NetworkSecretKey,
+        SessionKeys, FRAME_HEADER_LEN, MAX_CHUNK_LEN,
+    };
+    use minimint_api::PeerId;
+    use rand::rngs::OsRng;
+    use std::collections::BTreeMap;
+    use std::convert::TryInto;
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+
+    /// Identity keys of `peers` peers that know each other, followed by the keys of an impostor
+    /// claiming to be the last of them
+    fn identities(peers: u16) -> Vec<IdentityKeys> {
+        let mut rng = OsRng::new().unwrap();
+        let secret_keys = (0..=peers)
+            .map(|_| NetworkSecretKey::generate(&mut rng))
+            .collect::<Vec<_>>();
+        let peer_keys = (0..peers)
+            .map(|id| (PeerId::from(id), secret_keys[id as usize].public_key()))
+            .collect::<BTreeMap<_, _>>();
+
+        secret_keys
+            .into_iter()
+            .enumerate()
+            .map(|(id, secret_key)| {
+                let identity = PeerId::from(std::cmp::min(id as u16, peers - 1));
+                IdentityKeys::new(identity, secret_key, peer_keys.clone())
+            })
+            .collect()
+    }
+
+    async fn handshake(
+        dialer: &IdentityKeys,
+        acceptor: &IdentityKeys,
+        expected_peer: PeerId,
+    ) -> (
+        Result<(PeerId, SessionKeys), HandshakeError>,
+        Result<(PeerId, SessionKeys), HandshakeError>,
+    ) {
+        let (mut dialer_stream, mut acceptor_stream) = duplex(1024);
+        handshake_over(
+            dialer,
+            &mut dialer_stream,
+            acceptor,
+            &mut acceptor_stream,
+            expected_peer,
+        )
+        .await
+    }
+
+    async fn handshake_over(
+        dialer: &IdentityKeys,
+        dialer_stream: &mut DuplexStream,
+        acceptor: &IdentityKeys,
+        acceptor_stream: &mut DuplexStream,
+        expected_peer: PeerId,
+    ) -> (
+        Result<(PeerId, SessionKeys), HandshakeError>,
+        Result<(PeerId, SessionKeys), HandshakeError>,
+    ) {
+        let dial = async {
+            let result = dialer.handshake(dialer_stream, Some(expected_peer)).await;
+            // Let the acceptor fail instead of waiting for further messages
+            dialer_stream.shutdown().await.unwrap();
+            result
+        };
+        let accept = async {
+            let result = acceptor.handshake(acceptor_stream, None).await;
+            acceptor_stream.shutdown().await.unwrap();
+            result
+        };
+        tokio::join!(dial, accept)
+    }
+
+    fn decrypt(
+        cipher: &mut FrameCipher,
+        frame: &[u8],
+    ) -> Result<Vec<u8>, FrameAuthenticationError> {
+        let (header, body) = frame.split_at(FRAME_HEADER_LEN);
+        let len = cipher.decrypt_header(header.try_into().unwrap())?;
+        assert_eq!(FrameCipher::ciphertext_len(len as usize), body.len());
+        cipher.decrypt_body(body)
+    }
+
+    #[tokio::test]
+    async fn test_handshake() {
+        let keys = identities(2);
+        let (dialed, accepted) = handshake(&keys[0], &keys[1], PeerId::from(1)).await;
+        let (peer, mut dialer) = dialed.unwrap();
+        assert_eq!(peer, PeerId::from(1));
+        let (peer, mut acceptor) = accepted.unwrap();
+        assert_eq!(peer, PeerId::from(0));
+
+        let first = dialer.send.encrypt(b"first message");
+        let second = dialer.send.encrypt(b"second message");
+        assert_eq!(
+            decrypt(&mut acceptor.receive, &first).unwrap(),
+            b"first message"
+        );
+        assert_eq!(
+            decrypt(&mut acceptor.receive, &second).unwrap(),
+            b"second message"
+        );
+
+        // Frames that don't fit into a single Noise message are split up
+        let large = vec![42u8; 2 * MAX_CHUNK_LEN + 1];
+        let frame = acceptor.send.encrypt(&large);
+        assert_eq!(decrypt(&mut dialer.receive, &frame).unwrap(), large);
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_peer() {
+        let keys = identities(3);
+        let (dialed, accepted) = handshake(&keys[0], &keys[1], PeerId::from(2)).await;
+        assert!(matches!(
+            dialed,
+            Err(HandshakeError::UnexpectedPeer { expected, actual })
+                if expected == PeerId::from(2) && actual == PeerId::from(1)
+        ));
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_unknown_key() {
+        // The impostor claims to be peer 1, but doesn't hold its key
+        let keys = identities(2);
+        let impostor = &keys[2];
+
+        let (_, accepted) = handshake(impostor, &keys[0], PeerId::from(0)).await;
+        assert!(
+            matches!(accepted, Err(HandshakeError::UnknownPeer(key)) if key == impostor.secret_key.public_key())
+        );
+
+        let (dialed, accepted) = handshake(&keys[0], impostor, PeerId::from(1)).await;
+        assert!(matches!(dialed, Err(HandshakeError::UnknownPeer(_))));
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_handshake() {
+        let keys = identities(2);
+
+        // The acceptor's static key is transmitted encrypted in its first message, after the
+        // length prefix and its ephemeral key
+        let (mut dialer_stream, mut relay_dialer) = duplex(1024);
+        let (mut relay_acceptor, mut acceptor_stream) = duplex(1024);
+        tokio::spawn(async move {
+            let mut msg = [0u8; 2 + 32];
+            relay_dialer.read_exact(&mut msg).await.unwrap();
+            relay_acceptor.write_all(&msg).await.unwrap();
+
+            let mut msg = vec![0u8; 2 + 32 + 48 + 16];
+            relay_acceptor.read_exact(&mut msg).await.unwrap();
+            msg[40] ^= 1;
+            relay_dialer.write_all(&msg).await.unwrap();
+        });
+
+        let (dialed, accepted) = handshake_over(
+            &keys[0],
+            &mut dialer_stream,
+            &keys[1],
+            &mut acceptor_stream,
+            PeerId::from(1),
+        )
+        .await;
+        assert!(matches!(dialed, Err(HandshakeError::Noise(_))));
+        assert!(accepted.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_tampered_frames() {
+        let keys = identities(2);
+        let (dialed, accepted) = handshake(&keys[0], &keys[1], PeerId::from(1)).await;
+        let (_, mut sender) = dialed.unwrap();
+        let (_, mut receiver) = accepted.unwrap();
+
+        let first = sender.send.encrypt(b"first message");
+        let second = sender.send.encrypt(b"second message");
+
+        // Frames can't be reordered and their length can't be changed
+        assert!(decrypt(&mut receiver.receive, &second).is_err());
+        let mut tampered_len = first.clone();
+        tampered_len[0] ^= 1;
+        assert!(decrypt(&mut receiver.receive, &tampered_len).is_err());
+
+        assert_eq!(
+            decrypt(&mut receiver.receive, &first).unwrap(),
+            b"first message"
+        );
+        assert_eq!(
+            decrypt(&mut receiver.receive, &second).unwrap(),
+            b"second message"
+        );
+
+        // Frames can't be replayed
+        assert!(decrypt(&mut receiver.receive, &first).is_err());
+
+        // Frames can't be tampered with
+        let mut tampered = sender.send.encrypt(b"third message");
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&mut receiver.receive, &tampered).is_err());
+
+        // Frames sent in the other direction use different keys
+        let reflected = receiver.send.encrypt(b"reflected");
+        assert!(decrypt(&mut receiver.receive, &reflected).is_err());
+    }
+}
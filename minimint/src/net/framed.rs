@@ -1,3 +1,4 @@
+use crate::net::encryption::{FrameAuthenticationError, FrameCipher, FRAME_HEADER_LEN};
 use futures::{AsyncRead, AsyncWrite};
 use futures::{Sink, Stream};
 use serde::de::DeserializeOwned;
@@ -7,30 +8,37 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 use tracing::{debug, trace};
 
+/// Maximum size of a serialized message. Larger frames are rejected before allocating memory for
+/// them, and messages exceeding it can't be sent.
+pub const MAX_FRAME_LEN: u64 = 64 * 1024 * 1024;
+
 // FIXME: look into using tokio for that, I  just didn't know it was a core component by now
+/// Messages sent over an encrypted connection in one direction, see [`FrameCipher`] for the
+/// format of the frames
 pub struct Framed<S, T> {
     stream: S,
+    cipher: FrameCipher,
     write_buffer: Vec<u8>,
-    read_len_len: usize,
-    read_len_buffer: [u8; 8],
-    read_len_actual: usize,
-    read_buffer: Vec<u8>,
+    read_header: [u8; FRAME_HEADER_LEN],
+    read_header_len: usize,
+    /// Encrypted body of the frame currently being read, allocated once its header was decrypted
+    read_buffer: Option<Vec<u8>>,
+    read_len: usize,
     _phantom: PhantomData<T>,
 }
 
-impl<S, T> Framed<S, T>
-where
-    S: AsyncRead + AsyncWrite + Unpin,
-    T: Serialize + DeserializeOwned + Unpin,
-{
-    pub fn new(stream: S) -> Self {
+impl<S, T> Framed<S, T> {
+    /// Creates a framed stream that is only used in one direction, encrypting all frames that are
+    /// sent or decrypting all frames that are received using `cipher`
+    pub fn new(stream: S, cipher: FrameCipher) -> Self {
         Framed {
             stream,
+            cipher,
             write_buffer: Vec::new(),
-            read_len_len: 0,
-            read_len_buffer: [0u8; 8],
-            read_len_actual: 0,
-            read_buffer: Vec::new(),
+            read_header: [0u8; FRAME_HEADER_LEN],
+            read_header_len: 0,
+            read_buffer: None,
+            read_len: 0,
             _phantom: PhantomData,
         }
     }
@@ -46,36 +54,37 @@ where
     fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
         let mut_self = self.get_mut();
 
-        if mut_self.write_buffer.is_empty() {
-            return Poll::Ready(Ok(()));
+        // We have to keep writing until the stream returns `Pending`, otherwise we wouldn't be
+        // woken up again
+        while !mut_self.write_buffer.is_empty() {
+            match Pin::new(&mut mut_self.stream).poll_write(cx, &mut_self.write_buffer) {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(FrameError::IOError(
+                        std::io::ErrorKind::WriteZero.into(),
+                    )))
+                }
+                Poll::Ready(Ok(len)) => {
+                    mut_self.write_buffer = mut_self.write_buffer[len..].to_vec()
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(FrameError::IOError(e))),
+                Poll::Pending => return Poll::Pending,
+            };
         }
 
-        match Pin::new(&mut mut_self.stream).poll_write(cx, &mut_self.write_buffer) {
-            Poll::Ready(Ok(len)) => mut_self.write_buffer = mut_self.write_buffer[len..].to_vec(),
-            Poll::Ready(Err(e)) => return Poll::Ready(Err(FrameError::IOError(e))),
-            Poll::Pending => return Poll::Pending,
-        };
-
-        if mut_self.write_buffer.is_empty() {
-            Poll::Ready(Ok(()))
-        } else {
-            Poll::Pending
-        }
+        Poll::Ready(Ok(()))
     }
 
     fn start_send(self: Pin<&mut Self>, item: &T) -> Result<(), Self::Error> {
-        match bincode::serialize(item) {
-            Ok(encoded) => {
-                let mut frame = Vec::with_capacity(encoded.len() + 8);
-                frame.extend_from_slice(&encoded.len().to_be_bytes());
-                frame.extend_from_slice(&encoded);
-                debug!("Sending  {} bytes", encoded.len());
-                trace!("Sending  {:x?}", encoded);
-                self.get_mut().write_buffer = frame;
-                Ok(())
-            }
-            Err(e) => Err(FrameError::CodingError(e)),
+        let encoded = bincode::serialize(item)?;
+        if encoded.len() as u64 > MAX_FRAME_LEN {
+            return Err(FrameError::TooLarge(encoded.len() as u64));
         }
+
+        debug!("Sending  {} bytes", encoded.len());
+        trace!("Sending  {:x?}", encoded);
+        let mut_self = self.get_mut();
+        mut_self.write_buffer = mut_self.cipher.encrypt(&encoded);
+        Ok(())
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
@@ -95,61 +104,91 @@ where
     type Item = Result<T, FrameError>;
 
     fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
-        let mut_self = self.get_mut();
+        let Framed {
+            stream,
+            cipher,
+            read_header,
+            read_header_len,
+            read_buffer,
+            read_len,
+            ..
+        } = self.get_mut();
 
-        if mut_self.read_len_len != 8 {
-            match Pin::new(&mut mut_self.stream)
-                .poll_read(cx, &mut mut_self.read_len_buffer[mut_self.read_len_len..])
-            {
-                Poll::Ready(Ok(len)) => {
-                    mut_self.read_len_len += len;
+        // We have to keep reading until the stream returns `Pending`, otherwise we wouldn't be
+        // woken up again
+        loop {
+            let body = match read_buffer.as_mut() {
+                Some(body) => body,
+                None if *read_header_len < FRAME_HEADER_LEN => {
+                    match Pin::new(&mut *stream).poll_read(cx, &mut read_header[*read_header_len..])
+                    {
+                        Poll::Ready(Ok(0)) if *read_header_len == 0 => return Poll::Ready(None),
+                        Poll::Ready(Ok(0)) => return Poll::Ready(Some(Err(unexpected_eof()))),
+                        Poll::Ready(Ok(len)) => {
+                            *read_header_len += len;
+                        }
+                        Poll::Ready(Err(e)) => {
+                            return Poll::Ready(Some(Err(FrameError::IOError(e))))
+                        }
+                        Poll::Pending => return Poll::Pending,
+                    }
+                    continue;
                 }
-                Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(FrameError::IOError(e)))),
-                Poll::Pending => return Poll::Pending,
-            }
-        }
+                None => {
+                    let plaintext_len = match cipher.decrypt_header(read_header) {
+                        Ok(len) => len,
+                        Err(e) => return Poll::Ready(Some(Err(e.into()))),
+                    };
+                    if plaintext_len > MAX_FRAME_LEN {
+                        return Poll::Ready(Some(Err(FrameError::TooLarge(plaintext_len))));
+                    }
 
-        if mut_self.read_len_len == 8 {
-            let exp_len = u64::from_be_bytes(mut_self.read_len_buffer) as usize;
-            if exp_len != mut_self.read_buffer.len() {
-                mut_self.read_buffer = vec![0; exp_len as usize];
-            }
+                    let body_len = FrameCipher::ciphertext_len(plaintext_len as usize);
+                    read_buffer.insert(vec![0; body_len])
+                }
+            };
 
-            if exp_len > mut_self.read_len_actual {
-                match Pin::new(&mut mut_self.stream)
-                    .poll_read(cx, &mut mut_self.read_buffer[mut_self.read_len_actual..])
-                {
+            if *read_len < body.len() {
+                match Pin::new(&mut *stream).poll_read(cx, &mut body[*read_len..]) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Some(Err(unexpected_eof()))),
                     Poll::Ready(Ok(len)) => {
-                        mut_self.read_len_actual += len;
+                        *read_len += len;
                     }
                     Poll::Ready(Err(e)) => return Poll::Ready(Some(Err(FrameError::IOError(e)))),
                     Poll::Pending => return Poll::Pending,
                 }
+                continue;
             }
 
-            if exp_len == mut_self.read_len_actual {
-                debug!("Received {} bytes", exp_len);
-                trace!("Received {:x?}", mut_self.read_buffer);
-                let res = match bincode::deserialize(&mut_self.read_buffer) {
-                    Ok(decoded) => Ok(decoded),
-                    Err(e) => Err(FrameError::CodingError(e)),
-                };
-
-                mut_self.read_len_len = 0;
-                mut_self.read_len_actual = 0;
+            let body = read_buffer.take().expect("checked above");
+            *read_header_len = 0;
+            *read_len = 0;
 
-                return Poll::Ready(Some(res));
-            }
+            debug!("Received {} bytes", body.len());
+            trace!("Received {:x?}", body);
+            let res = cipher
+                .decrypt_body(&body)
+                .map_err(FrameError::from)
+                .and_then(|plaintext| Ok(bincode::deserialize(&plaintext)?));
+            return Poll::Ready(Some(res));
         }
-
-        Poll::Pending
     }
 }
 
+fn unexpected_eof() -> FrameError {
+    FrameError::IOError(std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "Stream closed in the middle of a frame",
+    ))
+}
+
 #[derive(Debug)]
 pub enum FrameError {
     CodingError(bincode::Error),
     IOError(std::io::Error),
+    AuthenticationError(FrameAuthenticationError),
+    /// The frame's length exceeds [`MAX_FRAME_LEN`]
+    TooLarge(u64),
 }
 
 impl From<bincode::Error> for FrameError {
@@ -158,8 +197,72 @@ impl From<bincode::Error> for FrameError {
     }
 }
 
+impl From<FrameAuthenticationError> for FrameError {
+    fn from(e: FrameAuthenticationError) -> Self {
+        FrameError::AuthenticationError(e)
+    }
+}
+
 impl From<std::io::Error> for FrameError {
     fn from(e: std::io::Error) -> Self {
         FrameError::IOError(e)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{FrameError, Framed, MAX_FRAME_LEN};
+    use crate::testing::session_keys;
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{duplex, AsyncWriteExt};
+    use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
+
+    #[tokio::test]
+    async fn test_send_and_receive() {
+        let (dialer, acceptor) = session_keys().await;
+        let (writer, reader) = duplex(1024);
+        let mut sender = Framed::<_, Vec<u8>>::new(writer.compat_write(), dialer.send);
+        let mut receiver = Framed::<_, Vec<u8>>::new(reader.compat(), acceptor.receive);
+
+        // The frame doesn't fit into the stream's buffer, so it is sent while being received
+        let msg = vec![42u8; 200_000];
+        let (sent, received) = tokio::join!(sender.send(&msg), receiver.next());
+        sent.unwrap();
+        assert_eq!(received.unwrap().unwrap(), msg);
+
+        drop(sender);
+        assert!(receiver.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frame() {
+        let (mut dialer, acceptor) = session_keys().await;
+        let (mut writer, reader) = duplex(1024);
+        let mut receiver = Framed::<_, u64>::new(reader.compat(), acceptor.receive);
+
+        // The frame is rejected based on its header, the body is never read
+        writer
+            .write_all(&dialer.send.encrypt_header(MAX_FRAME_LEN + 1))
+            .await
+            .unwrap();
+        assert!(matches!(
+            receiver.next().await,
+            Some(Err(FrameError::TooLarge(len))) if len == MAX_FRAME_LEN + 1
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_tampered_length() {
+        let (mut dialer, acceptor) = session_keys().await;
+        let (mut writer, reader) = duplex(1024);
+        let mut receiver = Framed::<_, u64>::new(reader.compat(), acceptor.receive);
+
+        let mut frame = dialer.send.encrypt(&bincode::serialize(&42u64).unwrap());
+        frame[0] ^= 1;
+        writer.write_all(&frame).await.unwrap();
+        assert!(matches!(
+            receiver.next().await,
+            Some(Err(FrameError::AuthenticationError(_)))
+        ));
+    }
+}
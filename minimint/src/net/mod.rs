@@ -1,11 +1,29 @@
+use crate::consensus::{EpochHistory, HoneyBadgerMessage};
 use async_trait::async_trait;
 use hbbft::{NodeIdT, Target};
 use serde::de::DeserializeOwned;
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 
 pub mod api;
+pub mod catchup;
 pub mod connect;
+pub mod dkg;
+pub mod encryption;
 pub mod framed;
+pub mod sim;
+
+/// Messages exchanged between the guardians' consensus processes
+#[derive(Debug, Serialize, Deserialize)]
+pub enum PeerMessage {
+    /// HoneyBadger message belonging to the currently running epoch
+    Consensus(HoneyBadgerMessage),
+    /// Request for the outcomes of all epochs starting at the given one, sent by a guardian that
+    /// fell behind
+    EpochRequest(u64),
+    /// Outcomes of consecutive epochs starting at the requested one, might be empty if the peer
+    /// doesn't know them either
+    EpochResponse(Vec<EpochHistory>),
+}
 
 #[async_trait]
 pub trait PeerConnections<T>
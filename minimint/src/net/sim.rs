@@ -0,0 +1,432 @@
+use crate::net::PeerConnections;
+use async_trait::async_trait;
+use hbbft::Target;
+use minimint_api::PeerId;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, HashMap};
+use std::marker::PhantomData;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use tracing::trace;
+
+/// Describes how the simulated network treats messages sent over it
+#[derive(Debug, Clone)]
+pub struct SimNetworkConfig {
+    /// Minimum time it takes for a message to be delivered
+    pub min_latency: Duration,
+    /// Maximum time it takes for a message to be delivered, the actual latency of each message is
+    /// chosen uniformly at random between `min_latency` and `max_latency`, so messages sent later
+    /// may overtake earlier ones
+    pub max_latency: Duration,
+    /// Probability with which any single message is lost
+    pub drop_probability: f64,
+    /// Seed for the random number generator deciding on latency and message loss
+    pub seed: u64,
+}
+
+/// In-process network connecting any number of peers via channels. It allows running several
+/// guardians in a single tokio runtime for testing purposes without opening any sockets.
+///
+/// Messages are serialized like on a real network connection, so they can't share state.
+///
+/// Delivery is deterministic for a given seed and order of sends: every message is scheduled for
+/// a point in tokio's clock when it is sent and receivers take messages in the order of these
+/// points, ties are broken by the order of sending. Tests should pause tokio's clock
+/// (`#[tokio::test(start_paused = true)]`) so that latency doesn't depend on the machine's load.
+#[derive(Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<SimNetworkState>>,
+}
+
+struct SimNetworkState {
+    cfg: SimNetworkConfig,
+    rng: StdRng,
+    peers: HashMap<PeerId, Arc<Inbox>>,
+    /// Number of messages sent so far, orders messages scheduled for the same point in time
+    sent: u64,
+    /// Partition each peer belongs to, peers in different partitions can't reach each other
+    partitions: HashMap<PeerId, usize>,
+}
+
+/// Connection of a single peer to the [`SimNetwork`]
+pub struct SimConnections<T> {
+    identity: PeerId,
+    state: Arc<Mutex<SimNetworkState>>,
+    inbox: Arc<Inbox>,
+    _pd: PhantomData<fn() -> T>,
+}
+
+/// Messages on their way to a single peer
+#[derive(Default)]
+struct Inbox {
+    in_flight: Mutex<BinaryHeap<Reverse<InFlight>>>,
+    /// Notified whenever a message is added to `in_flight`
+    sent: Notify,
+}
+
+struct InFlight {
+    deliver_at: Instant,
+    seq: u64,
+    from: PeerId,
+    msg: Vec<u8>,
+}
+
+impl Default for SimNetworkConfig {
+    fn default() -> Self {
+        SimNetworkConfig {
+            min_latency: Duration::from_millis(0),
+            max_latency: Duration::from_millis(0),
+            drop_probability: 0.0,
+            seed: 0,
+        }
+    }
+}
+
+impl SimNetwork {
+    pub fn new(cfg: SimNetworkConfig) -> SimNetwork {
+        SimNetwork {
+            state: Arc::new(Mutex::new(SimNetworkState {
+                rng: StdRng::seed_from_u64(cfg.seed),
+                cfg,
+                peers: HashMap::new(),
+                sent: 0,
+                partitions: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Adds a peer with the given `identity` to the network and returns its connections
+    pub fn connect<T>(&self, identity: PeerId) -> SimConnections<T> {
+        let inbox = Arc::new(Inbox::default());
+        let mut state = self.state.lock().unwrap();
+        assert!(
+            state.peers.insert(identity, inbox.clone()).is_none(),
+            "Peer {} connected twice",
+            identity
+        );
+
+        SimConnections {
+            identity,
+            state: self.state.clone(),
+            inbox,
+            _pd: PhantomData,
+        }
+    }
+
+    /// Replaces the latency and message loss settings, the random number generator is not reseeded
+    pub fn set_config(&self, cfg: SimNetworkConfig) {
+        self.state.lock().unwrap().cfg = cfg;
+    }
+
+    /// Splits the network into the given groups of peers. Messages between peers of different
+    /// groups are dropped. Peers not mentioned in any group form a group of their own.
+    pub fn partition(&self, groups: &[&[PeerId]]) {
+        let mut state = self.state.lock().unwrap();
+        state.partitions = groups
+            .iter()
+            .enumerate()
+            .flat_map(|(partition, peers)| peers.iter().map(move |peer| (*peer, partition)))
+            .collect();
+    }
+
+    /// Removes all partitions so every peer can reach every other peer again
+    pub fn heal(&self) {
+        self.state.lock().unwrap().partitions.clear();
+    }
+}
+
+impl SimNetworkState {
+    fn can_reach(&self, from: PeerId, to: PeerId) -> bool {
+        self.partitions.is_empty() || self.partitions.get(&from) == self.partitions.get(&to)
+    }
+
+    fn deliver(&mut self, from: PeerId, to: PeerId, msg: Vec<u8>) {
+        if !self.can_reach(from, to) {
+            trace!("Dropping message from {} to {}: partitioned", from, to);
+            return;
+        }
+
+        if self.cfg.drop_probability > 0.0 && self.rng.gen_bool(self.cfg.drop_probability) {
+            trace!("Dropping message from {} to {}: lost", from, to);
+            return;
+        }
+
+        let inbox = match self.peers.get(&to) {
+            Some(inbox) => inbox.clone(),
+            None => {
+                trace!("Dropping message from {} to {}: unknown peer", from, to);
+                return;
+            }
+        };
+
+        let latency = if self.cfg.max_latency > self.cfg.min_latency {
+            self.rng
+                .gen_range(self.cfg.min_latency, self.cfg.max_latency)
+        } else {
+            self.cfg.min_latency
+        };
+
+        let seq = self.sent;
+        self.sent += 1;
+        inbox.in_flight.lock().unwrap().push(Reverse(InFlight {
+            deliver_at: Instant::now() + latency,
+            seq,
+            from,
+            msg,
+        }));
+        inbox.sent.notify_one();
+    }
+}
+
+impl Inbox {
+    /// Waits for the next message to arrive and returns it together with its sender
+    async fn receive(&self) -> (PeerId, Vec<u8>) {
+        loop {
+            // A notification sent after the lock is released is stored until we wait for it
+            let next_arrival = {
+                let mut in_flight = self.in_flight.lock().unwrap();
+                match in_flight.peek() {
+                    Some(Reverse(next)) if next.deliver_at <= Instant::now() => {
+                        let Reverse(next) = in_flight.pop().expect("peeked above");
+                        return (next.from, next.msg);
+                    }
+                    Some(Reverse(next)) => Some(next.deliver_at),
+                    None => None,
+                }
+            };
+
+            match next_arrival {
+                Some(deliver_at) => {
+                    tokio::select! {
+                        _ = tokio::time::sleep_until(deliver_at) => {},
+                        _ = self.sent.notified() => {},
+                    }
+                }
+                None => self.sent.notified().await,
+            }
+        }
+    }
+}
+
+impl Ord for InFlight {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.deliver_at, self.seq).cmp(&(other.deliver_at, other.seq))
+    }
+}
+
+impl PartialOrd for InFlight {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl PartialEq for InFlight {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for InFlight {}
+
+#[async_trait]
+impl<T> PeerConnections<T> for SimConnections<T>
+where
+    T: Serialize + DeserializeOwned + Unpin + Send + Sync + 'static,
+{
+    type Id = PeerId;
+
+    async fn send(&mut self, target: Target<Self::Id>, msg: T) {
+        trace!("Sending message to {:?}", target);
+        let msg = bincode::serialize(&msg).expect("Failed to serialize message");
+
+        let mut state = self.state.lock().unwrap();
+        let recipients = match target {
+            Target::All => state
+                .peers
+                .keys()
+                .copied()
+                .filter(|peer| *peer != self.identity)
+                .collect::<Vec<_>>(),
+            Target::Node(peer) => vec![peer],
+        };
+
+        for peer in recipients {
+            state.deliver(self.identity, peer, msg.clone());
+        }
+    }
+
+    async fn receive(&mut self) -> (Self::Id, T) {
+        let (peer, msg) = self.inbox.receive().await;
+        let msg = bincode::deserialize(&msg).expect("Failed to deserialize message");
+        (peer, msg)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SimNetwork, SimNetworkConfig};
+    use crate::net::{PeerConnections, PeerMessage};
+    use crate::testing::{federation, federation_configs};
+    use hbbft::Target;
+    use minimint_api::{Amount, PeerId};
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::task::LocalSet;
+
+    #[tokio::test(start_paused = true)]
+    async fn test_broadcast_and_partition() {
+        let net = SimNetwork::new(SimNetworkConfig {
+            min_latency: Duration::from_millis(1),
+            max_latency: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let mut peers = (0..3u16)
+            .map(|id| net.connect::<u64>(PeerId::from(id)))
+            .collect::<Vec<_>>();
+
+        peers[0].send(Target::All, 42).await;
+        assert_eq!(peers[1].receive().await, (PeerId::from(0), 42));
+        assert_eq!(peers[2].receive().await, (PeerId::from(0), 42));
+
+        net.partition(&[&[PeerId::from(0), PeerId::from(1)], &[PeerId::from(2)]]);
+        peers[2].send(Target::All, 1).await;
+        peers[0].send(Target::Node(PeerId::from(2)), 2).await;
+        peers[0].send(Target::Node(PeerId::from(1)), 3).await;
+        assert_eq!(peers[1].receive().await, (PeerId::from(0), 3));
+
+        net.heal();
+        peers[2].send(Target::Node(PeerId::from(0)), 4).await;
+        assert_eq!(peers[0].receive().await, (PeerId::from(2), 4));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_message_loss() {
+        let net = SimNetwork::new(SimNetworkConfig {
+            drop_probability: 1.0,
+            ..Default::default()
+        });
+        let mut sender = net.connect::<u64>(PeerId::from(0));
+        let mut receiver = net.connect::<u64>(PeerId::from(1));
+
+        sender.send(Target::All, 1).await;
+        net.set_config(SimNetworkConfig::default());
+        sender.send(Target::All, 2).await;
+        assert_eq!(receiver.receive().await, (PeerId::from(0), 2));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_deterministic_delivery() {
+        async fn arrival_order(seed: u64) -> Vec<u64> {
+            let net = SimNetwork::new(SimNetworkConfig {
+                min_latency: Duration::from_millis(1),
+                max_latency: Duration::from_millis(100),
+                seed,
+                ..Default::default()
+            });
+            let mut sender = net.connect::<u64>(PeerId::from(0));
+            let mut receiver = net.connect::<u64>(PeerId::from(1));
+
+            for msg in 0..20 {
+                sender.send(Target::All, msg).await;
+            }
+            let mut order = vec![];
+            for _ in 0..20 {
+                order.push(receiver.receive().await.1);
+            }
+            order
+        }
+
+        let order = arrival_order(1).await;
+        assert_eq!(order, arrival_order(1).await);
+        // Latency varies, so later messages overtake earlier ones
+        assert_ne!(order, (0..20).collect::<Vec<_>>());
+
+        // With a fixed latency messages arrive in the order they were sent
+        let net = SimNetwork::new(SimNetworkConfig {
+            min_latency: Duration::from_millis(10),
+            max_latency: Duration::from_millis(10),
+            ..Default::default()
+        });
+        let mut sender = net.connect::<u64>(PeerId::from(0));
+        let mut receiver = net.connect::<u64>(PeerId::from(1));
+        for msg in 0..20 {
+            sender.send(Target::All, msg).await;
+        }
+        for msg in 0..20 {
+            assert_eq!(receiver.receive().await, (PeerId::from(0), msg));
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_guardians_agree() {
+        const EPOCHS: u64 = 4;
+
+        let net = SimNetwork::new(SimNetworkConfig {
+            min_latency: Duration::from_millis(5),
+            max_latency: Duration::from_millis(200),
+            seed: 42,
+            ..Default::default()
+        });
+        let guardians = federation(&federation_configs(4))
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+
+        // Give the guardians something to agree on besides empty epochs
+        let mut fees = guardians[0].fee_schedule().active;
+        fees.fee_peg_in_abs = Amount::from_sat(1000);
+        for guardian in guardians.iter() {
+            guardian.propose_fees(fees.clone()).unwrap();
+        }
+
+        // Processing epochs doesn't need to be `Send`, so the guardians run on the test's thread
+        let local = LocalSet::new();
+        local
+            .run_until(async {
+                for guardian in guardians.iter() {
+                    let connections = net.connect::<PeerMessage>(guardian.cfg.identity);
+                    tokio::task::spawn_local(crate::run_consensus(guardian.clone(), connections));
+                }
+
+                let all_done = async {
+                    while !guardians
+                        .iter()
+                        .all(|guardian| guardian.last_processed_epoch() >= Some(EPOCHS))
+                    {
+                        tokio::time::sleep(Duration::from_millis(100)).await;
+                    }
+                };
+                tokio::time::timeout(Duration::from_secs(600), all_done)
+                    .await
+                    .expect("Consensus stalled");
+            })
+            .await;
+
+        for epoch in 0..=EPOCHS {
+            let header = guardians[0]
+                .epoch_header(epoch)
+                .expect("Epoch was processed");
+            let state_digest = guardians[0].state_digest(epoch);
+            assert_eq!(Some(header.0.state_commitment), state_digest);
+            for guardian in guardians.iter().skip(1) {
+                assert_eq!(guardian.epoch_header(epoch).as_ref(), Some(&header));
+                assert_eq!(guardian.state_digest(epoch), state_digest);
+            }
+        }
+        for guardian in guardians.iter() {
+            assert_eq!(
+                guardian
+                    .fee_schedule()
+                    .scheduled
+                    .map(|scheduled| scheduled.fees),
+                Some(fees.clone())
+            );
+        }
+    }
+}
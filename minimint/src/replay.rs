@@ -0,0 +1,271 @@
+use crate::config::ServerConfig;
+use crate::consensus::{EpochHistory, FediMintConsensus};
+use crate::db::{
+    EpochHistoryKey, DB_PREFIX_EVICTED_TRANSACTION, DB_PREFIX_PROPOSED_FEES,
+    DB_PREFIX_PROPOSED_TRANSACTION,
+};
+use crate::CloneRngGen;
+use minimint_api::db::buffered::BufferedDatabase;
+use minimint_api::db::mem_impl::MemDatabase;
+use minimint_api::db::{Database, RawDatabase};
+use minimint_api::module::ModuleRegistry;
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use thiserror::Error;
+use tracing::{debug, info};
+
+/// Key prefixes of data that is local to a guardian (e.g. submitted but not yet agreed upon
+/// transactions, the ones it evicted from its mempool or the fees proposed by its admin) and thus
+/// not reproduced by replaying the consensus history.
+const LOCAL_DB_PREFIXES: &[u8] = &[
+    DB_PREFIX_PROPOSED_TRANSACTION,
+    DB_PREFIX_EVICTED_TRANSACTION,
+    DB_PREFIX_PROPOSED_FEES,
+];
+
+/// Difference between the live and the replayed database for a single key
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum StateMismatch {
+    /// The key only exists in the live database
+    MissingInReplay(Vec<u8>),
+    /// The key only exists in the replayed database
+    MissingInLive(Vec<u8>),
+    /// The key exists in both databases but with different values
+    ValueMismatch(Vec<u8>),
+}
+
+/// Error that aborts a replay
+#[derive(Debug, Error)]
+pub enum ReplayError {
+    #[error("No config given for the first epoch")]
+    NoConfig,
+    #[error("Config {} that took effect in epoch {1} is missing", hex::encode(.0))]
+    MissingConfig([u8; 32], u64),
+}
+
+/// Rebuilds the state from the consensus history persisted in `live_db` into a fresh in-memory
+/// database using the default modules and compares the result to `live_db`. Returns the number of
+/// replayed epochs and all differences found.
+///
+/// `configs` have to contain all configs the guardian ran with, starting with the one it was
+/// created with, see [`replay`]. The live database must not be modified while replaying, i.e.
+/// the server has to be stopped.
+pub async fn replay_and_verify(
+    configs: &[ServerConfig],
+    live_db: Arc<dyn RawDatabase>,
+) -> Result<(u64, Vec<StateMismatch>), ReplayError> {
+    let replay_db = Arc::new(BufferedDatabase::new(Arc::new(MemDatabase::new())));
+
+    let epochs = replay(
+        configs,
+        live_db.as_ref(),
+        replay_db.clone(),
+        |cfg, db| async move { crate::default_modules(&cfg, db).await },
+    )
+    .await?;
+    let mismatches = compare_state(live_db.as_ref(), replay_db.as_ref());
+
+    Ok((epochs, mismatches))
+}
+
+/// Processes all epochs persisted in `history_db`, starting at epoch 0, using modules built by
+/// `build_modules` that operate on `target_db`. Returns the number of replayed epochs.
+///
+/// Each epoch is processed with the config that was active in it: replaying starts with the first
+/// of `configs` and switches to the config a replayed
+/// [`crate::consensus::ScheduledConfigChange`] refers to once it
+/// takes effect, so epochs before a reshare or key rotation are validated against the old keys.
+pub async fn replay<F, Fut>(
+    configs: &[ServerConfig],
+    history_db: &dyn RawDatabase,
+    target_db: Arc<BufferedDatabase>,
+    build_modules: F,
+) -> Result<u64, ReplayError>
+where
+    F: Fn(ServerConfig, Arc<BufferedDatabase>) -> Fut,
+    Fut: Future<Output = ModuleRegistry>,
+{
+    let consensus_with = |cfg: ServerConfig, modules| FediMintConsensus {
+        rng_gen: Box::new(CloneRngGen(Mutex::new(rand::rngs::OsRng::new().unwrap()))), //FIXME
+        cfg,
+        modules,
+        db: target_db.clone(),
+        next_cfg_digest: None,
+        hbbft_epoch: Default::default(),
+        metrics: Default::default(),
+        transaction_updates: Default::default(),
+        mempool_lock: Default::default(),
+    };
+
+    let cfg = configs.first().ok_or(ReplayError::NoConfig)?.clone();
+    let modules = build_modules(cfg.clone(), target_db.clone()).await;
+    let mut consensus = consensus_with(cfg, modules);
+    // The replay counts every coin from the first epoch on, like a guardian started on an empty
+    // database
+    consensus.backfill_mint_audit();
+
+    let mut epoch = 0;
+    while let Some(history) = history_db
+        .get_value::<_, EpochHistory>(&EpochHistoryKey(epoch))
+        .expect("DB error")
+    {
+        if let Some(change) = consensus.due_config_change(epoch) {
+            let cfg = configs
+                .iter()
+                .find(|cfg| cfg.public_digest() == change.digest)
+                .ok_or(ReplayError::MissingConfig(change.digest, epoch))?
+                .clone();
+            info!(
+                "Switching to config {} in epoch {}",
+                hex::encode(change.digest),
+                epoch
+            );
+            let modules = build_modules(cfg.clone(), target_db.clone()).await;
+            consensus = consensus_with(cfg, modules);
+        }
+
+        debug!("Replaying epoch {}", epoch);
+        consensus.process_consensus_outcome(history.into()).await;
+        epoch += 1;
+    }
+
+    info!("Replayed {} epochs", epoch);
+    Ok(epoch)
+}
+
+/// Compares all consensus relevant data in both databases
+pub fn compare_state(live_db: &dyn RawDatabase, replay_db: &dyn RawDatabase) -> Vec<StateMismatch> {
+    let load_state = |db: &dyn RawDatabase| {
+        db.raw_find_by_prefix(vec![])
+            .map(|res| res.expect("DB error"))
+            .filter(|(key, _)| {
+                key.first()
+                    .map_or(false, |prefix| !LOCAL_DB_PREFIXES.contains(prefix))
+            })
+            .collect::<BTreeMap<_, _>>()
+    };
+    let live_state = load_state(live_db);
+    let replay_state = load_state(replay_db);
+
+    let mut mismatches = live_state
+        .iter()
+        .filter_map(|(key, value)| match replay_state.get(key) {
+            None => Some(StateMismatch::MissingInReplay(key.clone())),
+            Some(replay_value) if replay_value != value => {
+                Some(StateMismatch::ValueMismatch(key.clone()))
+            }
+            Some(_) => None,
+        })
+        .collect::<Vec<_>>();
+    mismatches.extend(
+        replay_state
+            .keys()
+            .filter(|key| !live_state.contains_key(*key))
+            .map(|key| StateMismatch::MissingInLive(key.clone())),
+    );
+
+    mismatches
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::replay::{compare_state, replay, ReplayError};
+    use crate::testing::{
+        federation, federation_configs, guardian, mem_db, mint_modules, run_epoch,
+    };
+    use minimint_api::{Amount, PeerId};
+
+    #[tokio::test]
+    async fn test_replay_across_config_change() {
+        let old_configs = federation_configs(4);
+        let new_configs = federation_configs(4);
+        let new_digest = new_configs[&PeerId::from(0)].public_digest();
+
+        let mut guardians = federation(&old_configs);
+        for _ in 0..3 {
+            run_epoch(&guardians).await;
+        }
+
+        for guardian in guardians.iter_mut() {
+            guardian.next_cfg_digest = Some(new_digest);
+        }
+        let activation_epoch = loop {
+            run_epoch(&guardians).await;
+            let next_epoch = guardians[0].last_processed_epoch().unwrap() + 1;
+            if let Some(change) = guardians[0].due_config_change(next_epoch) {
+                break change.activation_epoch;
+            }
+        };
+
+        // Restart all guardians with the new keys, the epoch signatures are now checked against
+        // the new key set
+        let mut guardians = guardians
+            .into_iter()
+            .map(|consensus| guardian(new_configs[&consensus.cfg.identity].clone(), consensus.db))
+            .collect::<Vec<_>>();
+        for _ in 0..3 {
+            run_epoch(&guardians).await;
+        }
+        let live = guardians.remove(0);
+        let configs = [
+            old_configs[&PeerId::from(0)].clone(),
+            new_configs[&PeerId::from(0)].clone(),
+        ];
+
+        let replay_db = mem_db();
+        let epochs = replay(
+            &configs,
+            live.db.as_ref(),
+            replay_db.clone(),
+            |cfg, db| async move { mint_modules(&cfg, db) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(Some(epochs - 1), live.last_processed_epoch());
+        assert_eq!(compare_state(live.db.as_ref(), replay_db.as_ref()), vec![]);
+
+        let result = replay(
+            &configs[..1],
+            live.db.as_ref(),
+            mem_db(),
+            |cfg, db| async move { mint_modules(&cfg, db) },
+        )
+        .await;
+        assert!(matches!(
+            result,
+            Err(ReplayError::MissingConfig(digest, epoch))
+                if digest == new_digest && epoch == activation_epoch
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_replay_after_fee_proposal() {
+        let configs = federation_configs(4);
+        let guardians = federation(&configs);
+
+        let mut fees = guardians[0].fee_schedule().active;
+        fees.fee_peg_in_abs = Amount::from_sat(1000);
+        for guardian in guardians.iter() {
+            guardian.propose_fees(fees.clone()).unwrap();
+        }
+        while guardians[0].fee_schedule().scheduled.is_none() {
+            run_epoch(&guardians).await;
+        }
+        run_epoch(&guardians).await;
+
+        let replay_db = mem_db();
+        replay(
+            &[configs[&PeerId::from(0)].clone()],
+            guardians[0].db.as_ref(),
+            replay_db.clone(),
+            |cfg, db| async move { mint_modules(&cfg, db) },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            compare_state(guardians[0].db.as_ref(), replay_db.as_ref()),
+            vec![]
+        );
+    }
+}
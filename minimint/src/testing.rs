@@ -0,0 +1,215 @@
+//! Helpers to run the consensus of a whole federation in tests without networking
+
+use crate::config::{ServerConfig, ServerConfigParams};
+use crate::consensus::{ConsensusOutcome, FediMintConsensus};
+use crate::net::encryption::{IdentityKeys, NetworkSecretKey, SessionKeys};
+use crate::CloneRngGen;
+use bitcoin::hashes::Hash as BitcoinHash;
+use bitcoin::util::merkleblock::PartialMerkleTree;
+use bitcoin::{BlockHeader, TxIn, TxMerkleNode, TxOut};
+use hbbft::honey_badger::Batch;
+use minimint_api::config::GenerateConfig;
+use minimint_api::db::buffered::BufferedDatabase;
+use minimint_api::db::mem_impl::MemDatabase;
+use minimint_api::encoding::Decodable;
+use minimint_api::module::{ModuleItem, ModuleRegistry, MINT_MODULE_ID, WALLET_MODULE_ID};
+use minimint_api::transaction::{Input, Transaction};
+use minimint_api::{Amount, Coin, CoinNonce, Coins, KeySetId, PeerId, PegInProof, TxOutProof};
+use minimint_wallet::backend::FakeBitcoinBackend;
+use minimint_wallet::Wallet;
+use rand::rngs::OsRng;
+use secp256k1_zkp::schnorrsig;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+/// Generates the configs of a federation of `peers` guardians
+pub fn federation_configs(peers: u16) -> BTreeMap<PeerId, ServerConfig> {
+    let peers = (0..peers).map(PeerId::from).collect::<Vec<_>>();
+    let max_evil = (peers.len() - 1) / 3;
+    let params = ServerConfigParams {
+        hbbft_base_port: 17240,
+        api_base_port: 17340,
+        peer_hosts: BTreeMap::new(),
+        bind_host: "127.0.0.1".into(),
+        amount_tiers: vec![Amount::from_msat(1), Amount::from_msat(10)],
+    };
+
+    ServerConfig::trusted_dealer_gen(&peers, max_evil, &params, OsRng::new().unwrap()).0
+}
+
+/// Consensus of a guardian that only runs the mint module, the wallet is left out since it needs
+/// a bitcoin backend. Like on startup, the mint's audit is backfilled.
+pub fn guardian(cfg: ServerConfig, db: Arc<BufferedDatabase>) -> FediMintConsensus<OsRng> {
+    let modules = mint_modules(&cfg, db.clone());
+    guardian_with_modules(cfg, db, modules)
+}
+
+/// Like [`guardian`], but runs `modules`, which have to operate on `db`
+pub fn guardian_with_modules(
+    cfg: ServerConfig,
+    db: Arc<BufferedDatabase>,
+    modules: ModuleRegistry,
+) -> FediMintConsensus<OsRng> {
+    let guardian = FediMintConsensus {
+        rng_gen: Box::new(CloneRngGen(Mutex::new(OsRng::new().unwrap()))),
+        cfg,
+        modules,
+        db,
+        next_cfg_digest: None,
+        hbbft_epoch: Default::default(),
+        metrics: Default::default(),
+        transaction_updates: Default::default(),
+        mempool_lock: Default::default(),
+    };
+    guardian.backfill_mint_audit();
+    guardian
+}
+
+/// Consensus of one guardian per config, each with its own in-memory database
+pub fn federation(configs: &BTreeMap<PeerId, ServerConfig>) -> Vec<FediMintConsensus<OsRng>> {
+    configs
+        .values()
+        .map(|cfg| guardian(cfg.clone(), mem_db()))
+        .collect()
+}
+
+/// The modules used by [`guardian`]
+pub fn mint_modules(cfg: &ServerConfig, db: Arc<BufferedDatabase>) -> ModuleRegistry {
+    let threshold = cfg.peers.len() - cfg.max_faulty();
+    let mint = minimint_mint::Mint::new(cfg.mint.clone(), threshold, db);
+
+    let mut modules = ModuleRegistry::new();
+    modules.register(MINT_MODULE_ID, mint);
+    modules
+}
+
+/// Wallet module following an in-memory chain. It has to be created on a multi-threaded runtime
+/// since it queries the chain using [`tokio::task::block_in_place`].
+pub async fn wallet_module(cfg: &ServerConfig, db: Arc<BufferedDatabase>) -> Wallet {
+    let backend = FakeBitcoinBackend::new(cfg.wallet.network);
+    Wallet::new_with_backend(cfg.wallet.clone(), db, Arc::new(backend))
+        .await
+        .expect("Couldn't create wallet")
+}
+
+pub fn mem_db() -> Arc<BufferedDatabase> {
+    Arc::new(BufferedDatabase::new(Arc::new(MemDatabase::new())))
+}
+
+/// Outcome of the next epoch of `guardians` as if HBBFT had decided on the current proposals of
+/// all of them
+pub async fn next_outcome(guardians: &[FediMintConsensus<OsRng>]) -> ConsensusOutcome {
+    let epoch = guardians[0]
+        .last_processed_epoch()
+        .map_or(0, |epoch| epoch + 1);
+
+    let mut contributions = BTreeMap::new();
+    for guardian in guardians {
+        contributions.insert(
+            guardian.cfg.identity,
+            guardian.get_consensus_proposal().await,
+        );
+    }
+
+    Batch {
+        epoch,
+        contributions,
+    }
+}
+
+/// Lets all `guardians` process the outcome returned by [`next_outcome`]
+pub async fn run_epoch(guardians: &[FediMintConsensus<OsRng>]) -> ConsensusOutcome {
+    let outcome = next_outcome(guardians).await;
+    for guardian in guardians {
+        guardian.process_consensus_outcome(outcome.clone()).await;
+    }
+    outcome
+}
+
+/// Transaction without outputs spending `inputs`. It isn't signed, so it is only good for tests
+/// that don't process it.
+pub fn transaction(inputs: Vec<Input>) -> Transaction {
+    Transaction {
+        inputs,
+        outputs: vec![],
+        signature: schnorrsig::Signature::from_slice(&[0; 64]).unwrap(),
+    }
+}
+
+/// Mint input spending a single coin whose nonce is derived from `seed`, which has to be
+/// non-zero. The coin's signature is invalid.
+pub fn coin_input(seed: u8) -> Input {
+    let coin = Coin(
+        CoinNonce(public_key(seed)),
+        tbs::Signature(tbs::Message::from_bytes(&[seed]).0),
+        KeySetId(0),
+    );
+    let coins = vec![(Amount::from_msat(1), coin)]
+        .into_iter()
+        .collect::<Coins<Coin>>();
+    ModuleItem::new(MINT_MODULE_ID, &coins)
+}
+
+/// Wallet input claiming the only output of a bitcoin transaction derived from `tx_seed` for the
+/// contract key derived from `key_seed`, which has to be non-zero. The proof is well-formed but
+/// its output doesn't belong to the federation.
+pub fn peg_in_input(tx_seed: u32, key_seed: u8) -> Input {
+    let transaction = bitcoin::Transaction {
+        version: 2,
+        lock_time: tx_seed,
+        input: vec![TxIn::default()],
+        output: vec![TxOut {
+            value: 10_000,
+            script_pubkey: Default::default(),
+        }],
+    };
+    let txid = transaction.txid();
+
+    let block_header = BlockHeader {
+        version: 1,
+        prev_blockhash: Default::default(),
+        merkle_root: TxMerkleNode::from_inner(txid.into_inner()),
+        time: 0,
+        bits: 0,
+        nonce: 0,
+    };
+    let mut txout_proof = bitcoin::consensus::serialize(&block_header);
+    txout_proof.extend(bitcoin::consensus::serialize(
+        &PartialMerkleTree::from_txids(&[txid], &[true]),
+    ));
+    let txout_proof = TxOutProof::consensus_decode(std::io::Cursor::new(txout_proof)).unwrap();
+
+    let proof = PegInProof::new(txout_proof, transaction, 0, public_key(key_seed)).unwrap();
+    ModuleItem::new(WALLET_MODULE_ID, &proof)
+}
+
+/// Session keys of both ends of a connection from peer 0 to peer 1, established by a handshake
+/// over an in-memory stream
+pub async fn session_keys() -> (SessionKeys, SessionKeys) {
+    let mut rng = OsRng::new().unwrap();
+    let secret_keys = [
+        NetworkSecretKey::generate(&mut rng),
+        NetworkSecretKey::generate(&mut rng),
+    ];
+    let peer_keys = secret_keys
+        .iter()
+        .enumerate()
+        .map(|(id, key)| (PeerId::from(id as u16), key.public_key()))
+        .collect::<BTreeMap<_, _>>();
+    let dialer = IdentityKeys::new(PeerId::from(0), secret_keys[0].clone(), peer_keys.clone());
+    let acceptor = IdentityKeys::new(PeerId::from(1), secret_keys[1].clone(), peer_keys);
+
+    let (mut dialer_stream, mut acceptor_stream) = tokio::io::duplex(1024);
+    let (dialed, accepted) = tokio::join!(
+        dialer.handshake(&mut dialer_stream, Some(PeerId::from(1))),
+        acceptor.handshake(&mut acceptor_stream, None)
+    );
+    (dialed.unwrap().1, accepted.unwrap().1)
+}
+
+fn public_key(seed: u8) -> schnorrsig::PublicKey {
+    let secp = secp256k1_zkp::Secp256k1::new();
+    let key_pair =
+        schnorrsig::KeyPair::from_seckey_slice(&secp, &[seed; 32]).expect("Invalid seed");
+    schnorrsig::PublicKey::from_keypair(&secp, &key_pair)
+}
@@ -1,17 +1,21 @@
 use bitcoin::{Address, Script, Transaction};
 use futures::future::JoinAll;
-use minimint::config::ClientConfig;
+use minimint::config::{ClientConfig, InviteCode};
+use minimint::consensus::header::{ProofError, TransactionProof};
+use minimint::consensus::FeeSchedule;
+use minimint::net::api::{ClientConfigResponse, StatusSubscription};
 use minimint_api::db::batch::{BatchItem, DbBatch};
 use minimint_api::db::{
     Database, DatabaseKey, DatabaseKeyPrefix, DatabaseKeyPrefixConst, DecodingError, RawDatabase,
 };
 use minimint_api::encoding::{Decodable, Encodable};
-use minimint_api::outcome::{Final, OutputOutcome, TransactionStatus};
+use minimint_api::module::{ModuleItem, MINT_MODULE_ID, WALLET_MODULE_ID};
+use minimint_api::outcome::{Final, SubmissionError, TransactionRejection, TransactionStatus};
 use minimint_api::transaction as mint_tx;
 use minimint_api::transaction::OutPoint;
 use minimint_api::{
-    Amount, Coin, CoinNonce, Coins, InvalidAmountTierError, Keys, PegInProof, PegInProofError,
-    SigResponse, SignRequest, TransactionId, Tweakable, TxOutProof,
+    Amount, Coin, CoinNonce, Coins, InvalidAmountTierError, KeySetId, Keys, PegInProof,
+    PegInProofError, SigResponse, SignRequest, TransactionId, Tweakable, TxOutProof,
 };
 use miniscript::DescriptorTrait;
 use rand::seq::SliceRandom;
@@ -20,15 +24,17 @@ use reqwest::{RequestBuilder, StatusCode};
 use secp256k1_zkp::{All, Secp256k1, Signing};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::sync::Arc;
 use tbs::{blind_message, unblind_signature, AggregatePublicKey, BlindedMessage, BlindingKey};
 use thiserror::Error;
 use tokio::time::Duration;
-use tracing::debug;
+use tracing::{debug, warn};
 
 pub const DB_PREFIX_COIN: u8 = 0x20;
 pub const DB_PREFIX_OUTPUT_FINALIZATION_DATA: u8 = 0x21;
 pub const DB_PREFIX_PEG_IN: u8 = 0x22;
+pub const DB_PREFIX_CLIENT_CONFIG: u8 = 0x23;
 
 pub struct MintClient {
     cfg: ClientConfig,
@@ -79,6 +85,14 @@ impl DatabaseKeyPrefixConst for OutputFinalizationKeyPrefix {
     const DB_PREFIX: u8 = DB_PREFIX_OUTPUT_FINALIZATION_DATA;
 }
 
+/// The federation's config the client joined with, bincode encoded
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct ClientConfigKey;
+
+impl DatabaseKeyPrefixConst for ClientConfigKey {
+    const DB_PREFIX: u8 = DB_PREFIX_CLIENT_CONFIG;
+}
+
 #[derive(Debug, Clone)]
 pub struct CoinKey {
     amount: Amount,
@@ -106,6 +120,65 @@ impl MintClient {
         }
     }
 
+    /// Joins the federation described by `invite_code`. The config is fetched from the guardian
+    /// named in the invite code, it is only accepted if it matches the code's digest and enough
+    /// guardians of the federation confirm it. It is stored in `db`, so the client can be
+    /// [`MintClient::load`]ed later on.
+    pub async fn join(
+        invite_code: &InviteCode,
+        db: Arc<dyn RawDatabase>,
+        secp: Secp256k1<All>,
+    ) -> Result<Self, ClientError> {
+        let http_client = reqwest::Client::default();
+        let cfg = fetch_client_config(&http_client, &invite_code.endpoint)
+            .await?
+            .config;
+        let digest = cfg.digest();
+        if digest != invite_code.config_digest {
+            return Err(ClientError::ConfigMismatch);
+        }
+
+        // The invite code might come from a single malicious guardian, so we check that enough of
+        // the guardians it lists serve the same config
+        let confirmations = cfg
+            .api_endpoints
+            .iter()
+            .map(|endpoint| fetch_client_config(&http_client, endpoint))
+            .collect::<JoinAll<_>>()
+            .await
+            .into_iter()
+            .filter(
+                |response| matches!(response, Ok(response) if response.config.digest() == digest),
+            )
+            .count();
+        let threshold = cfg.api_endpoints.len() - cfg.max_faulty();
+        if confirmations < threshold {
+            return Err(ClientError::ConfigNotConfirmed(confirmations, threshold));
+        }
+
+        db.insert_entry(
+            &ClientConfigKey,
+            &bincode::serialize(&cfg).expect("Serialization can't fail"),
+        )
+        .expect("DB error");
+
+        Ok(MintClient {
+            cfg,
+            db,
+            http_client,
+            secp,
+        })
+    }
+
+    /// Loads a client that [`MintClient::join`]ed a federation before, `None` if it didn't
+    pub fn load(db: Arc<dyn RawDatabase>, secp: Secp256k1<All>) -> Option<Self> {
+        let cfg = db
+            .get_value::<_, Vec<u8>>(&ClientConfigKey)
+            .expect("DB error")?;
+        let cfg = bincode::deserialize(&cfg).expect("We stored a valid config");
+        Some(MintClient::new(cfg, db, secp))
+    }
+
     pub async fn send_tx<R: RngCore>(
         &self,
         tx: mint_tx::Transaction,
@@ -113,6 +186,7 @@ impl MintClient {
     ) -> Result<(), ClientError> {
         // Try all mints in random order, break early if enough could be reached
         let mut successes: usize = 0;
+        let mut rejection = None;
         for url in self
             .cfg
             .api_endpoints
@@ -128,6 +202,12 @@ impl MintClient {
 
             if res.status() == StatusCode::OK {
                 successes += 1;
+            } else if res.status() == StatusCode::BAD_REQUEST {
+                // A single mint might reject the transaction maliciously, so we keep trying
+                match res.json::<SubmissionError>().await {
+                    Ok(error) => rejection = Some(error),
+                    Err(e) => warn!("Mint {} returned malformed error: {}", url, e),
+                }
             }
 
             if successes >= 2 {
@@ -137,7 +217,7 @@ impl MintClient {
         }
 
         if successes == 0 {
-            Err(ClientError::MintError)
+            Err(rejection.map_or(ClientError::MintError, ClientError::SubmissionError))
         } else {
             Ok(())
         }
@@ -184,7 +264,11 @@ impl MintClient {
             .expect("Invalid proof");
         let sats = peg_in_proof.tx_output().value;
 
-        let amount = Amount::from_sat(sats).saturating_sub(self.cfg.fee_consensus.fee_peg_in_abs);
+        let fee = self
+            .fetch_fee_schedule()
+            .await?
+            .max_fee(|fees| fees.peg_in_fee(Amount::from_sat(sats)));
+        let amount = Amount::from_sat(sats).saturating_sub(fee);
         if amount == Amount::ZERO {
             return Err(ClientError::PegInAmountTooSmall);
         }
@@ -192,13 +276,14 @@ impl MintClient {
         let (coin_finalization_data, sig_req) =
             CoinFinalizationData::new(amount, &self.cfg.mint.tbs_pks, &self.secp, &mut rng);
 
-        let inputs = vec![mint_tx::Input::PegIn(Box::new(peg_in_proof))];
-        let outputs = vec![mint_tx::Output::Coins(
-            sig_req
+        let inputs = vec![ModuleItem::new(WALLET_MODULE_ID, &peg_in_proof)];
+        let outputs = vec![ModuleItem::new(
+            MINT_MODULE_ID,
+            &sig_req
                 .0
                 .into_iter()
                 .map(|(amt, token)| (amt, mint_tx::BlindToken(token)))
-                .collect(),
+                .collect::<Coins<_>>(),
         )];
 
         let peg_in_req_sig = {
@@ -237,7 +322,106 @@ impl MintClient {
         Ok(tx_id)
     }
 
+    /// Fetches the fees charged by the federation, which may have changed since the client joined
+    pub async fn fetch_fee_schedule(&self) -> Result<FeeSchedule, ClientError> {
+        self.query_any_mint(|client, mint| client.get(&format!("{}/fees", mint)))
+            .await
+    }
+
     pub async fn fetch_coins(&self, outpoint: OutPoint) -> Result<(), ClientError> {
+        let tx_outcome = self
+            .query_any_mint::<TransactionStatus, _>(|client, mint| {
+                let url = format!("{}/transaction/{}", mint, outpoint.txid);
+                client.get(&url)
+            })
+            .await?;
+
+        self.finalize_coins(outpoint, tx_outcome).await
+    }
+
+    /// Waits for the issuance at `outpoint` to be finalized and fetches the resulting coins.
+    /// Instead of polling, we subscribe to changes of the transaction's status at the guardians.
+    pub async fn await_coins(&self, outpoint: OutPoint) -> Result<(), ClientError> {
+        let mut known_status = None;
+        loop {
+            let status = match self
+                .await_status_change(outpoint.txid, known_status.clone())
+                .await
+            {
+                Ok(status) => status,
+                // TODO: make mint error more expressive (currently any HTTP error)
+                Err(ClientError::MintError) => {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Err(e) => return Err(e),
+            };
+
+            if let Some(tx_outcome) = status.clone() {
+                match self.finalize_coins(outpoint, tx_outcome).await {
+                    Err(ClientError::OutputNotReadyYet(_)) => {}
+                    // The federation signs the outcome an epoch or two after deciding it
+                    Err(ClientError::ProofNotAvailable(_)) => {
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                    res => return res,
+                }
+            }
+            known_status = status;
+        }
+    }
+
+    /// Waits until the status of transaction `txid` differs from `known_status` and returns the
+    /// new status. If it doesn't change before the guardians' subscription timeout the known
+    /// status is returned.
+    pub async fn await_status_change(
+        &self,
+        txid: TransactionId,
+        known_status: Option<TransactionStatus>,
+    ) -> Result<Option<TransactionStatus>, ClientError> {
+        let subscription = StatusSubscription {
+            known: vec![(txid, known_status.clone())].into_iter().collect(),
+        };
+        let mut changed = self
+            .query_any_mint::<BTreeMap<TransactionId, Option<TransactionStatus>>, _>(
+                |client, mint| {
+                    let url = format!("{}/transaction/subscribe", mint);
+                    client.post(&url).json(&subscription)
+                },
+            )
+            .await?;
+
+        Ok(changed.remove(&txid).unwrap_or(known_status))
+    }
+
+    /// Checks the final `status` of transaction `txid` received from a single guardian against a
+    /// proof signed by the federation
+    pub async fn verify_transaction_status(
+        &self,
+        txid: TransactionId,
+        status: &TransactionStatus,
+    ) -> Result<(), ClientError> {
+        let proof = self
+            .query_any_mint::<TransactionProof, _>(|client, mint| {
+                let url = format!("{}/transaction/{}/proof", mint, txid);
+                client.get(&url)
+            })
+            .await
+            .map_err(|_| ClientError::ProofNotAvailable(txid))?;
+
+        proof
+            .verify(txid, status, &self.cfg.federation_pk)
+            .map_err(ClientError::InvalidProof)
+    }
+
+    /// Turns the issuance at `outpoint` into spendable coins if `tx_outcome` contains its blind
+    /// signature
+    async fn finalize_coins(
+        &self,
+        outpoint: OutPoint,
+        tx_outcome: TransactionStatus,
+    ) -> Result<(), ClientError> {
         let issuance = self
             .db
             .get_value::<_, CoinFinalizationData>(&OutputFinalizationKey(outpoint))
@@ -246,40 +430,42 @@ impl MintClient {
                 CoinFinalizationError::UnknowinIssuance,
             ))?;
 
-        let tx_outcome = self
-            .query_any_mint::<TransactionStatus, _>(|client, mint| {
-                let url = format!("{}/transaction/{}", mint, outpoint.txid);
-                client.get(&url)
-            })
-            .await?;
-
-        // TODO: check another mint if the answer was malicious
         if !tx_outcome.is_final() {
             return Err(ClientError::OutputNotReadyYet(outpoint));
         }
 
-        let outputs = match tx_outcome {
-            TransactionStatus::AwaitingConsensus => {
+        // Blind signatures are checked when finalizing the coins, so an accepted outcome needs no
+        // further proof
+        let outputs = match &tx_outcome {
+            TransactionStatus::AwaitingConsensus | TransactionStatus::Evicted { .. } => {
                 unreachable!()
             }
-            TransactionStatus::Error(e) => {
-                panic!("Mint error: {}", e)
+            TransactionStatus::Error { error, .. } => {
+                // A single guardian could claim that the issuance failed to make us forget it, so
+                // we only stop tracking it once the federation's signed proof confirms that
+                self.verify_transaction_status(outpoint.txid, &tx_outcome)
+                    .await?;
+                self.db
+                    .remove_entry::<_, CoinFinalizationData>(&OutputFinalizationKey(outpoint))
+                    .expect("DB error");
+                return Err(ClientError::TransactionRejected(error.clone()));
             }
             TransactionStatus::Accepted { outputs, .. } => outputs,
         };
 
-        // TODO: remove clone
-        let bsig = outputs
+        let outcome = &outputs
             .get(outpoint.out_idx as usize)
-            .and_then(|outcome| match outcome {
-                OutputOutcome::Mint(mo) => Some(mo),
-                OutputOutcome::Wallet(_) => None,
-            })
             .ok_or(ClientError::InvalidOutcomeWrongStructure(outpoint))?
-            .clone()
+            .outcome;
+        if outcome.module != MINT_MODULE_ID {
+            return Err(ClientError::InvalidOutcomeType(outpoint));
+        }
+        let bsig = outcome
+            .decode::<Option<SigResponse>>()
+            .map_err(|_| ClientError::InvalidOutcomeType(outpoint))?
             .ok_or(ClientError::OutputNotReadyYet(outpoint))?;
 
-        let coins = issuance.finalize(bsig, &self.cfg.mint.tbs_pks)?;
+        let coins = issuance.finalize(bsig, &self.cfg.mint.tbs_pks, self.cfg.mint.key_set)?;
 
         let mut batch = DbBatch::new();
         batch.autocommit(|tx| {
@@ -346,16 +532,8 @@ impl MintClient {
             .map(|res| {
                 let (id, _) = res.expect("DB error");
                 async move {
-                    loop {
-                        match self.fetch_coins(id.0).await {
-                            Ok(()) => return Ok(id.0.txid),
-                            // TODO: make mint error more expressive (currently any HTTP error) and maybe use custom return type instead of error for retrying
-                            Err(ClientError::MintError | ClientError::OutputNotReadyYet(_)) => {
-                                tokio::time::sleep(Duration::from_secs(1)).await
-                            }
-                            Err(e) => return Err(e),
-                        }
-                    }
+                    self.await_coins(id.0).await?;
+                    Ok(id.0.txid)
                 }
             })
             .collect::<JoinAll<_>>()
@@ -374,6 +552,15 @@ impl MintClient {
             .collect()
     }
 
+    /// Coins of key sets the federation replaced by a newer one. They have to be reissued before
+    /// their key set's deprecation epoch, afterwards the federation rejects them.
+    pub fn retired_coins(&self) -> Coins<SpendableCoin> {
+        self.coins()
+            .into_iter()
+            .filter(|(_, coin)| coin.coin.key_set() != self.cfg.mint.key_set)
+            .collect()
+    }
+
     pub fn spend_coins(&self, coins: &Coins<SpendableCoin>) {
         let mut batch = DbBatch::new();
         batch.autocommit(|tx| {
@@ -405,8 +592,11 @@ impl MintClient {
             .map(|(amt, coin)| (coin.spend_key, (amt, coin.coin)))
             .unzip();
 
-        let inputs = vec![mint_tx::Input::Coins(coins)];
-        let outputs = vec![mint_tx::Output::Coins(sig_req.into())];
+        let inputs = vec![ModuleItem::new(MINT_MODULE_ID, &coins)];
+        let outputs = vec![ModuleItem::new(
+            MINT_MODULE_ID,
+            &Coins::<mint_tx::BlindToken>::from(sig_req),
+        )];
 
         // TODO: abstract away tx building somehow
         let signature = {
@@ -444,9 +634,13 @@ impl MintClient {
         address: bitcoin::Address,
         mut rng: R,
     ) -> Result<TransactionId, ClientError> {
+        let fee = self
+            .fetch_fee_schedule()
+            .await?
+            .max_fee(|fees| fees.peg_out_fee(amt.into()));
         let coins = self
             .coins()
-            .select_coins(Amount::from(amt) + self.cfg.fee_consensus.fee_peg_out_abs)
+            .select_coins(Amount::from(amt) + fee)
             .ok_or(ClientError::NotEnoughCoins)?;
 
         // mark spent in DB
@@ -458,11 +652,14 @@ impl MintClient {
             .map(|(amt, coin)| (coin.spend_key, (amt, coin.coin)))
             .unzip();
 
-        let inputs = vec![mint_tx::Input::Coins(coins)];
-        let outputs = vec![mint_tx::Output::PegOut(mint_tx::PegOut {
-            recipient: address,
-            amount: amt,
-        })];
+        let inputs = vec![ModuleItem::new(MINT_MODULE_ID, &coins)];
+        let outputs = vec![ModuleItem::new(
+            WALLET_MODULE_ID,
+            &mint_tx::PegOut {
+                recipient: address,
+                amount: amt,
+            },
+        )];
 
         let signature = {
             // FIXME: deduplicate tx signing code
@@ -516,6 +713,20 @@ impl MintClient {
     }
 }
 
+async fn fetch_client_config(
+    http_client: &reqwest::Client,
+    endpoint: &str,
+) -> Result<ClientConfigResponse, ClientError> {
+    http_client
+        .get(&format!("{}/config", endpoint))
+        .send()
+        .await
+        .map_err(|_| ClientError::MintError)?
+        .json()
+        .await
+        .map_err(|_| ClientError::MintError)
+}
+
 impl CoinFinalizationData {
     /// Generate a new `IssuanceRequest` and the associates [`SignRequest`]
     pub fn new<K, C>(
@@ -551,11 +762,13 @@ impl CoinFinalizationData {
 
     /// Finalize the issuance request using a [`SigResponse`] from the mint containing the blind
     /// signatures for all coins in this `IssuanceRequest`. It also takes the mint's
-    /// [`AggregatePublicKey`] to validate the supplied blind signatures.
+    /// [`AggregatePublicKey`] of the `key_set` the coins were issued under to validate the
+    /// supplied blind signatures.
     pub fn finalize(
         &self,
         bsigs: SigResponse,
         mint_pub_key: &Keys<AggregatePublicKey>,
+        key_set: KeySetId,
     ) -> Result<Coins<SpendableCoin>, CoinFinalizationError> {
         if !self.coins.structural_eq(&bsigs.0) {
             return Err(CoinFinalizationError::WrongMintAnswer);
@@ -567,7 +780,7 @@ impl CoinFinalizationData {
             .enumerate()
             .map(|(idx, ((amt, coin_req), (_amt, bsig)))| {
                 let sig = unblind_signature(coin_req.blinding_key, bsig);
-                let coin = Coin(coin_req.nonce.clone(), sig);
+                let coin = Coin(coin_req.nonce.clone(), sig, key_set);
                 if coin.verify(*mint_pub_key.tier(&amt)?) {
                     let coin = SpendableCoin {
                         coin,
@@ -648,6 +861,18 @@ pub enum ClientError {
     InvalidOutcomeWrongStructure(OutPoint),
     #[error("The transaction outcome returned by the mint has an invalid type (output {0})")]
     InvalidOutcomeType(OutPoint),
+    #[error("The federation rejected the transaction: {0}")]
+    TransactionRejected(TransactionRejection),
+    #[error("The transaction could not be submitted: {0}")]
+    SubmissionError(SubmissionError),
+    #[error("The guardian's config does not match the invite code")]
+    ConfigMismatch,
+    #[error("Only {0} guardians confirmed the federation's config, {1} are needed")]
+    ConfigNotConfirmed(usize, usize),
+    #[error("No signed proof of the outcome of transaction {0} is available yet")]
+    ProofNotAvailable(TransactionId),
+    #[error("The proof of the transaction outcome is invalid: {0}")]
+    InvalidProof(ProofError),
 }
 
 impl From<InvalidAmountTierError> for CoinFinalizationError {
@@ -1,6 +1,6 @@
 use bitcoin::{Address, Transaction};
 use bitcoin_hashes::hex::ToHex;
-use minimint::config::{load_from_file, ClientConfig};
+use minimint::config::{load_from_file, ClientConfig, InviteCode};
 use minimint_api::encoding::Decodable;
 use minimint_api::{Amount, Coins, TxOutProof};
 use mint_client::{MintClient, SpendableCoin};
@@ -10,7 +10,7 @@ use std::error::Error;
 use std::path::PathBuf;
 use std::sync::Arc;
 use structopt::StructOpt;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 use tracing_subscriber::EnvFilter;
 
 #[derive(StructOpt)]
@@ -22,6 +22,10 @@ struct Options {
 
 #[derive(StructOpt)]
 enum Command {
+    #[structopt(
+        about = "Join a federation using an invite code, afterwards no client.json is needed"
+    )]
+    Join { invite_code: InviteCode },
     #[structopt(about = "Generate a new peg-in address, funds sent to it can later be claimed")]
     PegInAddress,
     #[structopt(
@@ -38,6 +42,10 @@ enum Command {
         #[structopt(parse(from_str = parse_coins))]
         coins: Coins<SpendableCoin>,
     },
+    #[structopt(
+        about = "Reissue our coins of key sets the federation retired, they won't be accepted after the key set is deprecated"
+    )]
+    ReissueRetired,
     #[structopt(about = "Prepare coins to send to a third party as a payment")]
     Spend { amount: Amount },
     #[structopt(about = "Withdraw funds from the federation")]
@@ -73,17 +81,31 @@ async fn main() {
     let opts: Options = StructOpt::from_args();
     let cfg_path = opts.workdir.join("client.json");
     let db_path = opts.workdir.join("client.db");
-    let cfg: ClientConfig = load_from_file(&cfg_path);
-    let db = sled::open(&db_path)
-        .unwrap()
-        .open_tree("mint-client")
-        .unwrap();
+    let db = Arc::new(
+        sled::open(&db_path)
+            .unwrap()
+            .open_tree("mint-client")
+            .unwrap(),
+    );
 
     let mut rng = rand::rngs::OsRng::new().unwrap();
 
-    let client = MintClient::new(cfg, Arc::new(db), Default::default());
+    if let Command::Join { invite_code } = &opts.command {
+        match MintClient::join(invite_code, db, Default::default()).await {
+            Ok(_) => info!("Joined the federation"),
+            Err(e) => error!("Could not join the federation: {}", e),
+        }
+        return;
+    }
+
+    // Clients that didn't join using an invite code use the config in the work dir
+    let client = MintClient::load(db.clone(), Default::default()).unwrap_or_else(|| {
+        let cfg: ClientConfig = load_from_file(&cfg_path);
+        MintClient::new(cfg, db, Default::default())
+    });
 
     match opts.command {
+        Command::Join { .. } => unreachable!("Handled above"),
         Command::PegInAddress => {
             println!("{}", client.get_new_pegin_address(&mut rng))
         }
@@ -108,6 +130,20 @@ async fn main() {
                 id.to_hex()
             );
         }
+        Command::ReissueRetired => {
+            let coins = client.retired_coins();
+            if coins.coin_count() == 0 {
+                info!("We own no coins of retired key sets");
+            } else {
+                info!("Reissuing {} of retired key sets", coins.amount());
+                client.spend_coins(&coins);
+                let id = client.reissue(coins, &mut rng).await.unwrap();
+                info!(
+                    "Started reissuance {}, please fetch the result later",
+                    id.to_hex()
+                );
+            }
+        }
         Command::Spend { amount } => {
             match client.coins().select_coins(amount) {
                 Some(outgoing_coins) => {
@@ -134,6 +170,14 @@ async fn main() {
             for (amount, coins) in coins.coins {
                 info!("We own {} coins of denomination {}", coins.len(), amount);
             }
+
+            let retired_coins = client.retired_coins();
+            if retired_coins.coin_count() != 0 {
+                warn!(
+                    "{} of our coins belong to retired key sets, reissue them before they are deprecated",
+                    retired_coins.amount()
+                );
+            }
         }
         Command::PegOut { address, amount } => {
             client.peg_out(amount, address, &mut rng).await.unwrap();
@@ -1,21 +1,65 @@
-use minimint_api::config::GenerateConfig;
-use minimint_api::{Amount, Keys, PeerId};
+use async_trait::async_trait;
+use minimint_api::config::{exchange, DkgConnections, DkgError, GenerateConfig};
+use minimint_api::util::TieredMultiZip;
+use minimint_api::{Amount, KeySetId, Keys, PeerId};
 use rand::{CryptoRng, RngCore};
+use rand_chacha::rand_core::SeedableRng;
+use rand_chacha::ChaChaRng;
 use serde::{Deserialize, Serialize};
 use std::collections::{BTreeMap, HashMap};
-use tbs::{dealer_keygen, AggregatePublicKey};
+use tbs::{
+    combine_reshared_commitments, combine_reshared_shares, dealer_keygen, Aggregatable,
+    AggregatePublicKey, DkgPolynomial, PolynomialCommitment,
+};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct MintConfig {
     pub tbs_sks: Keys<tbs::SecretKeyShare>,
     pub peer_tbs_pks: BTreeMap<PeerId, Keys<tbs::PublicKeyShare>>,
+    /// Key set the keys above belong to, new coins are issued under it
+    #[serde(default)]
+    pub key_set: KeySetId,
+    /// Previous key sets whose coins are still accepted until they are deprecated
+    #[serde(default)]
+    pub retired_key_sets: BTreeMap<KeySetId, RetiredKeySet>,
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct MintClientConfig {
     pub tbs_pks: Keys<AggregatePublicKey>,
+    /// Key set of `tbs_pks`
+    #[serde(default)]
+    pub key_set: KeySetId,
+    #[serde(default)]
+    pub retired_key_sets: BTreeMap<KeySetId, RetiredKeySet>,
+}
+
+/// A key set that was replaced by a newer one. No coins are issued under it anymore, but its coins
+/// can still be spent before `deprecation_epoch`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RetiredKeySet {
+    pub tbs_pks: Keys<AggregatePublicKey>,
+    /// First consensus epoch in which coins of this key set are rejected
+    pub deprecation_epoch: u64,
+}
+
+/// Message every peer sends to every other peer during the distributed key generation
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct MintDkgMessage {
+    /// Commitments to the sender's polynomials, these are the same for all recipients
+    commitments: Keys<PolynomialCommitment>,
+    /// Shares of the sender's polynomials dealt to the recipient
+    shares: Keys<tbs::SecretKeyShare>,
+}
+
+/// Public keys of the current federation that are needed to reshare its keys
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MintResharingKeys {
+    pub peer_tbs_pks: BTreeMap<PeerId, Keys<tbs::PublicKeyShare>>,
+    pub client_cfg: MintClientConfig,
 }
 
+#[async_trait(?Send)]
 impl GenerateConfig for MintConfig {
     type Params = [Amount];
     type ClientConfig = MintClientConfig;
@@ -24,14 +68,16 @@ impl GenerateConfig for MintConfig {
         peers: &[PeerId],
         max_evil: usize,
         params: &Self::Params,
-        _rng: impl RngCore + CryptoRng,
+        rng: impl RngCore + CryptoRng,
     ) -> (BTreeMap<PeerId, Self>, Self::ClientConfig) {
         let tbs_threshold = peers.len() - max_evil;
+        let mut rng = tbs_rng(rng);
 
         let tbs_keys = params
             .iter()
             .map(|&amount| {
-                let (tbs_pk, tbs_pks, tbs_sks) = dealer_keygen(tbs_threshold, peers.len());
+                let (tbs_pk, tbs_pks, tbs_sks) =
+                    dealer_keygen(tbs_threshold, peers.len(), &mut rng);
                 (amount, (tbs_pk, tbs_pks, tbs_sks))
             })
             .collect::<HashMap<_, _>>();
@@ -54,6 +100,8 @@ impl GenerateConfig for MintConfig {
                             (key_peer, keys)
                         })
                         .collect(),
+                    key_set: KeySetId::default(),
+                    retired_key_sets: BTreeMap::new(),
                 };
                 (peer, config)
             })
@@ -64,8 +112,335 @@ impl GenerateConfig for MintConfig {
                 .into_iter()
                 .map(|(amount, (pk, _, _))| (amount, pk))
                 .collect(),
+            key_set: KeySetId::default(),
+            retired_key_sets: BTreeMap::new(),
         };
 
         (mint_cfg, client_cfg)
     }
+
+    /// Every peer deals shares of a random polynomial per amount tier to all peers, the tier keys
+    /// are the sums of these polynomials. Commitments to the polynomials allow verifying the
+    /// shares. Whether all peers received the same commitments has to be checked by the caller.
+    async fn distributed_gen<R>(
+        connections: &mut dyn DkgConnections,
+        max_evil: usize,
+        params: &Self::Params,
+        rng: R,
+    ) -> Result<(Self, Self::ClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let peers = connections.peers().to_vec();
+        let tbs_threshold = peers.len() - max_evil;
+        let mut rng = tbs_rng(rng);
+
+        let polynomials = params
+            .iter()
+            .map(|&amount| (amount, DkgPolynomial::random(tbs_threshold, &mut rng)))
+            .collect::<BTreeMap<_, _>>();
+        let msgs = peers
+            .iter()
+            .map(|&peer| {
+                let msg = MintDkgMessage {
+                    commitments: polynomials
+                        .iter()
+                        .map(|(&amount, poly)| (amount, poly.commitment()))
+                        .collect(),
+                    shares: polynomials
+                        .iter()
+                        .map(|(&amount, poly)| (amount, poly.share(peer.to_usize())))
+                        .collect(),
+                };
+                (peer, msg)
+            })
+            .collect();
+
+        let our_idx = connections.our_id().to_usize();
+        let received = exchange(connections, "mint", msgs).await?;
+        for (&peer, msg) in &received {
+            let is_valid = params.iter().all(|amount| {
+                match (msg.commitments.tier(amount), msg.shares.tier(amount)) {
+                    (Ok(commitment), Ok(share)) => {
+                        commitment.threshold() == tbs_threshold
+                            && commitment.verify_share(our_idx, *share)
+                    }
+                    _ => false,
+                }
+            });
+            if !is_valid {
+                return Err(DkgError::FaultyPeer(peer, "invalid key share".to_string()));
+            }
+        }
+
+        let commitments = params
+            .iter()
+            .map(|amount| {
+                let commitment = received
+                    .values()
+                    .map(|msg| msg.commitments.keys[amount].clone())
+                    .sum::<PolynomialCommitment>();
+                (*amount, commitment)
+            })
+            .collect::<BTreeMap<_, _>>();
+
+        let mint_cfg = MintConfig {
+            tbs_sks: params
+                .iter()
+                .map(|amount| {
+                    let share = received.values().map(|msg| msg.shares.keys[amount]).sum();
+                    (*amount, share)
+                })
+                .collect(),
+            peer_tbs_pks: peers
+                .iter()
+                .map(|&peer| {
+                    let keys = commitments
+                        .iter()
+                        .map(|(&amount, commitment)| (amount, commitment.evaluate(peer.to_usize())))
+                        .collect();
+                    (peer, keys)
+                })
+                .collect(),
+            key_set: KeySetId::default(),
+            retired_key_sets: BTreeMap::new(),
+        };
+
+        let client_cfg = MintClientConfig {
+            tbs_pks: commitments
+                .iter()
+                .map(|(&amount, commitment)| (amount, commitment.aggregate_public_key()))
+                .collect(),
+            key_set: KeySetId::default(),
+            retired_key_sets: BTreeMap::new(),
+        };
+
+        Ok((mint_cfg, client_cfg))
+    }
+}
+
+impl MintConfig {
+    /// Client config matching our keys. The aggregate public keys are interpolated from the
+    /// public key shares of all peers, `threshold` of which are needed to issue coins.
+    pub fn client_config(&self, threshold: usize) -> MintClientConfig {
+        let tbs_pks = TieredMultiZip::new(
+            self.peer_tbs_pks
+                .iter()
+                .map(|(_, keys)| keys.iter())
+                .collect(),
+        )
+        .map(|(amt, keys)| {
+            // TODO: avoid this through better aggregation API allowing references or
+            let keys = self
+                .peer_tbs_pks
+                .keys()
+                .map(|peer| peer.to_usize())
+                .zip(keys.into_iter().copied())
+                .collect::<Vec<_>>();
+            (amt, keys.aggregate(threshold))
+        })
+        .collect();
+
+        MintClientConfig {
+            tbs_pks,
+            key_set: self.key_set,
+            retired_key_sets: self.retired_key_sets.clone(),
+        }
+    }
+
+    /// Reshares the keys of the current federation described by `old_keys` among all peers taking
+    /// part, so the new federation keeps issuing coins under the same aggregate keys. Every peer
+    /// that holds key shares of the current federation passes its `old_cfg` and deals shares of a
+    /// polynomial evaluating to its old share at zero. The new shares are the Lagrange combination
+    /// of the dealt ones, which requires at least `old_threshold` dealers.
+    ///
+    /// Whether all peers received the same commitments has to be checked by the caller.
+    pub async fn reshare<R>(
+        connections: &mut dyn DkgConnections,
+        max_evil: usize,
+        old_cfg: Option<&MintConfig>,
+        old_keys: &MintResharingKeys,
+        old_threshold: usize,
+        rng: R,
+    ) -> Result<(MintConfig, MintClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let peers = connections.peers().to_vec();
+        let tbs_threshold = peers.len() - max_evil;
+        let mut rng = tbs_rng(rng);
+        let tiers = old_keys
+            .client_cfg
+            .tbs_pks
+            .tiers()
+            .copied()
+            .collect::<Vec<_>>();
+
+        let polynomials = old_cfg.map(|old_cfg| {
+            tiers
+                .iter()
+                .map(|amount| {
+                    let old_share = *old_cfg.tbs_sks.tier(amount).expect("Tiers are consistent");
+                    (
+                        *amount,
+                        DkgPolynomial::reshare(old_share, tbs_threshold, &mut rng),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>()
+        });
+        let msgs = peers
+            .iter()
+            .map(|&peer| {
+                let msg = polynomials.as_ref().map(|polynomials| MintDkgMessage {
+                    commitments: polynomials
+                        .iter()
+                        .map(|(&amount, poly)| (amount, poly.commitment()))
+                        .collect(),
+                    shares: polynomials
+                        .iter()
+                        .map(|(&amount, poly)| (amount, poly.share(peer.to_usize())))
+                        .collect(),
+                });
+                (peer, msg)
+            })
+            .collect();
+
+        let our_idx = connections.our_id().to_usize();
+        let mut dealt = BTreeMap::new();
+        for (peer, msg) in exchange(connections, "mint-reshare", msgs).await? {
+            let old_pks = old_keys.peer_tbs_pks.get(&peer);
+            let msg = match (old_pks, msg) {
+                (Some(old_pks), Some(msg)) => {
+                    let is_valid = tiers.iter().all(|amount| {
+                        match (msg.commitments.tier(amount), msg.shares.tier(amount)) {
+                            (Ok(commitment), Ok(share)) => {
+                                commitment.threshold() == tbs_threshold
+                                    && old_pks.tier(amount).map_or(false, |old_pk| {
+                                        *old_pk == commitment.constant_public_key_share()
+                                    })
+                                    && commitment.verify_share(our_idx, *share)
+                            }
+                            _ => false,
+                        }
+                    });
+                    if !is_valid {
+                        return Err(DkgError::FaultyPeer(peer, "invalid key share".to_string()));
+                    }
+                    msg
+                }
+                (None, None) => continue,
+                (Some(_), None) => {
+                    return Err(DkgError::FaultyPeer(
+                        peer,
+                        "did not reshare its key".to_string(),
+                    ))
+                }
+                (None, Some(_)) => {
+                    return Err(DkgError::FaultyPeer(
+                        peer,
+                        "dealt shares without holding a key".to_string(),
+                    ))
+                }
+            };
+            dealt.insert(peer, msg);
+        }
+
+        if dealt.len() < old_threshold {
+            return Err(DkgError::NotEnoughDealers(dealt.len(), old_threshold));
+        }
+
+        let mut tbs_sks = Vec::new();
+        let mut commitments = BTreeMap::new();
+        for amount in &tiers {
+            let share = combine_reshared_shares(
+                dealt
+                    .iter()
+                    .map(|(peer, msg)| (peer.to_usize(), msg.shares.keys[amount]))
+                    .collect::<Vec<_>>(),
+            );
+            let commitment = combine_reshared_commitments(
+                &dealt
+                    .iter()
+                    .map(|(peer, msg)| (peer.to_usize(), msg.commitments.keys[amount].clone()))
+                    .collect::<Vec<_>>(),
+            );
+
+            // Holds if the dealers' old shares are consistent with the old aggregate key
+            let old_pk = old_keys
+                .client_cfg
+                .tbs_pks
+                .tier(amount)
+                .expect("Tiers are consistent");
+            if commitment.aggregate_public_key() != *old_pk {
+                return Err(DkgError::ConfigMismatch);
+            }
+
+            tbs_sks.push((*amount, share));
+            commitments.insert(*amount, commitment);
+        }
+
+        let mint_cfg = MintConfig {
+            tbs_sks: tbs_sks.into_iter().collect(),
+            peer_tbs_pks: peers
+                .iter()
+                .map(|&peer| {
+                    let keys = commitments
+                        .iter()
+                        .map(|(&amount, commitment)| (amount, commitment.evaluate(peer.to_usize())))
+                        .collect();
+                    (peer, keys)
+                })
+                .collect(),
+            key_set: old_keys.client_cfg.key_set,
+            retired_key_sets: old_keys.client_cfg.retired_key_sets.clone(),
+        };
+
+        Ok((mint_cfg, old_keys.client_cfg.clone()))
+    }
+
+    /// Generates a new key set with the same amount tiers as the current one described by
+    /// `old_client_cfg`. The current key set is retired, its coins are accepted until
+    /// `deprecation_epoch`. Key sets that were retired before are kept, the mint stops accepting
+    /// their coins on its own once they are deprecated.
+    ///
+    /// Whether all peers received the same commitments has to be checked by the caller.
+    pub async fn rotate<R>(
+        connections: &mut dyn DkgConnections,
+        max_evil: usize,
+        old_client_cfg: &MintClientConfig,
+        deprecation_epoch: u64,
+        rng: R,
+    ) -> Result<(MintConfig, MintClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let tiers = old_client_cfg.tbs_pks.tiers().copied().collect::<Vec<_>>();
+        let (mut mint_cfg, mut client_cfg) =
+            MintConfig::distributed_gen(connections, max_evil, &tiers, rng).await?;
+
+        let key_set = old_client_cfg.key_set.next();
+        let mut retired_key_sets = old_client_cfg.retired_key_sets.clone();
+        retired_key_sets.insert(
+            old_client_cfg.key_set,
+            RetiredKeySet {
+                tbs_pks: old_client_cfg.tbs_pks.clone(),
+                deprecation_epoch,
+            },
+        );
+
+        mint_cfg.key_set = key_set;
+        mint_cfg.retired_key_sets = retired_key_sets.clone();
+        client_cfg.key_set = key_set;
+        client_cfg.retired_key_sets = retired_key_sets;
+
+        Ok((mint_cfg, client_cfg))
+    }
+}
+
+/// The tbs crate depends on a newer version of `rand` than we do, so its keys are generated with
+/// a ChaCha RNG seeded from the caller's `rng`
+pub(crate) fn tbs_rng(mut rng: impl RngCore + CryptoRng) -> ChaChaRng {
+    let mut seed = [0u8; 32];
+    rng.fill_bytes(&mut seed);
+    ChaChaRng::from_seed(seed)
 }
@@ -1,20 +1,71 @@
 use minimint_api::db::DatabaseKeyPrefixConst;
 use minimint_api::encoding::{Decodable, Encodable};
 use minimint_api::transaction::OutPoint;
-use minimint_api::{CoinNonce, PeerId};
+use minimint_api::{Amount, CoinNonce, KeySetId, PeerId};
 
 const DB_PREFIX_COIN_NONCE: u8 = 0x10;
 const DB_PREFIX_PROPOSED_PARTIAL_SIG: u8 = 0x11;
 const DB_PREFIX_RECEIVED_PARTIAL_SIG: u8 = 0x12;
 const DB_PREFIX_OUTPUT_OUTCOME: u8 = 0x13;
+const DB_PREFIX_ISSUANCE_AUDIT: u8 = 0x14;
+const DB_PREFIX_REDEMPTION_AUDIT: u8 = 0x15;
+const DB_PREFIX_TIER_AUDIT: u8 = 0x16;
+const DB_PREFIX_KEY_SET_COIN_NONCE: u8 = 0x17;
+const DB_PREFIX_EPOCH: u8 = 0x18;
+const DB_PREFIX_AUDIT_BACKFILL: u8 = 0x19;
+const DB_PREFIX_SPENT_NONCE_COUNT: u8 = 0x1a;
 
+/// Prefixes of the state all peers agree on, our proposed signature shares are local
+pub const CONSENSUS_DB_PREFIXES: &[u8] = &[
+    DB_PREFIX_COIN_NONCE,
+    DB_PREFIX_RECEIVED_PARTIAL_SIG,
+    DB_PREFIX_OUTPUT_OUTCOME,
+    DB_PREFIX_ISSUANCE_AUDIT,
+    DB_PREFIX_REDEMPTION_AUDIT,
+    DB_PREFIX_TIER_AUDIT,
+    DB_PREFIX_KEY_SET_COIN_NONCE,
+    DB_PREFIX_EPOCH,
+];
+
+/// Nonce of a spent coin of [`KeySetId::LEGACY`], stored like before key sets were introduced so
+/// coins spent before upgrading stay spent
 #[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash)]
-pub struct NonceKey(pub CoinNonce);
+pub struct LegacyNonceKey(pub CoinNonce);
 
-impl DatabaseKeyPrefixConst for NonceKey {
+impl DatabaseKeyPrefixConst for LegacyNonceKey {
+    const DB_PREFIX: u8 = DB_PREFIX_COIN_NONCE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct LegacyNonceKeyPrefix;
+
+impl DatabaseKeyPrefixConst for LegacyNonceKeyPrefix {
     const DB_PREFIX: u8 = DB_PREFIX_COIN_NONCE;
 }
 
+/// Nonce of a spent coin of any later key set, nonces are tracked per key set so they can be
+/// pruned once the key set is deprecated
+#[derive(Debug, Clone, Encodable, Decodable, Eq, PartialEq, Hash)]
+pub struct NonceKey(pub KeySetId, pub CoinNonce);
+
+impl DatabaseKeyPrefixConst for NonceKey {
+    const DB_PREFIX: u8 = DB_PREFIX_KEY_SET_COIN_NONCE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct NonceKeyPrefix;
+
+impl DatabaseKeyPrefixConst for NonceKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_KEY_SET_COIN_NONCE;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct NonceKeySetPrefix(pub KeySetId);
+
+impl DatabaseKeyPrefixConst for NonceKeySetPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_KEY_SET_COIN_NONCE;
+}
+
 #[derive(Debug, Encodable, Decodable)]
 pub struct ProposedPartialSignatureKey {
     pub request_id: OutPoint, // tx + output idx
@@ -64,3 +115,86 @@ pub struct OutputOutcomeKey(pub OutPoint);
 impl DatabaseKeyPrefixConst for OutputOutcomeKey {
     const DB_PREFIX: u8 = DB_PREFIX_OUTPUT_OUTCOME;
 }
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct OutputOutcomeKeyPrefix;
+
+impl DatabaseKeyPrefixConst for OutputOutcomeKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_OUTPUT_OUTCOME;
+}
+
+/// Coins issued by a transaction output of the current epoch, they are added to the
+/// [`TierAuditKey`] totals at the end of the epoch. Transactions are processed in parallel, so
+/// they can't update the totals themselves.
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct IssuanceAuditKey(pub OutPoint);
+
+impl DatabaseKeyPrefixConst for IssuanceAuditKey {
+    const DB_PREFIX: u8 = DB_PREFIX_ISSUANCE_AUDIT;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct IssuanceAuditKeyPrefix;
+
+impl DatabaseKeyPrefixConst for IssuanceAuditKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_ISSUANCE_AUDIT;
+}
+
+/// Amount tier of a coin spent in the current epoch, it is added to the [`TierAuditKey`] totals
+/// at the end of the epoch
+#[derive(Debug, Clone, Encodable, Decodable)]
+pub struct RedemptionAuditKey(pub KeySetId, pub CoinNonce);
+
+impl DatabaseKeyPrefixConst for RedemptionAuditKey {
+    const DB_PREFIX: u8 = DB_PREFIX_REDEMPTION_AUDIT;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct RedemptionAuditKeyPrefix;
+
+impl DatabaseKeyPrefixConst for RedemptionAuditKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_REDEMPTION_AUDIT;
+}
+
+/// Number of coins of an amount tier issued and redeemed so far
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct TierAuditKey(pub Amount);
+
+impl DatabaseKeyPrefixConst for TierAuditKey {
+    const DB_PREFIX: u8 = DB_PREFIX_TIER_AUDIT;
+}
+
+#[derive(Debug, Encodable, Decodable)]
+pub struct TierAuditKeyPrefix;
+
+impl DatabaseKeyPrefixConst for TierAuditKeyPrefix {
+    const DB_PREFIX: u8 = DB_PREFIX_TIER_AUDIT;
+}
+
+/// Consensus epoch the mint processed last, it decides which key sets are deprecated after a
+/// restart
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct EpochKey;
+
+impl DatabaseKeyPrefixConst for EpochKey {
+    const DB_PREFIX: u8 = DB_PREFIX_EPOCH;
+}
+
+/// Marks that the [`TierAuditKey`] totals include the coins issued and redeemed before they were
+/// tracked
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct AuditBackfillKey;
+
+impl DatabaseKeyPrefixConst for AuditBackfillKey {
+    const DB_PREFIX: u8 = DB_PREFIX_AUDIT_BACKFILL;
+}
+
+/// Number of spent nonces stored under [`LegacyNonceKey`] and [`NonceKey`], updated at the end of
+/// every epoch so the metrics don't have to count them. It is initialized by counting them once,
+/// so it's local like [`AuditBackfillKey`].
+#[derive(Debug, Clone, Copy, Encodable, Decodable)]
+pub struct SpentNonceCountKey;
+
+impl DatabaseKeyPrefixConst for SpentNonceCountKey {
+    const DB_PREFIX: u8 = DB_PREFIX_SPENT_NONCE_COUNT;
+}
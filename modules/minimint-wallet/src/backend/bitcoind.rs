@@ -0,0 +1,38 @@
+use crate::backend::{BackendError, BitcoinBackend};
+use crate::Feerate;
+use bitcoin::{BlockHash, Network, Transaction};
+use bitcoincore_rpc::RpcApi;
+
+impl BitcoinBackend for bitcoincore_rpc::Client {
+    fn get_network(&self) -> Result<Network, BackendError> {
+        let bc = self.get_blockchain_info()?;
+        match bc.chain.as_str() {
+            "main" => Ok(Network::Bitcoin),
+            "test" => Ok(Network::Testnet),
+            "regtest" => Ok(Network::Regtest),
+            _ => Err(BackendError::UnknownNetwork(bc.chain)),
+        }
+    }
+
+    fn get_block_height(&self) -> Result<u64, BackendError> {
+        Ok(self.get_block_count()?)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, BackendError> {
+        Ok(RpcApi::get_block_hash(self, height)?)
+    }
+
+    fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, BackendError> {
+        Ok(self
+            .estimate_smart_fee(confirmation_target, None)?
+            .fee_rate
+            .map(|per_kb| Feerate {
+                sats_per_kvb: per_kb.as_sat(),
+            }))
+    }
+
+    fn submit_transaction(&self, tx: &Transaction) -> Result<(), BackendError> {
+        self.send_raw_transaction(tx)?;
+        Ok(())
+    }
+}
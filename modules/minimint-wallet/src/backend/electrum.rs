@@ -0,0 +1,224 @@
+use crate::backend::{BackendError, BitcoinBackend};
+use crate::Feerate;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hashes::hex::{FromHex, ToHex};
+use bitcoin::{BlockHash, BlockHeader, Network, Transaction};
+use serde_json::{json, Value};
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::{debug, trace};
+
+/// Default time after which connecting to, reading from or writing to the server fails. The wallet
+/// queries the backend while processing epochs, so a stalled server must not block it forever.
+const ELECTRUM_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Backend querying an Electrum server (e.g. electrs, which also serves the Esplora API) using its
+/// line-based JSON-RPC protocol over plain TCP. The connection is established lazily and
+/// re-established after IO errors, including timeouts.
+pub struct ElectrumBackend {
+    address: String,
+    timeout: Duration,
+    connection: Mutex<Option<ElectrumConnection>>,
+}
+
+struct ElectrumConnection {
+    reader: BufReader<TcpStream>,
+    writer: TcpStream,
+    next_id: u64,
+}
+
+impl ElectrumBackend {
+    /// Creates a backend for the Electrum server at `address` (`host:port`)
+    pub fn new(address: String) -> ElectrumBackend {
+        ElectrumBackend::with_timeout(address, ELECTRUM_TIMEOUT)
+    }
+
+    /// Like [`ElectrumBackend::new`], but IO operations fail after `timeout` instead of
+    /// [`ELECTRUM_TIMEOUT`]
+    pub fn with_timeout(address: String, timeout: Duration) -> ElectrumBackend {
+        ElectrumBackend {
+            address,
+            timeout,
+            connection: Mutex::new(None),
+        }
+    }
+
+    fn request(&self, method: &str, params: Value) -> Result<Value, BackendError> {
+        let mut connection = self.connection.lock().expect("Lock poisoned");
+        if connection.is_none() {
+            debug!("Connecting to Electrum server {}", self.address);
+            *connection = Some(ElectrumConnection::connect(&self.address, self.timeout)?);
+        }
+
+        let result = connection
+            .as_mut()
+            .expect("connected above")
+            .request(method, params);
+        if let Err(BackendError::Io(_)) = result {
+            *connection = None;
+        }
+        result
+    }
+}
+
+impl ElectrumConnection {
+    fn connect(address: &str, timeout: Duration) -> Result<ElectrumConnection, BackendError> {
+        let socket_addr = address.to_socket_addrs()?.next().ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::NotFound,
+                format!("Could not resolve {}", address),
+            )
+        })?;
+        let writer = TcpStream::connect_timeout(&socket_addr, timeout)?;
+        writer.set_read_timeout(Some(timeout))?;
+        writer.set_write_timeout(Some(timeout))?;
+        let reader = BufReader::new(writer.try_clone()?);
+        Ok(ElectrumConnection {
+            reader,
+            writer,
+            next_id: 0,
+        })
+    }
+
+    fn request(&mut self, method: &str, params: Value) -> Result<Value, BackendError> {
+        let id = self.next_id;
+        self.next_id += 1;
+
+        let mut request = json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "method": method,
+            "params": params,
+        })
+        .to_string();
+        request.push('\n');
+        trace!("Electrum request: {}", request.trim_end());
+        self.writer.write_all(request.as_bytes())?;
+        self.writer.flush()?;
+
+        // Skip notifications, e.g. about new blocks after subscribing to headers
+        loop {
+            let mut line = String::new();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Err(BackendError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+
+            let mut response: Value = serde_json::from_str(&line)
+                .map_err(|e| BackendError::MalformedResponse(e.to_string()))?;
+            if response["id"].as_u64() != Some(id) {
+                continue;
+            }
+
+            return match response["error"].take() {
+                Value::Null => Ok(response["result"].take()),
+                error => Err(BackendError::Electrum(error.to_string())),
+            };
+        }
+    }
+}
+
+impl BitcoinBackend for ElectrumBackend {
+    fn get_network(&self) -> Result<Network, BackendError> {
+        let features = self.request("server.features", json!([]))?;
+        let genesis_hash = features["genesis_hash"]
+            .as_str()
+            .ok_or_else(|| BackendError::MalformedResponse(features.to_string()))?;
+
+        [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ]
+        .iter()
+        .copied()
+        .find(|&network| genesis_block(network).block_hash().to_hex() == genesis_hash)
+        .ok_or_else(|| BackendError::UnknownNetwork(genesis_hash.to_owned()))
+    }
+
+    fn get_block_height(&self) -> Result<u64, BackendError> {
+        let tip = self.request("blockchain.headers.subscribe", json!([]))?;
+        tip["height"]
+            .as_u64()
+            .ok_or_else(|| BackendError::MalformedResponse(tip.to_string()))
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, BackendError> {
+        let header = self.request("blockchain.block.header", json!([height]))?;
+        let header: BlockHeader = header
+            .as_str()
+            .and_then(|hex| Vec::<u8>::from_hex(hex).ok())
+            .and_then(|bytes| bitcoin::consensus::deserialize(&bytes).ok())
+            .ok_or_else(|| BackendError::MalformedResponse(header.to_string()))?;
+        Ok(header.block_hash())
+    }
+
+    fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, BackendError> {
+        let btc_per_kvb = self.request("blockchain.estimatefee", json!([confirmation_target]))?;
+        let btc_per_kvb = btc_per_kvb
+            .as_f64()
+            .ok_or_else(|| BackendError::MalformedResponse(btc_per_kvb.to_string()))?;
+
+        // The server returns -1 if it can't estimate the fee rate
+        if btc_per_kvb < 0.0 {
+            return Ok(None);
+        }
+        Ok(Some(Feerate {
+            sats_per_kvb: (btc_per_kvb * 100_000_000.0).round() as u64,
+        }))
+    }
+
+    fn submit_transaction(&self, tx: &Transaction) -> Result<(), BackendError> {
+        let raw_tx = bitcoin::consensus::serialize(tx).to_hex();
+        self.request("blockchain.transaction.broadcast", json!([raw_tx]))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{BackendError, BitcoinBackend, ElectrumBackend};
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::time::Duration;
+
+    #[test]
+    fn test_stalled_server() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let backend = ElectrumBackend::with_timeout(
+            listener.local_addr().unwrap().to_string(),
+            Duration::from_millis(100),
+        );
+
+        // The server accepts the connection but never answers
+        let stalled = std::thread::spawn({
+            let listener = listener.try_clone().unwrap();
+            move || listener.accept().unwrap()
+        });
+        assert!(matches!(
+            backend.get_block_height(),
+            Err(BackendError::Io(_))
+        ));
+        let _stalled_connection = stalled.join().unwrap();
+
+        // After the timeout the backend reconnects on the next request
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream.try_clone().unwrap());
+            let mut request = String::new();
+            reader.read_line(&mut request).unwrap();
+            let request: serde_json::Value = serde_json::from_str(&request).unwrap();
+            assert_eq!(request["method"], "blockchain.headers.subscribe");
+            let response = serde_json::json!({
+                "jsonrpc": "2.0",
+                "id": request["id"],
+                "result": {"height": 42, "hex": ""},
+            });
+            writeln!(&stream, "{}", response).unwrap();
+        });
+        assert_eq!(backend.get_block_height().unwrap(), 42);
+        server.join().unwrap();
+    }
+}
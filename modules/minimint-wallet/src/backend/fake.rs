@@ -0,0 +1,284 @@
+use crate::backend::{BackendError, BitcoinBackend};
+use crate::Feerate;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::consensus::Encodable;
+use bitcoin::hashes::Hash as BitcoinHash;
+use bitcoin::{BlockHash, Network, Transaction, Txid};
+use std::sync::{Arc, Mutex};
+
+/// In-memory chain for tests that is fully controlled by the test: blocks are only mined and
+/// reorged on request and the mempool can be inspected, cleared or made to reject transactions.
+/// Clones are handles to the same chain, so a test can keep one while the wallet uses another.
+#[derive(Clone)]
+pub struct FakeBitcoinBackend {
+    chain: Arc<Mutex<FakeChain>>,
+}
+
+struct FakeChain {
+    network: Network,
+    /// Blocks of the best chain, starting with the genesis block at height 0
+    blocks: Vec<FakeBlock>,
+    mempool: Vec<Transaction>,
+    fee_rate: Option<Feerate>,
+    reject_transactions: bool,
+    unreachable: bool,
+    /// Makes the hashes of blocks replacing reorged ones differ from the original ones
+    nonce: u64,
+}
+
+struct FakeBlock {
+    hash: BlockHash,
+    transactions: Vec<Transaction>,
+}
+
+impl FakeBitcoinBackend {
+    /// Creates a chain of `network` that only contains its genesis block
+    pub fn new(network: Network) -> FakeBitcoinBackend {
+        let genesis = FakeBlock {
+            hash: genesis_block(network).block_hash(),
+            transactions: vec![],
+        };
+        FakeBitcoinBackend {
+            chain: Arc::new(Mutex::new(FakeChain {
+                network,
+                blocks: vec![genesis],
+                mempool: vec![],
+                fee_rate: None,
+                reject_transactions: false,
+                unreachable: false,
+                nonce: 0,
+            })),
+        }
+    }
+
+    /// Mines `count` blocks on top of the current tip, the first one confirms the whole mempool.
+    /// Returns the hashes of the new blocks.
+    pub fn mine_blocks(&self, count: u64) -> Vec<BlockHash> {
+        let mut chain = self.chain.lock().expect("Lock poisoned");
+        (0..count)
+            .map(|_| {
+                let transactions = std::mem::take(&mut chain.mempool);
+                chain.mine_block(transactions)
+            })
+            .collect()
+    }
+
+    /// Removes the `depth` most recent blocks from the best chain, their transactions return to
+    /// the mempool
+    pub fn disconnect_blocks(&self, depth: u64) {
+        let mut chain = self.chain.lock().expect("Lock poisoned");
+        assert!(
+            (depth as usize) < chain.blocks.len(),
+            "The genesis block can't be disconnected"
+        );
+
+        let fork_point = chain.blocks.len() - depth as usize;
+        let mut transactions = chain
+            .blocks
+            .split_off(fork_point)
+            .into_iter()
+            .flat_map(|block| block.transactions)
+            .collect::<Vec<_>>();
+        transactions.append(&mut chain.mempool);
+        chain.mempool = transactions;
+    }
+
+    /// Replaces the `depth` most recent blocks with `new_blocks` different ones. The transactions
+    /// of the replaced blocks are confirmed again in the first new block, use
+    /// [`FakeBitcoinBackend::disconnect_blocks`] and [`FakeBitcoinBackend::mine_blocks`] to alter
+    /// them in between.
+    pub fn reorg(&self, depth: u64, new_blocks: u64) -> Vec<BlockHash> {
+        self.disconnect_blocks(depth);
+        self.mine_blocks(new_blocks)
+    }
+
+    /// Transactions waiting to be mined
+    pub fn mempool(&self) -> Vec<Transaction> {
+        self.chain.lock().expect("Lock poisoned").mempool.clone()
+    }
+
+    /// Drops all unconfirmed transactions as if they were evicted from the mempool
+    pub fn clear_mempool(&self) {
+        self.chain.lock().expect("Lock poisoned").mempool.clear();
+    }
+
+    /// Sets the fee rate estimate returned to the wallet, `None` simulates a backend without
+    /// enough data for an estimate
+    pub fn set_fee_rate(&self, fee_rate: Option<Feerate>) {
+        self.chain.lock().expect("Lock poisoned").fee_rate = fee_rate;
+    }
+
+    /// Makes the mempool reject all further transactions, e.g. to simulate a backend outage
+    pub fn reject_transactions(&self, reject: bool) {
+        self.chain
+            .lock()
+            .expect("Lock poisoned")
+            .reject_transactions = reject;
+    }
+
+    /// Makes all queries of the chain state time out, e.g. to simulate a stalled Electrum server
+    pub fn set_unreachable(&self, unreachable: bool) {
+        self.chain.lock().expect("Lock poisoned").unreachable = unreachable;
+    }
+
+    /// Number of confirmations of `txid` in the best chain, `None` if it isn't confirmed
+    pub fn confirmations(&self, txid: Txid) -> Option<u64> {
+        let chain = self.chain.lock().expect("Lock poisoned");
+        chain
+            .blocks
+            .iter()
+            .position(|block| block.transactions.iter().any(|tx| tx.txid() == txid))
+            .map(|height| (chain.blocks.len() - height) as u64)
+    }
+}
+
+impl FakeChain {
+    fn mine_block(&mut self, transactions: Vec<Transaction>) -> BlockHash {
+        let mut engine = BlockHash::engine();
+        self.blocks
+            .last()
+            .expect("There is always a genesis block")
+            .hash
+            .consensus_encode(&mut engine)
+            .expect("Writing to a hash engine can't fail");
+        self.nonce
+            .consensus_encode(&mut engine)
+            .expect("Writing to a hash engine can't fail");
+        for tx in transactions.iter() {
+            tx.txid()
+                .consensus_encode(&mut engine)
+                .expect("Writing to a hash engine can't fail");
+        }
+        self.nonce += 1;
+
+        let hash = BlockHash::from_engine(engine);
+        self.blocks.push(FakeBlock { hash, transactions });
+        hash
+    }
+
+    fn check_reachable(&self) -> Result<(), BackendError> {
+        if self.unreachable {
+            return Err(BackendError::Io(std::io::ErrorKind::TimedOut.into()));
+        }
+        Ok(())
+    }
+
+    fn contains(&self, txid: Txid) -> bool {
+        self.mempool
+            .iter()
+            .chain(
+                self.blocks
+                    .iter()
+                    .flat_map(|block| block.transactions.iter()),
+            )
+            .any(|tx| tx.txid() == txid)
+    }
+}
+
+impl BitcoinBackend for FakeBitcoinBackend {
+    fn get_network(&self) -> Result<Network, BackendError> {
+        Ok(self.chain.lock().expect("Lock poisoned").network)
+    }
+
+    fn get_block_height(&self) -> Result<u64, BackendError> {
+        let chain = self.chain.lock().expect("Lock poisoned");
+        chain.check_reachable()?;
+        Ok(chain.blocks.len() as u64 - 1)
+    }
+
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, BackendError> {
+        let chain = self.chain.lock().expect("Lock poisoned");
+        chain.check_reachable()?;
+        chain
+            .blocks
+            .get(height as usize)
+            .map(|block| block.hash)
+            .ok_or(BackendError::UnknownBlock(height))
+    }
+
+    fn get_fee_rate(&self, _confirmation_target: u16) -> Result<Option<Feerate>, BackendError> {
+        let chain = self.chain.lock().expect("Lock poisoned");
+        chain.check_reachable()?;
+        Ok(chain.fee_rate)
+    }
+
+    fn submit_transaction(&self, tx: &Transaction) -> Result<(), BackendError> {
+        let mut chain = self.chain.lock().expect("Lock poisoned");
+        if chain.reject_transactions {
+            return Err(BackendError::TransactionRejected(tx.txid().to_string()));
+        }
+        // The wallet rebroadcasts its transactions periodically, known ones are ignored
+        if !chain.contains(tx.txid()) {
+            chain.mempool.push(tx.clone());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::backend::{BackendError, BitcoinBackend, FakeBitcoinBackend};
+    use crate::Feerate;
+    use bitcoin::{Network, Transaction};
+
+    fn tx(lock_time: u32) -> Transaction {
+        Transaction {
+            version: 2,
+            lock_time,
+            input: vec![],
+            output: vec![],
+        }
+    }
+
+    #[test]
+    fn test_fake_chain() {
+        let backend = FakeBitcoinBackend::new(Network::Regtest);
+        let wallet_view: &dyn BitcoinBackend = &backend.clone();
+        assert_eq!(wallet_view.get_network().unwrap(), Network::Regtest);
+        assert_eq!(wallet_view.get_block_height().unwrap(), 0);
+        assert_eq!(wallet_view.get_fee_rate(10).unwrap(), None);
+
+        backend.set_fee_rate(Some(Feerate { sats_per_kvb: 1000 }));
+        assert_eq!(
+            wallet_view.get_fee_rate(10).unwrap(),
+            Some(Feerate { sats_per_kvb: 1000 })
+        );
+
+        // Mined blocks confirm the mempool
+        wallet_view.submit_transaction(&tx(0)).unwrap();
+        wallet_view.submit_transaction(&tx(0)).unwrap();
+        assert_eq!(backend.mempool(), vec![tx(0)]);
+        let hashes = backend.mine_blocks(3);
+        assert_eq!(wallet_view.get_block_height().unwrap(), 3);
+        assert_eq!(wallet_view.get_block_hash(1).unwrap(), hashes[0]);
+        assert!(matches!(
+            wallet_view.get_block_hash(4),
+            Err(BackendError::UnknownBlock(4))
+        ));
+        assert!(backend.mempool().is_empty());
+        assert_eq!(backend.confirmations(tx(0).txid()), Some(3));
+
+        // Reorged blocks get new hashes and their transactions are confirmed again
+        wallet_view.submit_transaction(&tx(1)).unwrap();
+        let new_hashes = backend.reorg(3, 4);
+        assert_eq!(wallet_view.get_block_height().unwrap(), 4);
+        assert_ne!(wallet_view.get_block_hash(1).unwrap(), hashes[0]);
+        assert_eq!(wallet_view.get_block_hash(1).unwrap(), new_hashes[0]);
+        assert_eq!(backend.confirmations(tx(0).txid()), Some(4));
+        assert_eq!(backend.confirmations(tx(1).txid()), Some(4));
+
+        // Transactions dropped while disconnected never confirm
+        backend.disconnect_blocks(4);
+        assert_eq!(backend.mempool(), vec![tx(0), tx(1)]);
+        backend.clear_mempool();
+        backend.mine_blocks(1);
+        assert_eq!(backend.confirmations(tx(0).txid()), None);
+
+        backend.reject_transactions(true);
+        assert!(matches!(
+            wallet_view.submit_transaction(&tx(2)),
+            Err(BackendError::TransactionRejected(_))
+        ));
+        assert!(backend.mempool().is_empty());
+    }
+}
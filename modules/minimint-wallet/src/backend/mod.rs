@@ -0,0 +1,67 @@
+//! Access of the wallet to the bitcoin network. The wallet only needs a handful of queries, which
+//! every [`BitcoinBackend`] implements for its source of blockchain data: a bitcoind node, an
+//! Electrum server or, for tests, an in-memory chain.
+
+mod bitcoind;
+pub mod electrum;
+pub mod fake;
+
+use crate::config::WalletConfig;
+use crate::Feerate;
+use bitcoin::{BlockHash, Network, Transaction};
+use bitcoincore_rpc::Auth;
+use std::sync::Arc;
+use thiserror::Error;
+
+pub use electrum::ElectrumBackend;
+pub use fake::FakeBitcoinBackend;
+
+/// Source of the blockchain data the wallet needs to follow the chain, estimate fees and publish
+/// its peg-out transactions. All calls block, the wallet runs them using
+/// [`tokio::task::block_in_place`].
+pub trait BitcoinBackend: Send + Sync {
+    /// Network of the chain the backend follows
+    fn get_network(&self) -> Result<Network, BackendError>;
+
+    /// Height of the current chain tip
+    fn get_block_height(&self) -> Result<u64, BackendError>;
+
+    /// Hash of the block at `height` in the current best chain
+    fn get_block_hash(&self, height: u64) -> Result<BlockHash, BackendError>;
+
+    /// Fee rate that should get a transaction confirmed within `confirmation_target` blocks,
+    /// `None` if the backend has no estimate yet
+    fn get_fee_rate(&self, confirmation_target: u16) -> Result<Option<Feerate>, BackendError>;
+
+    /// Publishes `tx` to the network
+    fn submit_transaction(&self, tx: &Transaction) -> Result<(), BackendError>;
+}
+
+#[derive(Debug, Error)]
+pub enum BackendError {
+    #[error("bitcoind RPC error: {0}")]
+    Bitcoind(#[from] bitcoincore_rpc::Error),
+    #[error("Electrum server error: {0}")]
+    Electrum(String),
+    #[error("Malformed response from the backend: {0}")]
+    MalformedResponse(String),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Unknown bitcoin network: {0}")]
+    UnknownNetwork(String),
+    #[error("No block at height {0}")]
+    UnknownBlock(u64),
+    #[error("Transaction rejected: {0}")]
+    TransactionRejected(String),
+}
+
+/// Creates the backend configured in `cfg`: an Electrum server if one is set, bitcoind otherwise
+pub fn backend_from_config(cfg: &WalletConfig) -> Result<Arc<dyn BitcoinBackend>, BackendError> {
+    match &cfg.electrum_address {
+        Some(address) => Ok(Arc::new(ElectrumBackend::new(address.clone()))),
+        None => Ok(Arc::new(bitcoincore_rpc::Client::new(
+            &cfg.btc_rpc_address,
+            Auth::UserPass(cfg.btc_rpc_user.clone(), cfg.btc_rpc_pass.clone()),
+        )?)),
+    }
+}
@@ -1,7 +1,8 @@
 use crate::Feerate;
+use async_trait::async_trait;
 use bitcoin::secp256k1::rand::{CryptoRng, RngCore};
 use bitcoin::Network;
-use minimint_api::config::GenerateConfig;
+use minimint_api::config::{broadcast, DkgConnections, DkgError, GenerateConfig};
 use minimint_api::{CompressedPublicKey, PeerId, PegInDescriptor};
 use miniscript::descriptor::Wsh;
 use serde::{Deserialize, Serialize};
@@ -18,6 +19,21 @@ pub struct WalletConfig {
     pub btc_rpc_address: String,
     pub btc_rpc_user: String,
     pub btc_rpc_pass: String,
+    /// Electrum server (`host:port`) the wallet queries instead of bitcoind if set
+    #[serde(default)]
+    pub electrum_address: Option<String>,
+    /// Peg-in wallet of the federation before its last membership change, its funds are swept to
+    /// the current peg-in descriptor
+    #[serde(default)]
+    pub legacy_peg_in: Option<LegacyPegInConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct LegacyPegInConfig {
+    pub peg_in_descriptor: PegInDescriptor,
+    pub peer_peg_in_keys: BTreeMap<PeerId, CompressedPublicKey>,
+    /// Our key if we were part of the federation before the membership change
+    pub peg_in_key: Option<secp256k1::SecretKey>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -26,6 +42,7 @@ pub struct WalletClientConfig {
     pub network: Network,
 }
 
+#[async_trait(?Send)]
 impl GenerateConfig for WalletConfig {
     type Params = ();
     type ClientConfig = WalletClientConfig;
@@ -42,45 +59,138 @@ impl GenerateConfig for WalletConfig {
             .iter()
             .map(|&id| (id, secp.generate_keypair(&mut rng)))
             .collect::<Vec<_>>();
-
-        let peg_in_descriptor = PegInDescriptor::Wsh(
-            Wsh::new_sortedmulti(
-                peers.len() - max_evil,
-                btc_pegin_keys
-                    .iter()
-                    .map(|(_, (_, pk))| CompressedPublicKey { key: *pk })
-                    .collect(),
-            )
-            .unwrap(),
-        );
+        let peer_peg_in_keys = btc_pegin_keys
+            .iter()
+            .map(|(peer_id, (_, pk))| (*peer_id, CompressedPublicKey { key: *pk }))
+            .collect::<BTreeMap<_, _>>();
 
         let wallet_cfg = btc_pegin_keys
             .iter()
             .map(|(id, (sk, _))| {
-                let cfg = WalletConfig {
-                    network: Network::Regtest,
-                    peg_in_descriptor: peg_in_descriptor.clone(), // TODO: remove redundancy?
-                    peer_peg_in_keys: btc_pegin_keys
-                        .iter()
-                        .map(|(peer_id, (_, pk))| (*peer_id, CompressedPublicKey { key: *pk }))
-                        .collect(),
-                    peg_in_key: *sk,
-                    finalty_delay: 10,
-                    default_fee: Feerate { sats_per_kvb: 2000 },
-                    btc_rpc_address: "127.0.0.1:18443".to_string(),
-                    btc_rpc_user: "bitcoin".to_string(),
-                    btc_rpc_pass: "bitcoin".to_string(),
-                };
-
+                let cfg = WalletConfig::new(peer_peg_in_keys.clone(), *sk, peers.len() - max_evil);
                 (*id, cfg)
             })
             .collect();
 
-        let client_cfg = WalletClientConfig {
-            peg_in_descriptor,
-            network: Network::Regtest,
-        };
+        let client_cfg = WalletClientConfig::new(peer_peg_in_keys, peers.len() - max_evil);
 
         (wallet_cfg, client_cfg)
     }
+
+    /// Every peer generates its own peg-in key and sends the public key to all other peers.
+    /// Whether all peers received the same keys has to be checked by the caller.
+    async fn distributed_gen<R>(
+        connections: &mut dyn DkgConnections,
+        max_evil: usize,
+        _params: &Self::Params,
+        mut rng: R,
+    ) -> Result<(Self, Self::ClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        let secp = secp256k1::Secp256k1::new();
+        let (sk, pk) = secp.generate_keypair(&mut rng);
+
+        let peer_peg_in_keys =
+            broadcast(connections, "wallet", CompressedPublicKey { key: pk }).await?;
+        let threshold = connections.peers().len() - max_evil;
+
+        Ok((
+            WalletConfig::new(peer_peg_in_keys.clone(), sk, threshold),
+            WalletClientConfig::new(peer_peg_in_keys, threshold),
+        ))
+    }
+}
+
+impl WalletConfig {
+    pub fn client_config(&self) -> WalletClientConfig {
+        WalletClientConfig {
+            peg_in_descriptor: self.peg_in_descriptor.clone(),
+            network: self.network,
+        }
+    }
+
+    fn new(
+        peer_peg_in_keys: BTreeMap<PeerId, CompressedPublicKey>,
+        peg_in_key: secp256k1::SecretKey,
+        threshold: usize,
+    ) -> WalletConfig {
+        WalletConfig {
+            network: Network::Regtest,
+            peg_in_descriptor: peg_in_descriptor(&peer_peg_in_keys, threshold), // TODO: remove redundancy?
+            peer_peg_in_keys,
+            peg_in_key,
+            finalty_delay: 10,
+            default_fee: Feerate { sats_per_kvb: 2000 },
+            btc_rpc_address: "127.0.0.1:18443".to_string(),
+            btc_rpc_user: "bitcoin".to_string(),
+            btc_rpc_pass: "bitcoin".to_string(),
+            electrum_address: None,
+            legacy_peg_in: None,
+        }
+    }
+
+    /// Generates new peg-in keys for a federation whose membership changed, the wallet described
+    /// by `old_client_cfg` and `old_peer_peg_in_keys` becomes the legacy wallet that is swept
+    /// once the change took effect. Peers that were part of the old federation pass their
+    /// `old_cfg` to be able to sign the sweep and to keep their local settings.
+    pub async fn reshare<R>(
+        connections: &mut dyn DkgConnections,
+        max_evil: usize,
+        old_cfg: Option<&WalletConfig>,
+        old_client_cfg: &WalletClientConfig,
+        old_peer_peg_in_keys: &BTreeMap<PeerId, CompressedPublicKey>,
+        old_threshold: usize,
+        rng: R,
+    ) -> Result<(WalletConfig, WalletClientConfig), DkgError>
+    where
+        R: RngCore + CryptoRng,
+    {
+        if peg_in_descriptor(old_peer_peg_in_keys, old_threshold)
+            != old_client_cfg.peg_in_descriptor
+        {
+            return Err(DkgError::ConfigMismatch);
+        }
+
+        let (mut cfg, mut client_cfg) =
+            WalletConfig::distributed_gen(connections, max_evil, &(), rng).await?;
+        cfg.network = old_client_cfg.network;
+        client_cfg.network = old_client_cfg.network;
+        if let Some(old_cfg) = old_cfg {
+            cfg.finalty_delay = old_cfg.finalty_delay;
+            cfg.default_fee = old_cfg.default_fee;
+            cfg.btc_rpc_address = old_cfg.btc_rpc_address.clone();
+            cfg.btc_rpc_user = old_cfg.btc_rpc_user.clone();
+            cfg.btc_rpc_pass = old_cfg.btc_rpc_pass.clone();
+            cfg.electrum_address = old_cfg.electrum_address.clone();
+        }
+        cfg.legacy_peg_in = Some(LegacyPegInConfig {
+            peg_in_descriptor: old_client_cfg.peg_in_descriptor.clone(),
+            peer_peg_in_keys: old_peer_peg_in_keys.clone(),
+            peg_in_key: old_cfg.map(|old_cfg| old_cfg.peg_in_key),
+        });
+
+        Ok((cfg, client_cfg))
+    }
+}
+
+impl WalletClientConfig {
+    fn new(
+        peer_peg_in_keys: BTreeMap<PeerId, CompressedPublicKey>,
+        threshold: usize,
+    ) -> WalletClientConfig {
+        WalletClientConfig {
+            peg_in_descriptor: peg_in_descriptor(&peer_peg_in_keys, threshold),
+            network: Network::Regtest,
+        }
+    }
+}
+
+pub fn peg_in_descriptor(
+    peer_peg_in_keys: &BTreeMap<PeerId, CompressedPublicKey>,
+    threshold: usize,
+) -> PegInDescriptor {
+    PegInDescriptor::Wsh(
+        Wsh::new_sortedmulti(threshold, peer_peg_in_keys.values().cloned().collect()).unwrap(),
+    )
 }
@@ -10,6 +10,16 @@ const DB_PREFIX_UNSIGNED_TRANSACTION: u8 = 0x34;
 const DB_PREFIX_PENDING_TRANSACTION: u8 = 0x35;
 const DB_PREFIX_PEG_OUT_TX_SIG_CI: u8 = 0x36;
 
+/// Prefixes of the state all peers agree on, our signatures of peg-out transactions are local
+pub const CONSENSUS_DB_PREFIXES: &[u8] = &[
+    DB_PREFIX_BLOCK_HASH,
+    DB_PREFIX_UTXO,
+    DB_PREFIX_ROUND_CONSENSUS,
+    DB_PREFIX_PEDNING_PEGOUT,
+    DB_PREFIX_UNSIGNED_TRANSACTION,
+    DB_PREFIX_PENDING_TRANSACTION,
+];
+
 #[derive(Clone, Debug, Encodable, Decodable)]
 pub struct BlockHashKey(pub BlockHash);
 
@@ -59,6 +69,13 @@ impl DatabaseKeyPrefixConst for UnsignedTransactionKey {
     const DB_PREFIX: u8 = DB_PREFIX_UNSIGNED_TRANSACTION;
 }
 
+#[derive(Clone, Debug, Encodable, Decodable)]
+pub struct UnsignedTransactionPrefixKey;
+
+impl DatabaseKeyPrefixConst for UnsignedTransactionPrefixKey {
+    const DB_PREFIX: u8 = DB_PREFIX_UNSIGNED_TRANSACTION;
+}
+
 #[derive(Clone, Debug, Encodable, Decodable)]
 pub struct PendingTransactionKey(pub Txid);
 
@@ -1,11 +1,14 @@
+pub mod backend;
 pub mod config;
 mod db;
 
+use crate::backend::{backend_from_config, BackendError, BitcoinBackend};
 use crate::config::WalletConfig;
 use crate::db::{
     BlockHashKey, PegOutTxSignatureCI, PegOutTxSignatureCIPrefix, PendingPegOutKey,
     PendingPegOutPrefixKey, PendingTransaction, PendingTransactionKey, PendingTransactionPrefixKey,
     RoundConsensusKey, UTXOKey, UTXOPrefixKey, UnsignedTransactionKey,
+    UnsignedTransactionPrefixKey,
 };
 use async_trait::async_trait;
 use bitcoin::hashes::hex::ToHex;
@@ -17,22 +20,27 @@ use bitcoin::util::psbt::{Global, Input, PartiallySignedTransaction};
 use bitcoin::{
     Address, AddressType, BlockHash, Network, Script, SigHashType, Transaction, TxIn, TxOut, Txid,
 };
-use bitcoincore_rpc::{Auth, RpcApi};
 use itertools::Itertools;
 use minimint_api::db::batch::{BatchItem, BatchTx};
 use minimint_api::db::{Database, RawDatabase};
 use minimint_api::encoding::{Decodable, Encodable};
+use minimint_api::metrics::{CounterVec, Histogram, MetricsEncoder, DURATION_BUCKETS};
+use minimint_api::module::{ErrorCode, RandomnessBeacon};
 use minimint_api::transaction::{OutPoint, PegOut};
 use minimint_api::{
-    CompressedPublicKey, FederationModule, PeerId, PegInProof, PegInProofError, Tweakable,
+    CompressedPublicKey, FederationModule, PeerId, PegInDescriptor, PegInProof, PegInProofError,
+    Tweakable,
 };
 use minimint_derive::UnzipConsensus;
 use miniscript::{Descriptor, DescriptorTrait, TranslatePk2};
-use rand::{CryptoRng, Rng, RngCore};
+use rand::{CryptoRng, RngCore};
 use secp256k1::{Message, Signature};
 use serde::{Deserialize, Serialize};
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, HashSet};
 use std::hash::Hasher;
 use std::sync::Arc;
+use std::time::Instant;
 use thiserror::Error;
 use tokio::time::Duration;
 use tracing::{debug, error, info, trace, warn};
@@ -44,22 +52,26 @@ pub const CONFIRMATION_TARGET: u16 = 24;
 /// waiting for 10 blocks, would cross a minimum urgency threshold of 100.  
 pub const MIN_PEG_OUT_URGENCY: u32 = 100;
 
+/// Maximum number of legacy UTXOs swept by a single transaction
+pub const MAX_SWEEP_INPUTS: usize = 100;
+
 pub type PartialSig = Vec<u8>;
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, UnzipConsensus)]
+#[derive(
+    Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Encodable, Decodable, UnzipConsensus,
+)]
 pub enum WalletConsensusItem {
     RoundConsensus(RoundConsensusItem),
     PegOutSignature(PegOutSignatureItem),
 }
 
-#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Encodable, Decodable)]
 pub struct RoundConsensusItem {
     block_height: u32, // FIXME: use block hash instead, but needs more complicated verification logic
     fee_rate: Feerate,
-    randomness: [u8; 32],
 }
 
-#[derive(Clone, Debug, Serialize, Deserialize)]
+#[derive(Clone, Debug, Serialize, Deserialize, Encodable, Decodable)]
 pub struct PegOutSignatureItem {
     txid: Txid,
     signature: Vec<secp256k1::Signature>,
@@ -69,14 +81,72 @@ pub struct PegOutSignatureItem {
 pub struct RoundConsensus {
     block_height: u32,
     fee_rate: Feerate,
-    randomness_beacon: [u8; 32],
+    /// Value of the federation's [`RandomnessBeacon`] produced in this epoch, which the change
+    /// tweaks of peg-out and sweep transactions are derived from. `None` if no beacon was
+    /// produced, reusing an earlier one would reuse change addresses.
+    randomness_beacon: Option<[u8; 32]>,
+}
+
+/// Summary of the wallet's state, reported by the guardian's admin API
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WalletStatus {
+    /// Block height and fee rate of the last round consensus, `None` if none was reached yet
+    pub consensus_height: Option<u32>,
+    pub fee_rate: Option<Feerate>,
+    /// Number of peg-outs waiting to be included in a peg-out transaction
+    pub pending_peg_outs: usize,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub pending_peg_out_amount: bitcoin::Amount,
+    /// Peg-out transactions that still lack signatures
+    pub unsigned_transactions: Vec<Txid>,
+    /// Fully signed transactions that are broadcast until they confirm
+    pub pending_transactions: Vec<Txid>,
+}
+
+/// Funds of the federation's wallet, reported by the guardian's audit API. Anyone can check them
+/// against their own view of the blockchain since every UTXO is locked to the script of a peg-in
+/// descriptor tweaked with the UTXO's tweak.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WalletAudit {
+    pub peg_in_descriptor: PegInDescriptor,
+    /// Descriptor of the legacy wallet, whose UTXOs are swept to `peg_in_descriptor`
+    pub legacy_peg_in_descriptor: Option<PegInDescriptor>,
+    pub utxos: Vec<AuditUTXO>,
+    /// Total value of `utxos`
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub reserves: bitcoin::Amount,
+    /// Total value of the requested peg-outs that aren't part of a peg-out transaction yet, these
+    /// are paid from the reserves
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub pending_peg_outs: bitcoin::Amount,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditUTXO {
+    pub out_point: bitcoin::OutPoint,
+    #[serde(with = "bitcoin::util::amount::serde::as_sat")]
+    pub amount: bitcoin::Amount,
+    pub tweak: secp256k1::schnorrsig::PublicKey,
+    pub script_pubkey: Script,
+    /// Whether this is the change output of one of our peg-out transactions, it might not be
+    /// confirmed yet
+    pub peg_out_change: bool,
 }
 
 pub struct Wallet {
     cfg: WalletConfig,
     secp: Secp256k1<All>,
-    btc_rpc: bitcoincore_rpc::Client,
+    backend: Arc<dyn BitcoinBackend>,
     db: Arc<dyn RawDatabase>,
+    metrics: Arc<WalletMetrics>,
+}
+
+/// Metrics of the wallet that are tracked continuously, the others are read from the database
+/// when scraped
+struct WalletMetrics {
+    rpc_duration: Histogram,
+    /// Failed calls to the bitcoin backend by method
+    rpc_errors: CounterVec,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize, Encodable, Decodable)]
@@ -132,44 +202,21 @@ impl FederationModule for Wallet {
 
     async fn consensus_proposal<'a>(
         &'a self,
-        mut rng: impl RngCore + CryptoRng + 'a,
+        _rng: impl RngCore + CryptoRng + 'a,
     ) -> Vec<Self::ConsensusItem> {
-        // TODO: implement retry logic in case bitcoind is temporarily unreachable
-        let our_network_height =
-            tokio::task::block_in_place(|| self.btc_rpc.get_block_count()).unwrap() as u32;
-        let our_target_height = our_network_height.saturating_sub(self.cfg.finalty_delay);
-
-        // In case the wallet just got created the height is not committed to the DB yet but will
-        // be set to 0 first, so we can assume that here.
-        let last_consensus_height = self.consensus_height().unwrap_or(0);
-
-        let proposed_height = if our_target_height >= last_consensus_height {
-            our_target_height
-        } else {
-            warn!(
-                "The block height shrunk, new proposal would be {}, but we are sticking to the last consensus height {}.",
-                our_target_height,
-                last_consensus_height
-            );
-            last_consensus_height
+        // If the backend is unreachable we don't propose a block height and fee rate this epoch and
+        // the other peers' proposals are used. The failure is counted in the RPC error metrics.
+        let round_ci = match self.round_consensus_proposal() {
+            Ok(proposal) => Some(WalletConsensusItem::RoundConsensus(proposal)),
+            Err(e) => {
+                warn!(
+                    "Skipping the round consensus proposal, the bitcoin backend failed: {}",
+                    e
+                );
+                None
+            }
         };
 
-        let fee_rate = tokio::task::block_in_place(|| {
-            self.btc_rpc.estimate_smart_fee(CONFIRMATION_TARGET, None)
-        })
-        .unwrap() // TODO: implement retry logic in case bitcoind is temporarily unreachable
-        .fee_rate
-        .map(|per_kb| Feerate {
-            sats_per_kvb: per_kb.as_sat(),
-        })
-        .unwrap_or(self.cfg.default_fee);
-
-        let round_ci = WalletConsensusItem::RoundConsensus(RoundConsensusItem {
-            block_height: proposed_height,
-            fee_rate,
-            randomness: rng.gen(),
-        });
-
         self.db
             .find_by_prefix::<_, PegOutTxSignatureCI, Vec<Signature>>(&PegOutTxSignatureCIPrefix)
             .map(|res| {
@@ -179,13 +226,15 @@ impl FederationModule for Wallet {
                     signature: val,
                 })
             })
-            .chain(std::iter::once(round_ci))
+            .chain(round_ci)
             .collect()
     }
 
     async fn begin_consensus_epoch<'a>(
         &'a self,
         mut batch: BatchTx<'a>,
+        _epoch: u64,
+        beacon: Option<RandomnessBeacon>,
         consensus_items: Vec<(PeerId, Self::ConsensusItem)>,
         _rng: impl RngCore + CryptoRng + 'a,
     ) {
@@ -198,17 +247,53 @@ impl FederationModule for Wallet {
             round_consensus,
         } = consensus_items.into_iter().unzip_wallet_consensus_item();
 
-        // Apply signatures to peg-out tx
+        // Apply signatures to peg-out tx. All signatures of this epoch have to be collected in
+        // memory first since the batch isn't visible in the DB yet.
+        let mut psbts = BTreeMap::<Txid, PartiallySignedTransaction>::new();
         for (peer, sig) in peg_out_signatures {
-            if let Err(e) = self.process_peg_out_signature(batch.subtransaction(), peer, &sig) {
+            let psbt = match psbts.entry(sig.txid) {
+                Entry::Occupied(entry) => entry.into_mut(),
+                Entry::Vacant(entry) => match self
+                    .db
+                    .get_value::<_, PartiallySignedTransaction>(&UnsignedTransactionKey(sig.txid))
+                    .expect("DB error")
+                {
+                    Some(psbt) => entry.insert(psbt),
+                    None => {
+                        warn!(
+                            "Error processing peer {}'s peg-out signature: {}",
+                            peer,
+                            ProcessPegOutSigError::UnknownTransaction(sig.txid)
+                        );
+                        continue;
+                    }
+                },
+            };
+
+            if let Err(e) = self.add_peg_out_signature(psbt, peer, &sig) {
                 warn!("Error processing peer {}'s peg-out signature: {}", peer, e)
             };
         }
 
+        for (txid, psbt) in psbts {
+            self.finalize_peg_out_tx(batch.subtransaction(), txid, psbt);
+        }
+
         // FIXME: also warn on less than 1/3, that should never happen
-        // Make sure we have enough contributions to continue
+        // If no peer could reach its bitcoin backend we keep the last block height and fee rate
         if round_consensus.is_empty() {
-            panic!("No proposals were submitted this round");
+            warn!("No round consensus proposals were submitted this round");
+            if let Some(last_round_consensus) = self.current_round_consensus() {
+                batch.append_insert(
+                    RoundConsensusKey,
+                    RoundConsensus {
+                        randomness_beacon: beacon.map(|beacon| beacon.value),
+                        ..last_round_consensus
+                    },
+                );
+            }
+            batch.commit();
+            return;
         }
 
         let fee_proposals = round_consensus.iter().map(|(_, rc)| rc.fee_rate).collect();
@@ -222,16 +307,10 @@ impl FederationModule for Wallet {
             .process_block_height_proposals(batch.subtransaction(), height_proposals)
             .await;
 
-        let randomness_contributions = round_consensus
-            .iter()
-            .map(|(_, rc)| rc.randomness)
-            .collect();
-        let randomness_beacon = self.process_randomness_contributions(randomness_contributions);
-
         let round_consensus = RoundConsensus {
             block_height,
             fee_rate,
-            randomness_beacon,
+            randomness_beacon: beacon.map(|beacon| beacon.value),
         };
 
         batch.append_insert(RoundConsensusKey, round_consensus);
@@ -325,16 +404,19 @@ impl FederationModule for Wallet {
             Some(consensus) => consensus,
             None => return,
         };
+        // Both peg-out and sweep transactions need a fresh beacon to derive their change tweak,
+        // they wait for the next one otherwise
+        let randomness_beacon = match round_consensus.randomness_beacon {
+            Some(beacon) => beacon,
+            None => return,
+        };
 
         // Check if we should create a peg-out transaction
         let (peg_out_ids, pending_peg_outs): (
             Vec<minimint_api::transaction::OutPoint>,
             Vec<PendingPegOut>,
         ) = self.pending_peg_outs().into_iter().unzip();
-        let urgency = pending_peg_outs
-            .iter()
-            .map(|peg_out| round_consensus.block_height - peg_out.pending_since_block)
-            .sum::<u32>();
+        let urgency = peg_out_urgency(round_consensus.block_height, &pending_peg_outs);
 
         trace!(
             "Pending peg outs: {}, urgency: {}, urgency threshold: {}",
@@ -343,11 +425,13 @@ impl FederationModule for Wallet {
             MIN_PEG_OUT_URGENCY
         );
 
-        // We only want to peg out if we have a real randomness beacon after the first consensus round
-        let peg_out_ready = self.current_round_consensus().is_some(); // TODO: maybe destructure instead?
-        if urgency > MIN_PEG_OUT_URGENCY && peg_out_ready {
+        if urgency > MIN_PEG_OUT_URGENCY {
             let mut psbt = self
-                .create_peg_out_tx(pending_peg_outs, round_consensus)
+                .create_peg_out_tx(
+                    pending_peg_outs,
+                    round_consensus.fee_rate,
+                    &randomness_beacon,
+                )
                 .await;
             let txid = psbt.global.unsigned_tx.txid();
 
@@ -389,6 +473,14 @@ impl FederationModule for Wallet {
             batch.append_insert_new(UnsignedTransactionKey(psbt.global.unsigned_tx.txid()), psbt);
             batch.append_insert_new(PegOutTxSignatureCI(txid), sigs);
         }
+
+        if self.cfg.legacy_peg_in.is_some() {
+            self.sweep_legacy_utxos(
+                batch.subtransaction(),
+                round_consensus.fee_rate,
+                &randomness_beacon,
+            );
+        }
         batch.commit();
     }
 
@@ -396,75 +488,128 @@ impl FederationModule for Wallet {
         // TODO: return BTC tx id once included in peg-out tx
         Some(())
     }
+
+    fn encode_metrics(&self, encoder: &mut MetricsEncoder) {
+        let (utxo_count, utxo_value) = self
+            .db
+            .find_by_prefix::<_, UTXOKey, SpendableUTXO>(&UTXOPrefixKey)
+            .map(|res| res.expect("DB error").1.amount)
+            .fold((0u64, 0u64), |(count, value), amount| {
+                (count + 1, value + amount.as_sat())
+            });
+        encoder.gauge(
+            "wallet_utxos",
+            "Number of UTXOs controlled by the federation",
+            utxo_count as f64,
+        );
+        encoder.gauge(
+            "wallet_utxo_value_sats",
+            "Total value of the UTXOs controlled by the federation",
+            utxo_value as f64,
+        );
+
+        let pending_peg_outs = self
+            .pending_peg_outs()
+            .into_iter()
+            .map(|(_, peg_out)| peg_out)
+            .collect::<Vec<_>>();
+        let urgency = self
+            .consensus_height()
+            .map_or(0, |height| peg_out_urgency(height, &pending_peg_outs));
+        encoder.gauge(
+            "wallet_pending_peg_outs",
+            "Number of peg-outs waiting to be included in a peg-out transaction",
+            pending_peg_outs.len() as f64,
+        );
+        encoder.gauge(
+            "wallet_peg_out_urgency",
+            "Sum of the blocks every pending peg-out has been waiting for",
+            urgency as f64,
+        );
+        encoder.gauge(
+            "wallet_min_peg_out_urgency",
+            "Urgency above which a peg-out transaction is created",
+            MIN_PEG_OUT_URGENCY as f64,
+        );
+
+        encoder.histogram(
+            "wallet_backend_call_duration_seconds",
+            "Latency of calls to the bitcoin backend",
+            &self.metrics.rpc_duration,
+        );
+        encoder.counter_vec(
+            "wallet_backend_call_errors_total",
+            "Failed calls to the bitcoin backend by method",
+            &self.metrics.rpc_errors,
+        );
+    }
+
+    fn consensus_db_prefixes(&self) -> &'static [u8] {
+        crate::db::CONSENSUS_DB_PREFIXES
+    }
 }
 
 impl Wallet {
+    /// Creates a wallet using the bitcoin backend configured in `cfg`
     pub async fn new(cfg: WalletConfig, db: Arc<dyn RawDatabase>) -> Result<Wallet, WalletError> {
-        let broadcaster_cfg = cfg.clone();
-        let broadcaster_db = db.clone();
-        tokio::spawn(async move {
-            let btc_rpc_address = broadcaster_cfg.btc_rpc_address.clone();
-
-            // FIXME: become resilient against bitcoind outages
-            let btc_rpc = bitcoincore_rpc::Client::new(
-                &btc_rpc_address,
-                Auth::UserPass(
-                    broadcaster_cfg.btc_rpc_user.clone(),
-                    broadcaster_cfg.btc_rpc_pass.clone(),
-                ),
-            )
-            .expect("Failed to connect to bitcoind");
-
-            broadcast_pending_tx(broadcaster_db, btc_rpc).await;
-        });
-
-        let btc_rpc = bitcoincore_rpc::Client::new(
-            &cfg.btc_rpc_address,
-            Auth::UserPass(cfg.btc_rpc_user.clone(), cfg.btc_rpc_pass.clone()),
-        )?;
+        let backend = backend_from_config(&cfg)?;
+        Wallet::new_with_backend(cfg, db, backend).await
+    }
 
-        let bitcoind_net = get_network(&btc_rpc).await?;
-        if bitcoind_net != cfg.network {
-            return Err(WalletError::WrongNetwork(cfg.network, bitcoind_net));
+    /// Creates a wallet that gets its blockchain data from `backend`, e.g. a
+    /// [`backend::FakeBitcoinBackend`] in tests
+    pub async fn new_with_backend(
+        cfg: WalletConfig,
+        db: Arc<dyn RawDatabase>,
+        backend: Arc<dyn BitcoinBackend>,
+    ) -> Result<Wallet, WalletError> {
+        let metrics = Arc::new(WalletMetrics::default());
+
+        let backend_net = metrics.rpc_call("get_network", || backend.get_network())?;
+        if backend_net != cfg.network {
+            return Err(WalletError::WrongNetwork(cfg.network, backend_net));
         }
 
+        // FIXME: become resilient against backend outages
+        tokio::spawn(broadcast_pending_tx(
+            db.clone(),
+            backend.clone(),
+            metrics.clone(),
+        ));
+
         let wallet = Wallet {
             cfg,
             secp: Default::default(),
-            btc_rpc,
+            backend,
             db,
+            metrics,
         };
 
         Ok(wallet)
     }
 
-    pub fn process_randomness_contributions(&self, randomness: Vec<[u8; 32]>) -> [u8; 32] {
-        fn xor(mut lhs: [u8; 32], rhs: [u8; 32]) -> [u8; 32] {
-            lhs.iter_mut().zip(rhs).for_each(|(lhs, rhs)| *lhs ^= rhs);
-            lhs
-        }
-
-        randomness.into_iter().fold([0; 32], xor)
-    }
-
-    /// Try to attach a contributed signature to a pending peg-out tx and try to finalize it.
-    fn process_peg_out_signature(
+    /// Try to attach a contributed signature to a pending peg-out tx. The `psbt` is only modified
+    /// if all signatures are valid.
+    fn add_peg_out_signature(
         &self,
-        mut batch: BatchTx,
+        psbt: &mut PartiallySignedTransaction,
         peer: PeerId,
         signature: &PegOutSignatureItem,
     ) -> Result<(), ProcessPegOutSigError> {
-        let mut psbt = self
-            .db
-            .get_value::<_, PartiallySignedTransaction>(&UnsignedTransactionKey(signature.txid))
-            .expect("DB error")
-            .ok_or(ProcessPegOutSigError::UnknownTransaction(signature.txid))?;
-
-        let peer_key = self
-            .cfg
-            .peer_peg_in_keys
+        let peer_keys = if is_sweep(psbt) {
+            &self
+                .cfg
+                .legacy_peg_in
+                .as_ref()
+                .expect("Sweeps are only created if there is a legacy wallet")
+                .peer_peg_in_keys
+        } else {
+            &self.cfg.peer_peg_in_keys
+        };
+        // Not all current peers were part of the legacy wallet
+        let peer_key = peer_keys
             .get(&peer)
-            .expect("always called with valid peer id");
+            .ok_or(ProcessPegOutSigError::UnknownSigner(peer))?;
 
         if psbt.inputs.len() != signature.signature.len() {
             return Err(ProcessPegOutSigError::WrongSignatureCount(
@@ -473,9 +618,9 @@ impl Wallet {
             ));
         }
 
+        let mut signed_inputs = psbt.inputs.clone();
         let mut tx_hasher = SigHashCache::new(&psbt.global.unsigned_tx);
-        for (idx, (input, signature)) in psbt
-            .inputs
+        for (idx, (input, signature)) in signed_inputs
             .iter_mut()
             .zip(signature.signature.iter())
             .enumerate()
@@ -520,6 +665,18 @@ impl Wallet {
             }
         }
 
+        psbt.inputs = signed_inputs;
+        Ok(())
+    }
+
+    /// Finalizes the peg-out tx `txid` if enough signatures were collected, otherwise saves the
+    /// collected signatures.
+    fn finalize_peg_out_tx(
+        &self,
+        mut batch: BatchTx,
+        txid: Txid,
+        mut psbt: PartiallySignedTransaction,
+    ) {
         // FIXME: actually recognize change UTXOs on maturity
         // We need to save the change output's tweak key to be able to access the funds later on.
         // The tweak is extracted here because the psbt is moved next and not available anymore
@@ -533,12 +690,12 @@ impl Wallet {
         match miniscript::psbt::finalize(&mut psbt, &self.secp) {
             Ok(()) => {}
             Err(e) => {
-                trace!("can't finalize peg-out tx {} yet: {}", signature.txid, e);
+                trace!("can't finalize peg-out tx {} yet: {}", txid, e);
 
                 // We want to save the new signature, so we need to overwrite the PSBT
-                batch.append_insert(UnsignedTransactionKey(signature.txid), psbt);
+                batch.append_insert(UnsignedTransactionKey(txid), psbt);
                 batch.commit();
-                return Ok(());
+                return;
             }
         }
 
@@ -552,25 +709,46 @@ impl Wallet {
                 );
 
                 // Who knows what went wrong, we still want to save the received signature
-                batch.append_insert(UnsignedTransactionKey(signature.txid), psbt);
+                batch.append_insert(UnsignedTransactionKey(txid), psbt);
                 batch.commit();
-                return Ok(());
+                return;
             }
         };
 
+        // The output of a sweep is spendable right away, peg-outs spending it before it confirms
+        // are only mined together with or after the sweep
+        if is_sweep(&psbt) {
+            let tweak = change_tweak
+                .as_ref()
+                .and_then(|tweak| secp256k1::schnorrsig::PublicKey::from_slice(tweak).ok())
+                .expect("Sweeps always pay to a tweaked key");
+            let output = &tx.output[0];
+            info!(
+                "Sweep {} of the legacy wallet is final, adding {} sat to the wallet",
+                txid, output.value
+            );
+            batch.append_insert_new(
+                UTXOKey(bitcoin::OutPoint { txid, vout: 0 }),
+                SpendableUTXO {
+                    tweak,
+                    amount: bitcoin::Amount::from_sat(output.value),
+                    script_pubkey: output.script_pubkey.clone(),
+                },
+            );
+        }
+
         // We were able to finalize the transaction, so we will delete the PSBT and instead keep the
         // extracted tx for periodic transmission and to accept the change into our wallet
         // eventually once it confirms.
-        batch.append_delete(UnsignedTransactionKey(signature.txid));
+        batch.append_delete(UnsignedTransactionKey(txid));
         batch.append_insert_new(
-            PendingTransactionKey(signature.txid),
+            PendingTransactionKey(txid),
             PendingTransaction {
                 tx,
                 tweak: change_tweak,
             },
         );
         batch.commit();
-        Ok(())
     }
 
     /// # Panics
@@ -619,10 +797,52 @@ impl Wallet {
             .expect("DB error")
     }
 
+    /// Block height and fee rate we propose according to our bitcoin backend
+    fn round_consensus_proposal(&self) -> Result<RoundConsensusItem, BackendError> {
+        let our_network_height = self
+            .metrics
+            .rpc_call("get_block_height", || self.backend.get_block_height())?
+            as u32;
+        let our_target_height = our_network_height.saturating_sub(self.cfg.finalty_delay);
+
+        // In case the wallet just got created the height is not committed to the DB yet but will
+        // be set to 0 first, so we can assume that here.
+        let last_consensus_height = self.consensus_height().unwrap_or(0);
+
+        let proposed_height = if our_target_height >= last_consensus_height {
+            our_target_height
+        } else {
+            warn!(
+                "The block height shrunk, new proposal would be {}, but we are sticking to the last consensus height {}.",
+                our_target_height,
+                last_consensus_height
+            );
+            last_consensus_height
+        };
+
+        let fee_rate = self
+            .metrics
+            .rpc_call("get_fee_rate", || {
+                self.backend.get_fee_rate(CONFIRMATION_TARGET)
+            })?
+            .unwrap_or(self.cfg.default_fee);
+
+        Ok(RoundConsensusItem {
+            block_height: proposed_height,
+            fee_rate,
+        })
+    }
+
     pub fn consensus_height(&self) -> Option<u32> {
         self.current_round_consensus().map(|rc| rc.block_height)
     }
 
+    /// Value of the beacon produced in the current epoch, see [`RoundConsensus`]
+    pub fn randomness_beacon(&self) -> Option<[u8; 32]> {
+        self.current_round_consensus()
+            .and_then(|rc| rc.randomness_beacon)
+    }
+
     async fn sync_up_to_consensus_heigh(&self, mut batch: BatchTx<'_>, new_height: u32) {
         let old_height = self.consensus_height().unwrap_or(0);
         if new_height < old_height {
@@ -653,9 +873,12 @@ impl Wallet {
             // TODO: use batching for mainnet syncing
             trace!("Fetching block hash for block {}", height);
             // TODO: implement retying failed RPC commands till they succeed while loudly complaining to alert the operator
-            let block_hash =
-                tokio::task::block_in_place(|| self.btc_rpc.get_block_hash(height as u64))
-                    .expect("Ignoring failure here would throw us out of consensus");
+            let block_hash = self
+                .metrics
+                .rpc_call("get_block_hash", || {
+                    self.backend.get_block_hash(height as u64)
+                })
+                .expect("Ignoring failure here would throw us out of consensus");
             batch.append_insert_new(
                 BlockHashKey(BlockHash::from_inner(block_hash.into_inner())),
                 (),
@@ -679,28 +902,294 @@ impl Wallet {
             .expect("DB error")
     }
 
+    pub fn status(&self) -> WalletStatus {
+        let round_consensus = self.current_round_consensus();
+        let pending_peg_outs = self.pending_peg_outs();
+        let unsigned_transactions = self
+            .db
+            .find_by_prefix::<_, UnsignedTransactionKey, PartiallySignedTransaction>(
+                &UnsignedTransactionPrefixKey,
+            )
+            .map_ok(|(key, _)| key.0)
+            .collect::<Result<_, _>>()
+            .expect("DB error");
+        let pending_transactions = self
+            .db
+            .find_by_prefix::<_, PendingTransactionKey, PendingTransaction>(
+                &PendingTransactionPrefixKey,
+            )
+            .map_ok(|(key, _)| key.0)
+            .collect::<Result<_, _>>()
+            .expect("DB error");
+
+        WalletStatus {
+            consensus_height: round_consensus.as_ref().map(|rc| rc.block_height),
+            fee_rate: round_consensus.map(|rc| rc.fee_rate),
+            pending_peg_outs: pending_peg_outs.len(),
+            pending_peg_out_amount: pending_peg_outs
+                .iter()
+                .map(|(_, peg_out)| peg_out.amount)
+                .fold(bitcoin::Amount::from_sat(0), |a, b| a + b),
+            unsigned_transactions,
+            pending_transactions,
+        }
+    }
+
+    pub fn audit(&self) -> WalletAudit {
+        let mut utxos = self
+            .db
+            .find_by_prefix::<_, UTXOKey, SpendableUTXO>(&UTXOPrefixKey)
+            .map_ok(|(key, utxo)| AuditUTXO {
+                out_point: key.0,
+                amount: utxo.amount,
+                tweak: utxo.tweak,
+                script_pubkey: utxo.script_pubkey,
+                peg_out_change: false,
+            })
+            .collect::<Result<Vec<_>, _>>()
+            .expect("DB error");
+
+        // The change of peg-out transactions isn't added to our UTXOs, but it's ours nonetheless
+        let known_utxos = utxos
+            .iter()
+            .map(|utxo| utxo.out_point)
+            .collect::<HashSet<_>>();
+        let peg_out_txs = self.peg_out_transactions();
+        let spent = peg_out_txs
+            .iter()
+            .flat_map(|(tx, _)| tx.input.iter().map(|input| input.previous_output))
+            .collect::<HashSet<_>>();
+        for (tx, tweak) in &peg_out_txs {
+            let tweak = match tweak {
+                Some(tweak) => *tweak,
+                None => continue,
+            };
+            let change_script = self
+                .cfg
+                .peg_in_descriptor
+                .tweak(&tweak, &self.secp)
+                .script_pubkey();
+            for (vout, output) in tx.output.iter().enumerate() {
+                let out_point = bitcoin::OutPoint {
+                    txid: tx.txid(),
+                    vout: vout as u32,
+                };
+                if output.script_pubkey == change_script
+                    && !known_utxos.contains(&out_point)
+                    && !spent.contains(&out_point)
+                {
+                    utxos.push(AuditUTXO {
+                        out_point,
+                        amount: bitcoin::Amount::from_sat(output.value),
+                        tweak,
+                        script_pubkey: output.script_pubkey.clone(),
+                        peg_out_change: true,
+                    });
+                }
+            }
+        }
+
+        WalletAudit {
+            peg_in_descriptor: self.cfg.peg_in_descriptor.clone(),
+            legacy_peg_in_descriptor: self
+                .cfg
+                .legacy_peg_in
+                .as_ref()
+                .map(|legacy| legacy.peg_in_descriptor.clone()),
+            reserves: utxos
+                .iter()
+                .map(|utxo| utxo.amount)
+                .fold(bitcoin::Amount::from_sat(0), |a, b| a + b),
+            utxos,
+            pending_peg_outs: self
+                .pending_peg_outs()
+                .iter()
+                .map(|(_, peg_out)| peg_out.amount)
+                .fold(bitcoin::Amount::from_sat(0), |a, b| a + b),
+        }
+    }
+
+    /// All unsigned and pending peg-out transactions, including sweeps of the legacy wallet,
+    /// together with the tweak of their change output if they have one
+    fn peg_out_transactions(&self) -> Vec<(Transaction, Option<secp256k1::schnorrsig::PublicKey>)> {
+        let parse_tweak = |tweak: &[u8]| {
+            secp256k1::schnorrsig::PublicKey::from_slice(tweak)
+                .expect("We only create change outputs with valid tweaks")
+        };
+
+        let unsigned = self
+            .db
+            .find_by_prefix::<_, UnsignedTransactionKey, PartiallySignedTransaction>(
+                &UnsignedTransactionPrefixKey,
+            )
+            .map_ok(|(_, psbt)| {
+                let tweak = psbt
+                    .outputs
+                    .iter()
+                    .flat_map(|output| output.proprietary.get(&proprietary_tweak_key()))
+                    .next()
+                    .map(|tweak| parse_tweak(tweak));
+                (psbt.global.unsigned_tx, tweak)
+            });
+        let pending = self
+            .db
+            .find_by_prefix::<_, PendingTransactionKey, PendingTransaction>(
+                &PendingTransactionPrefixKey,
+            )
+            .map_ok(|(_, pending)| {
+                let tweak = pending.tweak.as_deref().map(parse_tweak);
+                (pending.tx, tweak)
+            });
+
+        unsigned
+            .chain(pending)
+            .collect::<Result<_, _>>()
+            .expect("DB error")
+    }
+
     async fn create_peg_out_tx(
         &self,
         pending_peg_outs: Vec<PendingPegOut>,
-        consensus: RoundConsensus,
+        fee_rate: Feerate,
+        randomness_beacon: &[u8; 32],
     ) -> PartiallySignedTransaction {
         let wallet = self.offline_wallet();
         let mut psbt = wallet.create_tx(
             pending_peg_outs,
             self.available_utxos(),
-            consensus.fee_rate,
-            &consensus.randomness_beacon,
+            fee_rate,
+            randomness_beacon,
         );
         // TODO: extract sigs and do stuff?!
         wallet.sign_psbt(&mut psbt);
         psbt
     }
 
+    /// UTXOs locked to our current peg-in descriptor
     fn available_utxos(&self) -> Vec<(UTXOKey, SpendableUTXO)> {
-        self.db
+        self.utxos(false)
+    }
+
+    /// UTXOs still locked to the peg-in descriptor of the legacy wallet
+    fn legacy_utxos(&self) -> Vec<(UTXOKey, SpendableUTXO)> {
+        self.utxos(true)
+    }
+
+    fn utxos(&self, legacy: bool) -> Vec<(UTXOKey, SpendableUTXO)> {
+        let utxos = self
+            .db
             .find_by_prefix::<_, UTXOKey, SpendableUTXO>(&UTXOPrefixKey)
-            .collect::<Result<_, _>>()
-            .expect("DB error")
+            .collect::<Result<Vec<_>, _>>()
+            .expect("DB error");
+
+        if self.cfg.legacy_peg_in.is_none() {
+            return if legacy { vec![] } else { utxos };
+        }
+
+        utxos
+            .into_iter()
+            .filter(|(_, utxo)| {
+                let is_current = self
+                    .cfg
+                    .peg_in_descriptor
+                    .tweak(&utxo.tweak, &self.secp)
+                    .script_pubkey()
+                    == utxo.script_pubkey;
+                is_current != legacy
+            })
+            .collect()
+    }
+
+    /// Moves the funds of the legacy wallet to our current peg-in descriptor. All peers create
+    /// the same sweep transaction since it only depends on consensus state, the peers that were
+    /// part of the legacy wallet sign it like a peg-out.
+    fn sweep_legacy_utxos(
+        &self,
+        mut batch: BatchTx,
+        fee_rate: Feerate,
+        randomness_beacon: &[u8; 32],
+    ) {
+        let legacy = self
+            .cfg
+            .legacy_peg_in
+            .as_ref()
+            .expect("Only called with a legacy wallet");
+
+        let mut utxos = self.legacy_utxos();
+        if utxos.is_empty() {
+            return;
+        }
+        utxos.sort_by_key(|(_, utxo)| std::cmp::Reverse(utxo.amount));
+        utxos.truncate(MAX_SWEEP_INPUTS);
+
+        let tweak = {
+            let seed = sha256::Hash::hash(randomness_beacon);
+            let key_pair = secp256k1::schnorrsig::KeyPair::from_seckey_slice(&self.secp, &seed[..])
+                .expect("Hash is a valid secret key with overwhelming probability");
+            secp256k1::schnorrsig::PublicKey::from_keypair(&self.secp, &key_pair)
+        };
+        let destination = self
+            .cfg
+            .peg_in_descriptor
+            .tweak(&tweak, &self.secp)
+            .script_pubkey();
+
+        // Creating the transaction doesn't involve the secret key, it is only signed with our
+        // legacy key below
+        let legacy_wallet = StatelessWallet {
+            descriptor: &legacy.peg_in_descriptor,
+            secret_key: &self.cfg.peg_in_key,
+            secp: &self.secp,
+        };
+        let mut psbt = match legacy_wallet.create_sweep_tx(
+            utxos.clone(),
+            destination,
+            &tweak.serialize(),
+            fee_rate,
+        ) {
+            Some(psbt) => psbt,
+            None => {
+                debug!("Legacy wallet funds don't cover the fees of sweeping them yet");
+                return;
+            }
+        };
+        let txid = psbt.global.unsigned_tx.txid();
+        info!(
+            "Sweeping {} UTXOs of the legacy wallet in tx {}",
+            utxos.len(),
+            txid
+        );
+
+        if let Some(legacy_key) = legacy.peg_in_key.as_ref() {
+            let signing_wallet = StatelessWallet {
+                secret_key: legacy_key,
+                ..legacy_wallet
+            };
+            signing_wallet.sign_psbt(&mut psbt);
+            let sigs = psbt
+                .inputs
+                .iter_mut()
+                .map(|input| {
+                    // Like for peg-outs we take out our own signature so everyone finalizes the
+                    // tx in the same epoch
+                    let sig = std::mem::take(&mut input.partial_sigs)
+                        .into_values()
+                        .next()
+                        .expect("We just signed");
+                    secp256k1::Signature::from_der(&sig[..sig.len() - 1])
+                        .expect("we serialized it ourselves that way")
+                })
+                .collect::<Vec<_>>();
+            batch.append_insert_new(PegOutTxSignatureCI(txid), sigs);
+        }
+
+        batch.append_from_iter(
+            utxos
+                .into_iter()
+                .map(|(utxo_key, _)| BatchItem::delete(utxo_key)),
+        );
+        batch.append_insert_new(UnsignedTransactionKey(txid), psbt);
+        batch.commit();
     }
 
     fn offline_wallet(&self) -> StatelessWallet {
@@ -803,10 +1292,83 @@ impl<'a> StatelessWallet<'a> {
             change.as_btc()
         );
 
+        let outputs = outputs
+            .iter()
+            .map(|peg_out| (peg_out, None))
+            .chain(
+                change_output
+                    .as_ref()
+                    .map(|change| (change, Some(change_tweak))),
+            )
+            .map(|(peg_out, tweak)| {
+                let tx_out = TxOut {
+                    value: peg_out.amount.as_sat(),
+                    script_pubkey: peg_out.destination.clone(),
+                };
+                (tx_out, tweak)
+            })
+            .collect();
+        let psbt = self.build_psbt(selected_utxos, outputs);
+        info!("Creating peg-out tx {}", psbt.global.unsigned_tx.txid());
+
+        psbt
+    }
+
+    /// Creates a transaction spending all `utxos` to `destination`, which has to be derived from
+    /// the federation's current peg-in descriptor with `destination_tweak`. Returns `None` if the
+    /// `utxos` are not worth more than the fees.
+    fn create_sweep_tx(
+        &self,
+        utxos: Vec<(UTXOKey, SpendableUTXO)>,
+        destination: Script,
+        destination_tweak: &[u8],
+        feerate: Feerate,
+    ) -> Option<PartiallySignedTransaction> {
+        let max_input_weight = self
+            .descriptor
+            .max_satisfaction_weight()
+            .expect("is satisfyable")
+            + 128 // TxOutHash
+            + 16 // TxOutIndex
+            + 16; // sequence
+        let weight = 16 // version
+            + 12 // up to 2**16-1 inputs
+            + 12 // up to 2**16-1 outputs
+            + 1 + destination.len() * 4 + 32 // output
+            + 16 // lock time
+            + utxos.len() * max_input_weight;
+
+        let total_value = utxos
+            .iter()
+            .map(|(_, utxo)| utxo.amount)
+            .fold(bitcoin::Amount::from_sat(0), |a, b| a + b);
+        let fees = feerate.calculate_fee(weight);
+        if total_value < fees + destination.dust_value() {
+            return None;
+        }
+
+        let output = TxOut {
+            value: (total_value - fees).as_sat(),
+            script_pubkey: destination,
+        };
+        let mut psbt = self.build_psbt(utxos, vec![(output, Some(destination_tweak))]);
+        psbt.global
+            .proprietary
+            .insert(proprietary_sweep_key(), vec![]);
+        Some(psbt)
+    }
+
+    /// Builds an unsigned PSBT spending `utxos`, which have to be locked to our descriptor.
+    /// Outputs paying back to us carry the tweak they were derived with.
+    fn build_psbt(
+        &self,
+        utxos: Vec<(UTXOKey, SpendableUTXO)>,
+        outputs: Vec<(TxOut, Option<&[u8]>)>,
+    ) -> PartiallySignedTransaction {
         let transaction = Transaction {
             version: 2,
             lock_time: 0,
-            input: selected_utxos
+            input: utxos
                 .iter()
                 .map(|(utxo_key, _utxo)| TxIn {
                     previous_output: utxo_key.0,
@@ -815,19 +1377,11 @@ impl<'a> StatelessWallet<'a> {
                     witness: vec![],
                 })
                 .collect(),
-            output: outputs
-                .iter()
-                .chain(change_output.as_ref())
-                .map(|peg_out| TxOut {
-                    value: peg_out.amount.as_sat(),
-                    script_pubkey: peg_out.destination.clone(),
-                })
-                .collect(),
+            output: outputs.iter().map(|(tx_out, _)| tx_out.clone()).collect(),
         };
-        info!("Creating peg-out tx {}", transaction.txid());
 
         // FIXME: use custom data structure that guarantees more invariants and only convert to PSBT for finalization
-        let psbt = PartiallySignedTransaction {
+        PartiallySignedTransaction {
             global: Global {
                 unsigned_tx: transaction,
                 version: 0,
@@ -835,7 +1389,7 @@ impl<'a> StatelessWallet<'a> {
                 proprietary: Default::default(),
                 unknown: Default::default(),
             },
-            inputs: selected_utxos
+            inputs: utxos
                 .into_iter()
                 .map(|(_utxo_key, utxo)| Input {
                     non_witness_utxo: None,
@@ -863,18 +1417,18 @@ impl<'a> StatelessWallet<'a> {
                 })
                 .collect(),
             outputs: outputs
-                .iter()
-                .map(|_| Default::default())
-                .chain(change_output.map(|_| {
-                    let mut cout = bitcoin::util::psbt::Output::default();
-                    cout.proprietary
-                        .insert(proprietary_tweak_key(), change_tweak.to_vec());
-                    cout
-                }))
+                .into_iter()
+                .map(|(_, tweak)| {
+                    let mut output = bitcoin::util::psbt::Output::default();
+                    if let Some(tweak) = tweak {
+                        output
+                            .proprietary
+                            .insert(proprietary_tweak_key(), tweak.to_vec());
+                    }
+                    output
+                })
                 .collect(),
-        };
-
-        psbt
+        }
     }
 
     fn sign_psbt(&self, psbt: &mut PartiallySignedTransaction) {
@@ -958,24 +1512,29 @@ impl<'a> StatelessWallet<'a> {
     }
 }
 
-async fn get_network(rpc_client: &bitcoincore_rpc::Client) -> Result<Network, WalletError> {
-    let bc = tokio::task::block_in_place(|| rpc_client.get_blockchain_info())?;
-    match bc.chain.as_str() {
-        "main" => Ok(Network::Bitcoin),
-        "test" => Ok(Network::Testnet),
-        "regtest" => Ok(Network::Regtest),
-        _ => Err(WalletError::UnknownNetwork(bc.chain)),
+fn proprietary_tweak_key() -> ProprietaryKey {
+    ProprietaryKey {
+        prefix: b"minimint".to_vec(),
+        subtype: 0x00,
+        key: vec![],
     }
 }
 
-fn proprietary_tweak_key() -> ProprietaryKey {
+/// Marks a PSBT as a sweep of the legacy wallet, which is signed with the legacy peg-in keys
+fn proprietary_sweep_key() -> ProprietaryKey {
     ProprietaryKey {
         prefix: b"minimint".to_vec(),
-        subtype: 0x00,
+        subtype: 0x01,
         key: vec![],
     }
 }
 
+fn is_sweep(psbt: &PartiallySignedTransaction) -> bool {
+    psbt.global
+        .proprietary
+        .contains_key(&proprietary_sweep_key())
+}
+
 pub fn is_address_valid_for_network(address: &Address, network: Network) -> bool {
     match (address.network, address.address_type()) {
         (Network::Testnet, Some(AddressType::P2pkh))
@@ -987,7 +1546,11 @@ pub fn is_address_valid_for_network(address: &Address, network: Network) -> bool
     }
 }
 
-async fn broadcast_pending_tx(db: Arc<dyn RawDatabase>, rpc: bitcoincore_rpc::Client) {
+async fn broadcast_pending_tx(
+    db: Arc<dyn RawDatabase>,
+    backend: Arc<dyn BitcoinBackend>,
+    metrics: Arc<WalletMetrics>,
+) {
     loop {
         let pending_tx = db
             .find_by_prefix::<_, PendingTransactionKey, PendingTransaction>(
@@ -997,17 +1560,18 @@ async fn broadcast_pending_tx(db: Arc<dyn RawDatabase>, rpc: bitcoincore_rpc::Cl
             .expect("DB error");
 
         for (_, PendingTransaction { tx, .. }) in pending_tx {
-            let mut raw_tx = Vec::new();
-            tx.consensus_encode(&mut raw_tx)
-                .expect("Nothing can go wrong with a vec");
-
             trace!(
                 "Broadcasting peg-out tx {} (weight {})",
                 tx.txid(),
                 tx.get_weight()
             );
-            trace!("Transaction: {}", raw_tx.to_hex());
-            if let Err(e) = tokio::task::block_in_place(|| rpc.send_raw_transaction(&raw_tx)) {
+            trace!(
+                "Transaction: {}",
+                bitcoin::consensus::serialize(&tx).to_hex()
+            );
+            if let Err(e) =
+                metrics.rpc_call("submit_transaction", || backend.submit_transaction(&tx))
+            {
                 // FIXME: resubmit periodically, also in case it drops out of the mempool
                 trace!("Could not submit peg out transaction: {}", e);
             }
@@ -1016,6 +1580,41 @@ async fn broadcast_pending_tx(db: Arc<dyn RawDatabase>, rpc: bitcoincore_rpc::Cl
     }
 }
 
+/// Sum of the blocks every pending peg-out has been waiting for at `consensus_height`, a peg-out
+/// transaction is created once it exceeds [`MIN_PEG_OUT_URGENCY`]
+fn peg_out_urgency(consensus_height: u32, pending_peg_outs: &[PendingPegOut]) -> u32 {
+    pending_peg_outs
+        .iter()
+        .map(|peg_out| consensus_height - peg_out.pending_since_block)
+        .sum()
+}
+
+impl Default for WalletMetrics {
+    fn default() -> Self {
+        WalletMetrics {
+            rpc_duration: Histogram::new(DURATION_BUCKETS),
+            rpc_errors: CounterVec::new("method"),
+        }
+    }
+}
+
+impl WalletMetrics {
+    /// Runs a blocking call to the bitcoin backend, recording its latency and whether it failed
+    fn rpc_call<T>(
+        &self,
+        method: &str,
+        call: impl FnOnce() -> Result<T, BackendError>,
+    ) -> Result<T, BackendError> {
+        let start = Instant::now();
+        let result = tokio::task::block_in_place(call);
+        self.rpc_duration.observe_duration(start.elapsed());
+        if result.is_err() {
+            self.rpc_errors.inc(method);
+        }
+        result
+    }
+}
+
 impl Feerate {
     pub fn calculate_fee(&self, weight: usize) -> bitcoin::Amount {
         let sats = self.sats_per_kvb * (weight as u64) / 1000;
@@ -1042,12 +1641,10 @@ impl Eq for PegOutSignatureItem {}
 
 #[derive(Debug, Error)]
 pub enum WalletError {
-    #[error("Connected bitcoind is on wrong network, expected {0}, got {1}")]
+    #[error("Wrong bitcoin network, expected {0}, got {1}")]
     WrongNetwork(Network, Network),
-    #[error("Error querying bitcoind: {0}")]
-    RpcErrot(bitcoincore_rpc::Error),
-    #[error("Unknown bitcoin network: {0}")]
-    UnknownNetwork(String),
+    #[error("Error querying the bitcoin backend: {0}")]
+    RpcErrot(BackendError),
     #[error("Unknown block hash in peg-in proof: {0}")]
     UnknownPegInProofBlock(BlockHash),
     #[error("Invalid peg-in proof: {0}")]
@@ -1068,10 +1665,24 @@ pub enum ProcessPegOutSigError {
     InvalidSignature,
     #[error("Duplicate signature")]
     DuplicateSignature,
+    #[error("Peer {0} is not allowed to sign this transaction")]
+    UnknownSigner(PeerId),
 }
 
-impl From<bitcoincore_rpc::Error> for WalletError {
-    fn from(e: bitcoincore_rpc::Error) -> Self {
+impl ErrorCode for WalletError {
+    fn error_code(&self) -> &'static str {
+        match self {
+            WalletError::WrongNetwork(_, _) => "wallet_wrong_network",
+            WalletError::RpcErrot(_) => "wallet_rpc_error",
+            WalletError::UnknownPegInProofBlock(_) => "wallet_unknown_peg_in_proof_block",
+            WalletError::PegInProofError(_) => "wallet_invalid_peg_in_proof",
+            WalletError::PegInAlreadyClaimed => "wallet_peg_in_already_claimed",
+        }
+    }
+}
+
+impl From<BackendError> for WalletError {
+    fn from(e: BackendError) -> Self {
         WalletError::RpcErrot(e)
     }
 }
@@ -1085,15 +1696,409 @@ impl From<PegInProofError> for WalletError {
 #[cfg(test)]
 mod tests {
     use super::Feerate;
-    use crate::db::UTXOKey;
-    use crate::{PendingPegOut, SpendableUTXO, StatelessWallet};
+    use crate::backend::{BackendError, BitcoinBackend, FakeBitcoinBackend};
+    use crate::config::{LegacyPegInConfig, WalletConfig};
+    use crate::db::{
+        PegOutTxSignatureCI, PegOutTxSignatureCIPrefix, PendingPegOutKey, PendingTransactionKey,
+        RoundConsensusKey, UTXOKey, UnsignedTransactionKey,
+    };
+    use crate::{
+        PegOutSignatureItem, PendingPegOut, PendingTransaction, ProcessPegOutSigError,
+        RoundConsensus, RoundConsensusItem, SpendableUTXO, StatelessWallet, Wallet,
+        WalletConsensusItem, WalletError,
+    };
     use bitcoin::hashes::Hash as BitcoinHash;
-    use bitcoin::{Address, Amount, OutPoint, TxOut};
-    use minimint_api::{CompressedPublicKey, Tweakable};
+    use bitcoin::util::psbt::PartiallySignedTransaction;
+    use bitcoin::{Address, Amount, BlockHash, Network, OutPoint, TxOut};
+    use minimint_api::config::GenerateConfig;
+    use minimint_api::db::Database;
+    use minimint_api::module::testing::FakeFed;
+    use minimint_api::module::ErrorCode;
+    use minimint_api::{
+        CompressedPublicKey, FederationModule, PeerId, PegInProofError, TransactionId, Tweakable,
+    };
     use miniscript::descriptor::Wsh;
     use miniscript::policy::Concrete;
     use miniscript::{Descriptor, DescriptorTrait, Segwitv0};
+    use secp256k1::Signature;
     use std::str::FromStr;
+    use std::sync::Arc;
+
+    const PEERS: u16 = 4;
+    const MAX_EVIL: usize = 1;
+    const CONSENSUS_HEIGHT: u32 = 1000;
+    const CONSENSUS_FEE: Feerate = Feerate { sats_per_kvb: 2000 };
+
+    /// Builds a federation of wallets that already agreed on [`CONSENSUS_HEIGHT`]. As long as the
+    /// honest peers keep proposing that height the fake bitcoin backend is never queried.
+    fn build_fed() -> FakeFed<Wallet> {
+        build_fed_with(|_, _| {})
+    }
+
+    /// Like [`build_fed`], but the peers' configs are modified by `patch_cfg` first
+    fn build_fed_with(patch_cfg: impl Fn(PeerId, &mut WalletConfig)) -> FakeFed<Wallet> {
+        build_fed_on(&FakeBitcoinBackend::new(Network::Regtest), patch_cfg)
+    }
+
+    /// Like [`build_fed_with`], but all peers follow the chain of `backend`
+    fn build_fed_on(
+        backend: &FakeBitcoinBackend,
+        patch_cfg: impl Fn(PeerId, &mut WalletConfig),
+    ) -> FakeFed<Wallet> {
+        let peers = (0..PEERS).map(PeerId::from).collect::<Vec<_>>();
+        let (mut wallet_cfg, _) = WalletConfig::trusted_dealer_gen(
+            &peers,
+            MAX_EVIL,
+            &(),
+            rand::rngs::OsRng::new().unwrap(),
+        );
+        for (peer, cfg) in wallet_cfg.iter_mut() {
+            patch_cfg(*peer, cfg);
+        }
+
+        let fed = FakeFed::new(PEERS, |peer, db| Wallet {
+            cfg: wallet_cfg[&peer].clone(),
+            secp: Default::default(),
+            backend: Arc::new(backend.clone()),
+            db,
+            metrics: Default::default(),
+        });
+        fed.patch_dbs(|_, db| {
+            db.insert_entry(
+                &RoundConsensusKey,
+                &RoundConsensus {
+                    block_height: CONSENSUS_HEIGHT,
+                    fee_rate: CONSENSUS_FEE,
+                    randomness_beacon: Some([0; 32]),
+                },
+            )
+            .unwrap();
+        });
+        fed
+    }
+
+    /// Consensus proposal of an honest wallet that doesn't depend on the bitcoin backend
+    fn honest_proposal(_peer: PeerId, wallet: &Wallet) -> Vec<WalletConsensusItem> {
+        wallet
+            .db
+            .find_by_prefix::<_, PegOutTxSignatureCI, Vec<Signature>>(&PegOutTxSignatureCIPrefix)
+            .map(|res| {
+                let (key, signature) = res.unwrap();
+                WalletConsensusItem::PegOutSignature(PegOutSignatureItem {
+                    txid: key.0,
+                    signature,
+                })
+            })
+            .chain(std::iter::once(WalletConsensusItem::RoundConsensus(
+                RoundConsensusItem {
+                    block_height: CONSENSUS_HEIGHT,
+                    fee_rate: CONSENSUS_FEE,
+                },
+            )))
+            .collect()
+    }
+
+    /// Lets the federation sign a peg-out while the last peer alters its peg-out signatures using
+    /// `behaviour`. Returns the error an honest peer reports for the byzantine signature.
+    async fn peg_out_with_byzantine_peer(
+        behaviour: fn(PegOutSignatureItem) -> PegOutSignatureItem,
+    ) -> ProcessPegOutSigError {
+        let mut fed = build_fed();
+        let byzantine = PeerId::from(PEERS - 1);
+        fed.make_byzantine(byzantine, move |items: Vec<WalletConsensusItem>| {
+            items
+                .into_iter()
+                .map(|item| match item {
+                    WalletConsensusItem::PegOutSignature(sig) => {
+                        WalletConsensusItem::PegOutSignature(behaviour(sig))
+                    }
+                    item => item,
+                })
+                .collect()
+        });
+
+        // Fund the federation and queue a peg-out that is urgent enough to be processed
+        let secp = secp256k1::Secp256k1::new();
+        let tweak = secp256k1::schnorrsig::PublicKey::from_slice(&[0x02; 32][..]).unwrap();
+        fed.patch_dbs(|peer, db| {
+            let wallet = fed.member(peer).0;
+            db.insert_entry(
+                &UTXOKey(OutPoint::new(
+                    BitcoinHash::from_slice(&[1u8; 32]).unwrap(),
+                    1,
+                )),
+                &SpendableUTXO {
+                    tweak,
+                    amount: Amount::from_sat(100_000),
+                    script_pubkey: wallet
+                        .cfg
+                        .peg_in_descriptor
+                        .tweak(&tweak, &secp)
+                        .script_pubkey(),
+                },
+            )
+            .unwrap();
+            db.insert_entry(
+                &PendingPegOutKey(minimint_api::transaction::OutPoint {
+                    txid: TransactionId::from_inner([42; 32]),
+                    out_idx: 0,
+                }),
+                &PendingPegOut {
+                    destination: Address::from_str("bc1qkuzm3093vc7t9q80ul4p5sydkg39sk8gm0park")
+                        .unwrap()
+                        .script_pubkey(),
+                    amount: Amount::from_sat(42_000),
+                    pending_since_block: 0,
+                },
+            )
+            .unwrap();
+        });
+
+        let audit = fed.member(PeerId::from(0)).0.audit();
+        assert_eq!(audit.reserves, Amount::from_sat(100_000));
+        assert_eq!(audit.pending_peg_outs, Amount::from_sat(42_000));
+
+        // The peg-out transaction is created at the end of the first epoch and signed in the second
+        fed.round_with(honest_proposal).await;
+
+        let (honest_wallet, honest_db) = fed.member(PeerId::from(0));
+        let (byzantine_wallet, _) = fed.member(byzantine);
+        let (key, signature) = byzantine_wallet
+            .db
+            .find_by_prefix::<_, PegOutTxSignatureCI, Vec<Signature>>(&PegOutTxSignatureCIPrefix)
+            .next()
+            .expect("No peg-out transaction was created")
+            .unwrap();
+        let txid = key.0;
+
+        let mut psbt = honest_db
+            .get_value::<_, PartiallySignedTransaction>(&UnsignedTransactionKey(txid))
+            .unwrap()
+            .unwrap();
+        let unsigned_psbt = psbt.clone();
+        let byzantine_error = honest_wallet
+            .add_peg_out_signature(
+                &mut psbt,
+                byzantine,
+                &behaviour(PegOutSignatureItem { txid, signature }),
+            )
+            .expect_err("Byzantine signature was accepted");
+        assert_eq!(psbt, unsigned_psbt);
+
+        fed.round_with(honest_proposal).await;
+
+        for (peer, wallet) in fed.honest_members() {
+            let pending_tx = wallet
+                .db
+                .get_value::<_, PendingTransaction>(&PendingTransactionKey(txid))
+                .unwrap()
+                .unwrap_or_else(|| panic!("Peer {} didn't finalize the peg-out", peer));
+            assert_eq!(pending_tx.tx.txid(), txid);
+
+            // Only the change is left, the fees are paid from it
+            let audit = wallet.audit();
+            assert_eq!(audit.pending_peg_outs, Amount::from_sat(0));
+            assert_eq!(audit.utxos.len(), 1);
+            assert!(audit.utxos[0].peg_out_change);
+            assert_eq!(audit.utxos[0].out_point.txid, txid);
+            assert!(audit.reserves < Amount::from_sat(100_000 - 42_000));
+        }
+
+        byzantine_error
+    }
+
+    #[tokio::test]
+    async fn test_peg_out_with_invalid_signature() {
+        let error = peg_out_with_byzantine_peer(|mut sig| {
+            let secp = secp256k1::Secp256k1::new();
+            let (sk, _) = secp.generate_keypair(&mut rand::rngs::OsRng::new().unwrap());
+            let msg = secp256k1::Message::from_slice(&[0x42; 32]).unwrap();
+            sig.signature = vec![secp.sign(&msg, &sk); sig.signature.len()];
+            sig
+        })
+        .await;
+        assert!(matches!(error, ProcessPegOutSigError::InvalidSignature));
+    }
+
+    #[tokio::test]
+    async fn test_peg_out_with_wrong_signature_count() {
+        let error = peg_out_with_byzantine_peer(|mut sig| {
+            sig.signature.push(sig.signature[0]);
+            sig
+        })
+        .await;
+        assert!(matches!(
+            error,
+            ProcessPegOutSigError::WrongSignatureCount(1, 2)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sweep_legacy_utxos() {
+        let peers = (0..PEERS).map(PeerId::from).collect::<Vec<_>>();
+        let (legacy_cfg, _) = WalletConfig::trusted_dealer_gen(
+            &peers,
+            MAX_EVIL,
+            &(),
+            rand::rngs::OsRng::new().unwrap(),
+        );
+        let mut fed = build_fed_with(|peer, cfg| {
+            cfg.legacy_peg_in = Some(LegacyPegInConfig {
+                peg_in_descriptor: legacy_cfg[&peer].peg_in_descriptor.clone(),
+                peer_peg_in_keys: legacy_cfg[&peer].peer_peg_in_keys.clone(),
+                peg_in_key: Some(legacy_cfg[&peer].peg_in_key),
+            });
+        });
+
+        let secp = secp256k1::Secp256k1::new();
+        let tweak = secp256k1::schnorrsig::PublicKey::from_slice(&[0x02; 32][..]).unwrap();
+        let legacy_outpoint = OutPoint::new(BitcoinHash::from_slice(&[1u8; 32]).unwrap(), 1);
+        fed.patch_dbs(|peer, db| {
+            db.insert_entry(
+                &UTXOKey(legacy_outpoint),
+                &SpendableUTXO {
+                    tweak,
+                    amount: Amount::from_sat(100_000),
+                    script_pubkey: legacy_cfg[&peer]
+                        .peg_in_descriptor
+                        .tweak(&tweak, &secp)
+                        .script_pubkey(),
+                },
+            )
+            .unwrap();
+        });
+
+        // The sweep is created at the end of the first epoch and signed in the second
+        fed.round_with(honest_proposal).await;
+        fed.round_with(honest_proposal).await;
+
+        for (peer, wallet) in fed.honest_members() {
+            assert!(wallet.legacy_utxos().is_empty());
+            let utxos = wallet.available_utxos();
+            assert_eq!(utxos.len(), 1, "Peer {} didn't sweep", peer);
+
+            let (UTXOKey(outpoint), utxo) = &utxos[0];
+            assert!(utxo.amount < Amount::from_sat(100_000));
+            assert_eq!(
+                utxo.script_pubkey,
+                wallet
+                    .cfg
+                    .peg_in_descriptor
+                    .tweak(&utxo.tweak, &secp)
+                    .script_pubkey()
+            );
+            assert!(wallet
+                .db
+                .get_value::<_, PendingTransaction>(&PendingTransactionKey(outpoint.txid))
+                .unwrap()
+                .is_some());
+
+            // The swept output is already a UTXO and mustn't be counted as change again
+            let audit = wallet.audit();
+            assert_eq!(audit.utxos.len(), 1);
+            assert_eq!(audit.reserves, utxo.amount);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_extreme_round_consensus_proposals() {
+        for (height, fee) in [(u32::MAX, u64::MAX), (0, 0)] {
+            let mut fed = build_fed();
+            fed.make_byzantine(PeerId::from(PEERS - 1), move |items| {
+                items
+                    .into_iter()
+                    .map(|item| match item {
+                        WalletConsensusItem::RoundConsensus(_) => {
+                            WalletConsensusItem::RoundConsensus(RoundConsensusItem {
+                                block_height: height,
+                                fee_rate: Feerate { sats_per_kvb: fee },
+                            })
+                        }
+                        item => item,
+                    })
+                    .collect()
+            });
+
+            fed.round_with(honest_proposal).await;
+
+            for (_, wallet) in fed.honest_members() {
+                let round_consensus = wallet.current_round_consensus().unwrap();
+                assert_eq!(round_consensus.block_height, CONSENSUS_HEIGHT);
+                assert_eq!(round_consensus.fee_rate, CONSENSUS_FEE);
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_follow_backend_chain() {
+        let backend = FakeBitcoinBackend::new(Network::Regtest);
+        let mut fed = build_fed_on(&backend, |_, _| {});
+        let finalty_delay = fed.member(PeerId::from(0)).0.cfg.finalty_delay;
+        backend.mine_blocks((CONSENSUS_HEIGHT + finalty_delay + 5) as u64);
+        backend.set_fee_rate(Some(Feerate { sats_per_kvb: 3000 }));
+
+        fed.round().await;
+        for (_, wallet) in fed.honest_members() {
+            let round_consensus = wallet.current_round_consensus().unwrap();
+            assert_eq!(round_consensus.block_height, CONSENSUS_HEIGHT + 5);
+            assert_eq!(round_consensus.fee_rate, Feerate { sats_per_kvb: 3000 });
+            let block_hash = backend
+                .get_block_hash((CONSENSUS_HEIGHT + 5) as u64)
+                .unwrap();
+            assert!(wallet.block_is_known(block_hash));
+        }
+
+        // Reorgs shallower than the finality delay don't affect the federation
+        backend.reorg(3, 5);
+        fed.round().await;
+        for (_, wallet) in fed.honest_members() {
+            let round_consensus = wallet.current_round_consensus().unwrap();
+            assert_eq!(round_consensus.block_height, CONSENSUS_HEIGHT + 7);
+            let block_hash = backend
+                .get_block_hash((CONSENSUS_HEIGHT + 7) as u64)
+                .unwrap();
+            assert!(wallet.block_is_known(block_hash));
+        }
+
+        // Without an estimate from the backend the configured default fee rate is used
+        backend.set_fee_rate(None);
+        fed.round().await;
+        for (_, wallet) in fed.honest_members() {
+            let round_consensus = wallet.current_round_consensus().unwrap();
+            assert_eq!(round_consensus.fee_rate, wallet.cfg.default_fee);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_unreachable_backend() {
+        let backend = FakeBitcoinBackend::new(Network::Regtest);
+        let mut fed = build_fed_on(&backend, |_, _| {});
+        let finalty_delay = fed.member(PeerId::from(0)).0.cfg.finalty_delay;
+        backend.mine_blocks((CONSENSUS_HEIGHT + finalty_delay + 5) as u64);
+
+        // Nobody proposes a block height or fee rate, so the last ones are kept
+        backend.set_unreachable(true);
+        fed.round().await;
+        for (_, wallet) in fed.honest_members() {
+            assert!(wallet
+                .consensus_proposal(rand::rngs::OsRng::new().unwrap())
+                .await
+                .is_empty());
+            let round_consensus = wallet.current_round_consensus().unwrap();
+            assert_eq!(round_consensus.block_height, CONSENSUS_HEIGHT);
+            assert_eq!(round_consensus.fee_rate, CONSENSUS_FEE);
+            assert_ne!(round_consensus.randomness_beacon, Some([0; 32]));
+            assert!(wallet.metrics.rpc_errors.get("get_block_height") > 0);
+        }
+
+        // Proposals resume once the backend is reachable again
+        backend.set_unreachable(false);
+        fed.round().await;
+        for (_, wallet) in fed.honest_members() {
+            let round_consensus = wallet.current_round_consensus().unwrap();
+            assert_eq!(round_consensus.block_height, CONSENSUS_HEIGHT + 5);
+        }
+    }
 
     #[test]
     fn sign_tx() {
@@ -1158,4 +2163,33 @@ mod tests {
         })
         .unwrap()
     }
+
+    #[test]
+    fn test_error_codes() {
+        let cases = vec![
+            (
+                WalletError::WrongNetwork(Network::Bitcoin, Network::Regtest),
+                "wallet_wrong_network",
+            ),
+            (
+                WalletError::RpcErrot(BackendError::UnknownBlock(42)),
+                "wallet_rpc_error",
+            ),
+            (
+                WalletError::UnknownPegInProofBlock(BlockHash::from_inner([0; 32])),
+                "wallet_unknown_peg_in_proof_block",
+            ),
+            (
+                WalletError::PegInProofError(PegInProofError::ScriptDoesNotMatch),
+                "wallet_invalid_peg_in_proof",
+            ),
+            (
+                WalletError::PegInAlreadyClaimed,
+                "wallet_peg_in_already_claimed",
+            ),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.error_code(), code);
+        }
+    }
 }